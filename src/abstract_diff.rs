@@ -0,0 +1,2227 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::lines::{eol_style_fm_counts, EndOfLineStyle, Line, LineIndex, Lines, LinesIfce};
+
+/// How many candidate positions to try between checks of the search
+/// deadline.
+const DEADLINE_CHECK_INTERVAL: usize = 64;
+
+/// The default maximum number of context lines that may be dropped
+/// from each end of a hunk when looking for a compromised position.
+const MAX_CONTEXT_REDN: usize = 2;
+
+/// Limits on how many context lines may be sacrificed from each end of
+/// a hunk when looking for a compromised position.  Asymmetric limits
+/// are useful because leading context mismatches are far riskier for
+/// semantic correctness than trailing ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContextReductionLimits {
+    pub leading: usize,
+    pub trailing: usize,
+}
+
+impl Default for ContextReductionLimits {
+    fn default() -> Self {
+        ContextReductionLimits {
+            leading: MAX_CONTEXT_REDN,
+            trailing: MAX_CONTEXT_REDN,
+        }
+    }
+}
+
+/// How strictly lines must match when searching for the place to put
+/// a hunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WhitespaceHandling {
+    /// Lines must match exactly.
+    #[default]
+    Exact,
+    /// Trailing whitespace is ignored when comparing lines.
+    IgnoreTrailing,
+    /// Any run of whitespace matches any other and leading/trailing
+    /// whitespace is ignored (GNU patch's `--ignore-whitespace`).
+    IgnoreAll,
+}
+
+/// How the sections of a conflict are arranged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictStyle {
+    /// Two sections: the lines the hunk expected and the lines it
+    /// wanted to install.
+    #[default]
+    TwoWay,
+    /// Three sections in "diff3" style: the target's actual lines,
+    /// then (after a "|||||||" marker) the lines the hunk expected,
+    /// then the lines it wanted to install.  Unlike `TwoWay` the
+    /// target's lines are consumed by the conflict rather than left
+    /// after it.
+    Diff3,
+}
+
+/// How the conflict markers bracketing an unplaceable hunk are
+/// rendered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictMarkerStyle {
+    /// How the conflict's sections are arranged.
+    pub style: ConflictStyle,
+    /// The number of '<'/'='/'>' (or '|') characters in each marker.
+    pub marker_length: usize,
+    /// A label appended to the "<<<<<<<" marker.
+    pub ante_label: Option<String>,
+    /// A label appended to the "|||||||" marker of a `Diff3` conflict.
+    pub base_label: Option<String>,
+    /// A label appended to the ">>>>>>>" marker.
+    pub post_label: Option<String>,
+}
+
+impl Default for ConflictMarkerStyle {
+    fn default() -> Self {
+        ConflictMarkerStyle {
+            style: ConflictStyle::default(),
+            marker_length: 7,
+            ante_label: None,
+            base_label: None,
+            post_label: None,
+        }
+    }
+}
+
+impl ConflictMarkerStyle {
+    fn marker(&self, character: char, label: Option<&str>) -> Line {
+        let mut text = character.to_string().repeat(self.marker_length);
+        if let Some(label) = label {
+            text.push(' ');
+            text.push_str(label);
+        }
+        text.push('\n');
+        Arc::new(text)
+    }
+
+    fn ante_marker(&self) -> Line {
+        self.marker('<', self.ante_label.as_deref())
+    }
+
+    fn base_marker(&self) -> Line {
+        self.marker('|', self.base_label.as_deref())
+    }
+
+    fn separator(&self) -> Line {
+        self.marker('=', None)
+    }
+
+    fn post_marker(&self) -> Line {
+        self.marker('>', self.post_label.as_deref())
+    }
+}
+
+/// The knobs controlling an application attempt, gathered into a
+/// builder so that call sites only mention the ones that they care
+/// about.
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    pub(crate) reverse: bool,
+    pub(crate) fuzz: ContextReductionLimits,
+    pub(crate) conflict_markers: ConflictMarkerStyle,
+    pub(crate) structured_conflicts: bool,
+    pub(crate) dry_run: bool,
+    pub(crate) whitespace: WhitespaceHandling,
+    pub(crate) ignore_blank_lines: bool,
+    pub(crate) crlf_tolerant: bool,
+    pub(crate) max_offset: Option<usize>,
+    pub(crate) search_budget: Option<Duration>,
+    pub(crate) sort_hunks: bool,
+    pub(crate) backup_suffix: Option<String>,
+    pub(crate) fix_whitespace: bool,
+}
+
+impl ApplyOptions {
+    pub fn new() -> ApplyOptions {
+        ApplyOptions::default()
+    }
+
+    /// Apply the diff in reverse so that it undoes the change that it
+    /// describes.
+    pub fn reverse(mut self, reverse: bool) -> ApplyOptions {
+        self.reverse = reverse;
+        self
+    }
+
+    /// Fix whitespace errors (strip trailing whitespace, collapse a
+    /// space before a tab in the indentation, drop blank lines added
+    /// at the end of the file) on incoming added lines as "git apply
+    /// --whitespace=fix" does, logging what was fixed.  Ignored when
+    /// applying in reverse, where the "added" lines are the ones
+    /// being removed.
+    pub fn fix_whitespace(mut self, fix_whitespace: bool) -> ApplyOptions {
+        self.fix_whitespace = fix_whitespace;
+        self
+    }
+
+    /// Cap how much leading/trailing context may be sacrificed to
+    /// place a hunk.
+    pub fn fuzz(mut self, limits: ContextReductionLimits) -> ApplyOptions {
+        self.fuzz = limits;
+        self
+    }
+
+    /// Render the conflict markers bracketing unplaceable hunks in
+    /// `style`.
+    pub fn conflict_markers(mut self, style: ConflictMarkerStyle) -> ApplyOptions {
+        self.conflict_markers = style;
+        self
+    }
+
+    /// Record failed hunks as structured `Conflict` entries on the
+    /// result instead of splicing conflict marker lines into the
+    /// output, leaving the target's own lines in place.
+    pub fn structured_conflicts(mut self, structured_conflicts: bool) -> ApplyOptions {
+        self.structured_conflicts = structured_conflicts;
+        self
+    }
+
+    /// Search for and report the fate of every hunk but hand back the
+    /// target's lines unmodified.
+    pub fn dry_run(mut self, dry_run: bool) -> ApplyOptions {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// How strictly lines must match when searching for the place to
+    /// put a hunk.
+    pub fn whitespace(mut self, whitespace: WhitespaceHandling) -> ApplyOptions {
+        self.whitespace = whitespace;
+        self
+    }
+
+    /// Compare lines modulo `\r\n` vs `\n` endings and render the
+    /// replacement lines with the target's ending style, so that
+    /// diffs and targets from differently configured checkouts can be
+    /// combined.
+    pub fn crlf_tolerant(mut self, crlf_tolerant: bool) -> ApplyOptions {
+        self.crlf_tolerant = crlf_tolerant;
+        self
+    }
+
+    /// Skip blank line differences (blank lines present on only one
+    /// side) when locating a hunk's context.
+    pub fn ignore_blank_lines(mut self, ignore_blank_lines: bool) -> ApplyOptions {
+        self.ignore_blank_lines = ignore_blank_lines;
+        self
+    }
+
+    /// Treat a hunk whose only available place is more than
+    /// `max_offset` lines from where its header nominated as not
+    /// merged.
+    pub fn max_offset(mut self, max_offset: usize) -> ApplyOptions {
+        self.max_offset = Some(max_offset);
+        self
+    }
+
+    /// Abandon the relocation search for all hunks combined (marking
+    /// the affected hunks as not merged) once `budget` has been
+    /// exhausted.
+    pub fn search_budget(mut self, budget: Duration) -> ApplyOptions {
+        self.search_budget = Some(budget);
+        self
+    }
+
+    /// Sort any hunks found to be out of ascending order by their
+    /// start indices before application (the reordering is reported
+    /// either way).
+    pub fn sort_hunks(mut self, sort_hunks: bool) -> ApplyOptions {
+        self.sort_hunks = sort_hunks;
+        self
+    }
+
+    /// Save the pre-application content of each file that is actually
+    /// changed to `<file><suffix>` before overwriting it (a la
+    /// `patch -b`).  Only filesystem application honours this (see
+    /// `Patch::apply_to_directory`).
+    pub fn backup(mut self, suffix: &str) -> ApplyOptions {
+        self.backup_suffix = Some(suffix.to_string());
+        self
+    }
+
+    /// Do these options leave line comparison exact, so that a hash
+    /// index of the target's lines can stand in for comparing them?
+    pub(crate) fn lines_compare_exactly(&self) -> bool {
+        self.whitespace == WhitespaceHandling::Exact
+            && !self.crlf_tolerant
+            && !self.ignore_blank_lines
+    }
+}
+
+/// `line` shorn of its (`\r\n` or `\n`) line ending.
+fn trimmed_of_eol(line: &str) -> &str {
+    line.strip_suffix("\r\n")
+        .or_else(|| line.strip_suffix('\n'))
+        .unwrap_or(line)
+}
+
+/// Do `line` and `other` match under `options`?
+fn lines_match(line: &str, other: &str, options: &ApplyOptions) -> bool {
+    let (line, other) = if options.crlf_tolerant {
+        (trimmed_of_eol(line), trimmed_of_eol(other))
+    } else {
+        (line, other)
+    };
+    match options.whitespace {
+        WhitespaceHandling::Exact => line == other,
+        WhitespaceHandling::IgnoreTrailing => line.trim_end() == other.trim_end(),
+        WhitespaceHandling::IgnoreAll => line.split_whitespace().eq(other.split_whitespace()),
+    }
+}
+
+/// Does `lines[index..]` start with `sub_lines` under `options`?
+fn sub_lines_match_at(
+    lines: &[Line],
+    sub_lines: &[Line],
+    index: usize,
+    options: &ApplyOptions,
+) -> bool {
+    if index + sub_lines.len() > lines.len() {
+        return false;
+    }
+    if options.whitespace == WhitespaceHandling::Exact && !options.crlf_tolerant {
+        lines[index..index + sub_lines.len()] == sub_lines[..]
+    } else {
+        sub_lines
+            .iter()
+            .enumerate()
+            .all(|(offset, sub_line)| lines_match(&lines[index + offset], sub_line, options))
+    }
+}
+
+/// Is `line` blank (empty or whitespace only)?
+fn line_is_blank(line: &str) -> bool {
+    line.trim().is_empty()
+}
+
+/// The number of lines of `lines` (from `index`) that `sub_lines`
+/// matches under `options`, or `None` if it doesn't match there.  With
+/// blank line tolerance the two may differ in length.
+fn match_len_at(
+    lines: &[Line],
+    sub_lines: &[Line],
+    index: usize,
+    options: &ApplyOptions,
+) -> Option<usize> {
+    if !options.ignore_blank_lines {
+        return if sub_lines_match_at(lines, sub_lines, index, options) {
+            Some(sub_lines.len())
+        } else {
+            None
+        };
+    }
+    let mut line_index = index;
+    let mut sub_index = 0;
+    while sub_index < sub_lines.len() {
+        if line_index < lines.len()
+            && lines_match(&lines[line_index], &sub_lines[sub_index], options)
+        {
+            line_index += 1;
+            sub_index += 1;
+        } else if line_is_blank(&sub_lines[sub_index]) {
+            sub_index += 1;
+        } else if line_index < lines.len() && line_is_blank(&lines[line_index]) {
+            line_index += 1;
+        } else {
+            return None;
+        }
+    }
+    Some(line_index - index)
+}
+
+/// `line` rendered with the target's `eol_style` (`None` when the
+/// endings are to be left alone).
+fn line_with_eol_style(line: &Line, eol_style: Option<EndOfLineStyle>) -> Line {
+    match eol_style {
+        Some(EndOfLineStyle::CrLf) => {
+            if line.ends_with('\n') && !line.ends_with("\r\n") {
+                Arc::new(format!("{}\r\n", &line[..line.len() - 1]))
+            } else {
+                Arc::clone(line)
+            }
+        }
+        Some(EndOfLineStyle::Lf) => {
+            if line.ends_with("\r\n") {
+                Arc::new(format!("{}\n", &line[..line.len() - 2]))
+            } else {
+                Arc::clone(line)
+            }
+        }
+        _ => Arc::clone(line),
+    }
+}
+
+/// A diff chunk reduced to its essentials: where it starts in its file
+/// and the lines (context included) that it covers.
+#[derive(Debug, Clone)]
+pub struct AbstractChunk {
+    pub start_index: usize,
+    pub lines: Lines,
+}
+
+impl AbstractChunk {
+    /// Do `lines` match this chunk's lines (under `options`) at its
+    /// nominal start index adjusted by `offset`?
+    fn matches_lines(&self, lines: &[Line], offset: isize, options: &ApplyOptions) -> bool {
+        let start_index = self.start_index as isize + offset;
+        if start_index < 0 {
+            return false;
+        }
+        match_len_at(lines, &self.lines, start_index as usize, options).is_some()
+    }
+
+    /// Does this chunk's file end without a final newline?  The
+    /// unterminated last line is how a source's "\ No newline at end
+    /// of file" marker is represented once parsed.
+    pub fn ends_without_newline(&self) -> bool {
+        self.lines.last().is_some_and(|line| !line.ends_with('\n'))
+    }
+}
+
+/// Where a hunk was actually placed within the target file and how much
+/// context was sacrificed at each end in order to place it.
+#[derive(Debug, Clone, Copy)]
+pub struct AppliedPosnData {
+    start_posn: usize,
+    /// How many target lines the (possibly context reduced) ante chunk
+    /// matched: with blank line tolerance this may differ from the
+    /// number of lines that it quotes.
+    matched_len: usize,
+    ante_redn: usize,
+    post_redn: usize,
+}
+
+/// What an interactive applier is shown when it is asked to decide a
+/// hunk's fate.
+#[derive(Debug)]
+pub struct HunkView<'a> {
+    /// The hunk's (one based) position within its diff.
+    pub hunk_num: usize,
+    pub hunk: &'a AbstractHunk,
+}
+
+/// An interactive applier's decision on a hunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HunkDecision {
+    Apply,
+    Skip,
+    /// Skip this hunk and all of the hunks that follow it.
+    Quit,
+}
+
+/// The outcome of searching for somewhere to place a hunk.
+#[derive(Debug)]
+pub enum SearchOutcome {
+    Found(AppliedPosnData),
+    NotFound,
+    /// The search's time budget expired before the target was
+    /// exhausted.
+    SearchTimedOut,
+    /// The (remaining) target is shorter than the hunk so there was
+    /// nowhere to search.
+    TargetTooShort,
+}
+
+/// The multiplier of the Rabin-Karp polynomial rolling hash (an
+/// arbitrary odd constant so that multiplication modulo 2^64 is
+/// invertible).
+const ROLLING_BASE: u64 = 6364136223846793005;
+
+/// The hash of `line` under the equivalence that `options` impose on
+/// line comparison: lines that `lines_match` hash equally.
+fn line_key(line: &str, options: &ApplyOptions) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    let mut hasher = DefaultHasher::new();
+    let line = if options.crlf_tolerant {
+        trimmed_of_eol(line)
+    } else {
+        line
+    };
+    match options.whitespace {
+        WhitespaceHandling::Exact => line.hash(&mut hasher),
+        WhitespaceHandling::IgnoreTrailing => line.trim_end().hash(&mut hasher),
+        WhitespaceHandling::IgnoreAll => {
+            for word in line.split_whitespace() {
+                word.hash(&mut hasher);
+            }
+        }
+    }
+    hasher.finish()
+}
+
+/// The per line hash keys of a target's lines under the options'
+/// comparison equivalence, computed once so that every placement
+/// search (one per context reduction level per hunk) can roll a
+/// Rabin-Karp window over them instead of re-comparing lines.
+struct RollingHashLines {
+    keys: Vec<u64>,
+}
+
+impl RollingHashLines {
+    fn new(lines: &[Line], options: &ApplyOptions) -> RollingHashLines {
+        RollingHashLines {
+            keys: lines.iter().map(|line| line_key(line, options)).collect(),
+        }
+    }
+
+    /// The hash of the window of `keys` starting at `index`.
+    fn window_hash(keys: &[u64], index: usize, length: usize) -> u64 {
+        keys[index..index + length].iter().fold(0_u64, |hash, key| {
+            hash.wrapping_mul(ROLLING_BASE).wrapping_add(*key)
+        })
+    }
+
+    /// Find the first position at or after `not_before` (but not after
+    /// `last_candidate`) whose rolling window hash matches that of
+    /// `sub_lines`, verifying candidates against the real lines before
+    /// accepting them.
+    fn find_first_sub_lines(
+        &self,
+        lines: &Lines,
+        sub_lines: &[Line],
+        not_before: usize,
+        last_candidate: usize,
+        deadline: Option<Instant>,
+        options: &ApplyOptions,
+    ) -> SearchOutcome {
+        let length = sub_lines.len();
+        let sub_keys: Vec<u64> = sub_lines
+            .iter()
+            .map(|line| line_key(line, options))
+            .collect();
+        let sub_hash = RollingHashLines::window_hash(&sub_keys, 0, length);
+        let top_weight = ROLLING_BASE.wrapping_pow(length as u32 - 1);
+        let mut hash = RollingHashLines::window_hash(&self.keys, not_before, length);
+        for (count, index) in (not_before..=last_candidate).enumerate() {
+            if count % DEADLINE_CHECK_INTERVAL == 0 {
+                if let Some(deadline) = deadline {
+                    if Instant::now() > deadline {
+                        return SearchOutcome::SearchTimedOut;
+                    }
+                }
+            }
+            if hash == sub_hash && sub_lines_match_at(lines, sub_lines, index, options) {
+                return SearchOutcome::Found(AppliedPosnData {
+                    start_posn: index,
+                    matched_len: length,
+                    ante_redn: 0,
+                    post_redn: 0,
+                });
+            }
+            if index < last_candidate {
+                hash = hash
+                    .wrapping_sub(self.keys[index].wrapping_mul(top_weight))
+                    .wrapping_mul(ROLLING_BASE)
+                    .wrapping_add(self.keys[index + length]);
+            }
+        }
+        SearchOutcome::NotFound
+    }
+}
+
+/// The per target data that accelerates the placement search, chosen
+/// (and computed) once per application to suit the options.
+enum SearchAccel {
+    /// Exact line comparison: a hash index of the target's lines.
+    Indexed(LineIndex),
+    /// Normalized line comparison: Rabin-Karp over per line hashes.
+    Rolling(RollingHashLines),
+    /// Blank line tolerance makes match lengths variable so no fixed
+    /// window technique applies: scan.
+    Scan,
+}
+
+impl SearchAccel {
+    fn new(lines: &Lines, options: &ApplyOptions) -> SearchAccel {
+        if options.lines_compare_exactly() {
+            SearchAccel::Indexed(LineIndex::new(lines))
+        } else if !options.ignore_blank_lines {
+            SearchAccel::Rolling(RollingHashLines::new(lines, options))
+        } else {
+            SearchAccel::Scan
+        }
+    }
+}
+
+/// Find the first occurrence of `sub_lines` in `lines` at or after
+/// `not_before` giving up if `deadline` passes, using whatever
+/// acceleration `accel` provides for the options in force.
+fn find_first_sub_lines_timed(
+    lines: &Lines,
+    sub_lines: &[Line],
+    not_before: usize,
+    accel: &SearchAccel,
+    deadline: Option<Instant>,
+    options: &ApplyOptions,
+) -> SearchOutcome {
+    if sub_lines.is_empty() {
+        return SearchOutcome::NotFound;
+    }
+    let last_candidate = if options.ignore_blank_lines {
+        // With blank line tolerance a match may consume fewer target
+        // lines than the hunk quotes so any start position could do.
+        if not_before >= lines.len() {
+            return SearchOutcome::TargetTooShort;
+        }
+        lines.len() - 1
+    } else {
+        if not_before + sub_lines.len() > lines.len() {
+            return SearchOutcome::TargetTooShort;
+        }
+        lines.len() - sub_lines.len()
+    };
+    match accel {
+        SearchAccel::Indexed(line_index) => {
+            if let Some(deadline) = deadline {
+                if Instant::now() > deadline {
+                    return SearchOutcome::SearchTimedOut;
+                }
+            }
+            match line_index.find_first_sub_lines(sub_lines, not_before) {
+                Some(start_posn) => SearchOutcome::Found(AppliedPosnData {
+                    start_posn,
+                    matched_len: sub_lines.len(),
+                    ante_redn: 0,
+                    post_redn: 0,
+                }),
+                None => SearchOutcome::NotFound,
+            }
+        }
+        SearchAccel::Rolling(rolling) => rolling.find_first_sub_lines(
+            lines,
+            sub_lines,
+            not_before,
+            last_candidate,
+            deadline,
+            options,
+        ),
+        SearchAccel::Scan => {
+            for (count, index) in (not_before..=last_candidate).enumerate() {
+                if count % DEADLINE_CHECK_INTERVAL == 0 {
+                    if let Some(deadline) = deadline {
+                        if Instant::now() > deadline {
+                            return SearchOutcome::SearchTimedOut;
+                        }
+                    }
+                }
+                if let Some(matched_len) = match_len_at(lines, sub_lines, index, options) {
+                    return SearchOutcome::Found(AppliedPosnData {
+                        start_posn: index,
+                        matched_len,
+                        ante_redn: 0,
+                        post_redn: 0,
+                    });
+                }
+            }
+            SearchOutcome::NotFound
+        }
+    }
+}
+
+/// A hunk reduced to its essentials: the chunk it replaces and the
+/// chunk that replaces it.
+#[derive(Debug, Clone)]
+pub struct AbstractHunk {
+    ante_chunk: AbstractChunk,
+    post_chunk: AbstractChunk,
+    heading: Option<String>,
+}
+
+impl AbstractHunk {
+    pub fn new(ante_chunk: AbstractChunk, post_chunk: AbstractChunk) -> AbstractHunk {
+        AbstractHunk {
+            ante_chunk,
+            post_chunk,
+            heading: None,
+        }
+    }
+
+    /// This hunk carrying `heading` as its section heading: the text
+    /// (e.g. the enclosing function) that followed the closing "@@"
+    /// of the hunk's header line.
+    pub fn with_heading(mut self, heading: &str) -> AbstractHunk {
+        self.heading = Some(heading.to_string());
+        self
+    }
+
+    /// The hunk's section heading, if it has one.
+    pub fn heading(&self) -> Option<&str> {
+        self.heading.as_deref()
+    }
+
+    pub fn ante_chunk(&self) -> &AbstractChunk {
+        &self.ante_chunk
+    }
+
+    pub fn post_chunk(&self) -> &AbstractChunk {
+        &self.post_chunk
+    }
+
+    /// The number of context lines at the front and end of this hunk.
+    pub(crate) fn context_lengths(&self) -> (usize, usize) {
+        let ante_lines = &self.ante_chunk.lines;
+        let post_lines = &self.post_chunk.lines;
+        let fm_head = match ante_lines.first_inequality_fm_head(post_lines) {
+            Some(index) => index,
+            None => ante_lines.len().min(post_lines.len()),
+        };
+        let fm_tail = match ante_lines.first_inequality_fm_tail(post_lines) {
+            Some(index) => index,
+            None => ante_lines.len().min(post_lines.len()),
+        };
+        (fm_head, fm_tail.min(ante_lines.len() - fm_head))
+    }
+
+    /// Find a place in `lines` at or after `not_before` where this
+    /// hunk's (possibly context reduced) ante chunk matches.
+    fn get_applied_posn(
+        &self,
+        lines: &Lines,
+        not_before: usize,
+        accel: &SearchAccel,
+        deadline: Option<Instant>,
+        options: &ApplyOptions,
+    ) -> SearchOutcome {
+        let chunk = if options.reverse {
+            &self.post_chunk
+        } else {
+            &self.ante_chunk
+        };
+        if chunk.lines.is_empty() {
+            // A pure insertion quoting no context (e.g. a file creation
+            // hunk) has nothing to search for: it goes at its nominal
+            // index.
+            let start_posn = chunk.start_index.clamp(not_before, lines.len());
+            return SearchOutcome::Found(AppliedPosnData {
+                start_posn,
+                matched_len: 0,
+                ante_redn: 0,
+                post_redn: 0,
+            });
+        }
+        match find_first_sub_lines_timed(lines, &chunk.lines, not_before, accel, deadline, options)
+        {
+            SearchOutcome::NotFound => {
+                self.get_compromised_posn(lines, not_before, accel, deadline, options)
+            }
+            SearchOutcome::TargetTooShort => {
+                // A reduced context version may still fit.
+                match self.get_compromised_posn(lines, not_before, accel, deadline, options) {
+                    SearchOutcome::NotFound => SearchOutcome::TargetTooShort,
+                    outcome => outcome,
+                }
+            }
+            outcome => outcome,
+        }
+    }
+
+    /// Find a place in `lines` at or after `not_before` where this
+    /// hunk matches after sacrificing up to the option's fuzz limits
+    /// of context lines from the respective ends.
+    fn get_compromised_posn(
+        &self,
+        lines: &Lines,
+        not_before: usize,
+        accel: &SearchAccel,
+        deadline: Option<Instant>,
+        options: &ApplyOptions,
+    ) -> SearchOutcome {
+        let chunk = if options.reverse {
+            &self.post_chunk
+        } else {
+            &self.ante_chunk
+        };
+        let redn_limits = options.fuzz;
+        let (head_context_len, tail_context_len) = self.context_lengths();
+        let mut last_redns = (0, 0);
+        let mut target_too_short = false;
+        for redn in 1..=redn_limits.leading.max(redn_limits.trailing) {
+            let ante_redn = redn.min(redn_limits.leading).min(head_context_len);
+            let post_redn = redn.min(redn_limits.trailing).min(tail_context_len);
+            if (ante_redn, post_redn) == last_redns {
+                break;
+            }
+            last_redns = (ante_redn, post_redn);
+            if ante_redn + post_redn >= chunk.lines.len() {
+                break;
+            }
+            let sub_lines = &chunk.lines[ante_redn..chunk.lines.len() - post_redn];
+            match find_first_sub_lines_timed(lines, sub_lines, not_before, accel, deadline, options)
+            {
+                SearchOutcome::Found(posn_data) => {
+                    return SearchOutcome::Found(AppliedPosnData {
+                        start_posn: posn_data.start_posn,
+                        matched_len: posn_data.matched_len,
+                        ante_redn,
+                        post_redn,
+                    });
+                }
+                SearchOutcome::SearchTimedOut => return SearchOutcome::SearchTimedOut,
+                SearchOutcome::TargetTooShort => target_too_short = true,
+                SearchOutcome::NotFound => (),
+            }
+        }
+        if target_too_short {
+            SearchOutcome::TargetTooShort
+        } else {
+            SearchOutcome::NotFound
+        }
+    }
+}
+
+/// The closest the target came to matching a hunk that could not be
+/// placed: something for the user to look at, unlike a bare "NOT
+/// MERGED".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NearestMatch {
+    /// The (one based) target line at which the best candidate region
+    /// starts.
+    pub start_posn: usize,
+    /// How many of the hunk's ante lines disagree with the target
+    /// there.
+    pub mismatches: usize,
+}
+
+/// Why a hunk could not be merged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    /// No acceptable place for the hunk was found; the contained
+    /// nearest match (when the target wasn't empty) says where the
+    /// search came closest.
+    NoMatchingPlace(Option<NearestMatch>),
+    /// The (remaining) target is shorter than the hunk.
+    TargetTooShort,
+    /// The placement search's time budget expired.
+    SearchTimedOut,
+}
+
+/// The target region (anywhere in `lines`) that disagrees with the
+/// fewest of `chunk`'s lines, compared under `options`: the candidate
+/// quoted in failure diagnostics.
+fn nearest_match(
+    chunk: &AbstractChunk,
+    lines: &[Line],
+    options: &ApplyOptions,
+) -> Option<NearestMatch> {
+    if chunk.lines.is_empty() || lines.is_empty() {
+        return None;
+    }
+    let mut best: Option<NearestMatch> = None;
+    for start in 0..lines.len() {
+        let mut mismatches = 0_usize;
+        for (offset, line) in chunk.lines.iter().enumerate() {
+            match lines.get(start + offset) {
+                Some(target) if lines_match(target, line, options) => (),
+                _ => mismatches += 1,
+            }
+        }
+        if best.is_none_or(|best| mismatches < best.mismatches) {
+            best = Some(NearestMatch {
+                start_posn: start + 1,
+                mismatches,
+            });
+        }
+    }
+    best
+}
+
+/// Receives structured events describing the progress of an
+/// application attempt, so that e.g. GUI frontends can render them
+/// natively rather than parse preformatted strings.  Every
+/// `io::Write` sink is an `ApplyReporter` which renders the classic
+/// text diagnostics.
+pub trait ApplyReporter {
+    /// The diff's hunks were found to be out of ascending order and
+    /// were (`sorted` true) or were not sorted before application.
+    fn hunks_out_of_order(&mut self, file_path: Option<&Path>, sorted: bool) -> io::Result<()>;
+
+    /// `hunk_num` was merged with its post chunk starting at
+    /// `start_posn` (one based), `offset` lines away from where its
+    /// header nominated and sacrificing the given numbers of context
+    /// lines from its ends.
+    #[allow(clippy::too_many_arguments)]
+    fn hunk_merged(
+        &mut self,
+        file_path: Option<&Path>,
+        hunk_num: usize,
+        start_posn: usize,
+        offset: isize,
+        ante_redn: usize,
+        post_redn: usize,
+    ) -> io::Result<()>;
+
+    /// The target already contained `hunk_num`'s post chunk at
+    /// `start_posn` (one based) so it was left alone.
+    fn hunk_already_applied(
+        &mut self,
+        file_path: Option<&Path>,
+        hunk_num: usize,
+        start_posn: usize,
+    ) -> io::Result<()>;
+
+    /// `hunk_num` could not be merged.
+    fn hunk_failed(
+        &mut self,
+        file_path: Option<&Path>,
+        hunk_num: usize,
+        reason: FailureReason,
+    ) -> io::Result<()>;
+
+    /// The interactive applier declined `hunk_num`.
+    fn hunk_skipped(&mut self, file_path: Option<&Path>, hunk_num: usize) -> io::Result<()>;
+
+    /// The interactive applier abandoned the application at
+    /// `hunk_num`.
+    fn application_abandoned(
+        &mut self,
+        file_path: Option<&Path>,
+        hunk_num: usize,
+    ) -> io::Result<()>;
+}
+
+/// `file_path` as quoted in text diagnostics.
+pub(crate) fn reported_file_path(file_path: Option<&Path>) -> String {
+    match file_path {
+        Some(path) => path.to_string_lossy().to_string(),
+        None => "<unknown file>".to_string(),
+    }
+}
+
+impl<W: io::Write> ApplyReporter for W {
+    fn hunks_out_of_order(&mut self, file_path: Option<&Path>, sorted: bool) -> io::Result<()> {
+        if sorted {
+            writeln!(
+                self,
+                "{}: hunks not in ascending order: sorted before application.",
+                reported_file_path(file_path)
+            )
+        } else {
+            writeln!(
+                self,
+                "{}: Warning: hunks not in ascending order.",
+                reported_file_path(file_path)
+            )
+        }
+    }
+
+    fn hunk_merged(
+        &mut self,
+        file_path: Option<&Path>,
+        hunk_num: usize,
+        start_posn: usize,
+        offset: isize,
+        ante_redn: usize,
+        post_redn: usize,
+    ) -> io::Result<()> {
+        if ante_redn > 0 || post_redn > 0 {
+            writeln!(
+                self,
+                "{}: Hunk #{} merged at {} with reduced context (leading {}, trailing {}).",
+                reported_file_path(file_path),
+                hunk_num,
+                start_posn,
+                ante_redn,
+                post_redn
+            )
+        } else if offset != 0 {
+            writeln!(
+                self,
+                "{}: Hunk #{} merged at {} (offset {} lines).",
+                reported_file_path(file_path),
+                hunk_num,
+                start_posn,
+                offset
+            )
+        } else {
+            // Exact merges are unremarkable.
+            Ok(())
+        }
+    }
+
+    fn hunk_already_applied(
+        &mut self,
+        file_path: Option<&Path>,
+        hunk_num: usize,
+        start_posn: usize,
+    ) -> io::Result<()> {
+        writeln!(
+            self,
+            "{}: Hunk #{} already applied at {}.",
+            reported_file_path(file_path),
+            hunk_num,
+            start_posn
+        )
+    }
+
+    fn hunk_failed(
+        &mut self,
+        file_path: Option<&Path>,
+        hunk_num: usize,
+        reason: FailureReason,
+    ) -> io::Result<()> {
+        match reason {
+            FailureReason::NoMatchingPlace(Some(nearest)) => writeln!(
+                self,
+                "{}: Hunk #{} NOT MERGED (best match at line {}, {} mismatching line{}).",
+                reported_file_path(file_path),
+                hunk_num,
+                nearest.start_posn,
+                nearest.mismatches,
+                if nearest.mismatches == 1 { "" } else { "s" }
+            ),
+            FailureReason::NoMatchingPlace(None) => writeln!(
+                self,
+                "{}: Hunk #{} NOT MERGED.",
+                reported_file_path(file_path),
+                hunk_num
+            ),
+            FailureReason::TargetTooShort => writeln!(
+                self,
+                "{}: Hunk #{} target too short for hunk: NOT MERGED.",
+                reported_file_path(file_path),
+                hunk_num
+            ),
+            FailureReason::SearchTimedOut => writeln!(
+                self,
+                "{}: Hunk #{} placement search timed out: NOT MERGED.",
+                reported_file_path(file_path),
+                hunk_num
+            ),
+        }
+    }
+
+    fn hunk_skipped(&mut self, file_path: Option<&Path>, hunk_num: usize) -> io::Result<()> {
+        writeln!(
+            self,
+            "{}: Hunk #{} skipped.",
+            reported_file_path(file_path),
+            hunk_num
+        )
+    }
+
+    fn application_abandoned(
+        &mut self,
+        file_path: Option<&Path>,
+        hunk_num: usize,
+    ) -> io::Result<()> {
+        writeln!(
+            self,
+            "{}: application abandoned at Hunk #{}.",
+            reported_file_path(file_path),
+            hunk_num
+        )
+    }
+}
+
+/// What happened to one hunk during an application attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HunkOutcome {
+    /// The hunk applied exactly, `offset` lines away from where the
+    /// diff nominated.
+    Clean { offset: isize },
+    /// The hunk applied after sacrificing context lines.
+    Fuzzed { ante_redn: usize, post_redn: usize },
+    /// The target already contained the hunk's post chunk so it was
+    /// left alone.
+    AlreadyApplied,
+    /// The interactive applier declined the hunk (or abandoned the
+    /// application before reaching it).
+    Skipped,
+    /// The hunk could not be placed: `conflict_range` spans the
+    /// conflict markers inserted into the result lines (an empty
+    /// range if the placement search timed out before markers could
+    /// be placed).
+    Failed { conflict_range: (usize, usize) },
+}
+
+/// A failed hunk's conflict captured as data (see
+/// `ApplyOptions::structured_conflicts`) so that merge frontends don't
+/// have to re-parse marker lines out of the output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    /// The target's lines at the place where the hunk expected to
+    /// apply.
+    pub ours: Lines,
+    /// The lines that the hunk wanted to install.
+    pub theirs: Lines,
+    /// Where `ours` sits within the result lines.
+    pub range: Range<usize>,
+}
+
+/// The result of applying a diff to the lines of a file: the patched
+/// lines plus a record of what happened to each hunk along the way
+/// (in application order).
+#[derive(Debug)]
+pub struct ApplnResult {
+    pub(crate) lines: Lines,
+    pub(crate) hunk_outcomes: Vec<HunkOutcome>,
+    pub(crate) conflicts: Vec<Conflict>,
+    pub(crate) rejected_hunks: Vec<AbstractHunk>,
+}
+
+impl ApplnResult {
+    /// Did every hunk that was attempted get merged?
+    pub fn is_successful(&self) -> bool {
+        !self
+            .hunk_outcomes
+            .iter()
+            .any(|outcome| matches!(outcome, HunkOutcome::Failed { .. }))
+    }
+
+    /// The lines of the patched file (conflict markers included for
+    /// any failed hunks).
+    pub fn lines(&self) -> &Lines {
+        &self.lines
+    }
+
+    /// What happened to each hunk, in application order.
+    pub fn hunk_outcomes(&self) -> &[HunkOutcome] {
+        &self.hunk_outcomes
+    }
+
+    /// The structured conflicts recorded for failed hunks (empty
+    /// unless `ApplyOptions::structured_conflicts` was set).
+    pub fn conflicts(&self) -> &[Conflict] {
+        &self.conflicts
+    }
+
+    /// The hunks that could not be placed, each in the orientation in
+    /// which its application was attempted, ready to be rendered as a
+    /// reject stream (see `unified_diff::format_reject_stream`).
+    pub fn rejected_hunks(&self) -> &[AbstractHunk] {
+        &self.rejected_hunks
+    }
+
+    /// The number of hunks that were merged (cleanly or otherwise).
+    pub fn successes(&self) -> usize {
+        self.hunk_outcomes
+            .iter()
+            .filter(|outcome| {
+                matches!(
+                    outcome,
+                    HunkOutcome::Clean { .. }
+                        | HunkOutcome::Fuzzed { .. }
+                        | HunkOutcome::AlreadyApplied
+                )
+            })
+            .count()
+    }
+
+    /// The number of hunks that could not be merged.
+    pub fn failures(&self) -> usize {
+        self.hunk_outcomes
+            .iter()
+            .filter(|outcome| matches!(outcome, HunkOutcome::Failed { .. }))
+            .count()
+    }
+
+    /// The lines of the patched file, consuming the result.
+    pub fn into_lines(self) -> Lines {
+        self.lines
+    }
+
+    /// The text of the patched file, consuming the result.
+    pub fn into_string(self) -> String {
+        self.lines.iter().map(|line| line.as_str()).collect()
+    }
+
+    /// Write the text of the patched file to `w`.
+    pub fn write_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        for line in self.lines.iter() {
+            w.write_all(line.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// A diff reduced to its essentials so that a single application
+/// mechanism can be used regardless of the format it was parsed from.
+#[derive(Debug)]
+pub struct AbstractDiff {
+    hunks: Vec<AbstractHunk>,
+}
+
+impl AbstractDiff {
+    pub fn new(hunks: Vec<AbstractHunk>) -> AbstractDiff {
+        AbstractDiff { hunks }
+    }
+
+    /// Are this diff's hunks in ascending order of their start indices
+    /// within the file that they apply to?  Some broken diff
+    /// generators emit them interleaved which makes the applier
+    /// mis-track its offsets.
+    pub fn hunks_are_ordered(&self, reverse: bool) -> bool {
+        let starts = self.hunks.iter().map(|hunk| {
+            if reverse {
+                hunk.post_chunk.start_index
+            } else {
+                hunk.ante_chunk.start_index
+            }
+        });
+        let mut previous = 0_usize;
+        for start in starts {
+            if start < previous {
+                return false;
+            }
+            previous = start;
+        }
+        true
+    }
+
+    /// Apply this diff to `lines` as directed by `options` returning
+    /// the resulting lines and a record of what happened to each hunk
+    /// along the way.  Diagnostics are written to `reporter` (any
+    /// `io::Write` sink or an `ApplyReporter` implementation) using
+    /// `repd_file_path` to identify the file being patched.  An `Err`
+    /// is only returned if the reporter fails.
+    pub fn apply_to_lines<R: ApplyReporter>(
+        &self,
+        lines: &Lines,
+        reporter: &mut R,
+        repd_file_path: Option<&Path>,
+        options: &ApplyOptions,
+    ) -> io::Result<ApplnResult> {
+        self.apply_to_lines_interactive(lines, reporter, repd_file_path, options, |_| {
+            HunkDecision::Apply
+        })
+    }
+
+    /// As `apply_to_lines` but asking `decide` for each hunk whether
+    /// it should be applied, skipped or (together with all of the
+    /// hunks after it) abandoned, so that callers can drive an
+    /// interactive "apply this hunk?" loop.  Skipped hunks don't make
+    /// the application unsuccessful.
+    pub fn apply_to_lines_interactive<R, F>(
+        &self,
+        lines: &Lines,
+        reporter: &mut R,
+        repd_file_path: Option<&Path>,
+        options: &ApplyOptions,
+        mut decide: F,
+    ) -> io::Result<ApplnResult>
+    where
+        R: ApplyReporter,
+        F: FnMut(&HunkView) -> HunkDecision,
+    {
+        let reverse = options.reverse;
+        let deadline = options.search_budget.map(|budget| Instant::now() + budget);
+        let mut hunks: Vec<&AbstractHunk> = self.hunks.iter().collect();
+        if !self.hunks_are_ordered(reverse) {
+            if options.sort_hunks {
+                hunks.sort_by_key(|hunk| {
+                    if reverse {
+                        hunk.post_chunk.start_index
+                    } else {
+                        hunk.ante_chunk.start_index
+                    }
+                });
+                reporter.hunks_out_of_order(repd_file_path, true)?;
+            } else {
+                reporter.hunks_out_of_order(repd_file_path, false)?;
+            }
+        }
+        let accel = SearchAccel::new(lines, options);
+        let target_eol_style = if options.crlf_tolerant {
+            let crlf = lines.iter().filter(|line| line.ends_with("\r\n")).count();
+            let lf = lines.iter().filter(|line| line.ends_with('\n')).count() - crlf;
+            Some(eol_style_fm_counts(lf, crlf))
+        } else {
+            None
+        };
+        let mut result_lines: Lines = Vec::new();
+        let mut hunk_outcomes: Vec<HunkOutcome> = Vec::with_capacity(hunks.len());
+        let mut conflicts: Vec<Conflict> = Vec::new();
+        let mut rejected_hunks: Vec<AbstractHunk> = Vec::new();
+        let mut current_index = 0_usize;
+        let mut current_offset = 0_isize;
+        for (index, hunk) in hunks.iter().enumerate() {
+            let hunk_num = index + 1;
+            match decide(&HunkView { hunk_num, hunk }) {
+                HunkDecision::Apply => (),
+                HunkDecision::Skip => {
+                    reporter.hunk_skipped(repd_file_path, hunk_num)?;
+                    hunk_outcomes.push(HunkOutcome::Skipped);
+                    continue;
+                }
+                HunkDecision::Quit => {
+                    reporter.application_abandoned(repd_file_path, hunk_num)?;
+                    hunk_outcomes.resize(hunks.len(), HunkOutcome::Skipped);
+                    break;
+                }
+            }
+            let (ante_chunk, post_chunk) = if reverse {
+                (&hunk.post_chunk, &hunk.ante_chunk)
+            } else {
+                (&hunk.ante_chunk, &hunk.post_chunk)
+            };
+            if !ante_chunk.matches_lines(lines, current_offset, options)
+                && post_chunk.matches_lines(lines, current_offset, options)
+            {
+                // The hunk has already been applied so leave the lines alone.
+                let start_index = ((post_chunk.start_index as isize + current_offset) as usize)
+                    .max(current_index);
+                let end_index = (start_index + post_chunk.lines.len()).min(lines.len());
+                let end_index = end_index.max(current_index);
+                for line in lines[current_index..end_index].iter() {
+                    result_lines.push(Arc::clone(line));
+                }
+                current_index = end_index;
+                current_offset += post_chunk.lines.len() as isize - ante_chunk.lines.len() as isize;
+                hunk_outcomes.push(HunkOutcome::AlreadyApplied);
+                reporter.hunk_already_applied(repd_file_path, hunk_num, start_index + 1)?;
+                continue;
+            }
+            let search_outcome =
+                match hunk.get_applied_posn(lines, current_index, &accel, deadline, options) {
+                    SearchOutcome::Found(posn_data)
+                        if exceeds_max_offset(&posn_data, ante_chunk, options.max_offset) =>
+                    {
+                        SearchOutcome::NotFound
+                    }
+                    outcome => outcome,
+                };
+            match search_outcome {
+                SearchOutcome::Found(posn_data) => {
+                    for line in lines[current_index..posn_data.start_posn].iter() {
+                        result_lines.push(Arc::clone(line));
+                    }
+                    let post_end = post_chunk.lines.len() - posn_data.post_redn;
+                    for line in post_chunk.lines[posn_data.ante_redn..post_end].iter() {
+                        result_lines.push(line_with_eol_style(line, target_eol_style));
+                    }
+                    current_index = posn_data.start_posn + posn_data.matched_len;
+                    current_offset = posn_data.start_posn as isize
+                        - posn_data.ante_redn as isize
+                        - ante_chunk.start_index as isize;
+                    if posn_data.ante_redn > 0 || posn_data.post_redn > 0 {
+                        hunk_outcomes.push(HunkOutcome::Fuzzed {
+                            ante_redn: posn_data.ante_redn,
+                            post_redn: posn_data.post_redn,
+                        });
+                    } else {
+                        hunk_outcomes.push(HunkOutcome::Clean {
+                            offset: posn_data.start_posn as isize - ante_chunk.start_index as isize,
+                        });
+                    }
+                    reporter.hunk_merged(
+                        repd_file_path,
+                        hunk_num,
+                        posn_data.start_posn + 1,
+                        current_offset,
+                        posn_data.ante_redn,
+                        posn_data.post_redn,
+                    )?;
+                }
+                outcome @ (SearchOutcome::NotFound | SearchOutcome::TargetTooShort) => {
+                    rejected_hunks.push(AbstractHunk {
+                        ante_chunk: ante_chunk.clone(),
+                        post_chunk: post_chunk.clone(),
+                        heading: hunk.heading.clone(),
+                    });
+                    let expected_index = ((ante_chunk.start_index as isize + current_offset)
+                        .max(current_index as isize)
+                        as usize)
+                        .min(lines.len());
+                    for line in lines[current_index..expected_index].iter() {
+                        result_lines.push(Arc::clone(line));
+                    }
+                    current_index = expected_index;
+                    let conflict_start = result_lines.len();
+                    if options.structured_conflicts {
+                        let ours_end = (current_index + ante_chunk.lines.len()).min(lines.len());
+                        for line in lines[current_index..ours_end].iter() {
+                            result_lines.push(Arc::clone(line));
+                        }
+                        current_index = ours_end;
+                        conflicts.push(Conflict {
+                            ours: result_lines[conflict_start..].to_vec(),
+                            theirs: post_chunk.lines.clone(),
+                            range: conflict_start..result_lines.len(),
+                        });
+                        hunk_outcomes.push(HunkOutcome::Failed {
+                            conflict_range: (conflict_start, result_lines.len()),
+                        });
+                        let reason = if matches!(outcome, SearchOutcome::TargetTooShort) {
+                            FailureReason::TargetTooShort
+                        } else {
+                            FailureReason::NoMatchingPlace(nearest_match(
+                                ante_chunk, lines, options,
+                            ))
+                        };
+                        reporter.hunk_failed(repd_file_path, hunk_num, reason)?;
+                        continue;
+                    }
+                    result_lines.push(options.conflict_markers.ante_marker());
+                    if options.conflict_markers.style == ConflictStyle::Diff3 {
+                        // Quote (and consume) the target's own lines
+                        // before the base section.
+                        let ours_end = (current_index + ante_chunk.lines.len()).min(lines.len());
+                        for line in lines[current_index..ours_end].iter() {
+                            result_lines.push(Arc::clone(line));
+                        }
+                        current_index = ours_end;
+                        result_lines.push(options.conflict_markers.base_marker());
+                    }
+                    for line in ante_chunk.lines.iter() {
+                        result_lines.push(Arc::clone(line));
+                    }
+                    result_lines.push(options.conflict_markers.separator());
+                    for line in post_chunk.lines.iter() {
+                        result_lines.push(Arc::clone(line));
+                    }
+                    result_lines.push(options.conflict_markers.post_marker());
+                    hunk_outcomes.push(HunkOutcome::Failed {
+                        conflict_range: (conflict_start, result_lines.len()),
+                    });
+                    let reason = if matches!(outcome, SearchOutcome::TargetTooShort) {
+                        FailureReason::TargetTooShort
+                    } else {
+                        FailureReason::NoMatchingPlace(nearest_match(ante_chunk, lines, options))
+                    };
+                    reporter.hunk_failed(repd_file_path, hunk_num, reason)?;
+                }
+                SearchOutcome::SearchTimedOut => {
+                    rejected_hunks.push(AbstractHunk {
+                        ante_chunk: ante_chunk.clone(),
+                        post_chunk: post_chunk.clone(),
+                        heading: hunk.heading.clone(),
+                    });
+                    if options.structured_conflicts {
+                        conflicts.push(Conflict {
+                            ours: Vec::new(),
+                            theirs: post_chunk.lines.clone(),
+                            range: result_lines.len()..result_lines.len(),
+                        });
+                    }
+                    hunk_outcomes.push(HunkOutcome::Failed {
+                        conflict_range: (result_lines.len(), result_lines.len()),
+                    });
+                    reporter.hunk_failed(
+                        repd_file_path,
+                        hunk_num,
+                        FailureReason::SearchTimedOut,
+                    )?;
+                }
+            }
+        }
+        for line in lines[current_index..].iter() {
+            result_lines.push(Arc::clone(line));
+        }
+        // An unterminated line is only meaningful as the very last
+        // line of the file: terminate any that trailing context or a
+        // following hunk has left in the interior of the result.
+        for index in 0..result_lines.len().saturating_sub(1) {
+            if !result_lines[index].ends_with('\n') {
+                result_lines[index] = Arc::new(format!("{}\n", result_lines[index]));
+            }
+        }
+        let lines = if options.dry_run {
+            // A dry run reports what would happen but hands back the
+            // target unmodified (so `Failed` conflict ranges don't
+            // refer to real lines).
+            lines.to_vec()
+        } else {
+            result_lines
+        };
+        Ok(ApplnResult {
+            lines,
+            hunk_outcomes,
+            conflicts,
+            rejected_hunks,
+        })
+    }
+}
+
+/// Would accepting `posn_data` place the hunk further from where its
+/// header nominated than `max_offset` allows?
+fn exceeds_max_offset(
+    posn_data: &AppliedPosnData,
+    ante_chunk: &AbstractChunk,
+    max_offset: Option<usize>,
+) -> bool {
+    match max_offset {
+        Some(max_offset) => {
+            let offset = posn_data.start_posn as isize
+                - posn_data.ante_redn as isize
+                - ante_chunk.start_index as isize;
+            offset.unsigned_abs() > max_offset
+        }
+        None => false,
+    }
+}
+
+/// Merge two chunks (in file order) that touch or overlap into one,
+/// or `None` if they don't touch or disagree about the overlap.
+fn merge_chunks(first: &AbstractChunk, second: &AbstractChunk) -> Option<AbstractChunk> {
+    if second.start_index < first.start_index {
+        return None;
+    }
+    let first_end = first.start_index + first.lines.len();
+    if second.start_index > first_end {
+        return None;
+    }
+    let overlap = first_end - second.start_index;
+    if overlap > second.lines.len()
+        || second.lines[..overlap] != first.lines[first.lines.len() - overlap..]
+    {
+        return None;
+    }
+    let mut lines = first.lines.clone();
+    lines.extend(second.lines[overlap..].iter().map(Arc::clone));
+    Some(AbstractChunk {
+        start_index: first.start_index,
+        lines,
+    })
+}
+
+/// Merge two hunks (in file order) whose chunks touch or overlap into
+/// a single equivalent hunk, or `None` if there is a gap between them
+/// or they disagree about the overlapping lines.
+pub fn merge_abstract_hunks(first: &AbstractHunk, second: &AbstractHunk) -> Option<AbstractHunk> {
+    let ante_chunk = merge_chunks(&first.ante_chunk, &second.ante_chunk)?;
+    let post_chunk = merge_chunks(&first.post_chunk, &second.post_chunk)?;
+    Some(AbstractHunk {
+        ante_chunk,
+        post_chunk,
+        // The merged hunk starts where the first did so its heading
+        // is the one that still applies.
+        heading: first.heading.clone().or_else(|| second.heading.clone()),
+    })
+}
+
+/// One step of the shortest edit script between two line sequences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Edit {
+    /// The next line is common to both sequences.
+    Keep,
+    /// The next line of the first sequence is not in the second.
+    Remove,
+    /// The next line of the second sequence is not in the first.
+    Insert,
+}
+
+/// The shortest edit script transforming `ante` into `post` (Myers'
+/// greedy algorithm).
+pub(crate) fn shortest_edit_script(ante: &[Line], post: &[Line]) -> Vec<Edit> {
+    let n = ante.len() as isize;
+    let m = post.len() as isize;
+    let max = n + m;
+    if max == 0 {
+        return Vec::new();
+    }
+    let offset = max;
+    let mut v = vec![0_isize; (2 * max + 1).max(1) as usize];
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    'outer: for d in 0..=max {
+        trace.push(v.clone());
+        let mut k = -d;
+        while k <= d {
+            let mut x = if k == -d
+                || (k != d && v[(offset + k - 1) as usize] < v[(offset + k + 1) as usize])
+            {
+                v[(offset + k + 1) as usize]
+            } else {
+                v[(offset + k - 1) as usize] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && ante[x as usize] == post[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[(offset + k) as usize] = x;
+            if x >= n && y >= m {
+                break 'outer;
+            }
+            k += 2;
+        }
+    }
+    let mut edits: Vec<Edit> = Vec::new();
+    let mut x = n;
+    let mut y = m;
+    for d in (0..trace.len() as isize).rev() {
+        let v = &trace[d as usize];
+        let k = x - y;
+        let prev_k =
+            if k == -d || (k != d && v[(offset + k - 1) as usize] < v[(offset + k + 1) as usize]) {
+                k + 1
+            } else {
+                k - 1
+            };
+        let prev_x = v[(offset + prev_k) as usize];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Keep);
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert);
+                y -= 1;
+            } else {
+                edits.push(Edit::Remove);
+                x -= 1;
+            }
+        }
+    }
+    edits.reverse();
+    edits
+}
+
+/// A maximal run of consecutive non-`Keep` edits: the half open line
+/// ranges that it covers in the ante and post sequences.
+struct ChangeRun {
+    ante_start: usize,
+    ante_end: usize,
+    post_start: usize,
+    post_end: usize,
+}
+
+/// Generate the abstract hunks (quoting `context` lines of context)
+/// that transform `ante` into `post`.  Changes closer together than
+/// two contexts are folded into a single hunk.
+pub fn generate_abstract_hunks(ante: &Lines, post: &Lines, context: usize) -> Vec<AbstractHunk> {
+    let mut runs: Vec<ChangeRun> = Vec::new();
+    let mut x = 0_usize;
+    let mut y = 0_usize;
+    for edit in shortest_edit_script(ante, post) {
+        match edit {
+            Edit::Keep => {
+                x += 1;
+                y += 1;
+            }
+            Edit::Remove | Edit::Insert => {
+                match runs.last_mut() {
+                    Some(run) if run.ante_end == x && run.post_end == y => {
+                        run.ante_end += (edit == Edit::Remove) as usize;
+                        run.post_end += (edit == Edit::Insert) as usize;
+                    }
+                    _ => runs.push(ChangeRun {
+                        ante_start: x,
+                        ante_end: x + (edit == Edit::Remove) as usize,
+                        post_start: y,
+                        post_end: y + (edit == Edit::Insert) as usize,
+                    }),
+                }
+                x += (edit == Edit::Remove) as usize;
+                y += (edit == Edit::Insert) as usize;
+            }
+        }
+    }
+    // Fold together runs whose contexts would touch or overlap.
+    let mut groups: Vec<ChangeRun> = Vec::new();
+    for run in runs {
+        match groups.last_mut() {
+            Some(group) if run.ante_start - group.ante_end <= 2 * context => {
+                group.ante_end = run.ante_end;
+                group.post_end = run.post_end;
+            }
+            _ => groups.push(run),
+        }
+    }
+    groups
+        .iter()
+        .map(|group| {
+            let head = context.min(group.ante_start).min(group.post_start);
+            let tail = context.min(ante.len() - group.ante_end);
+            AbstractHunk::new(
+                AbstractChunk {
+                    start_index: group.ante_start - head,
+                    lines: ante[group.ante_start - head..group.ante_end + tail].to_vec(),
+                },
+                AbstractChunk {
+                    start_index: group.post_start - head,
+                    lines: post[group.post_start - head..group.post_end + tail].to_vec(),
+                },
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn abstract_hunk(ante_start: usize, ante: &str, post_start: usize, post: &str) -> AbstractHunk {
+        AbstractHunk::new(
+            AbstractChunk {
+                start_index: ante_start,
+                lines: Lines::from_string(ante),
+            },
+            AbstractChunk {
+                start_index: post_start,
+                lines: Lines::from_string(post),
+            },
+        )
+    }
+
+    #[test]
+    fn apply_clean_hunk() {
+        let lines = Lines::from_string("a\nb\nc\nd\ne\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "b\nc\nd\n", 1, "b\nx\nd\n")]);
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(*result.lines(), Lines::from_string("a\nb\nx\nd\ne\n"));
+        assert!(err_w.is_empty());
+    }
+
+    #[test]
+    fn apply_reverse_undoes_forward() {
+        let lines = Lines::from_string("a\nb\nx\nd\ne\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "b\nc\nd\n", 1, "b\nx\nd\n")]);
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(
+                &lines,
+                &mut err_w,
+                None,
+                &ApplyOptions::default().reverse(true),
+            )
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(*result.lines(), Lines::from_string("a\nb\nc\nd\ne\n"));
+    }
+
+    #[test]
+    fn apply_relocated_hunk() {
+        let lines = Lines::from_string("new\na\nb\nc\nd\ne\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "b\nc\nd\n", 1, "b\nx\nd\n")]);
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(*result.lines(), Lines::from_string("new\na\nb\nx\nd\ne\n"));
+        assert_eq!(result.hunk_outcomes()[0], HunkOutcome::Clean { offset: 1 });
+        let report = String::from_utf8(err_w).unwrap();
+        assert!(report.contains("Hunk #1 merged at 3 (offset 1 lines)."));
+    }
+
+    #[test]
+    fn apply_failed_hunk_inserts_markers() {
+        let lines = Lines::from_string("p\nq\nr\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "b\nc\nd\n", 1, "b\nx\nd\n")]);
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(!result.is_successful());
+        assert!(result.lines().iter().any(|l| l.starts_with("<<<<<<<")));
+        let report = String::from_utf8(err_w).unwrap();
+        assert!(report.contains("Hunk #1 NOT MERGED (best match at line 1, 3 mismatching lines)."));
+    }
+
+    #[test]
+    fn failed_hunk_reports_its_nearest_match() {
+        let lines = Lines::from_string("p\nq\nb\nC\nd\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(0, "b\nc\nd\n", 0, "b\nx\nd\n")]);
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(!result.is_successful());
+        let report = String::from_utf8(err_w).unwrap();
+        assert!(report.contains("(best match at line 3, 1 mismatching line)."));
+    }
+
+    #[test]
+    fn apply_hunk_bigger_than_target() {
+        let lines = Lines::from_string("a\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(
+            0,
+            "a\nb\nc\nd\ne\nf\ng\n",
+            0,
+            "a\nb\nc\nx\ne\nf\ng\n",
+        )]);
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(!result.is_successful());
+        let report = String::from_utf8(err_w).unwrap();
+        assert!(report.contains("target too short for hunk"));
+    }
+
+    #[test]
+    fn apply_with_asymmetric_reduction_limits() {
+        let lines = Lines::from_string("a\nB\nc\nd\ne\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "b\nc\nd\n", 1, "b\nx\nd\n")]);
+        // The leading context does not match so placement requires a
+        // leading reduction which the limits below forbid.
+        let mut err_w = Vec::new();
+        let limits = ContextReductionLimits {
+            leading: 0,
+            trailing: MAX_CONTEXT_REDN,
+        };
+        let result = diff
+            .apply_to_lines(
+                &lines,
+                &mut err_w,
+                None,
+                &ApplyOptions::default().fuzz(limits),
+            )
+            .unwrap();
+        assert!(!result.is_successful());
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(*result.lines(), Lines::from_string("a\nB\nx\nd\ne\n"));
+        let report = String::from_utf8(err_w).unwrap();
+        assert!(report.contains("reduced context (leading 1"));
+    }
+
+    #[test]
+    fn structured_reporter_receives_events() {
+        #[derive(Default)]
+        struct EventCollector {
+            events: Vec<String>,
+        }
+
+        impl ApplyReporter for EventCollector {
+            fn hunks_out_of_order(&mut self, _: Option<&Path>, sorted: bool) -> io::Result<()> {
+                self.events.push(format!("out_of_order sorted={}", sorted));
+                Ok(())
+            }
+
+            fn hunk_merged(
+                &mut self,
+                _: Option<&Path>,
+                hunk_num: usize,
+                start_posn: usize,
+                offset: isize,
+                ante_redn: usize,
+                post_redn: usize,
+            ) -> io::Result<()> {
+                self.events.push(format!(
+                    "merged #{} at {} offset {} redns {}/{}",
+                    hunk_num, start_posn, offset, ante_redn, post_redn
+                ));
+                Ok(())
+            }
+
+            fn hunk_already_applied(
+                &mut self,
+                _: Option<&Path>,
+                hunk_num: usize,
+                start_posn: usize,
+            ) -> io::Result<()> {
+                self.events
+                    .push(format!("already applied #{} at {}", hunk_num, start_posn));
+                Ok(())
+            }
+
+            fn hunk_failed(
+                &mut self,
+                _: Option<&Path>,
+                hunk_num: usize,
+                reason: FailureReason,
+            ) -> io::Result<()> {
+                self.events
+                    .push(format!("failed #{} {:?}", hunk_num, reason));
+                Ok(())
+            }
+
+            fn hunk_skipped(&mut self, _: Option<&Path>, hunk_num: usize) -> io::Result<()> {
+                self.events.push(format!("skipped #{}", hunk_num));
+                Ok(())
+            }
+
+            fn application_abandoned(
+                &mut self,
+                _: Option<&Path>,
+                hunk_num: usize,
+            ) -> io::Result<()> {
+                self.events.push(format!("abandoned at #{}", hunk_num));
+                Ok(())
+            }
+        }
+
+        let lines = Lines::from_string("a\nb\nc\nd\ne\n");
+        let diff = AbstractDiff::new(vec![
+            abstract_hunk(1, "b\nc\nd\n", 1, "b\nx\nd\n"),
+            abstract_hunk(10, "p\nq\nr\n", 10, "p\nQ\nr\n"),
+        ]);
+        let mut reporter = EventCollector::default();
+        let result = diff
+            .apply_to_lines(&lines, &mut reporter, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(!result.is_successful());
+        assert_eq!(
+            reporter.events,
+            vec![
+                "merged #1 at 2 offset 0 redns 0/0".to_string(),
+                "failed #2 TargetTooShort".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn appln_result_records_hunk_outcomes() {
+        let lines = Lines::from_string("a\nB\nc\nd\ne\nf\ng\nh\ni\n");
+        let diff = AbstractDiff::new(vec![
+            abstract_hunk(1, "b\nc\nd\n", 1, "b\nx\nd\n"),
+            abstract_hunk(5, "f\ng\nh\n", 5, "f\nY\nh\n"),
+            abstract_hunk(20, "p\nq\nr\n", 20, "p\nQ\nr\n"),
+        ]);
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(!result.is_successful());
+        assert_eq!(
+            result.hunk_outcomes()[0],
+            HunkOutcome::Fuzzed {
+                ante_redn: 1,
+                post_redn: 1
+            }
+        );
+        assert_eq!(result.hunk_outcomes()[1], HunkOutcome::Clean { offset: 0 });
+        if let HunkOutcome::Failed { conflict_range } = result.hunk_outcomes()[2] {
+            assert!(result.lines()[conflict_range.0].starts_with("<<<<<<<"));
+            assert!(result.lines()[conflict_range.1 - 1].starts_with(">>>>>>>"));
+        } else {
+            panic!("expected a failed outcome: {:?}", result.hunk_outcomes()[2]);
+        }
+        assert_eq!(result.successes(), 2);
+        assert_eq!(result.failures(), 1);
+        let mut out = Vec::new();
+        result.write_to(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), result.into_string());
+    }
+
+    #[test]
+    fn apply_interactively_with_skip_and_quit() {
+        let lines = Lines::from_string("a\nb\nc\nd\ne\nf\ng\nh\n");
+        let diff = AbstractDiff::new(vec![
+            abstract_hunk(0, "a\nb\nc\n", 0, "a\nX\nc\n"),
+            abstract_hunk(4, "e\nf\ng\n", 4, "e\nY\ng\n"),
+        ]);
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines_interactive(
+                &lines,
+                &mut err_w,
+                None,
+                &ApplyOptions::default(),
+                |view: &HunkView| {
+                    if view.hunk_num == 1 {
+                        HunkDecision::Skip
+                    } else {
+                        HunkDecision::Apply
+                    }
+                },
+            )
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(
+            *result.lines(),
+            Lines::from_string("a\nb\nc\nd\ne\nY\ng\nh\n")
+        );
+        let report = String::from_utf8(err_w).unwrap();
+        assert!(report.contains("Hunk #1 skipped."));
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines_interactive(&lines, &mut err_w, None, &ApplyOptions::default(), |_| {
+                HunkDecision::Quit
+            })
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(*result.lines(), lines);
+        let report = String::from_utf8(err_w).unwrap();
+        assert!(report.contains("abandoned at Hunk #1."));
+    }
+
+    #[test]
+    fn apply_out_of_order_hunks_with_sorting() {
+        let lines = Lines::from_string("a\nb\nc\nd\ne\nf\ng\nh\n");
+        let hunks = vec![
+            abstract_hunk(4, "e\nf\ng\n", 4, "e\nY\ng\n"),
+            abstract_hunk(0, "a\nb\nc\n", 0, "a\nX\nc\n"),
+        ];
+        let diff = AbstractDiff::new(hunks);
+        assert!(!diff.hunks_are_ordered(false));
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(
+                &lines,
+                &mut err_w,
+                None,
+                &ApplyOptions::default().sort_hunks(true),
+            )
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(
+            *result.lines(),
+            Lines::from_string("a\nX\nc\nd\ne\nY\ng\nh\n")
+        );
+        let report = String::from_utf8(err_w).unwrap();
+        assert!(report.contains("sorted before application"));
+    }
+
+    #[test]
+    fn apply_with_exhausted_search_budget() {
+        let lines = Lines::from_string("a\nb\nc\nd\ne\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "w\nx\ny\n", 1, "w\nz\ny\n")]);
+        let mut err_w = Vec::new();
+        let result = diff.apply_to_lines(
+            &lines,
+            &mut err_w,
+            None,
+            &ApplyOptions::default().search_budget(Duration::from_secs(0)),
+        );
+        assert!(!result.unwrap().is_successful());
+        let report = String::from_utf8(err_w).unwrap();
+        assert!(report.contains("timed out"));
+    }
+
+    #[test]
+    fn apply_with_ignored_whitespace() {
+        let lines = Lines::from_string("a\nb  \nc\nd\ne\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "b\nc\nd\n", 1, "b\nx\nd\n")]);
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(matches!(
+            result.hunk_outcomes()[0],
+            HunkOutcome::Fuzzed { .. }
+        ));
+        let mut err_w = Vec::new();
+        let options = ApplyOptions::default().whitespace(WhitespaceHandling::IgnoreTrailing);
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &options)
+            .unwrap();
+        assert_eq!(result.hunk_outcomes()[0], HunkOutcome::Clean { offset: 0 });
+        assert_eq!(*result.lines(), Lines::from_string("a\nb\nx\nd\ne\n"));
+    }
+
+    #[test]
+    fn apply_with_whitespace_insensitive_matching() {
+        // The target has been re-indented since the diff was generated.
+        let lines = Lines::from_string("a\n    b\n c  d\ne\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "b\nc d\n", 1, "b\nX\n")]);
+        let mut err_w = Vec::new();
+        let options = ApplyOptions::default()
+            .fuzz(ContextReductionLimits {
+                leading: 0,
+                trailing: 0,
+            })
+            .whitespace(WhitespaceHandling::IgnoreAll);
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &options)
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(result.hunk_outcomes()[0], HunkOutcome::Clean { offset: 0 });
+        assert_eq!(*result.lines(), Lines::from_string("a\nb\nX\ne\n"));
+    }
+
+    #[test]
+    fn rolling_hash_search_places_displaced_fuzzy_hunks() {
+        // Trailing whitespace tolerance routes the placement search
+        // through the rolling hash: the hunk is displaced and its
+        // leading context line is wrong so it also needs fuzz.
+        let mut text = String::new();
+        for index in 0..200 {
+            text.push_str(&format!("line {}  \n", index));
+        }
+        let lines = Lines::from_string(&text);
+        let diff = AbstractDiff::new(vec![abstract_hunk(
+            10,
+            "wrong\nline 151\nline 152\nline 153\n",
+            10,
+            "wrong\nline 151\nCHANGED\nline 153\n",
+        )]);
+        let mut err_w = Vec::new();
+        let options = ApplyOptions::default().whitespace(WhitespaceHandling::IgnoreTrailing);
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &options)
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(
+            result.hunk_outcomes()[0],
+            HunkOutcome::Fuzzed {
+                ante_redn: 1,
+                post_redn: 1,
+            }
+        );
+        assert_eq!(*result.lines()[152], *"CHANGED\n");
+    }
+
+    #[test]
+    fn diff3_style_conflict_markers() {
+        let lines = Lines::from_string("p\nq\nr\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "b\nc\n", 1, "b\nx\n")]);
+        let mut err_w = Vec::new();
+        let style = ConflictMarkerStyle {
+            style: ConflictStyle::Diff3,
+            ante_label: Some("ours".to_string()),
+            base_label: Some("base".to_string()),
+            post_label: Some("theirs".to_string()),
+            ..ConflictMarkerStyle::default()
+        };
+        let options = ApplyOptions::default().conflict_markers(style);
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &options)
+            .unwrap();
+        assert!(!result.is_successful());
+        assert_eq!(
+            result.into_string(),
+            "p\n<<<<<<< ours\nq\nr\n||||||| base\nb\nc\n=======\nb\nx\n>>>>>>> theirs\n"
+        );
+    }
+
+    #[test]
+    fn structured_conflict_regions() {
+        let lines = Lines::from_string("p\nq\nr\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "b\nc\n", 1, "b\nx\n")]);
+        let mut err_w = Vec::new();
+        let options = ApplyOptions::default().structured_conflicts(true);
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &options)
+            .unwrap();
+        assert!(!result.is_successful());
+        // The target's own lines are left in place, marker free.
+        assert_eq!(*result.lines(), lines);
+        assert_eq!(result.conflicts().len(), 1);
+        let conflict = &result.conflicts()[0];
+        assert_eq!(conflict.ours, Lines::from_string("q\nr\n"));
+        assert_eq!(conflict.theirs, Lines::from_string("b\nx\n"));
+        assert_eq!(conflict.range, 1..3);
+        assert_eq!(
+            result.hunk_outcomes()[0],
+            HunkOutcome::Failed {
+                conflict_range: (1, 3)
+            }
+        );
+    }
+
+    #[test]
+    fn apply_with_crlf_tolerance() {
+        // The target came from a Windows checkout; the diff did not.
+        let lines = Lines::from_string("a\r\nb\r\nc\r\nd\r\ne\r\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "b\nc\nd\n", 1, "b\nx\nd\n")]);
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(!result.is_successful());
+        let mut err_w = Vec::new();
+        let options = ApplyOptions::default().crlf_tolerant(true);
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &options)
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(result.hunk_outcomes()[0], HunkOutcome::Clean { offset: 0 });
+        assert_eq!(
+            *result.lines(),
+            Lines::from_string("a\r\nb\r\nx\r\nd\r\ne\r\n")
+        );
+    }
+
+    #[test]
+    fn apply_with_blank_line_tolerance() {
+        // The target has gained a blank separator line inside the
+        // hunk's context.
+        let lines = Lines::from_string("a\nb\n\nc\nd\ne\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "b\nc\nd\n", 1, "b\nc\nX\n")]);
+        let strict = ApplyOptions::default().fuzz(ContextReductionLimits {
+            leading: 0,
+            trailing: 0,
+        });
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &strict)
+            .unwrap();
+        assert!(!result.is_successful());
+        let mut err_w = Vec::new();
+        let tolerant = strict.ignore_blank_lines(true);
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &tolerant)
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(result.hunk_outcomes()[0], HunkOutcome::Clean { offset: 0 });
+        assert_eq!(*result.lines(), Lines::from_string("a\nb\nc\nX\ne\n"));
+    }
+
+    #[test]
+    fn apply_with_max_offset() {
+        let lines = Lines::from_string("new\na\nb\nc\nd\ne\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "b\nc\nd\n", 1, "b\nx\nd\n")]);
+        let mut err_w = Vec::new();
+        let options = ApplyOptions::default().max_offset(0);
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &options)
+            .unwrap();
+        assert!(!result.is_successful());
+        let mut err_w = Vec::new();
+        let options = ApplyOptions::default().max_offset(1);
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &options)
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(result.hunk_outcomes()[0], HunkOutcome::Clean { offset: 1 });
+    }
+
+    #[test]
+    fn dry_run_reports_without_modifying() {
+        let lines = Lines::from_string("a\nb\nc\nd\ne\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "b\nc\nd\n", 1, "b\nx\nd\n")]);
+        let mut err_w = Vec::new();
+        let options = ApplyOptions::default().dry_run(true);
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &options)
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(result.hunk_outcomes()[0], HunkOutcome::Clean { offset: 0 });
+        assert_eq!(*result.lines(), lines);
+    }
+
+    #[test]
+    fn styled_conflict_markers() {
+        let lines = Lines::from_string("p\nq\nr\n");
+        let diff = AbstractDiff::new(vec![abstract_hunk(1, "b\nc\nd\n", 1, "b\nx\nd\n")]);
+        let mut err_w = Vec::new();
+        let style = ConflictMarkerStyle {
+            marker_length: 5,
+            ante_label: Some("target".to_string()),
+            post_label: Some("patch".to_string()),
+            ..ConflictMarkerStyle::default()
+        };
+        let options = ApplyOptions::default().conflict_markers(style);
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &options)
+            .unwrap();
+        assert!(!result.is_successful());
+        let expected_ante: Line = Arc::new("<<<<< target\n".to_string());
+        let expected_post: Line = Arc::new(">>>>> patch\n".to_string());
+        assert!(result.lines().contains(&expected_ante));
+        assert!(result.lines().contains(&expected_post));
+    }
+}
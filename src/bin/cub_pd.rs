@@ -0,0 +1,220 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A thin command line wrapper around the library: parse a patch file
+//! and apply, check, summarize, list or reverse it.  Exit status 0
+//! means success, 1 that the patch didn't (or wouldn't) apply cleanly
+//! and 2 a usage, read or parse error.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use cub_pd::abstract_diff::ApplyOptions;
+use cub_pd::lines::DecodingFallback;
+use cub_pd::patch::{diffstat_lines, ChangeKind, Patch, PatchParser, PatchState};
+
+const USAGE: &str = "\
+Usage: cub-pd <command> [options] <patchfile> [<dir>]
+
+Commands:
+  apply    apply the patch to the tree rooted at <dir> (default \".\")
+  check    report whether the patch is applied, unapplied or reversed
+  stat     print a diffstat for the patch
+  ls       list the files the patch touches
+  reverse  print the patch reversed
+
+Options:
+  -p<n>, -p <n>    strip <n> leading path components (default 1)
+  -R, --reverse    apply the patch in reverse (apply only)
+  --dry-run        report what would happen without writing (apply only)
+  --backup <sfx>   keep overwritten files with suffix <sfx> (apply only)
+";
+
+fn die(message: &str) -> ! {
+    eprintln!("cub-pd: {}", message);
+    exit(2);
+}
+
+/// Write `text` to stdout, treating a closed pipe (e.g. "| head") as a
+/// normal way to finish rather than an error.
+fn emit(text: &str) {
+    if let Err(error) = std::io::stdout().write_all(text.as_bytes()) {
+        if error.kind() == std::io::ErrorKind::BrokenPipe {
+            exit(0);
+        }
+        die(&format!("stdout: {}", error));
+    }
+}
+
+struct Invocation {
+    strip: usize,
+    reverse: bool,
+    dry_run: bool,
+    backup: Option<String>,
+    positional: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Invocation {
+    let mut invocation = Invocation {
+        strip: 1,
+        reverse: false,
+        dry_run: false,
+        backup: None,
+        positional: Vec::new(),
+    };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-R" | "--reverse" => invocation.reverse = true,
+            "--dry-run" => invocation.dry_run = true,
+            "--backup" => match iter.next() {
+                Some(suffix) => invocation.backup = Some(suffix.clone()),
+                None => die("--backup needs a suffix"),
+            },
+            "-p" => match iter.next().map(|count| count.parse()) {
+                Some(Ok(strip)) => invocation.strip = strip,
+                _ => die("-p needs a count"),
+            },
+            arg if arg.starts_with("-p") => match arg[2..].parse() {
+                Ok(strip) => invocation.strip = strip,
+                Err(_) => die("-p needs a count"),
+            },
+            arg if arg.starts_with('-') => die(&format!("unknown option: {}", arg)),
+            _ => invocation.positional.push(arg.clone()),
+        }
+    }
+    invocation
+}
+
+/// Read and parse the patch at `path`, dying with a diagnostic if
+/// either step fails.  Non UTF-8 content is decoded Latin-1 style, as
+/// `patch` itself copes with it.
+fn parse_patch(path: &str) -> Patch {
+    let (lines, _) = match cub_pd::lines::read_lines_with_fallback(path, DecodingFallback::Latin1) {
+        Ok(read) => read,
+        Err(error) => die(&format!("{}: {}", path, error)),
+    };
+    match PatchParser::new().parse_lines(&lines) {
+        Ok(patch) => patch,
+        Err(error) => die(&format!("{}: {}", path, error)),
+    }
+}
+
+fn patch_and_dir(invocation: &Invocation) -> (Patch, PathBuf) {
+    let path = match invocation.positional.first() {
+        Some(path) => path,
+        None => die("a patch file is needed"),
+    };
+    if invocation.positional.len() > 2 {
+        die("too many arguments");
+    }
+    let dir = invocation
+        .positional
+        .get(1)
+        .map_or_else(|| PathBuf::from("."), PathBuf::from);
+    (parse_patch(path), dir)
+}
+
+fn apply(invocation: &Invocation) -> i32 {
+    let (patch, dir) = patch_and_dir(invocation);
+    let mut options = ApplyOptions::default()
+        .reverse(invocation.reverse)
+        .dry_run(invocation.dry_run);
+    if let Some(suffix) = &invocation.backup {
+        options = options.backup(suffix);
+    }
+    match patch.apply_to_directory(&dir, invocation.strip, &options) {
+        Ok(report) => {
+            emit(&report.log);
+            i32::from(!report.is_successful())
+        }
+        Err(error) => die(&format!("{}: {}", dir.display(), error)),
+    }
+}
+
+fn check(invocation: &Invocation) -> i32 {
+    let (patch, dir) = patch_and_dir(invocation);
+    let fetch = |file_path: &Path| fs::read(dir.join(file_path)).ok();
+    let state = patch.check_against(invocation.strip, &fetch);
+    let described = match state {
+        PatchState::NotApplied => "not applied",
+        PatchState::FullyApplied => "fully applied",
+        PatchState::AppearsReversed => "appears reversed",
+        PatchState::PartiallyApplied => "partially applied",
+    };
+    emit(&format!("{}\n", described));
+    i32::from(state == PatchState::PartiallyApplied)
+}
+
+fn stat(invocation: &Invocation) -> i32 {
+    let (patch, _) = patch_and_dir(invocation);
+    for line in diffstat_lines(patch.diff_pluses()) {
+        emit(&line);
+    }
+    0
+}
+
+fn ls(invocation: &Invocation) -> i32 {
+    let (patch, _) = patch_and_dir(invocation);
+    for (file_path, change_kind) in patch.touched_files(invocation.strip) {
+        match change_kind {
+            ChangeKind::Added => emit(&format!("A {}\n", file_path.display())),
+            ChangeKind::Deleted => emit(&format!("D {}\n", file_path.display())),
+            ChangeKind::Modified => emit(&format!("M {}\n", file_path.display())),
+            ChangeKind::Renamed(from) => emit(&format!(
+                "R {} -> {}\n",
+                from.display(),
+                file_path.display()
+            )),
+            ChangeKind::Copied(from) => emit(&format!(
+                "C {} -> {}\n",
+                from.display(),
+                file_path.display()
+            )),
+        }
+    }
+    0
+}
+
+fn reverse(invocation: &Invocation) -> i32 {
+    let (patch, _) = patch_and_dir(invocation);
+    for line in patch.reverse().to_lines() {
+        emit(&line);
+    }
+    0
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => die("a command is needed (try --help)"),
+    };
+    if command == "-h" || command == "--help" {
+        emit(USAGE);
+        exit(0);
+    }
+    let invocation = parse_args(&args[1..]);
+    let status = match command {
+        "apply" => apply(&invocation),
+        "check" => check(&invocation),
+        "stat" => stat(&invocation),
+        "ls" => ls(&invocation),
+        "reverse" => reverse(&invocation),
+        _ => die(&format!("unknown command: {} (try --help)", command)),
+    };
+    exit(status);
+}
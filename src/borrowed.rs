@@ -0,0 +1,310 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A zero copy structural view of a patch: preambles, headers and
+//! hunks as `&str` slices into the caller's buffer rather than
+//! `Arc<String>` copies of every line.  Individual diffs can be
+//! upgraded to the owned representation on demand, so only the files
+//! actually worked on cost any allocation.
+
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::diff::{DiffPlus, DiffPlusParser};
+use crate::lines::LinesIfce;
+use crate::preamble::EXTRAS_LABELS;
+use crate::text_diff::{parse_hunk_header, DiffParseError, DiffParseResult, PATH_RE_STR};
+use crate::DiffFormat;
+
+/// Split `text` into its lines with their newline terminators
+/// retained: the borrowed analogue of `Lines::from_string`.
+pub fn split_lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+/// One "@@" hunk as slices into the patch text; the first line is the
+/// "@@" line itself.
+#[derive(Debug)]
+pub struct BorrowedHunk<'a> {
+    pub lines: Vec<&'a str>,
+}
+
+/// One file's diff (with optional git preamble) as slices into the
+/// patch text.
+#[derive(Debug)]
+pub struct BorrowedDiffPlus<'a> {
+    pub preamble_lines: Vec<&'a str>,
+    /// The "---"/"+++" pair (empty for a pure rename or copy).
+    pub header_lines: Vec<&'a str>,
+    pub hunks: Vec<BorrowedHunk<'a>>,
+}
+
+impl BorrowedDiffPlus<'_> {
+    /// The number of patch text lines that this diff occupies.
+    pub fn len(&self) -> usize {
+        self.preamble_lines.len()
+            + self.header_lines.len()
+            + self
+                .hunks
+                .iter()
+                .map(|hunk| hunk.lines.len())
+                .sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Upgrade this view to the owned representation, allocating only
+    /// for this diff's lines.
+    pub fn to_diff_plus(&self) -> DiffParseResult<DiffPlus> {
+        let mut text = String::new();
+        for line in self
+            .preamble_lines
+            .iter()
+            .chain(self.header_lines.iter())
+            .chain(self.hunks.iter().flat_map(|hunk| hunk.lines.iter()))
+        {
+            text.push_str(line);
+        }
+        let lines = crate::lines::Lines::from_string(&text);
+        DiffPlusParser::shared()
+            .get_diff_plus_at(&lines, 0)?
+            .ok_or(DiffParseError::SyntaxError(DiffFormat::Unified, 0))
+    }
+}
+
+/// A whole patch as slices into the patch text.
+#[derive(Debug)]
+pub struct BorrowedPatch<'a> {
+    pub header_lines: Vec<&'a str>,
+    pub diff_pluses: Vec<BorrowedDiffPlus<'a>>,
+    pub rubbish: Vec<&'a str>,
+}
+
+struct BorrowedParser {
+    preamble_cre: &'static Regex,
+    extras_cre: &'static Regex,
+    header_cre: &'static Regex,
+}
+
+impl BorrowedParser {
+    fn new() -> BorrowedParser {
+        // The regexes are compiled once per process, not per parse.
+        static PREAMBLE_CRE: OnceLock<Regex> = OnceLock::new();
+        static EXTRAS_CRE: OnceLock<Regex> = OnceLock::new();
+        static HEADER_CRE: OnceLock<Regex> = OnceLock::new();
+        let preamble_cre = PREAMBLE_CRE.get_or_init(|| {
+            let e = format!(
+                r"^diff\s+--git\s+({})\s+({})(\n)?$",
+                PATH_RE_STR, PATH_RE_STR
+            );
+            Regex::new(&e).unwrap()
+        });
+        let extras_cre = EXTRAS_CRE.get_or_init(|| {
+            let e = format!(r"^({})\s+(.+?)\s*(\n)?$", EXTRAS_LABELS);
+            Regex::new(&e).unwrap()
+        });
+        let header_cre = HEADER_CRE.get_or_init(|| Regex::new(r"^--- ").unwrap());
+        BorrowedParser {
+            preamble_cre,
+            extras_cre,
+            header_cre,
+        }
+    }
+
+    /// The hunk starting at `index`, `None` if there isn't one there.
+    /// The body is consumed trusting the "@@" line's counts, exactly
+    /// as the owned parser does.
+    fn get_hunk_at<'a>(
+        &self,
+        lines: &[&'a str],
+        index: usize,
+    ) -> DiffParseResult<Option<BorrowedHunk<'a>>> {
+        let header = match parse_hunk_header(lines[index])? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let ante_length = header.ante_length.unwrap_or(1);
+        let post_length = header.post_length.unwrap_or(1);
+        let start_index = index;
+        let mut index = index + 1;
+        let mut ante_count = 0;
+        let mut post_count = 0;
+        while ante_count < ante_length || post_count < post_length {
+            if index >= lines.len() {
+                return Err(DiffParseError::UnexpectedEndHunk(
+                    DiffFormat::Unified,
+                    index,
+                ));
+            }
+            if lines[index].starts_with('-') {
+                ante_count += 1
+            } else if lines[index].starts_with('+') {
+                post_count += 1
+            } else if lines[index].starts_with(' ') || lines[index] == "\n" {
+                ante_count += 1;
+                post_count += 1
+            } else if !lines[index].starts_with('\\') {
+                return Err(DiffParseError::SyntaxError(DiffFormat::Unified, index));
+            }
+            index += 1;
+        }
+        if index < lines.len() && lines[index].starts_with('\\') {
+            index += 1;
+        }
+        Ok(Some(BorrowedHunk {
+            lines: lines[start_index..index].to_vec(),
+        }))
+    }
+
+    /// The diff (with optional preamble) starting at `index`, `None`
+    /// if there isn't one there.
+    fn get_diff_at<'a>(
+        &self,
+        lines: &[&'a str],
+        index: usize,
+    ) -> DiffParseResult<Option<BorrowedDiffPlus<'a>>> {
+        let mut preamble_lines: Vec<&'a str> = Vec::new();
+        let mut index = index;
+        if self.preamble_cre.is_match(lines[index]) {
+            preamble_lines.push(lines[index]);
+            index += 1;
+            while index < lines.len() && self.extras_cre.is_match(lines[index]) {
+                preamble_lines.push(lines[index]);
+                index += 1;
+            }
+        }
+        let mut header_lines: Vec<&'a str> = Vec::new();
+        if index + 1 < lines.len()
+            && self.header_cre.is_match(lines[index])
+            && lines[index + 1].starts_with("+++ ")
+        {
+            header_lines.push(lines[index]);
+            header_lines.push(lines[index + 1]);
+            index += 2;
+        } else if preamble_lines
+            .iter()
+            .any(|line| line.starts_with("rename from ") || line.starts_with("copy from "))
+        {
+            // A pure rename or copy has no "---"/"+++" section at all.
+            return Ok(Some(BorrowedDiffPlus {
+                preamble_lines,
+                header_lines,
+                hunks: Vec::new(),
+            }));
+        } else {
+            return Ok(None);
+        }
+        let mut hunks: Vec<BorrowedHunk<'a>> = Vec::new();
+        while index < lines.len() {
+            if let Some(hunk) = self.get_hunk_at(lines, index)? {
+                index += hunk.lines.len();
+                hunks.push(hunk);
+            } else {
+                break;
+            }
+        }
+        if hunks.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(BorrowedDiffPlus {
+                preamble_lines,
+                header_lines,
+                hunks,
+            }))
+        }
+    }
+}
+
+/// Parse `text` into a zero copy structural view of its patch.
+pub fn parse_borrowed(text: &str) -> DiffParseResult<BorrowedPatch<'_>> {
+    let lines = split_lines(text);
+    let parser = BorrowedParser::new();
+    let mut header_lines: Vec<&str> = Vec::new();
+    let mut diff_pluses: Vec<BorrowedDiffPlus<'_>> = Vec::new();
+    let mut rubbish: Vec<&str> = Vec::new();
+    let mut index = 0_usize;
+    while index < lines.len() {
+        if let Some(diff_plus) = parser.get_diff_at(&lines, index)? {
+            index += diff_plus.len();
+            diff_pluses.push(diff_plus);
+        } else if diff_pluses.is_empty() {
+            header_lines.push(lines[index]);
+            index += 1;
+        } else {
+            rubbish.push(lines[index]);
+            index += 1;
+        }
+    }
+    Ok(BorrowedPatch {
+        header_lines,
+        diff_pluses,
+        rubbish,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abstract_diff::ApplyOptions;
+    use crate::lines::Lines;
+
+    #[test]
+    fn borrowed_views_slice_the_callers_buffer() {
+        let text = "A patch description.\n\
+                    diff --git a/x b/x\n\
+                    index 0123456..89abcde 100644\n\
+                    --- a/x\n\
+                    +++ b/x\n\
+                    @@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                    --- a/y\n\
+                    +++ b/y\n\
+                    @@ -1,1 +1,1 @@\n-p\n+P\n";
+        let patch = parse_borrowed(text).unwrap();
+        assert_eq!(patch.header_lines, vec!["A patch description.\n"]);
+        assert_eq!(patch.diff_pluses.len(), 2);
+        assert!(patch.rubbish.is_empty());
+        let diff = &patch.diff_pluses[0];
+        assert_eq!(diff.preamble_lines.len(), 2);
+        assert_eq!(diff.header_lines, vec!["--- a/x\n", "+++ b/x\n"]);
+        assert_eq!(diff.hunks.len(), 1);
+        assert_eq!(diff.hunks[0].lines[0], "@@ -1,3 +1,3 @@\n");
+        // The slices borrow the caller's buffer: no line was copied.
+        let range = text.as_bytes().as_ptr_range();
+        for line in diff.hunks[0].lines.iter() {
+            assert!(range.start <= line.as_ptr() && line.as_ptr() < range.end);
+        }
+        // Upgrading one diff allocates just that diff and the result
+        // behaves exactly like the eagerly parsed form.
+        let owned = diff.to_diff_plus().unwrap();
+        assert_eq!(
+            owned.index_blob_ids(),
+            Some(("0123456".to_string(), "89abcde".to_string()))
+        );
+        let owned_diff = owned.diff().unified().unwrap();
+        let mut err_w = Vec::new();
+        let result = owned_diff
+            .apply_to_lines(
+                &Lines::from_string("a\nb\nc\n"),
+                &mut err_w,
+                None,
+                &ApplyOptions::default(),
+            )
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(result.into_string(), "a\nB\nc\n");
+    }
+}
@@ -0,0 +1,1009 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use regex::Regex;
+
+use crate::abstract_diff::{ApplnResult, ApplyOptions, HunkOutcome};
+use crate::lines::{Lines, LinesIfce};
+use crate::patch::strip_path;
+use crate::preamble::{Preamble, PreambleParser};
+use crate::svn_diff::{SvnPropertiesDiff, SvnPropertiesParser};
+use crate::text_diff::{
+    is_dev_null, stripped_path, Consumed, DiffParseResult, PathAndTimestamp, TextDiffHeader,
+    TextDiffParser,
+};
+use crate::unified_diff::{UnifiedDiff, UnifiedDiffParser};
+use crate::DiffFormat;
+
+/// GNU diff's "Binary files X and Y differ" marker: the named files
+/// differ but the patch does not include their content.
+#[derive(Debug, Clone)]
+pub struct BinaryMarker {
+    pub(crate) start_index: usize,
+    pub(crate) lines: Lines,
+    pub(crate) ante_file_path: PathBuf,
+    pub(crate) post_file_path: PathBuf,
+}
+
+impl BinaryMarker {
+    /// The number of lines in the patch file that this marker
+    /// occupies.
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn ante_file_path(&self) -> &PathBuf {
+        &self.ante_file_path
+    }
+
+    pub fn post_file_path(&self) -> &PathBuf {
+        &self.post_file_path
+    }
+
+    /// The ante side's file path after removing `strip` leading
+    /// components (and any leading "./").
+    pub fn ante_path(&self, strip: usize) -> PathBuf {
+        stripped_path(&self.ante_file_path, strip)
+    }
+
+    /// The post side's file path after removing `strip` leading
+    /// components (and any leading "./").
+    pub fn post_path(&self, strip: usize) -> PathBuf {
+        stripped_path(&self.post_file_path, strip)
+    }
+
+    /// This marker with its direction reversed: the file paths
+    /// exchanged.
+    pub(crate) fn reversed(&self) -> BinaryMarker {
+        BinaryMarker {
+            start_index: self.start_index,
+            lines: vec![Arc::new(format!(
+                "Binary files {} and {} differ\n",
+                self.post_file_path.display(),
+                self.ante_file_path.display()
+            ))],
+            ante_file_path: self.post_file_path.clone(),
+            post_file_path: self.ante_file_path.clone(),
+        }
+    }
+}
+
+impl Consumed for BinaryMarker {
+    fn start_index(&self) -> usize {
+        self.start_index
+    }
+
+    fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+}
+
+/// A diff of any of the formats we recognize.
+#[derive(Debug, Clone)]
+pub enum Diff {
+    Unified(UnifiedDiff),
+    /// A binary difference marker: the fact that the file changed
+    /// without the change's content.
+    BinaryMarker(BinaryMarker),
+    /// An svn "Property changes on:" block.
+    SvnProperties(SvnPropertiesDiff),
+}
+
+impl Diff {
+    /// The number of lines in the patch file that this diff occupies.
+    pub fn len(&self) -> usize {
+        match self {
+            Diff::Unified(diff) => diff.len(),
+            Diff::BinaryMarker(marker) => marker.len(),
+            Diff::SvnProperties(properties) => properties.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn header(&self) -> Option<&TextDiffHeader> {
+        match self {
+            Diff::Unified(diff) => Some(diff.header()),
+            Diff::BinaryMarker(_) | Diff::SvnProperties(_) => None,
+        }
+    }
+
+    /// The unified diff within, unless this is a binary difference
+    /// marker.
+    pub fn unified(&self) -> Option<&UnifiedDiff> {
+        match self {
+            Diff::Unified(diff) => Some(diff),
+            Diff::BinaryMarker(_) | Diff::SvnProperties(_) => None,
+        }
+    }
+}
+
+impl Consumed for Diff {
+    fn start_index(&self) -> usize {
+        match self {
+            Diff::Unified(diff) => diff.start_index(),
+            Diff::BinaryMarker(marker) => marker.start_index(),
+            Diff::SvnProperties(properties) => properties.start_index(),
+        }
+    }
+
+    fn line_count(&self) -> usize {
+        self.len()
+    }
+}
+
+/// A user supplied hook for tagging the content type of the file that
+/// a diff touches.
+pub type ContentTagger<'a> = &'a dyn Fn(&DiffPlus) -> Option<String>;
+
+/// Read only access to git style blobs by the ids that "index" lines
+/// quote.
+pub trait BlobProvider {
+    /// The contents of the blob identified by `id`, if it is present.
+    fn fetch_blob(&self, id: &str) -> Option<Vec<u8>>;
+}
+
+/// Access to a git style object database: blobs are fetched and stored
+/// by the ids that "index" lines quote.
+pub trait ObjectStore: BlobProvider {
+    /// Store `content` as a blob and return its id.
+    fn store_blob(&mut self, content: &[u8]) -> String;
+}
+
+/// A diff together with the (optional) preamble that preceded it in
+/// the patch file.
+#[derive(Debug, Clone)]
+pub struct DiffPlus {
+    pub(crate) preamble: Option<Preamble>,
+    pub(crate) diff: Diff,
+}
+
+impl DiffPlus {
+    /// The number of lines in the patch file that this diff and its
+    /// preamble occupy.
+    pub fn len(&self) -> usize {
+        match &self.preamble {
+            Some(preamble) => preamble.len() + self.diff.len(),
+            None => self.diff.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn preamble(&self) -> Option<&Preamble> {
+        self.preamble.as_ref()
+    }
+
+    pub fn diff(&self) -> &Diff {
+        &self.diff
+    }
+
+    /// Choose the best target name for this diff a la POSIX/GNU
+    /// `patch`: among the ante, post and git preamble names (after
+    /// removing `strip` leading components, with "/dev/null" standing
+    /// for a missing side), prefer a name that `exists` says is
+    /// already present, then the name with the fewest path components,
+    /// then the shortest basename, then the shortest name overall.
+    pub fn best_target_name<F: Fn(&Path) -> bool>(&self, strip: usize, exists: F) -> PathBuf {
+        let mut raw: Vec<&PathBuf> = Vec::new();
+        if let Some(header) = self.diff.header() {
+            raw.push(&header.ante_pat.file_path);
+            raw.push(&header.post_pat.file_path);
+        }
+        if let Diff::BinaryMarker(marker) = &self.diff {
+            raw.push(marker.ante_file_path());
+            raw.push(marker.post_file_path());
+        }
+        if let Diff::SvnProperties(properties) = &self.diff {
+            raw.push(properties.file_path());
+        }
+        if let Some(preamble) = &self.preamble {
+            raw.push(preamble.ante_file_path());
+            raw.push(preamble.post_file_path());
+        }
+        let mut candidates: Vec<PathBuf> = Vec::new();
+        for path in raw {
+            if is_dev_null(path) {
+                continue;
+            }
+            let stripped = strip_path(path, strip);
+            if stripped.as_os_str().is_empty() || candidates.contains(&stripped) {
+                continue;
+            }
+            candidates.push(stripped);
+        }
+        let score = |path: &&PathBuf| {
+            (
+                path.components().count(),
+                path.file_name().map_or(0, |name| name.len()),
+                path.as_os_str().len(),
+            )
+        };
+        let existing: Vec<&PathBuf> = candidates.iter().filter(|path| exists(path)).collect();
+        let pool = if existing.is_empty() {
+            candidates.iter().collect::<Vec<&PathBuf>>()
+        } else {
+            existing
+        };
+        pool.into_iter()
+            .min_by_key(score)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// One side's file path reconciled between the preamble and the
+    /// diff's own names: the preamble's name is preferred (it is the
+    /// authoritative one for git diffs) but a "/dev/null" on either
+    /// account means the file does not exist on that side.
+    fn side_path(&self, ante: bool) -> Option<PathBuf> {
+        let header_path = match &self.diff {
+            Diff::Unified(diff) => Some(if ante {
+                &diff.header().ante_pat.file_path
+            } else {
+                &diff.header().post_pat.file_path
+            }),
+            Diff::BinaryMarker(marker) => Some(if ante {
+                marker.ante_file_path()
+            } else {
+                marker.post_file_path()
+            }),
+            Diff::SvnProperties(properties) => Some(properties.file_path()),
+        };
+        if header_path.is_some_and(|path| is_dev_null(path)) {
+            return None;
+        }
+        let preamble_path = self.preamble.as_ref().map(|preamble| {
+            if ante {
+                preamble.ante_file_path()
+            } else {
+                preamble.post_file_path()
+            }
+        });
+        let path = preamble_path.or(header_path)?;
+        if is_dev_null(path) {
+            None
+        } else {
+            Some(path.clone())
+        }
+    }
+
+    /// The ante side's file path after removing `strip` leading
+    /// components (and any leading "./"), reconciling the preamble's
+    /// name with the "---" header's (see `side_path`), or `None` for a
+    /// file creation.
+    pub fn ante_path(&self, strip: usize) -> Option<PathBuf> {
+        self.side_path(true).map(|path| stripped_path(&path, strip))
+    }
+
+    /// The post side's file path after removing `strip` leading
+    /// components (and any leading "./"), reconciling the preamble's
+    /// name with the "+++" header's (see `side_path`), or `None` for a
+    /// file deletion.
+    pub fn post_path(&self, strip: usize) -> Option<PathBuf> {
+        self.side_path(false)
+            .map(|path| stripped_path(&path, strip))
+    }
+
+    /// The path (after removing `strip` leading components) of the
+    /// file this diff leaves behind: the post side, falling back to
+    /// the ante side for a deletion.  `None` only for a diff naming
+    /// "/dev/null" on both sides.
+    pub fn effective_path(&self, strip: usize) -> Option<PathBuf> {
+        self.post_path(strip).or_else(|| self.ante_path(strip))
+    }
+
+    /// The path (as named in the patch) most likely to be useful for
+    /// identifying the touched file's content: the post file unless
+    /// that is "/dev/null".
+    pub(crate) fn tag_path(&self) -> Option<PathBuf> {
+        if let Some(header) = self.diff.header() {
+            for pat in [&header.post_pat, &header.ante_pat].iter() {
+                if !pat.file_path.ends_with("dev/null") {
+                    return Some(pat.file_path.clone());
+                }
+            }
+        }
+        if let Diff::BinaryMarker(marker) = &self.diff {
+            for path in [marker.post_file_path(), marker.ante_file_path()].iter() {
+                if !path.ends_with("dev/null") {
+                    return Some((*path).clone());
+                }
+            }
+        }
+        if let Diff::SvnProperties(properties) = &self.diff {
+            return Some(properties.file_path().clone());
+        }
+        self.preamble
+            .as_ref()
+            .map(|preamble| preamble.post_file_path().clone())
+    }
+
+    /// A tag describing the probable content type of the touched file,
+    /// for use by renderers choosing e.g. syntax highlighters or word
+    /// diff tokenizers.  `user_tagger` (if provided) gets first say;
+    /// otherwise the file's extension is used or, for extensionless
+    /// files, the interpreter named by any "#!" line quoted in the
+    /// diff.
+    pub fn content_tag(&self, user_tagger: Option<ContentTagger>) -> Option<String> {
+        if let Some(tagger) = user_tagger {
+            if let Some(tag) = tagger(self) {
+                return Some(tag);
+            }
+        }
+        if let Some(path) = self.tag_path() {
+            if let Some(extension) = path.extension() {
+                return Some(extension.to_string_lossy().to_string());
+            }
+        }
+        self.shebang_tag()
+    }
+
+    /// The ante and post blob ids quoted by this diff's preamble's
+    /// "index" line, if it has one.
+    pub fn index_blob_ids(&self) -> Option<(String, String)> {
+        let value = self.preamble.as_ref()?.get_extra("index")?;
+        let hashes = value.split_whitespace().next()?;
+        let (ante_id, post_id) = hashes.split_once("..")?;
+        Some((ante_id.to_string(), post_id.to_string()))
+    }
+
+    /// Apply this diff to the ante blob fetched by id from `store`
+    /// (using the preamble's "index" line hashes), store the result as
+    /// a new blob and return its content and id: application to a
+    /// commit without a worktree.  Diagnostics go to `err_w` and
+    /// `None` is returned if the index data or the ante blob is
+    /// unavailable or the diff does not apply cleanly.
+    pub fn apply_to_object_store<O: ObjectStore, W: io::Write>(
+        &self,
+        store: &mut O,
+        reverse: bool,
+        err_w: &mut W,
+    ) -> Option<(Vec<u8>, String)> {
+        let (ante_id, post_id) = match self.index_blob_ids() {
+            Some(ids) => ids,
+            None => {
+                writeln!(err_w, "diff has no usable \"index\" line.").unwrap();
+                return None;
+            }
+        };
+        let fetch_id = if reverse { post_id } else { ante_id };
+        let blob = match store.fetch_blob(&fetch_id) {
+            Some(blob) => blob,
+            None => {
+                writeln!(err_w, "{}: blob not found in object store.", fetch_id).unwrap();
+                return None;
+            }
+        };
+        let lines = Lines::from_string(&String::from_utf8_lossy(&blob));
+        let diff = match &self.diff {
+            Diff::Unified(diff) => diff,
+            Diff::BinaryMarker(_) => {
+                writeln!(err_w, "binary files differ: content not included.").unwrap();
+                return None;
+            }
+            Diff::SvnProperties(_) => {
+                writeln!(err_w, "property changes cannot be applied to a blob.").unwrap();
+                return None;
+            }
+        };
+        let repd_file_path = self.tag_path();
+        let result = diff
+            .apply_to_lines(
+                &lines,
+                err_w,
+                repd_file_path.as_deref(),
+                &ApplyOptions::default().reverse(reverse),
+            )
+            .ok()?;
+        if !result.is_successful() {
+            return None;
+        }
+        let content: Vec<u8> = result
+            .lines()
+            .iter()
+            .flat_map(|line| line.bytes())
+            .collect();
+        let id = store.store_blob(&content);
+        Some((content, id))
+    }
+
+    /// Apply this diff to `lines`, falling back to a three way merge
+    /// when straight application fails and the preamble's "index" line
+    /// names an ante blob that `provider` can supply (a la
+    /// `git am -3`): the ancestor is the ante blob, "theirs" is the
+    /// post blob (reconstructed by clean application if the provider
+    /// doesn't hold it) and conflicts surface as they do for `merge3`.
+    /// Diagnostics go to `err_w`.
+    pub fn apply_to_lines_with_3way_fallback<P: BlobProvider, W: io::Write>(
+        &self,
+        lines: &Lines,
+        provider: &P,
+        err_w: &mut W,
+        options: &ApplyOptions,
+    ) -> io::Result<ApplnResult> {
+        let diff = match &self.diff {
+            Diff::Unified(diff) => diff,
+            Diff::BinaryMarker(_) | Diff::SvnProperties(_) => {
+                // Neither kind carries line content that could be
+                // applied.
+                writeln!(err_w, "no line content to apply.")?;
+                return Ok(ApplnResult {
+                    lines: lines.clone(),
+                    hunk_outcomes: vec![HunkOutcome::Failed {
+                        conflict_range: (0, 0),
+                    }],
+                    conflicts: Vec::new(),
+                    rejected_hunks: Vec::new(),
+                });
+            }
+        };
+        let repd_file_path = self.tag_path();
+        let mut first_try_log: Vec<u8> = Vec::new();
+        let result = diff.apply_to_lines(
+            lines,
+            &mut first_try_log,
+            repd_file_path.as_deref(),
+            options,
+        )?;
+        if result.is_successful() {
+            err_w.write_all(&first_try_log)?;
+            return Ok(result);
+        }
+        let (ante_id, post_id) = match self.index_blob_ids() {
+            Some(ids) => {
+                if options.reverse {
+                    (ids.1, ids.0)
+                } else {
+                    ids
+                }
+            }
+            None => {
+                err_w.write_all(&first_try_log)?;
+                return Ok(result);
+            }
+        };
+        let ancestor = match provider.fetch_blob(&ante_id) {
+            Some(blob) => Lines::from_string(&String::from_utf8_lossy(&blob)),
+            None => {
+                err_w.write_all(&first_try_log)?;
+                writeln!(
+                    err_w,
+                    "{}: blob not available: no three way merge.",
+                    ante_id
+                )?;
+                return Ok(result);
+            }
+        };
+        let theirs = match provider.fetch_blob(&post_id) {
+            Some(blob) => Lines::from_string(&String::from_utf8_lossy(&blob)),
+            None => {
+                // Reconstruct the post image by clean application to
+                // the ancestor.
+                let mut log: Vec<u8> = Vec::new();
+                let reconstruction =
+                    diff.apply_to_lines(&ancestor, &mut log, repd_file_path.as_deref(), options)?;
+                if !reconstruction.is_successful() {
+                    err_w.write_all(&first_try_log)?;
+                    writeln!(
+                        err_w,
+                        "{}: post image could not be reconstructed: no three way merge.",
+                        post_id
+                    )?;
+                    return Ok(result);
+                }
+                reconstruction.into_lines()
+            }
+        };
+        writeln!(
+            err_w,
+            "{}: falling back to three way merge.",
+            crate::abstract_diff::reported_file_path(repd_file_path.as_deref())
+        )?;
+        Ok(crate::merge3::merge3(&ancestor, lines, &theirs, options))
+    }
+
+    /// The interpreter named by a "#!" line quoted in this diff's
+    /// first hunk (which must cover the file's first line), if any.
+    fn shebang_tag(&self) -> Option<String> {
+        let diff = self.diff.unified()?;
+        let hunk = diff.hunks.first()?;
+        if hunk.ante_chunk.start_line_num > 1 && hunk.post_chunk.start_line_num > 1 {
+            return None;
+        }
+        for line in hunk.lines[1..].iter() {
+            let text = line[1..].trim_end_matches('\n');
+            if let Some(shebang) = text.strip_prefix("#!") {
+                let mut words = shebang.split_whitespace();
+                let interpreter = Path::new(words.next()?);
+                let name = interpreter.file_name()?.to_string_lossy().to_string();
+                if name == "env" {
+                    return words.next().map(|word| word.to_string());
+                }
+                return Some(name);
+            }
+        }
+        None
+    }
+}
+
+impl Consumed for DiffPlus {
+    fn start_index(&self) -> usize {
+        match &self.preamble {
+            Some(preamble) => preamble.start_index(),
+            None => self.diff.start_index(),
+        }
+    }
+
+    fn line_count(&self) -> usize {
+        self.len()
+    }
+}
+
+pub struct DiffPlusParser {
+    preamble_parser: PreambleParser,
+    unified_diff_parser: UnifiedDiffParser,
+    svn_properties_parser: SvnPropertiesParser,
+    binary_marker_cre: &'static Regex,
+}
+
+impl Default for DiffPlusParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DiffPlusParser {
+    pub fn new() -> DiffPlusParser {
+        Self::with_preamble_parser(PreambleParser::new())
+    }
+
+    /// A parser recognizing `preamble_parser`'s registered preamble
+    /// kinds instead of the default set.
+    pub fn with_preamble_parser(preamble_parser: PreambleParser) -> DiffPlusParser {
+        // The regexes are compiled once per process, not per parser.
+        static BINARY_MARKER_CRE: OnceLock<Regex> = OnceLock::new();
+        let binary_marker_cre = BINARY_MARKER_CRE
+            .get_or_init(|| Regex::new(r"^Binary files (.+?) and (.+?) differ\s*(\n)?$").unwrap());
+        DiffPlusParser {
+            preamble_parser,
+            unified_diff_parser: UnifiedDiffParser::new(),
+            svn_properties_parser: SvnPropertiesParser::new(),
+            binary_marker_cre,
+        }
+    }
+
+    /// A process wide instance for callers that don't want to carry
+    /// their own.
+    pub fn shared() -> &'static DiffPlusParser {
+        static SHARED: OnceLock<DiffPlusParser> = OnceLock::new();
+        SHARED.get_or_init(DiffPlusParser::new)
+    }
+
+    /// Cross-check every hunk header's declared counts against its
+    /// body: see `UnifiedDiffParser::with_strict_counts`.
+    pub fn with_strict_counts(mut self) -> DiffPlusParser {
+        self.unified_diff_parser = UnifiedDiffParser::new().with_strict_counts();
+        self
+    }
+
+    /// If `lines` contains a diff (with optional preamble) starting at
+    /// `start_index` return it.
+    pub fn get_diff_plus_at(
+        &self,
+        lines: &Lines,
+        start_index: usize,
+    ) -> DiffParseResult<Option<DiffPlus>> {
+        let preamble = self.preamble_parser.get_preamble_at(lines, start_index);
+        let index = match &preamble {
+            Some(preamble) => start_index + preamble.len(),
+            None => start_index,
+        };
+        if let Some(diff) = self.unified_diff_parser.get_diff_at(lines, index)? {
+            Ok(Some(DiffPlus {
+                preamble,
+                diff: Diff::Unified(diff),
+            }))
+        } else if let Some(marker) = self.get_binary_marker_at(lines, index) {
+            Ok(Some(DiffPlus {
+                preamble,
+                diff: Diff::BinaryMarker(marker),
+            }))
+        } else if let Some(properties) = self.svn_properties_parser.get_properties_at(lines, index)
+        {
+            Ok(Some(DiffPlus {
+                preamble,
+                diff: Diff::SvnProperties(properties),
+            }))
+        } else if let Some(preamble) = preamble {
+            // A pure rename or copy has no "---"/"+++" section at all:
+            // synthesize a hunk-less diff so that the file operation is
+            // still represented.
+            if preamble.get_extra("rename from").is_some()
+                || preamble.get_extra("copy from").is_some()
+            {
+                let header = TextDiffHeader {
+                    start_index: index,
+                    lines: Vec::new(),
+                    ante_pat: PathAndTimestamp {
+                        file_path: preamble.ante_file_path().clone(),
+                        time_stamp: None,
+                    },
+                    post_pat: PathAndTimestamp {
+                        file_path: preamble.post_file_path().clone(),
+                        time_stamp: None,
+                    },
+                };
+                let diff = UnifiedDiff {
+                    lines_consumed: 0,
+                    diff_format: DiffFormat::Unified,
+                    header,
+                    hunks: Vec::new(),
+                };
+                Ok(Some(DiffPlus {
+                    preamble: Some(preamble),
+                    diff: Diff::Unified(diff),
+                }))
+            } else {
+                Ok(None)
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// If `lines` contains a GNU diff "Binary files X and Y differ"
+    /// marker at `start_index` return it.
+    fn get_binary_marker_at(&self, lines: &Lines, start_index: usize) -> Option<BinaryMarker> {
+        let line = lines.get(start_index)?;
+        let captures = self.binary_marker_cre.captures(line)?;
+        Some(BinaryMarker {
+            start_index,
+            lines: lines[start_index..start_index + 1].to_vec(),
+            ante_file_path: PathBuf::from(captures.get(1).unwrap().as_str()),
+            post_file_path: PathBuf::from(captures.get(2).unwrap().as_str()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lines::LinesIfce;
+
+    #[test]
+    fn best_target_name_prefers_existing_files() {
+        let parser = DiffPlusParser::new();
+        let diff_plus = parser
+            .get_diff_plus_at(
+                &Lines::from_string("--- foo.orig\n+++ foo\n@@ -1,1 +1,1 @@\n-a\n+A\n"),
+                0,
+            )
+            .unwrap()
+            .unwrap();
+        // Neither exists: the shortest name wins.
+        assert_eq!(
+            diff_plus.best_target_name(0, |_| false),
+            PathBuf::from("foo")
+        );
+        // Only the backup exists: it wins despite being longer.
+        assert_eq!(
+            diff_plus.best_target_name(0, |path| path == Path::new("foo.orig")),
+            PathBuf::from("foo.orig")
+        );
+        // A file creation offers only its post side name.
+        let creation = parser
+            .get_diff_plus_at(
+                &Lines::from_string("--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1,1 @@\n+x\n"),
+                0,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            creation.best_target_name(1, |_| false),
+            PathBuf::from("new.txt")
+        );
+        // Preamble names take part too: with strip 1 the git names
+        // resolve even when the header sides are both "/dev/null"
+        // free but differently prefixed.
+        let with_preamble = parser
+            .get_diff_plus_at(
+                &Lines::from_string(
+                    "diff --git a/sub/x b/sub/x\n\
+                     index 1111111..2222222 100644\n\
+                     --- a/sub/x\n+++ b/sub/x\n@@ -1,1 +1,1 @@\n-a\n+A\n",
+                ),
+                0,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            with_preamble.best_target_name(1, |path| path == Path::new("sub/x")),
+            PathBuf::from("sub/x")
+        );
+    }
+
+    #[test]
+    fn resolved_paths_prefer_the_preamble() {
+        let parser = DiffPlusParser::new();
+        // A plain diff has only its header names to go on.
+        let diff_plus = parser
+            .get_diff_plus_at(
+                &Lines::from_string("--- a/x\n+++ b/x\n@@ -1,1 +1,1 @@\n-a\n+A\n"),
+                0,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(diff_plus.ante_path(1), Some(PathBuf::from("x")));
+        assert_eq!(diff_plus.post_path(1), Some(PathBuf::from("x")));
+        assert_eq!(diff_plus.effective_path(1), Some(PathBuf::from("x")));
+        // A creation's ante side does not exist even though the git
+        // preamble names it.
+        let creation = parser
+            .get_diff_plus_at(
+                &Lines::from_string(
+                    "diff --git a/new.txt b/new.txt\n\
+                     new file mode 100644\n\
+                     index 0000000..2222222\n\
+                     --- /dev/null\n+++ b/new.txt\n@@ -0,0 +1,1 @@\n+x\n",
+                ),
+                0,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(creation.ante_path(1), None);
+        assert_eq!(creation.post_path(1), Some(PathBuf::from("new.txt")));
+        assert_eq!(creation.effective_path(1), Some(PathBuf::from("new.txt")));
+        // A deletion falls back to the ante side.
+        let deletion = parser
+            .get_diff_plus_at(
+                &Lines::from_string("--- a/gone\n+++ /dev/null\n@@ -1,1 +0,0 @@\n-x\n"),
+                0,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(deletion.post_path(1), None);
+        assert_eq!(deletion.effective_path(1), Some(PathBuf::from("gone")));
+        // A pure rename has no "---"/"+++" section: the preamble's
+        // names are all there is.
+        let rename = parser
+            .get_diff_plus_at(
+                &Lines::from_string(
+                    "diff --git a/old b/new\n\
+                     similarity index 100%\n\
+                     rename from old\n\
+                     rename to new\n",
+                ),
+                0,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(rename.ante_path(1), Some(PathBuf::from("old")));
+        assert_eq!(rename.post_path(1), Some(PathBuf::from("new")));
+        assert_eq!(rename.effective_path(1), Some(PathBuf::from("new")));
+    }
+
+    #[test]
+    fn parse_diff_plus_from_test_file() {
+        let lines = Lines::read("test_diffs/test_1.diff").unwrap();
+        let parser = DiffPlusParser::new();
+        let diff_plus = parser.get_diff_plus_at(&lines, 0).unwrap().unwrap();
+        assert!(diff_plus.preamble().is_some());
+        assert_eq!(diff_plus.len(), 12);
+        let diff_plus = parser
+            .get_diff_plus_at(&lines, diff_plus.len())
+            .unwrap()
+            .unwrap();
+        assert!(diff_plus.preamble().is_some());
+    }
+
+    #[test]
+    fn apply_diff_to_object_store() {
+        use std::collections::HashMap;
+
+        struct TestStore {
+            blobs: HashMap<String, Vec<u8>>,
+        }
+
+        impl BlobProvider for TestStore {
+            fn fetch_blob(&self, id: &str) -> Option<Vec<u8>> {
+                self.blobs.get(id).cloned()
+            }
+        }
+
+        impl ObjectStore for TestStore {
+            fn store_blob(&mut self, content: &[u8]) -> String {
+                let id = format!("blob{}", self.blobs.len());
+                self.blobs.insert(id.clone(), content.to_vec());
+                id
+            }
+        }
+
+        let diff_text = "diff --git a/greeting.txt b/greeting.txt\n\
+                         index 1111111..2222222 100644\n\
+                         --- a/greeting.txt\n+++ b/greeting.txt\n\
+                         @@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n";
+        let parser = DiffPlusParser::new();
+        let diff_plus = parser
+            .get_diff_plus_at(&Lines::from_string(diff_text), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            diff_plus.index_blob_ids(),
+            Some(("1111111".to_string(), "2222222".to_string()))
+        );
+        let mut store = TestStore {
+            blobs: HashMap::new(),
+        };
+        store
+            .blobs
+            .insert("1111111".to_string(), b"a\nb\nc\n".to_vec());
+        let mut err_w = Vec::new();
+        let (content, id) = diff_plus
+            .apply_to_object_store(&mut store, false, &mut err_w)
+            .unwrap();
+        assert_eq!(content, b"a\nB\nc\n");
+        assert_eq!(store.fetch_blob(&id), Some(content));
+        let mut err_w = Vec::new();
+        assert!(diff_plus
+            .apply_to_object_store(&mut store, true, &mut err_w)
+            .is_none());
+        assert!(String::from_utf8_lossy(&err_w).contains("not found"));
+    }
+
+    #[test]
+    fn apply_with_three_way_fallback() {
+        use std::collections::HashMap;
+
+        struct TestProvider {
+            blobs: HashMap<String, Vec<u8>>,
+        }
+
+        impl BlobProvider for TestProvider {
+            fn fetch_blob(&self, id: &str) -> Option<Vec<u8>> {
+                self.blobs.get(id).cloned()
+            }
+        }
+
+        let diff_text = "diff --git a/x b/x\n\
+                         index 1111111..2222222 100644\n\
+                         --- a/x\n+++ b/x\n\
+                         @@ -1,5 +1,5 @@\n a\n b\n-c\n+C\n d\n e\n";
+        let parser = DiffPlusParser::new();
+        let diff_plus = parser
+            .get_diff_plus_at(&Lines::from_string(diff_text), 0)
+            .unwrap()
+            .unwrap();
+        // The target has drifted too far from the patch's context for
+        // direct application.
+        let ours = Lines::from_string("a\nX\nY\nd\ne\n");
+        let mut provider = TestProvider {
+            blobs: HashMap::new(),
+        };
+        let options = crate::abstract_diff::ApplyOptions::default().structured_conflicts(true);
+        // Without the ante blob the failed direct result is returned.
+        let mut err_w = Vec::new();
+        let result = diff_plus
+            .apply_to_lines_with_3way_fallback(&ours, &provider, &mut err_w, &options)
+            .unwrap();
+        assert!(!result.is_successful());
+        assert!(String::from_utf8_lossy(&err_w).contains("no three way merge"));
+        // With it the failure becomes a resolvable merge conflict.
+        provider
+            .blobs
+            .insert("1111111".to_string(), b"a\nb\nc\nd\ne\n".to_vec());
+        let mut err_w = Vec::new();
+        let result = diff_plus
+            .apply_to_lines_with_3way_fallback(&ours, &provider, &mut err_w, &options)
+            .unwrap();
+        assert!(String::from_utf8_lossy(&err_w).contains("falling back to three way merge"));
+        assert!(!result.is_successful());
+        assert_eq!(result.conflicts().len(), 1);
+        let conflict = &result.conflicts()[0];
+        assert!(conflict
+            .theirs
+            .contains(&std::sync::Arc::new("C\n".to_string())));
+        assert!(conflict
+            .ours
+            .contains(&std::sync::Arc::new("X\n".to_string())));
+    }
+
+    #[test]
+    fn content_tag_for_diff() {
+        let parser = DiffPlusParser::new();
+        let lines =
+            Lines::from_string("--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1,1 +1,1 @@\n-a\n+b\n");
+        let diff_plus = parser.get_diff_plus_at(&lines, 0).unwrap().unwrap();
+        assert_eq!(diff_plus.content_tag(None), Some("rs".to_string()));
+        let tagger = |_: &DiffPlus| Some("rust".to_string());
+        assert_eq!(
+            diff_plus.content_tag(Some(&tagger)),
+            Some("rust".to_string())
+        );
+        let lines = Lines::from_string(
+            "--- a/bin/doit\n+++ b/bin/doit\n@@ -1,2 +1,2 @@\n #!/usr/bin/env python3\n-a\n+b\n",
+        );
+        let diff_plus = parser.get_diff_plus_at(&lines, 0).unwrap().unwrap();
+        assert_eq!(diff_plus.content_tag(None), Some("python3".to_string()));
+    }
+
+    #[test]
+    fn binary_difference_markers_parse() {
+        let parser = DiffPlusParser::new();
+        let lines = Lines::from_string(
+            "diff --git a/logo.png b/logo.png\n\
+             index 1111111..2222222 100644\n\
+             Binary files a/logo.png and b/logo.png differ\n",
+        );
+        let diff_plus = parser.get_diff_plus_at(&lines, 0).unwrap().unwrap();
+        assert!(diff_plus.preamble().is_some());
+        assert_eq!(diff_plus.len(), 3);
+        let marker = match diff_plus.diff() {
+            Diff::BinaryMarker(marker) => marker,
+            _ => panic!("expected a binary marker"),
+        };
+        assert_eq!(marker.ante_file_path(), &PathBuf::from("a/logo.png"));
+        assert_eq!(marker.post_path(1), PathBuf::from("logo.png"));
+        assert!(diff_plus.diff().unified().is_none());
+        // A bare GNU diff marker needs no preamble.
+        let lines = Lines::from_string("Binary files old/app.bin and new/app.bin differ\n");
+        let diff_plus = parser.get_diff_plus_at(&lines, 0).unwrap().unwrap();
+        assert!(diff_plus.preamble().is_none());
+        assert_eq!(diff_plus.len(), 1);
+        assert_eq!(
+            diff_plus.best_target_name(1, |_| false),
+            PathBuf::from("app.bin")
+        );
+    }
+
+    #[test]
+    fn parsers_are_shareable_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<DiffPlusParser>();
+        assert_send_sync::<crate::preamble::GitPreambleParser>();
+        assert_send_sync::<UnifiedDiffParser>();
+
+        let parser = DiffPlusParser::shared();
+        assert!(std::ptr::eq(parser, DiffPlusParser::shared()));
+        let handles: Vec<_> = (0..4)
+            .map(|index| {
+                std::thread::spawn(move || {
+                    let lines = Lines::from_string(&format!(
+                        "--- a/f{0}\n+++ b/f{0}\n@@ -1,1 +1,1 @@\n-a\n+b\n",
+                        index
+                    ));
+                    DiffPlusParser::shared()
+                        .get_diff_plus_at(&lines, 0)
+                        .unwrap()
+                        .unwrap()
+                        .len()
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 5);
+        }
+    }
+}
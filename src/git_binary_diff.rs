@@ -0,0 +1,534 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parsing and generation of git's "GIT binary patch" sections: a
+//! forward data block and (usually) a reverse block that undoes it,
+//! each holding either the literal post content or a pack style delta,
+//! zlib deflated and base85 encoded onto length prefixed lines.
+
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use crate::git_delta::{create_delta, patch_delta, DeltaError};
+use crate::git_hash::blob_oid;
+use crate::lines::{Line, Lines};
+use crate::text_diff::{Consumed, DiffParseError, DiffParseResult};
+use crate::DiffFormat;
+
+/// The characters that git's base85 encoding maps the values 0 to 84
+/// onto, in order.
+const ENCODE_85: &[u8; 85] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz!#$%&()*+-;<=>?@^_`{|}~";
+
+/// The number of content bytes that a data line may carry: 1 to 26
+/// prefixed 'A' to 'Z' and 27 to 52 prefixed 'a' to 'z'.
+const MAX_BYTES_PER_LINE: usize = 52;
+
+/// Encode up to `MAX_BYTES_PER_LINE` `bytes` as one length prefixed
+/// base85 data line (including its newline).
+fn encode_data_line(bytes: &[u8]) -> String {
+    debug_assert!(!bytes.is_empty() && bytes.len() <= MAX_BYTES_PER_LINE);
+    let prefix = if bytes.len() <= 26 {
+        (b'A' + bytes.len() as u8 - 1) as char
+    } else {
+        (b'a' + bytes.len() as u8 - 27) as char
+    };
+    let mut line = prefix.to_string();
+    for group in bytes.chunks(4) {
+        let mut acc = 0_u32;
+        for index in 0..4 {
+            acc = (acc << 8) | u32::from(*group.get(index).unwrap_or(&0));
+        }
+        let mut encoded = [0_u8; 5];
+        for byte in encoded.iter_mut().rev() {
+            *byte = ENCODE_85[(acc % 85) as usize];
+            acc /= 85;
+        }
+        line.push_str(std::str::from_utf8(&encoded).unwrap());
+    }
+    line.push('\n');
+    line
+}
+
+/// Decode one length prefixed base85 data line (the `line_index` is
+/// for error reports only) appending its content bytes to `data`.
+fn decode_data_line(line: &Line, line_index: usize, data: &mut Vec<u8>) -> DiffParseResult<()> {
+    let syntax_error = || DiffParseError::SyntaxError(DiffFormat::GitBinary, line_index);
+    let text = line.trim_end_matches('\n').as_bytes();
+    let byte_count = match text.first() {
+        Some(prefix @ b'A'..=b'Z') => (prefix - b'A') as usize + 1,
+        Some(prefix @ b'a'..=b'z') => (prefix - b'a') as usize + 27,
+        _ => return Err(syntax_error()),
+    };
+    if text.len() != 1 + byte_count.div_ceil(4) * 5 {
+        return Err(syntax_error());
+    }
+    let mut remaining = byte_count;
+    for group in text[1..].chunks(5) {
+        let mut acc = 0_u32;
+        for byte in group.iter() {
+            let value = ENCODE_85
+                .iter()
+                .position(|encoding| encoding == byte)
+                .ok_or_else(syntax_error)? as u32;
+            acc = acc
+                .checked_mul(85)
+                .and_then(|acc| acc.checked_add(value))
+                .ok_or_else(syntax_error)?;
+        }
+        for _ in 0..remaining.min(4) {
+            data.push((acc >> 24) as u8);
+            acc <<= 8;
+        }
+        remaining = remaining.saturating_sub(4);
+    }
+    Ok(())
+}
+
+/// Zlib deflate `content` (at the default compression level, as git
+/// does).
+fn deflate(content: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(content)
+        .expect("writes to an in-memory buffer cannot fail");
+    encoder
+        .finish()
+        .expect("writes to an in-memory buffer cannot fail")
+}
+
+/// Zlib inflate `deflated` expecting exactly `size` bytes of content
+/// (the `line_index` is for error reports only).
+fn inflate(deflated: &[u8], size: usize, line_index: usize) -> DiffParseResult<Vec<u8>> {
+    let mut content = Vec::with_capacity(size);
+    ZlibDecoder::new(deflated)
+        .read_to_end(&mut content)
+        .map_err(|error| DiffParseError::ZlibError(line_index, error))?;
+    if content.len() != size {
+        return Err(DiffParseError::SyntaxError(
+            DiffFormat::GitBinary,
+            line_index,
+        ));
+    }
+    Ok(content)
+}
+
+/// How one data block of a "GIT binary patch" section encodes its
+/// content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryDataMethod {
+    /// The block holds the target content itself.
+    Literal,
+    /// The block holds a pack style delta from the source content.
+    Delta,
+}
+
+/// One data block of a "GIT binary patch" section: a "literal"/"delta"
+/// method line followed by base85 data lines.
+#[derive(Debug, Clone)]
+pub struct GitBinaryDiffData {
+    pub(crate) start_index: usize,
+    pub(crate) lines: Lines,
+    pub(crate) method: BinaryDataMethod,
+    /// The inflated content of the data lines: the target bytes for a
+    /// "literal" block or the delta buffer for a "delta" block.
+    pub(crate) data: Vec<u8>,
+}
+
+impl Consumed for GitBinaryDiffData {
+    fn start_index(&self) -> usize {
+        self.start_index
+    }
+
+    fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+}
+
+impl GitBinaryDiffData {
+    pub fn method(&self) -> BinaryDataMethod {
+        self.method
+    }
+
+    /// The inflated content of the data lines: the target bytes for a
+    /// "literal" block or the delta buffer for a "delta" block.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// A "GIT binary patch" section: the forward data block and (usually)
+/// the reverse block that undoes it.
+#[derive(Debug, Clone)]
+pub struct GitBinaryDiff {
+    pub(crate) start_index: usize,
+    pub(crate) lines: Lines,
+    pub(crate) forward: GitBinaryDiffData,
+    pub(crate) reverse: Option<GitBinaryDiffData>,
+}
+
+impl Consumed for GitBinaryDiff {
+    fn start_index(&self) -> usize {
+        self.start_index
+    }
+
+    fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+}
+
+/// The ways in which applying a "GIT binary patch" section can fail.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BinaryApplyError {
+    /// Reverse application was asked of a section without a reverse
+    /// data block.
+    NoReverseData,
+    /// The delta buffer would not apply to the given bytes.
+    Delta(DeltaError),
+    /// The given bytes don't hash to the expected source blob OID.
+    SourceHashMismatch { expected: String, actual: String },
+    /// The result doesn't hash to the expected target blob OID.
+    ResultHashMismatch { expected: String, actual: String },
+}
+
+impl From<DeltaError> for BinaryApplyError {
+    fn from(error: DeltaError) -> BinaryApplyError {
+        BinaryApplyError::Delta(error)
+    }
+}
+
+impl std::fmt::Display for BinaryApplyError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BinaryApplyError::NoReverseData => write!(
+                formatter,
+                "reverse application needs a reverse data block and there isn't one"
+            ),
+            BinaryApplyError::Delta(_) => write!(formatter, "the delta would not apply"),
+            BinaryApplyError::SourceHashMismatch { expected, actual } => write!(
+                formatter,
+                "source hashes to {} rather than the expected {}",
+                actual, expected
+            ),
+            BinaryApplyError::ResultHashMismatch { expected, actual } => write!(
+                formatter,
+                "result hashes to {} rather than the expected {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BinaryApplyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BinaryApplyError::Delta(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Does `content` hash to the (possibly abbreviated) blob OID
+/// `expected`?  An all zero OID just asserts that the blob doesn't
+/// exist so any content is accepted, as git does.
+fn content_matches_oid(content: &[u8], expected: &str) -> Result<(), String> {
+    if expected.bytes().all(|byte| byte == b'0') {
+        return Ok(());
+    }
+    let actual = blob_oid(content);
+    if actual.starts_with(expected) {
+        Ok(())
+    } else {
+        Err(actual)
+    }
+}
+
+impl GitBinaryDiff {
+    pub fn forward(&self) -> &GitBinaryDiffData {
+        &self.forward
+    }
+
+    pub fn reverse(&self) -> Option<&GitBinaryDiffData> {
+        self.reverse.as_ref()
+    }
+
+    /// Apply this section to the `before` bytes, using the reverse
+    /// data block to undo the change when `reverse`.  When
+    /// `expected_oids` (the ante and post blob OIDs from the
+    /// preamble's "index" line, possibly abbreviated) is given, verify
+    /// that `before` hashes to the ante OID and the result to the post
+    /// OID, erroring on mismatch: without this, delta application
+    /// against the wrong base silently produces garbage.
+    pub fn apply(
+        &self,
+        before: &[u8],
+        reverse: bool,
+        expected_oids: Option<(&str, &str)>,
+    ) -> Result<Vec<u8>, BinaryApplyError> {
+        let (block, source_oid, result_oid) = if reverse {
+            let block = self
+                .reverse
+                .as_ref()
+                .ok_or(BinaryApplyError::NoReverseData)?;
+            (
+                block,
+                expected_oids.map(|oids| oids.1),
+                expected_oids.map(|oids| oids.0),
+            )
+        } else {
+            (
+                &self.forward,
+                expected_oids.map(|oids| oids.0),
+                expected_oids.map(|oids| oids.1),
+            )
+        };
+        if let Some(expected) = source_oid {
+            content_matches_oid(before, expected).map_err(|actual| {
+                BinaryApplyError::SourceHashMismatch {
+                    expected: expected.to_string(),
+                    actual,
+                }
+            })?;
+        }
+        let result = match block.method {
+            BinaryDataMethod::Literal => block.data.clone(),
+            BinaryDataMethod::Delta => patch_delta(before, &block.data)?,
+        };
+        if let Some(expected) = result_oid {
+            content_matches_oid(&result, expected).map_err(|actual| {
+                BinaryApplyError::ResultHashMismatch {
+                    expected: expected.to_string(),
+                    actual,
+                }
+            })?;
+        }
+        Ok(result)
+    }
+}
+
+/// Read the data block starting at `start_index` in `lines`, `None` if
+/// there is no method line there.
+fn get_data_block_at(
+    lines: &Lines,
+    start_index: usize,
+) -> DiffParseResult<Option<GitBinaryDiffData>> {
+    let method_line = match lines.get(start_index) {
+        Some(line) => line.strip_suffix('\n').unwrap_or(line),
+        None => return Ok(None),
+    };
+    let (method, size_text) = if let Some(size_text) = method_line.strip_prefix("literal ") {
+        (BinaryDataMethod::Literal, size_text)
+    } else if let Some(size_text) = method_line.strip_prefix("delta ") {
+        (BinaryDataMethod::Delta, size_text)
+    } else {
+        return Ok(None);
+    };
+    if size_text.is_empty() || !size_text.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Ok(None);
+    }
+    let size = size_text.parse::<usize>()?;
+    let mut index = start_index + 1;
+    let mut deflated: Vec<u8> = Vec::new();
+    while index < lines.len() && *lines[index] != "\n" && !lines[index].is_empty() {
+        decode_data_line(&lines[index], index, &mut deflated)?;
+        index += 1;
+    }
+    let data = inflate(&deflated, size, start_index)?;
+    if index < lines.len() && *lines[index] == "\n" {
+        // The blank line terminating the block belongs to it.
+        index += 1;
+    }
+    Ok(Some(GitBinaryDiffData {
+        start_index,
+        lines: lines[start_index..index].to_vec(),
+        method,
+        data,
+    }))
+}
+
+/// Read the "GIT binary patch" section starting at `start_index` in
+/// `lines`, `None` if there isn't one there.
+pub fn get_git_binary_diff_at(
+    lines: &Lines,
+    start_index: usize,
+) -> DiffParseResult<Option<GitBinaryDiff>> {
+    match lines.get(start_index) {
+        Some(line) if line.trim_end_matches('\n') == "GIT binary patch" => (),
+        _ => return Ok(None),
+    }
+    let forward = get_data_block_at(lines, start_index + 1)?.ok_or(DiffParseError::SyntaxError(
+        DiffFormat::GitBinary,
+        start_index + 1,
+    ))?;
+    let mut index = forward.end_index();
+    let reverse = get_data_block_at(lines, index)?;
+    if let Some(reverse) = &reverse {
+        index = reverse.end_index();
+    }
+    Ok(Some(GitBinaryDiff {
+        start_index,
+        lines: lines[start_index..index].to_vec(),
+        forward,
+        reverse,
+    }))
+}
+
+/// Append a "literal"/"delta" data block to `lines`: its method line
+/// stating `size` and then `deflated` on base85 data lines.
+fn push_data_block(lines: &mut Lines, method: &str, size: usize, deflated: &[u8]) {
+    lines.push(Arc::new(format!("{} {}\n", method, size)));
+    for chunk in deflated.chunks(MAX_BYTES_PER_LINE) {
+        lines.push(Arc::new(encode_data_line(chunk)));
+    }
+    lines.push(Arc::new("\n".to_string()));
+}
+
+/// Append whichever of a "literal" block carrying `content` and a
+/// "delta" block transforming `base` into it deflates smaller (as git
+/// chooses) to `lines`.
+fn push_best_data_block(lines: &mut Lines, base: &[u8], content: &[u8]) {
+    let literal = deflate(content);
+    let delta = create_delta(base, content);
+    let deflated_delta = deflate(&delta);
+    if deflated_delta.len() < literal.len() {
+        push_data_block(lines, "delta", delta.len(), &deflated_delta);
+    } else {
+        push_data_block(lines, "literal", content.len(), &literal);
+    }
+}
+
+/// Generate the lines of a "GIT binary patch" section recording the
+/// change from `before` to `after`, with a forward block and a reverse
+/// block so that the result can also be applied in reverse.
+pub fn generate_git_binary_diff_lines(before: &[u8], after: &[u8]) -> Lines {
+    let mut lines: Lines = vec![Arc::new("GIT binary patch\n".to_string())];
+    push_best_data_block(&mut lines, before, after);
+    push_best_data_block(&mut lines, after, before);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_delta::patch_delta;
+    use crate::lines::LinesIfce;
+
+    #[test]
+    fn parse_sections_from_test_file() {
+        let lines = Lines::read("test_diffs/test_2.binary_diff").unwrap();
+        assert!(get_git_binary_diff_at(&lines, 0).unwrap().is_none());
+        let diff = get_git_binary_diff_at(&lines, 2).unwrap().unwrap();
+        assert_eq!(diff.start_index(), 2);
+        assert_eq!(diff.forward().method(), BinaryDataMethod::Delta);
+        assert_eq!(diff.forward().data().len(), 37);
+        let reverse = diff.reverse().unwrap();
+        assert_eq!(reverse.method(), BinaryDataMethod::Delta);
+        assert_eq!(reverse.data().len(), 4);
+        assert_eq!(diff.end_index(), 9);
+        // The "newbinary" creation: a literal forward block and a
+        // "literal 0" reverse block.
+        let diff = get_git_binary_diff_at(&lines, 49).unwrap().unwrap();
+        assert_eq!(diff.forward().method(), BinaryDataMethod::Literal);
+        assert_eq!(diff.forward().data().len(), 21);
+        assert!(diff.reverse().unwrap().data().is_empty());
+    }
+
+    /// The content that `block` records given the `base` bytes it
+    /// would be applied to.
+    fn block_content(block: &GitBinaryDiffData, base: &[u8]) -> Vec<u8> {
+        match block.method() {
+            BinaryDataMethod::Literal => block.data().to_vec(),
+            BinaryDataMethod::Delta => patch_delta(base, block.data()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn generated_sections_parse_back_to_their_content() {
+        let before: Vec<u8> = (0..=255).collect();
+        let after: Vec<u8> = (0..=255).rev().cycle().take(600).collect();
+        let lines = generate_git_binary_diff_lines(&before, &after);
+        let diff = get_git_binary_diff_at(&lines, 0).unwrap().unwrap();
+        assert_eq!(diff.end_index(), lines.len());
+        assert_eq!(block_content(diff.forward(), &before), after);
+        assert_eq!(block_content(diff.reverse().unwrap(), &after), before);
+        // Empty sides (file creation or deletion) must round trip too.
+        let lines = generate_git_binary_diff_lines(b"", b"fred\n");
+        let diff = get_git_binary_diff_at(&lines, 0).unwrap().unwrap();
+        assert_eq!(diff.forward().data(), b"fred\n");
+        assert!(diff.reverse().unwrap().data().is_empty());
+    }
+
+    #[test]
+    fn small_edits_to_large_files_come_out_as_deltas() {
+        let before: Vec<u8> = (0..=255).cycle().take(4000).collect();
+        let mut after = before.clone();
+        after.splice(2000..2000, b"a small insertion".iter().copied());
+        let lines = generate_git_binary_diff_lines(&before, &after);
+        let diff = get_git_binary_diff_at(&lines, 0).unwrap().unwrap();
+        assert_eq!(diff.forward().method(), BinaryDataMethod::Delta);
+        assert_eq!(block_content(diff.forward(), &before), after);
+        assert_eq!(block_content(diff.reverse().unwrap(), &after), before);
+    }
+
+    #[test]
+    fn apply_verifies_index_line_oids() {
+        let before: Vec<u8> = (0..=255).cycle().take(4000).collect();
+        let mut after = before.clone();
+        after.splice(2000..2000, b"a small insertion".iter().copied());
+        let lines = generate_git_binary_diff_lines(&before, &after);
+        let diff = get_git_binary_diff_at(&lines, 0).unwrap().unwrap();
+        let before_oid = blob_oid(&before);
+        let after_oid = blob_oid(&after);
+        // Abbreviated OIDs, as "index" lines carry them, suffice.
+        let oids = Some((&before_oid[..10], &after_oid[..10]));
+        assert_eq!(diff.apply(&before, false, oids).unwrap(), after);
+        assert_eq!(diff.apply(&after, true, oids).unwrap(), before);
+        assert_eq!(diff.apply(&before, false, None).unwrap(), after);
+        // The wrong base is caught before the delta ever applies.
+        assert!(matches!(
+            diff.apply(&after, false, oids),
+            Err(BinaryApplyError::SourceHashMismatch { .. })
+        ));
+        // An all zero OID (no blob on that side) is not checked.
+        let creation = generate_git_binary_diff_lines(b"", b"fred\n");
+        let creation = get_git_binary_diff_at(&creation, 0).unwrap().unwrap();
+        let fred_oid = blob_oid(b"fred\n");
+        assert_eq!(
+            creation
+                .apply(b"", false, Some(("0000000000", &fred_oid[..10])))
+                .unwrap(),
+            b"fred\n"
+        );
+    }
+
+    #[test]
+    fn corrupt_data_lines_are_reported() {
+        // "!!!!!" is valid base85 but not a valid zlib stream.
+        let text = "GIT binary patch\nliteral 4\nD!!!!!\n\n";
+        let result = get_git_binary_diff_at(&Lines::from_string(text), 0);
+        assert!(matches!(result, Err(DiffParseError::ZlibError(1, _))));
+        // The length prefix "E" promises more base85 characters than
+        // the line carries.
+        let text = "GIT binary patch\nliteral 4\nE!!!!!\n\n";
+        let result = get_git_binary_diff_at(&Lines::from_string(text), 0);
+        assert!(matches!(
+            result,
+            Err(DiffParseError::SyntaxError(DiffFormat::GitBinary, _))
+        ));
+    }
+}
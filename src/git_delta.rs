@@ -0,0 +1,373 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Creation and application of git's pack style binary deltas: two
+//! varint sizes (base then target) followed by instructions that either
+//! copy a run of bytes from the base or insert literal bytes.
+
+use std::collections::HashMap;
+
+/// The ways in which a delta buffer can fail to apply.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeltaError {
+    /// The delta ended in the middle of a header or an instruction.
+    Truncated,
+    /// The base size in the delta's header isn't the base's size.
+    BaseSizeMismatch { stated: usize, actual: usize },
+    /// A copy instruction reaches outside the base.
+    CopyOutOfRange,
+    /// The reserved all zero instruction byte.
+    ReservedInstruction,
+    /// The result's size isn't the target size in the delta's header.
+    ResultSizeMismatch { stated: usize, actual: usize },
+}
+
+impl std::fmt::Display for DeltaError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DeltaError::Truncated => write!(formatter, "delta buffer is truncated"),
+            DeltaError::BaseSizeMismatch { stated, actual } => write!(
+                formatter,
+                "delta names a base of {} bytes but the base has {}",
+                stated, actual
+            ),
+            DeltaError::CopyOutOfRange => {
+                write!(formatter, "delta copy instruction reaches outside the base")
+            }
+            DeltaError::ReservedInstruction => {
+                write!(formatter, "delta uses the reserved all zero instruction")
+            }
+            DeltaError::ResultSizeMismatch { stated, actual } => write!(
+                formatter,
+                "delta promises a result of {} bytes but produced {}",
+                stated, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+pub type DeltaResult<T> = Result<T, DeltaError>;
+
+/// The length of the base blocks that `create_delta` indexes and hence
+/// the shortest run of bytes that it will encode as a copy.
+const BLOCK_SIZE: usize = 16;
+
+/// The longest run of bytes that one copy instruction may cover: the
+/// length that a copy instruction with no size bytes denotes.
+const MAX_COPY_SIZE: usize = 0x10000;
+
+/// The most literal bytes that one insert instruction may carry.
+const MAX_INSERT_SIZE: usize = 0x7f;
+
+/// Append `value` to `delta` in the delta header's varint encoding:
+/// seven bits per byte, least significant group first, the high bit
+/// flagging that another byte follows.
+fn push_size(delta: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            delta.push(byte);
+            break;
+        }
+        delta.push(byte | 0x80);
+    }
+}
+
+/// Read a varint encoded size from `delta` at `index` returning it and
+/// the index of the byte after it.
+fn get_size(delta: &[u8], mut index: usize) -> DeltaResult<(usize, usize)> {
+    let mut value = 0_usize;
+    let mut shift = 0_u32;
+    loop {
+        let byte = *delta.get(index).ok_or(DeltaError::Truncated)?;
+        index += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value, index));
+        }
+        shift += 7;
+    }
+}
+
+/// Flush `pending` literal bytes (if any) to `delta` as insert
+/// instructions.
+fn push_inserts(delta: &mut Vec<u8>, pending: &mut Vec<u8>) {
+    for chunk in pending.chunks(MAX_INSERT_SIZE) {
+        delta.push(chunk.len() as u8);
+        delta.extend_from_slice(chunk);
+    }
+    pending.clear();
+}
+
+/// Append copy instructions covering `length` bytes of the base at
+/// `offset` to `delta`.
+fn push_copies(delta: &mut Vec<u8>, mut offset: usize, mut length: usize) {
+    while length > 0 {
+        let op_length = length.min(MAX_COPY_SIZE);
+        let mut op_code = 0x80_u8;
+        let mut op_data: Vec<u8> = Vec::new();
+        for shift in 0..4 {
+            let byte = ((offset >> (shift * 8)) & 0xff) as u8;
+            if byte != 0 {
+                op_code |= 1 << shift;
+                op_data.push(byte);
+            }
+        }
+        if op_length != MAX_COPY_SIZE {
+            for shift in 0..3 {
+                let byte = ((op_length >> (shift * 8)) & 0xff) as u8;
+                if byte != 0 {
+                    op_code |= 0x10 << shift;
+                    op_data.push(byte);
+                }
+            }
+        }
+        delta.push(op_code);
+        delta.extend_from_slice(&op_data);
+        offset += op_length;
+        length -= op_length;
+    }
+}
+
+/// Generate a delta buffer that transforms `base` into `target`: the
+/// encoder counterpart of `patch_delta`.  The base is indexed in
+/// `BLOCK_SIZE` byte blocks and runs of `target` found in the index are
+/// encoded as copies, everything else as literal inserts.
+pub fn create_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta: Vec<u8> = Vec::new();
+    push_size(&mut delta, base.len());
+    push_size(&mut delta, target.len());
+    let mut index: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for offset in (0..base.len().saturating_sub(BLOCK_SIZE - 1)).step_by(BLOCK_SIZE) {
+        index
+            .entry(&base[offset..offset + BLOCK_SIZE])
+            .or_default()
+            .push(offset);
+    }
+    let mut pending: Vec<u8> = Vec::new();
+    let mut position = 0_usize;
+    while position < target.len() {
+        let mut best: Option<(usize, usize)> = None;
+        if position + BLOCK_SIZE <= target.len() {
+            if let Some(offsets) = index.get(&target[position..position + BLOCK_SIZE]) {
+                for &offset in offsets.iter() {
+                    let length = base[offset..]
+                        .iter()
+                        .zip(target[position..].iter())
+                        .take_while(|(base_byte, target_byte)| base_byte == target_byte)
+                        .count();
+                    if best.is_none_or(|(_, best_length)| length > best_length) {
+                        best = Some((offset, length));
+                    }
+                }
+            }
+        }
+        if let Some((offset, length)) = best {
+            push_inserts(&mut delta, &mut pending);
+            push_copies(&mut delta, offset, length);
+            position += length;
+        } else {
+            pending.push(target[position]);
+            position += 1;
+        }
+    }
+    push_inserts(&mut delta, &mut pending);
+    delta
+}
+
+/// One instruction of a delta buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeltaOp {
+    /// Copy `length` bytes of the base starting at `offset`.
+    Copy { offset: usize, length: usize },
+    /// Insert the carried literal bytes.
+    Insert(Vec<u8>),
+}
+
+/// A delta buffer decoded into its header sizes and instructions, for
+/// tools that want to inspect or display a delta rather than apply it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedDelta {
+    /// The size that the delta says its base has.
+    pub base_size: usize,
+    /// The size that the delta says its target has.
+    pub target_size: usize,
+    pub ops: Vec<DeltaOp>,
+}
+
+/// Decode the `delta` buffer into its header sizes and instructions.
+pub fn parse_delta(delta: &[u8]) -> DeltaResult<ParsedDelta> {
+    let (base_size, index) = get_size(delta, 0)?;
+    let (target_size, mut index) = get_size(delta, index)?;
+    let mut ops: Vec<DeltaOp> = Vec::new();
+    while index < delta.len() {
+        let op_code = delta[index];
+        index += 1;
+        if op_code & 0x80 != 0 {
+            let mut offset = 0_usize;
+            for shift in 0..4 {
+                if op_code & (1 << shift) != 0 {
+                    let byte = *delta.get(index).ok_or(DeltaError::Truncated)?;
+                    index += 1;
+                    offset |= (byte as usize) << (shift * 8);
+                }
+            }
+            let mut length = 0_usize;
+            for shift in 0..3 {
+                if op_code & (0x10 << shift) != 0 {
+                    let byte = *delta.get(index).ok_or(DeltaError::Truncated)?;
+                    index += 1;
+                    length |= (byte as usize) << (shift * 8);
+                }
+            }
+            if length == 0 {
+                length = MAX_COPY_SIZE;
+            }
+            ops.push(DeltaOp::Copy { offset, length });
+        } else if op_code != 0 {
+            let length = op_code as usize;
+            if index + length > delta.len() {
+                return Err(DeltaError::Truncated);
+            }
+            ops.push(DeltaOp::Insert(delta[index..index + length].to_vec()));
+            index += length;
+        } else {
+            return Err(DeltaError::ReservedInstruction);
+        }
+    }
+    Ok(ParsedDelta {
+        base_size,
+        target_size,
+        ops,
+    })
+}
+
+/// Apply the `delta` buffer to `base` reconstructing the target bytes.
+pub fn patch_delta(base: &[u8], delta: &[u8]) -> DeltaResult<Vec<u8>> {
+    let parsed = parse_delta(delta)?;
+    if parsed.base_size != base.len() {
+        return Err(DeltaError::BaseSizeMismatch {
+            stated: parsed.base_size,
+            actual: base.len(),
+        });
+    }
+    let mut target: Vec<u8> = Vec::with_capacity(parsed.target_size);
+    for op in parsed.ops.iter() {
+        match op {
+            DeltaOp::Copy { offset, length } => {
+                if offset + length > base.len() {
+                    return Err(DeltaError::CopyOutOfRange);
+                }
+                target.extend_from_slice(&base[*offset..offset + length]);
+            }
+            DeltaOp::Insert(bytes) => target.extend_from_slice(bytes),
+        }
+    }
+    if target.len() != parsed.target_size {
+        return Err(DeltaError::ResultSizeMismatch {
+            stated: parsed.target_size,
+            actual: target.len(),
+        });
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn created_deltas_patch_back_to_their_target() {
+        let base: Vec<u8> = (0..=255).cycle().take(4000).collect();
+        let mut target = base.clone();
+        target.splice(1000..1000, b"inserted".iter().copied());
+        target.drain(2500..2600);
+        target.extend_from_slice(b"appended tail");
+        let delta = create_delta(&base, &target);
+        assert!(delta.len() < target.len());
+        assert_eq!(patch_delta(&base, &delta).unwrap(), target);
+        // Degenerate bases and targets must round trip too.
+        for (base, target) in [
+            (&b""[..], &b"brand new"[..]),
+            (&b"going away"[..], &b""[..]),
+            (&b"unrelated"[..], &b"contents"[..]),
+        ] {
+            assert_eq!(
+                patch_delta(base, &create_delta(base, target)).unwrap(),
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn long_copies_split_at_the_instruction_limit() {
+        let base: Vec<u8> = (0..=255).cycle().take(MAX_COPY_SIZE + 4000).collect();
+        let delta = create_delta(&base, &base);
+        assert_eq!(patch_delta(&base, &delta).unwrap(), base);
+    }
+
+    #[test]
+    fn parse_delta_exposes_instructions() {
+        let base: Vec<u8> = (0..=255).cycle().take(2000).collect();
+        let mut target = base.clone();
+        target.splice(1000..1000, b"inserted".iter().copied());
+        let parsed = parse_delta(&create_delta(&base, &target)).unwrap();
+        assert_eq!(parsed.base_size, base.len());
+        assert_eq!(parsed.target_size, target.len());
+        assert!(parsed
+            .ops
+            .iter()
+            .any(|op| matches!(op, DeltaOp::Copy { offset: 0, .. })));
+        assert!(parsed
+            .ops
+            .iter()
+            .any(|op| matches!(op, DeltaOp::Insert(bytes) if bytes.starts_with(b"inserted"))));
+        // Replaying the instructions by hand reproduces the target.
+        let mut replayed: Vec<u8> = Vec::new();
+        for op in parsed.ops.iter() {
+            match op {
+                DeltaOp::Copy { offset, length } => {
+                    replayed.extend_from_slice(&base[*offset..offset + length])
+                }
+                DeltaOp::Insert(bytes) => replayed.extend_from_slice(bytes),
+            }
+        }
+        assert_eq!(replayed, target);
+    }
+
+    #[test]
+    fn damaged_deltas_are_reported() {
+        let base = b"some base contents";
+        let delta = create_delta(base, b"some other contents");
+        assert_eq!(
+            patch_delta(&base[1..], &delta),
+            Err(DeltaError::BaseSizeMismatch {
+                stated: base.len(),
+                actual: base.len() - 1,
+            })
+        );
+        assert_eq!(
+            patch_delta(base, &delta[..delta.len() - 1]),
+            Err(DeltaError::Truncated)
+        );
+        // Sizes 18 and 1 followed by the reserved zero instruction.
+        assert_eq!(
+            patch_delta(base, &[18, 1, 0]),
+            Err(DeltaError::ReservedInstruction)
+        );
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Computation of the SHA-1 object ids that git's "index" preamble
+//! lines quote, so that content can be verified against them (and
+//! generated diffs can carry real hashes).
+
+use std::convert::TryInto;
+
+/// The SHA-1 digest of `data` as forty lower case hex digits.
+fn sha1_hex(data: &[u8]) -> String {
+    let mut digest: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut schedule = [0_u32; 80];
+        for (index, word) in schedule.iter_mut().take(16).enumerate() {
+            *word = u32::from_be_bytes(block[index * 4..index * 4 + 4].try_into().unwrap());
+        }
+        for index in 16..80 {
+            schedule[index] = (schedule[index - 3]
+                ^ schedule[index - 8]
+                ^ schedule[index - 14]
+                ^ schedule[index - 16])
+                .rotate_left(1);
+        }
+        let (mut a, mut b, mut c, mut d, mut e) =
+            (digest[0], digest[1], digest[2], digest[3], digest[4]);
+        for (index, word) in schedule.iter().enumerate() {
+            let (mixed, constant) = match index / 20 {
+                0 => ((b & c) | (!b & d), 0x5a827999_u32),
+                1 => (b ^ c ^ d, 0x6ed9eba1),
+                2 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let next = a
+                .rotate_left(5)
+                .wrapping_add(mixed)
+                .wrapping_add(e)
+                .wrapping_add(constant)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = next;
+        }
+        digest[0] = digest[0].wrapping_add(a);
+        digest[1] = digest[1].wrapping_add(b);
+        digest[2] = digest[2].wrapping_add(c);
+        digest[3] = digest[3].wrapping_add(d);
+        digest[4] = digest[4].wrapping_add(e);
+    }
+    digest.iter().map(|word| format!("{:08x}", word)).collect()
+}
+
+/// The object id that git gives a blob holding `content`: the SHA-1 of
+/// the content behind a "blob <length>\0" header.
+pub fn blob_oid(content: &[u8]) -> String {
+    let mut object = format!("blob {}\0", content.len()).into_bytes();
+    object.extend_from_slice(content);
+    sha1_hex(&object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blob_oids_match_git_hash_object() {
+        // The well known empty blob and "git hash-object" on "test\n".
+        assert_eq!(blob_oid(b""), "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391");
+        assert_eq!(
+            blob_oid(b"test\n"),
+            "9daeafb9864cf43055ae93beb0afd6c7d144bfa4"
+        );
+        // Content longer than one SHA-1 block.
+        let long: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        assert_eq!(blob_oid(&long).len(), 40);
+        assert_ne!(blob_oid(&long), blob_oid(&long[..999]));
+    }
+}
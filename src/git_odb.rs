@@ -0,0 +1,80 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Adapters connecting the blob access traits to a real git object
+//! database (via the `git2` crate) so that three way merges and index
+//! verification can pull blobs by the (possibly abbreviated) OIDs in
+//! preamble "index" extras.  Only built with the "git-odb" feature.
+
+use git2::{ObjectType, Odb, Oid, Repository};
+
+use crate::diff::{BlobProvider, ObjectStore};
+
+/// A `BlobProvider`/`ObjectStore` backed by a repository's object
+/// database: usable against bare repositories, so a server process can
+/// apply patches without a work tree.
+pub struct GitOdb<'repo> {
+    odb: Odb<'repo>,
+}
+
+impl<'repo> GitOdb<'repo> {
+    /// The object database of `repository`.
+    pub fn new(repository: &'repo Repository) -> Result<GitOdb<'repo>, git2::Error> {
+        Ok(GitOdb {
+            odb: repository.odb()?,
+        })
+    }
+}
+
+impl BlobProvider for GitOdb<'_> {
+    fn fetch_blob(&self, id: &str) -> Option<Vec<u8>> {
+        // Preamble "index" hashes are usually abbreviated: let the odb
+        // disambiguate the prefix.
+        let short_oid = Oid::from_str(id).ok()?;
+        let oid = self.odb.exists_prefix(short_oid, id.len()).ok()?;
+        let object = self.odb.read(oid).ok()?;
+        if object.kind() != ObjectType::Blob {
+            return None;
+        }
+        Some(object.data().to_vec())
+    }
+}
+
+impl ObjectStore for GitOdb<'_> {
+    fn store_blob(&mut self, content: &[u8]) -> String {
+        match self.odb.write(ObjectType::Blob, content) {
+            Ok(oid) => oid.to_string(),
+            Err(_) => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn fetch_blobs_by_abbreviated_oid_from_bare_repository() {
+        let root = std::env::temp_dir().join(format!("cub_pd_git_odb_{}", std::process::id()));
+        let repository = Repository::init_bare(&root).unwrap();
+        let mut odb = GitOdb::new(&repository).unwrap();
+        let oid = odb.store_blob(b"fred\njoe\n");
+        assert_eq!(oid.len(), 40);
+        assert_eq!(odb.fetch_blob(&oid), Some(b"fred\njoe\n".to_vec()));
+        assert_eq!(odb.fetch_blob(&oid[..7]), Some(b"fred\njoe\n".to_vec()));
+        assert_eq!(odb.fetch_blob("0123456"), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate regex;
+
+pub mod abstract_diff;
+pub mod borrowed;
+pub mod diff;
+pub mod git_binary_diff;
+pub mod git_delta;
+pub mod git_hash;
+#[cfg(feature = "git-odb")]
+pub mod git_odb;
+pub mod lines;
+#[cfg(feature = "mmap")]
+pub mod mapped;
+pub mod merge3;
+pub mod patch;
+pub mod pipeline;
+pub mod preamble;
+pub mod svn_diff;
+pub mod testing;
+pub mod text_diff;
+pub mod unified_diff;
+
+/// The format of a parsed diff.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DiffFormat {
+    Unified,
+    Context,
+    /// Traditional "diff" output without any context.
+    Normal,
+    /// An "ed" script.
+    Ed,
+    /// An "rcsdiff -n" style script.
+    Rcs,
+    /// A "diff --cc"/"diff --combined" merge diff.
+    Combined,
+    /// A "Binary files ... differ" (or git "GIT binary patch")
+    /// placeholder.
+    BinaryPlaceholder,
+    /// A "GIT binary patch" section complete with its data.
+    GitBinary,
+}
+
+impl DiffFormat {
+    /// Can a diff of this format be mechanically applied to the lines
+    /// of a target file?
+    pub fn supports_apply(self) -> bool {
+        match self {
+            DiffFormat::Unified | DiffFormat::Context | DiffFormat::Normal => true,
+            DiffFormat::Ed | DiffFormat::Rcs => true,
+            DiffFormat::Combined | DiffFormat::BinaryPlaceholder => false,
+            // Its content is byte oriented rather than line oriented.
+            DiffFormat::GitBinary => false,
+        }
+    }
+
+    /// Can a diff of this format be reversed so that applying it
+    /// undoes the change?  Script style formats don't quote the text
+    /// that they delete so they cannot be.
+    pub fn supports_reverse(self) -> bool {
+        match self {
+            DiffFormat::Unified | DiffFormat::Context | DiffFormat::Normal => true,
+            DiffFormat::Ed | DiffFormat::Rcs => false,
+            DiffFormat::Combined | DiffFormat::BinaryPlaceholder => false,
+            // The section's reverse data block, when present, undoes
+            // the change but reversal isn't purely mechanical.
+            DiffFormat::GitBinary => false,
+        }
+    }
+
+    /// Is this format a placeholder for a binary change (whose content
+    /// is not usefully line oriented)?
+    pub fn is_binary(self) -> bool {
+        matches!(self, DiffFormat::BinaryPlaceholder | DiffFormat::GitBinary)
+    }
+}
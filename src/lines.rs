@@ -0,0 +1,665 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// A single line of text (including any terminating newline).
+pub type Line = Arc<String>;
+
+/// The contents of a text file as a sequence of `Line`s.
+pub type Lines = Vec<Line>;
+
+/// Operations on sequences of `Line`s needed by diff parsing and
+/// application.
+pub trait LinesIfce {
+    /// Read `Lines` from the file at `path`.
+    fn read<P: AsRef<Path>>(path: P) -> io::Result<Lines>;
+
+    /// Read `Lines` from `reader`: a pipe, socket, archive entry or
+    /// in memory buffer as much as a file.
+    fn read_from<R: Read>(reader: R) -> io::Result<Lines>;
+
+    /// Split `string` into `Lines` (newlines are retained).
+    fn from_string(string: &str) -> Lines;
+
+    /// Index of the first line where `self` and `other` disagree or
+    /// `None` if one is a prefix of the other.
+    fn first_inequality_fm_head(&self, other: &[Line]) -> Option<usize>;
+
+    /// Distance from the end of the shorter sequence to the last line
+    /// where `self` and `other` disagree or `None` if one is a suffix
+    /// of the other.
+    fn first_inequality_fm_tail(&self, other: &[Line]) -> Option<usize>;
+
+    /// Do the lines starting at `index` match `sub_lines`?
+    fn contains_sub_lines_at(&self, sub_lines: &[Line], index: usize) -> bool;
+
+    /// Find the index of the first occurrence of `sub_lines` at or
+    /// after `not_before`.
+    fn find_first_sub_lines(&self, sub_lines: &[Line], not_before: usize) -> Option<usize>;
+
+    /// Write the lines to the file at `path` atomically (see
+    /// `write_bytes_atomically`): a crash or error part way leaves
+    /// either the old content or the new, never a mixture.
+    fn write_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()>;
+}
+
+/// The UTF-8 byte order mark as it appears in decoded text.
+pub const UTF8_BOM: char = '\u{feff}';
+
+/// Remove a leading UTF-8 byte order mark from `lines` (it belongs to
+/// the file, not to its first line's text, and breaks header and
+/// context matching if left in), reporting whether one was there so
+/// that writers can restore it.
+pub fn strip_utf8_bom(lines: &mut Lines) -> bool {
+    let rest = match lines.first().and_then(|line| line.strip_prefix(UTF8_BOM)) {
+        Some(rest) => rest.to_string(),
+        None => return false,
+    };
+    if rest.is_empty() {
+        lines.remove(0);
+    } else {
+        lines[0] = Arc::new(rest);
+    }
+    true
+}
+
+/// Put a UTF-8 byte order mark back on the front of `lines`: the
+/// inverse of `strip_utf8_bom`.
+pub fn restore_utf8_bom(lines: &mut Lines) {
+    match lines.first() {
+        Some(first) => lines[0] = Arc::new(format!("{}{}", UTF8_BOM, first)),
+        None => lines.push(Arc::new(UTF8_BOM.to_string())),
+    }
+}
+
+/// What to do with input bytes that are not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodingFallback {
+    /// Fail with an `InvalidData` error, as `Lines::read` does.
+    Error,
+    /// Decode byte for char (Latin-1 style): lossless and invertible
+    /// via `ByteLines::from_lines`.
+    Latin1,
+    /// Decode with U+FFFD replacement characters: lossy but always
+    /// printable.
+    Lossy,
+}
+
+/// The decoding that `decode_bytes` actually applied, so that callers
+/// can report (or refuse to write back) content that didn't arrive as
+/// clean UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodingUsed {
+    Utf8,
+    Latin1,
+    Lossy,
+}
+
+/// Decode `bytes` into `Lines`, falling back as directed when they are
+/// not valid UTF-8: legacy trees routinely contain a few stray bytes
+/// and shouldn't abort a whole apply.
+pub fn decode_bytes(bytes: &[u8], fallback: DecodingFallback) -> io::Result<(Lines, DecodingUsed)> {
+    match std::str::from_utf8(bytes) {
+        Ok(string) => Ok((Lines::from_string(string), DecodingUsed::Utf8)),
+        Err(error) => match fallback {
+            DecodingFallback::Error => Err(io::Error::new(io::ErrorKind::InvalidData, error)),
+            DecodingFallback::Latin1 => Ok((
+                ByteLines::from_bytes(bytes).to_lines(),
+                DecodingUsed::Latin1,
+            )),
+            DecodingFallback::Lossy => Ok((
+                Lines::from_string(&String::from_utf8_lossy(bytes)),
+                DecodingUsed::Lossy,
+            )),
+        },
+    }
+}
+
+/// Read `Lines` from the file at `path` with the given fallback for
+/// non UTF-8 content, reporting which decoding was used.
+pub fn read_lines_with_fallback<P: AsRef<Path>>(
+    path: P,
+    fallback: DecodingFallback,
+) -> io::Result<(Lines, DecodingUsed)> {
+    decode_bytes(&fs::read(path)?, fallback)
+}
+
+/// As `Lines::from_string` but a bare carriage return (classic-Mac
+/// style, not part of a `\r\n` pair) also ends a line: `from_string`
+/// alone leaves such a file as one giant line.  When `normalize` is set
+/// each bare `\r` terminator is rewritten as `\n` so that the result
+/// compares equal to Unix content.
+pub fn from_string_with_cr(string: &str, normalize: bool) -> Lines {
+    let mut lines = Lines::new();
+    let mut start = 0_usize;
+    let mut characters = string.char_indices().peekable();
+    while let Some((index, character)) = characters.next() {
+        let ends_line = match character {
+            '\n' => true,
+            '\r' => !matches!(characters.peek(), Some(&(_, '\n'))),
+            _ => false,
+        };
+        if ends_line {
+            let line = &string[start..=index];
+            if normalize && character == '\r' {
+                lines.push(Arc::new(format!("{}\n", &line[..line.len() - 1])));
+            } else {
+                lines.push(Arc::new(line.to_string()));
+            }
+            start = index + 1;
+        }
+    }
+    if start < string.len() {
+        lines.push(Arc::new(string[start..].to_string()));
+    }
+    lines
+}
+
+/// As `Lines::read` but splitting on bare carriage returns too (see
+/// `from_string_with_cr`).
+pub fn read_lines_with_cr<P: AsRef<Path>>(path: P, normalize: bool) -> io::Result<Lines> {
+    Ok(from_string_with_cr(&fs::read_to_string(path)?, normalize))
+}
+
+/// Write `bytes` to `path` via a temporary file in the same directory
+/// which is fsynced and then renamed into place, preserving an
+/// existing file's permissions: the safe write-back that every
+/// filesystem applying consumer needs.
+pub(crate) fn write_bytes_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name.to_string_lossy().into_owned(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "path has no file name",
+            ))
+        }
+    };
+    let temp_path = path.with_file_name(format!(".{}.cub_pd.{}", file_name, std::process::id()));
+    let mut file = File::create(&temp_path)?;
+    let outcome = (|| {
+        file.write_all(bytes)?;
+        if let Ok(metadata) = fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    })();
+    if outcome.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    outcome
+}
+
+impl LinesIfce for Lines {
+    fn read<P: AsRef<Path>>(path: P) -> io::Result<Lines> {
+        Lines::read_from(File::open(path)?)
+    }
+
+    fn read_from<R: Read>(mut reader: R) -> io::Result<Lines> {
+        let mut string = String::new();
+        reader.read_to_string(&mut string)?;
+        Ok(Lines::from_string(&string))
+    }
+
+    fn from_string(string: &str) -> Lines {
+        string
+            .split_inclusive('\n')
+            .map(|s| Arc::new(s.to_string()))
+            .collect()
+    }
+
+    fn first_inequality_fm_head(&self, other: &[Line]) -> Option<usize> {
+        (0..self.len().min(other.len())).find(|&index| self[index] != other[index])
+    }
+
+    fn first_inequality_fm_tail(&self, other: &[Line]) -> Option<usize> {
+        (0..self.len().min(other.len()))
+            .find(|&index| self[self.len() - index - 1] != other[other.len() - index - 1])
+    }
+
+    fn contains_sub_lines_at(&self, sub_lines: &[Line], index: usize) -> bool {
+        if sub_lines.len() + index > self.len() {
+            return false;
+        }
+        self[index..index + sub_lines.len()] == *sub_lines
+    }
+
+    fn find_first_sub_lines(&self, sub_lines: &[Line], not_before: usize) -> Option<usize> {
+        if sub_lines.is_empty() {
+            // An empty sequence trivially matches anywhere within bounds.
+            return if not_before <= self.len() {
+                Some(not_before)
+            } else {
+                None
+            };
+        }
+        if not_before + sub_lines.len() > self.len() {
+            return None;
+        }
+        (not_before..=self.len() - sub_lines.len())
+            .find(|&index| self.contains_sub_lines_at(sub_lines, index))
+    }
+
+    fn write_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut bytes: Vec<u8> = Vec::new();
+        for line in self.iter() {
+            bytes.extend_from_slice(line.as_bytes());
+        }
+        write_bytes_atomically(path.as_ref(), &bytes)
+    }
+}
+
+/// A hash index over a file's lines: each distinct line mapped to the
+/// ascending list of positions at which it occurs.  Built once per
+/// target file it turns the per hunk placement search from a scan of
+/// every position into a lookup of the candidate positions, which
+/// matters when many displaced hunks are applied to a large file.
+#[derive(Debug)]
+pub struct LineIndex {
+    lines: Lines,
+    positions: HashMap<Line, Vec<usize>>,
+}
+
+impl LineIndex {
+    pub fn new(lines: &[Line]) -> LineIndex {
+        let mut positions: HashMap<Line, Vec<usize>> = HashMap::new();
+        for (index, line) in lines.iter().enumerate() {
+            positions.entry(Arc::clone(line)).or_default().push(index);
+        }
+        LineIndex {
+            lines: lines.to_vec(),
+            positions,
+        }
+    }
+
+    /// The ascending positions at which `line` occurs in the indexed
+    /// lines.
+    pub fn positions(&self, line: &Line) -> &[usize] {
+        match self.positions.get(line) {
+            Some(positions) => positions,
+            None => &[],
+        }
+    }
+
+    /// As `LinesIfce::find_first_sub_lines` on the indexed lines but
+    /// only the positions where `sub_lines`' first line occurs are
+    /// examined.
+    pub fn find_first_sub_lines(&self, sub_lines: &[Line], not_before: usize) -> Option<usize> {
+        let first_line = match sub_lines.first() {
+            Some(line) => line,
+            None => return self.lines.find_first_sub_lines(sub_lines, not_before),
+        };
+        if not_before + sub_lines.len() > self.lines.len() {
+            return None;
+        }
+        let last_candidate = self.lines.len() - sub_lines.len();
+        let positions = self.positions(first_line);
+        let start = positions.partition_point(|&position| position < not_before);
+        positions[start..]
+            .iter()
+            .take_while(|&&position| position <= last_candidate)
+            .find(|&&position| self.lines.contains_sub_lines_at(sub_lines, position))
+            .copied()
+    }
+}
+
+/// A single line of a file that need not be valid UTF-8 (including
+/// any terminating newline).
+pub type ByteLine = Arc<[u8]>;
+
+/// The contents of a possibly non-UTF-8 file as a sequence of
+/// `ByteLine`s.
+pub type ByteLines = Vec<ByteLine>;
+
+/// Operations turning byte content into lines and back so that Latin-1
+/// or mixed encoding files can flow through the `String` based
+/// machinery: `to_lines` maps every byte to the char with its value (a
+/// lossless Latin-1 style decoding) and `from_lines` inverts it.  Both
+/// the patch and its target must travel through the same decoding for
+/// their lines to compare equal.
+pub trait ByteLinesIfce {
+    /// Read `ByteLines` from the file at `path` without any decoding.
+    fn read<P: AsRef<Path>>(path: P) -> io::Result<ByteLines>;
+
+    /// Read `ByteLines` from `reader` without any decoding.
+    fn read_from<R: Read>(reader: R) -> io::Result<ByteLines>;
+
+    /// Split `bytes` into `ByteLines` (newlines are retained).
+    fn from_bytes(bytes: &[u8]) -> ByteLines;
+
+    /// The lines decoded byte for char, ready for the `String` based
+    /// parsing and application machinery.
+    fn to_lines(&self) -> Lines;
+
+    /// Re-encode lines produced by `to_lines` (or by applying a diff
+    /// to them) back to bytes, `None` if a line carries a char too
+    /// wide to have come from a byte.
+    fn from_lines(lines: &[Line]) -> Option<ByteLines>;
+
+    /// The concatenated bytes of the lines.
+    fn to_bytes(&self) -> Vec<u8>;
+
+    /// Write the lines to the file at `path` atomically (see
+    /// `write_bytes_atomically`), without any encoding.
+    fn write_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()>;
+}
+
+impl ByteLinesIfce for ByteLines {
+    fn read<P: AsRef<Path>>(path: P) -> io::Result<ByteLines> {
+        ByteLines::read_from(File::open(path)?)
+    }
+
+    fn read_from<R: Read>(mut reader: R) -> io::Result<ByteLines> {
+        let mut bytes: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Ok(ByteLines::from_bytes(&bytes))
+    }
+
+    fn from_bytes(bytes: &[u8]) -> ByteLines {
+        let mut lines: ByteLines = Vec::new();
+        let mut start = 0_usize;
+        for (index, byte) in bytes.iter().enumerate() {
+            if *byte == b'\n' {
+                lines.push(Arc::from(&bytes[start..=index]));
+                start = index + 1;
+            }
+        }
+        if start < bytes.len() {
+            lines.push(Arc::from(&bytes[start..]));
+        }
+        lines
+    }
+
+    fn to_lines(&self) -> Lines {
+        self.iter()
+            .map(|line| Arc::new(line.iter().map(|byte| *byte as char).collect::<String>()))
+            .collect()
+    }
+
+    fn from_lines(lines: &[Line]) -> Option<ByteLines> {
+        lines
+            .iter()
+            .map(|line| {
+                line.chars()
+                    .map(|character| {
+                        if (character as u32) < 0x100 {
+                            Some(character as u8)
+                        } else {
+                            None
+                        }
+                    })
+                    .collect::<Option<Vec<u8>>>()
+                    .map(Arc::from)
+            })
+            .collect()
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        for line in self.iter() {
+            bytes.extend_from_slice(line);
+        }
+        bytes
+    }
+
+    fn write_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        write_bytes_atomically(path.as_ref(), &self.to_bytes())
+    }
+}
+
+/// The end of line convention used by some text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndOfLineStyle {
+    Lf,
+    CrLf,
+    /// Both conventions appear.
+    Mixed,
+    /// There were no line terminators to judge by.
+    Indeterminate,
+}
+
+/// The end of line style implied by counts of plain and carriage
+/// return preceded newlines.
+pub(crate) fn eol_style_fm_counts(lf_count: usize, crlf_count: usize) -> EndOfLineStyle {
+    if lf_count > 0 && crlf_count > 0 {
+        EndOfLineStyle::Mixed
+    } else if crlf_count > 0 {
+        EndOfLineStyle::CrLf
+    } else if lf_count > 0 {
+        EndOfLineStyle::Lf
+    } else {
+        EndOfLineStyle::Indeterminate
+    }
+}
+
+/// The character encoding detected for some bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Unknown,
+}
+
+/// A summary of the textual characteristics of a file's content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentSummary {
+    pub encoding: Encoding,
+    pub eol_style: EndOfLineStyle,
+    pub has_bom: bool,
+}
+
+/// Summarize the detected encoding, end of line style and byte order
+/// mark presence of `bytes`.
+pub fn summarize_content(bytes: &[u8]) -> ContentSummary {
+    let (encoding, has_bom) = if bytes.starts_with(&[0xef, 0xbb, 0xbf]) {
+        (Encoding::Utf8, true)
+    } else if bytes.starts_with(&[0xff, 0xfe]) {
+        (Encoding::Utf16Le, true)
+    } else if bytes.starts_with(&[0xfe, 0xff]) {
+        (Encoding::Utf16Be, true)
+    } else if std::str::from_utf8(bytes).is_ok() {
+        (Encoding::Utf8, false)
+    } else {
+        (Encoding::Unknown, false)
+    };
+    let mut lf_count = 0;
+    let mut crlf_count = 0;
+    for (index, byte) in bytes.iter().enumerate() {
+        if *byte == b'\n' {
+            if index > 0 && bytes[index - 1] == b'\r' {
+                crlf_count += 1;
+            } else {
+                lf_count += 1;
+            }
+        }
+    }
+    ContentSummary {
+        encoding,
+        eol_style: eol_style_fm_counts(lf_count, crlf_count),
+        has_bom,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf8_boms_strip_and_restore() {
+        let mut lines = Lines::from_string("\u{feff}a\nb\n");
+        assert!(strip_utf8_bom(&mut lines));
+        assert_eq!(lines, Lines::from_string("a\nb\n"));
+        assert!(!strip_utf8_bom(&mut lines));
+        restore_utf8_bom(&mut lines);
+        assert_eq!(lines, Lines::from_string("\u{feff}a\nb\n"));
+        // A file that is nothing but a BOM round trips too.
+        let mut lines = Lines::from_string("\u{feff}");
+        assert!(strip_utf8_bom(&mut lines));
+        assert!(lines.is_empty());
+        restore_utf8_bom(&mut lines);
+        assert_eq!(*lines[0], "\u{feff}");
+    }
+
+    #[test]
+    fn non_utf8_input_decodes_via_the_chosen_fallback() {
+        let bytes = b"caf\xe9\nplain\n";
+        assert!(decode_bytes(bytes, DecodingFallback::Error).is_err());
+        let (lines, used) = decode_bytes(bytes, DecodingFallback::Latin1).unwrap();
+        assert_eq!(used, DecodingUsed::Latin1);
+        assert_eq!(*lines[0], "caf\u{e9}\n");
+        // Latin-1 decoding is invertible.
+        assert_eq!(ByteLines::from_lines(&lines).unwrap().to_bytes(), bytes);
+        let (lines, used) = decode_bytes(bytes, DecodingFallback::Lossy).unwrap();
+        assert_eq!(used, DecodingUsed::Lossy);
+        assert_eq!(*lines[0], "caf\u{fffd}\n");
+        // Clean UTF-8 never engages the fallback.
+        let (_, used) = decode_bytes(b"a\n", DecodingFallback::Error).unwrap();
+        assert_eq!(used, DecodingUsed::Utf8);
+    }
+
+    #[test]
+    fn lines_read_from_any_reader() {
+        let lines = Lines::read_from(io::Cursor::new(b"a\nb\nc\n")).unwrap();
+        assert_eq!(lines, Lines::from_string("a\nb\nc\n"));
+        assert!(Lines::read_from(io::Cursor::new(b"caf\xe9\n")).is_err());
+        let byte_lines = ByteLines::read_from(io::Cursor::new(b"caf\xe9\n")).unwrap();
+        assert_eq!(&*byte_lines[0], b"caf\xe9\n");
+    }
+
+    #[test]
+    fn lines_from_string() {
+        let lines = Lines::from_string("a\nb\nc\n");
+        assert_eq!(lines.len(), 3);
+        assert_eq!(*lines[1], "b\n");
+        let lines = Lines::from_string("a\nb\nc");
+        assert_eq!(lines.len(), 3);
+        assert_eq!(*lines[2], "c");
+    }
+
+    #[test]
+    fn classic_mac_carriage_returns_split_lines() {
+        let text = "a\rb\rc";
+        assert_eq!(Lines::from_string(text).len(), 1);
+        let lines = from_string_with_cr(text, false);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(*lines[0], "a\r");
+        assert_eq!(*lines[2], "c");
+        assert_eq!(
+            from_string_with_cr(text, true),
+            Lines::from_string("a\nb\nc")
+        );
+        // A `\r\n` pair is a single terminator and the `\r` belongs to
+        // its line, normalized or not.
+        let lines = from_string_with_cr("a\r\nb\rc\n", true);
+        assert_eq!(lines, Lines::from_string("a\r\nb\nc\n"));
+    }
+
+    #[test]
+    fn write_to_replaces_files_atomically() {
+        let dir_path = std::env::temp_dir().join(format!("cub_pd_write_{}", std::process::id()));
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let file_path = dir_path.join("x.txt");
+        let lines = Lines::from_string("a\nb\nc\n");
+        lines.write_to(&file_path).unwrap();
+        assert_eq!(Lines::read(&file_path).unwrap(), lines);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(0o751)).unwrap();
+        }
+        let replacement = Lines::from_string("a\nB\nc\n");
+        replacement.write_to(&file_path).unwrap();
+        assert_eq!(Lines::read(&file_path).unwrap(), replacement);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&file_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o751);
+        }
+        // No temporary droppings are left beside the file.
+        assert_eq!(std::fs::read_dir(&dir_path).unwrap().count(), 1);
+        let byte_lines = ByteLines::from_bytes(b"caf\xe9\n");
+        byte_lines.write_to(&file_path).unwrap();
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"caf\xe9\n");
+        std::fs::remove_dir_all(&dir_path).unwrap();
+    }
+
+    #[test]
+    fn byte_lines_round_trip_non_utf8_content() {
+        // Latin-1 "caf\xe9" is invalid UTF-8.
+        let bytes = b"caf\xe9\nplain\ntail";
+        let byte_lines = ByteLines::from_bytes(bytes);
+        assert_eq!(byte_lines.len(), 3);
+        assert_eq!(&*byte_lines[0], b"caf\xe9\n");
+        assert_eq!(byte_lines.to_bytes(), bytes);
+        let lines = byte_lines.to_lines();
+        assert_eq!(*lines[0], "caf\u{e9}\n");
+        let recovered = ByteLines::from_lines(&lines).unwrap();
+        assert_eq!(recovered.to_bytes(), bytes);
+        // Lines that never came from bytes are refused.
+        let wide = Lines::from_string("\u{2603}\n");
+        assert!(ByteLines::from_lines(&wide).is_none());
+    }
+
+    #[test]
+    fn summarize_file_content() {
+        let summary = summarize_content(b"a\nb\nc\n");
+        assert_eq!(summary.encoding, Encoding::Utf8);
+        assert_eq!(summary.eol_style, EndOfLineStyle::Lf);
+        assert!(!summary.has_bom);
+        let summary = summarize_content(b"\xef\xbb\xbfa\r\nb\r\n");
+        assert_eq!(summary.encoding, Encoding::Utf8);
+        assert_eq!(summary.eol_style, EndOfLineStyle::CrLf);
+        assert!(summary.has_bom);
+        let summary = summarize_content(b"a\nb\r\n");
+        assert_eq!(summary.eol_style, EndOfLineStyle::Mixed);
+        let summary = summarize_content(b"\xff\xfea\x00");
+        assert_eq!(summary.encoding, Encoding::Utf16Le);
+        assert_eq!(summarize_content(b"\xc3\x28").encoding, Encoding::Unknown);
+    }
+
+    #[test]
+    fn lines_find_first_sub_lines() {
+        let lines = Lines::from_string("a\nb\nc\nb\nc\nd\n");
+        let sub_lines = Lines::from_string("b\nc\n");
+        assert_eq!(lines.find_first_sub_lines(&sub_lines, 0), Some(1));
+        assert_eq!(lines.find_first_sub_lines(&sub_lines, 2), Some(3));
+        assert_eq!(lines.find_first_sub_lines(&sub_lines, 4), None);
+        assert!(lines.contains_sub_lines_at(&sub_lines, 3));
+        assert!(!lines.contains_sub_lines_at(&sub_lines, 2));
+    }
+
+    #[test]
+    fn line_index_agrees_with_the_scan() {
+        let lines = Lines::from_string("a\nb\nc\nb\nc\nd\n");
+        let index = LineIndex::new(&lines);
+        assert_eq!(index.positions(&lines[1]), &[1, 3]);
+        for sub_lines in ["b\nc\n", "b\nc\nd\n", "a\n", "missing\n", ""] {
+            let sub_lines = Lines::from_string(sub_lines);
+            for not_before in 0..=lines.len() + 1 {
+                assert_eq!(
+                    index.find_first_sub_lines(&sub_lines, not_before),
+                    lines.find_first_sub_lines(&sub_lines, not_before)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A memory mapped backend for line oriented file content: the file is
+//! mapped rather than read and only its line boundaries are indexed, so
+//! neither a large patch file nor a large application target costs a
+//! resident copy until (and unless) its lines are actually needed in
+//! the owned representation.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::lines::{Line, Lines};
+
+/// A file's content memory mapped with its line boundaries indexed.
+#[derive(Debug)]
+pub struct MappedLines {
+    map: Mmap,
+    /// The byte offset at which each line starts plus a final entry for
+    /// the end of the file.
+    boundaries: Vec<usize>,
+}
+
+impl MappedLines {
+    /// Map the file at `file_path` and index its line boundaries.  The
+    /// content must be valid UTF-8 and the caller must not modify the
+    /// file while the mapping is alive.
+    pub fn open<P: AsRef<Path>>(file_path: P) -> io::Result<MappedLines> {
+        let file = File::open(file_path)?;
+        // Safe so long as the file isn't modified underneath us, which
+        // is the caller's side of the bargain stated above.
+        let map = unsafe { Mmap::map(&file)? };
+        if std::str::from_utf8(&map).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "mapped file is not valid UTF-8",
+            ));
+        }
+        let mut boundaries = vec![0];
+        for (index, byte) in map.iter().enumerate() {
+            if *byte == b'\n' {
+                boundaries.push(index + 1);
+            }
+        }
+        if *boundaries.last().unwrap() != map.len() {
+            boundaries.push(map.len());
+        }
+        Ok(MappedLines { map, boundaries })
+    }
+
+    /// The number of lines in the mapped file.
+    pub fn len(&self) -> usize {
+        self.boundaries.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The line at `index` (newline terminator retained) as a slice of
+    /// the mapping.
+    pub fn line(&self, index: usize) -> &str {
+        let bytes = &self.map[self.boundaries[index]..self.boundaries[index + 1]];
+        // Validated over the whole mapping in `open`.
+        unsafe { std::str::from_utf8_unchecked(bytes) }
+    }
+
+    /// The lines of the mapped file as slices of the mapping.
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        (0..self.len()).map(move |index| self.line(index))
+    }
+
+    /// The whole mapped file as one slice of the mapping.
+    pub fn as_str(&self) -> &str {
+        // Validated in `open`.
+        unsafe { std::str::from_utf8_unchecked(&self.map) }
+    }
+
+    /// Upgrade the mapped content to the owned representation for the
+    /// machinery that requires it (e.g. applying a diff to the file).
+    pub fn to_lines(&self) -> Lines {
+        self.lines()
+            .map(|line| Line::new(line.to_string()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::borrowed::parse_borrowed;
+    use crate::lines::LinesIfce;
+    use std::fs;
+
+    #[test]
+    fn mapped_files_index_lines_without_copying() {
+        let dir_path = std::env::temp_dir().join(format!("cub_pd_mapped_{}", std::process::id()));
+        fs::create_dir_all(&dir_path).unwrap();
+        let target_path = dir_path.join("target.txt");
+        fs::write(&target_path, "a\nb\nc\nno final newline").unwrap();
+        let mapped = MappedLines::open(&target_path).unwrap();
+        assert_eq!(mapped.len(), 4);
+        assert_eq!(mapped.line(1), "b\n");
+        assert_eq!(mapped.line(3), "no final newline");
+        // The lines are slices of the mapping: no content was copied.
+        let range = mapped.map.as_ptr_range();
+        for line in mapped.lines() {
+            assert!(range.start <= line.as_ptr() && line.as_ptr() < range.end);
+        }
+        assert_eq!(mapped.to_lines(), Lines::read(&target_path).unwrap());
+        // A mapped patch file parses through the zero copy view.
+        let patch_path = dir_path.join("patch.diff");
+        fs::write(
+            &patch_path,
+            "--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n",
+        )
+        .unwrap();
+        let mapped = MappedLines::open(&patch_path).unwrap();
+        let patch = parse_borrowed(mapped.as_str()).unwrap();
+        assert_eq!(patch.diff_pluses.len(), 1);
+        let bad_path = dir_path.join("bad.bin");
+        fs::write(&bad_path, b"caf\xe9\n").unwrap();
+        assert!(MappedLines::open(&bad_path).is_err());
+        fs::remove_dir_all(&dir_path).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Three way, line level merging of two descendants of a common
+//! ancestor, built from the same machinery as patch application: the
+//! changes from the ancestor to one descendant are generated as an
+//! abstract diff and replayed onto the other.
+
+use crate::abstract_diff::{generate_abstract_hunks, AbstractDiff, ApplnResult, ApplyOptions};
+use crate::lines::Lines;
+
+/// The number of context lines quoted around each ancestor-to-theirs
+/// change when the merge's abstract diff is generated.
+const MERGE_CONTEXT: usize = 3;
+
+/// Merge `ours` and `theirs`, two descendants of `ancestor`, by
+/// replaying the ancestor-to-theirs changes onto `ours` under
+/// `options`.  Changes that cannot be placed surface exactly as failed
+/// hunks do during patch application: as conflict marker lines in the
+/// result (in the configured `ConflictMarkerStyle`) or, with
+/// `ApplyOptions::structured_conflicts`, as `Conflict` entries on the
+/// result.
+pub fn merge3(
+    ancestor: &Lines,
+    ours: &Lines,
+    theirs: &Lines,
+    options: &ApplyOptions,
+) -> ApplnResult {
+    let hunks = generate_abstract_hunks(ancestor, theirs, MERGE_CONTEXT);
+    let diff = AbstractDiff::new(hunks);
+    let mut log: Vec<u8> = Vec::new();
+    diff.apply_to_lines(ours, &mut log, None, options)
+        .expect("writes to an in-memory log cannot fail")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lines::LinesIfce;
+
+    #[test]
+    fn merge_disjoint_changes_cleanly() {
+        let ancestor = Lines::from_string("a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n");
+        let ours = Lines::from_string("a\nB\nc\nd\ne\nf\ng\nh\ni\nj\n");
+        let theirs = Lines::from_string("a\nb\nc\nd\ne\nf\ng\nh\nI\nj\n");
+        let result = merge3(&ancestor, &ours, &theirs, &ApplyOptions::default());
+        assert!(result.is_successful());
+        assert_eq!(
+            *result.lines(),
+            Lines::from_string("a\nB\nc\nd\ne\nf\ng\nh\nI\nj\n")
+        );
+    }
+
+    #[test]
+    fn merge_competing_changes_conflict() {
+        let ancestor = Lines::from_string("a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n");
+        let ours = Lines::from_string("a\nb\nc\nd\nE1\nf\ng\nh\ni\nj\n");
+        let theirs = Lines::from_string("a\nb\nc\nd\nE2\nf\ng\nh\ni\nj\n");
+        let options = ApplyOptions::default().structured_conflicts(true);
+        let result = merge3(&ancestor, &ours, &theirs, &options);
+        assert!(!result.is_successful());
+        assert_eq!(*result.lines(), ours);
+        assert_eq!(result.conflicts().len(), 1);
+        let conflict = &result.conflicts()[0];
+        assert!(conflict
+            .ours
+            .contains(&std::sync::Arc::new("E1\n".to_string())));
+        assert!(conflict
+            .theirs
+            .contains(&std::sync::Arc::new("E2\n".to_string())));
+    }
+}
@@ -0,0 +1,4010 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use crate::abstract_diff::{
+    generate_abstract_hunks, AbstractChunk, AbstractHunk, ApplnResult, ApplyOptions, HunkDecision,
+    HunkOutcome,
+};
+use crate::diff::{Diff, DiffPlus, DiffPlusParser};
+use crate::git_hash::blob_oid;
+use crate::lines::{
+    eol_style_fm_counts, summarize_content, ContentSummary, Encoding, EndOfLineStyle, Line, Lines,
+    LinesIfce,
+};
+use crate::preamble::{GitPreamble, Preamble};
+use crate::text_diff::{
+    encode_c_quoted_path, is_dev_null, Consumed, DiffParseError, DiffParseResult, PathAndTimestamp,
+    TextDiffHeader, TextDiffHunk,
+};
+use crate::unified_diff::{UnifiedDiff, UnifiedDiffHunk, WhitespaceError, WhitespaceErrorKind};
+use crate::DiffFormat;
+
+/// What a patch does to one of the files that it touches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Deleted,
+    Modified,
+    /// Renamed from the contained path (as the preamble names it,
+    /// without any "a/" prefix).
+    Renamed(PathBuf),
+    /// Copied from the contained path (as the preamble names it,
+    /// without any "a/" prefix).
+    Copied(PathBuf),
+}
+
+/// A patch's leading free text, parsed: for "git format-patch" output
+/// the email headers and commit message are separated from the
+/// diffstat instead of being lumped together.
+#[derive(Debug, Clone, Default)]
+pub struct PatchHeader {
+    author: Option<String>,
+    date: Option<String>,
+    subject: Option<String>,
+    description: Lines,
+    diffstat: Lines,
+}
+
+impl PatchHeader {
+    /// Parse `lines` (the lines that precede a patch's first diff).
+    /// `From:`, `Date:` and `Subject:` email headers (and any mbox
+    /// "From " line) are recognized; the lines after them up to a
+    /// "---" separator are the description and the rest (diffstat
+    /// etc.) follows it.
+    pub fn new(lines: &Lines) -> PatchHeader {
+        let mut header = PatchHeader::default();
+        let mut index = 0;
+        while index < lines.len() {
+            let line = lines[index].trim_end_matches('\n');
+            if index == 0 && line.starts_with("From ") {
+                // The mbox separator line: not an author.
+            } else if let Some(value) = line.strip_prefix("From: ") {
+                header.author = Some(value.trim().to_string());
+            } else if let Some(value) = line.strip_prefix("Date: ") {
+                header.date = Some(value.trim().to_string());
+            } else if let Some(value) = line.strip_prefix("Subject: ") {
+                let mut subject = value.trim().to_string();
+                // Folded continuation lines belong to the subject.
+                while index + 1 < lines.len()
+                    && lines[index + 1].starts_with(char::is_whitespace)
+                    && !lines[index + 1].trim().is_empty()
+                {
+                    index += 1;
+                    subject.push(' ');
+                    subject.push_str(lines[index].trim());
+                }
+                // "git am" style: drop the "[PATCH ...]" tag.
+                if subject.starts_with('[') {
+                    if let Some(end) = subject.find(']') {
+                        subject = subject[end + 1..].trim_start().to_string();
+                    }
+                }
+                header.subject = Some(subject);
+            } else {
+                break;
+            }
+            index += 1;
+        }
+        let mut in_diffstat = false;
+        for line in lines[index..].iter() {
+            if !in_diffstat && line.trim_end_matches('\n') == "---" {
+                in_diffstat = true;
+            } else if in_diffstat {
+                header.diffstat.push(Arc::clone(line));
+            } else if !(header.description.is_empty() && line.trim().is_empty()) {
+                header.description.push(Arc::clone(line));
+            }
+        }
+        header
+    }
+
+    /// The author named by a "From:" header, if there was one.
+    pub fn author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+
+    /// The date named by a "Date:" header, if there was one.
+    pub fn date(&self) -> Option<&str> {
+        self.date.as_deref()
+    }
+
+    /// The subject named by a "Subject:" header (with any
+    /// "[PATCH ...]" tag removed), if there was one.
+    pub fn subject(&self) -> Option<&str> {
+        self.subject.as_deref()
+    }
+
+    /// The free text description (the commit message body for
+    /// "git format-patch" output).
+    pub fn description(&self) -> &Lines {
+        &self.description
+    }
+
+    /// The lines after the "---" separator: the diffstat and anything
+    /// else that is not part of the commit message.
+    pub fn diffstat(&self) -> &Lines {
+        &self.diffstat
+    }
+
+    /// The per file tallies parsed out of the diffstat (empty when
+    /// the header has no recognizable diffstat lines).
+    pub fn file_stats(&self) -> Vec<FileDiffStats> {
+        parse_diffstat_lines(&self.diffstat)
+    }
+
+    /// Replace the free text description with `text`.
+    pub fn set_description(&mut self, text: &str) {
+        self.description = Lines::from_string(text);
+    }
+
+    /// The header as patch file lines: the inverse of `new` (with any
+    /// mbox "From " line dropped and folded subjects unfolded).
+    pub fn to_lines(&self) -> Lines {
+        let mut lines: Lines = Vec::new();
+        if let Some(author) = &self.author {
+            lines.push(Arc::new(format!("From: {}\n", author)));
+        }
+        if let Some(date) = &self.date {
+            lines.push(Arc::new(format!("Date: {}\n", date)));
+        }
+        if let Some(subject) = &self.subject {
+            lines.push(Arc::new(format!("Subject: {}\n", subject)));
+        }
+        if !lines.is_empty() {
+            lines.push(Arc::new("\n".to_string()));
+        }
+        lines.extend(self.description.iter().cloned());
+        if !self.diffstat.is_empty() {
+            lines.push(Arc::new("---\n".to_string()));
+            lines.extend(self.diffstat.iter().cloned());
+        }
+        lines
+    }
+}
+
+/// A complete patch: a header (description, diffstat etc.) followed by
+/// a sequence of diffs.
+#[derive(Debug)]
+pub struct Patch {
+    pub(crate) header_lines: Lines,
+    pub(crate) diff_pluses: Vec<DiffPlus>,
+    pub(crate) rubbish: Lines,
+}
+
+/// Remove `strip` leading components from `path`.
+pub(crate) fn strip_path(path: &Path, strip: usize) -> PathBuf {
+    let mut components = path.components();
+    for _ in 0..strip {
+        components.next();
+    }
+    components.as_path().to_path_buf()
+}
+
+impl Patch {
+    /// The lines that precede the first diff in the patch file.
+    pub fn header_lines(&self) -> &Lines {
+        &self.header_lines
+    }
+
+    /// The header lines parsed into author, date, subject,
+    /// description and diffstat (see `PatchHeader`).
+    pub fn header(&self) -> PatchHeader {
+        PatchHeader::new(&self.header_lines)
+    }
+
+    /// Replace the header's free text description with `text`,
+    /// regenerating the diffstat while it's at it.  Email headers (and
+    /// the diffs themselves) are untouched, so the patch still round
+    /// trips through `to_lines`.
+    pub fn set_description(&mut self, text: &str) {
+        let mut header = self.header();
+        header.set_description(text);
+        self.set_header(header);
+    }
+
+    /// Replace the patch's header lines with `header` rendered back to
+    /// text, with its diffstat regenerated from the diffs actually in
+    /// the patch.
+    pub fn set_header(&mut self, mut header: PatchHeader) {
+        header.diffstat = diffstat_lines(&self.diff_pluses);
+        self.header_lines = header.to_lines();
+    }
+
+    /// The diffs (with their preambles) that make up this patch.
+    pub fn diff_pluses(&self) -> &Vec<DiffPlus> {
+        &self.diff_pluses
+    }
+
+    /// Any lines between or after the diffs that we could not make
+    /// sense of.
+    pub fn rubbish(&self) -> &Lines {
+        &self.rubbish
+    }
+
+    /// Split this patch into one standalone single file patch per
+    /// diff (a la "splitdiff").  The originating patch's header is not
+    /// propagated: each resulting patch gets a minimal (empty) header.
+    pub fn split_per_file(&self) -> Vec<Patch> {
+        self.diff_pluses
+            .iter()
+            .map(|diff_plus| Patch {
+                header_lines: Vec::new(),
+                diff_pluses: vec![diff_plus.clone()],
+                rubbish: Vec::new(),
+            })
+            .collect()
+    }
+
+    /// A patch containing only the hunks for which `choose` returns
+    /// true (given the touched file's path as named in the patch),
+    /// with post side line numbers renumbered to keep the result
+    /// valid.  Diffs left with no chosen hunks are dropped entirely.
+    pub fn select<F: Fn(&Path, &UnifiedDiffHunk) -> bool>(&self, choose: F) -> Patch {
+        let diff_pluses = self
+            .diff_pluses
+            .iter()
+            .filter_map(|diff_plus| {
+                let path = diff_plus.tag_path().unwrap_or_default();
+                let diff = match diff_plus.diff() {
+                    Diff::Unified(diff) => diff,
+                    // Neither kind has hunks to choose from.
+                    Diff::BinaryMarker(_) | Diff::SvnProperties(_) => return None,
+                };
+                let indices: Vec<usize> = diff
+                    .hunks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, hunk)| choose(&path, hunk))
+                    .map(|(index, _)| index)
+                    .collect();
+                if indices.is_empty() {
+                    return None;
+                }
+                let mut selected = diff_plus.clone();
+                selected.diff = Diff::Unified(diff.select_hunks(&indices));
+                Some(selected)
+            })
+            .collect();
+        Patch {
+            header_lines: self.header_lines.clone(),
+            diff_pluses,
+            rubbish: Vec::new(),
+        }
+    }
+
+    /// Recompute the "@@" line counts of every diff in this patch from
+    /// the hunk bodies (a la "recountdiff").
+    pub fn recount(&mut self) {
+        for diff_plus in self.diff_pluses.iter_mut() {
+            if let Diff::Unified(diff) = &mut diff_plus.diff {
+                diff.recount();
+            }
+        }
+    }
+
+    /// A patch whose application undoes this one: every diff's ante
+    /// and post chunks and "---"/"+++" header lines are exchanged and
+    /// any git preamble directions are swapped.
+    pub fn reverse(&self) -> Patch {
+        let diff_pluses = self
+            .diff_pluses
+            .iter()
+            .map(|diff_plus| {
+                let mut reversed = reversed_diff_plus(diff_plus);
+                reversed.preamble = diff_plus.preamble().map(|preamble| preamble.reversed());
+                reversed
+            })
+            .collect();
+        Patch {
+            header_lines: self.header_lines.clone(),
+            diff_pluses,
+            rubbish: Vec::new(),
+        }
+    }
+
+    /// The files that this patch touches (after removing `strip`
+    /// leading path components) and what it does to each of them,
+    /// resolving preamble data against the `---`/`+++` header names.
+    pub fn touched_files(&self, strip: usize) -> Vec<(PathBuf, ChangeKind)> {
+        self.diff_pluses
+            .iter()
+            .map(|diff_plus| touched_file(diff_plus, strip))
+            .collect()
+    }
+
+    /// Summarize, per touched file (after removing `strip` leading
+    /// path components), the detected encoding, end of line style and
+    /// byte order mark of the file's current content (as supplied by
+    /// `fetch`) and whether this patch's hunks are consistent with
+    /// them, so that a failed hunk caused by e.g. CRLF vs LF can be
+    /// explained rather than just reported.
+    pub fn content_reports<F>(&self, strip: usize, fetch: F) -> Vec<TargetContentReport>
+    where
+        F: Fn(&Path) -> Option<Vec<u8>>,
+    {
+        self.diff_pluses
+            .iter()
+            .map(|diff_plus| {
+                let (file_path, _) = touched_file(diff_plus, strip);
+                let summary = fetch(&file_path).map(|bytes| summarize_content(&bytes));
+                let patch_eol_style = patch_eol_style(diff_plus);
+                let consistent = match &summary {
+                    Some(summary) => {
+                        summary.encoding == Encoding::Utf8
+                            && eol_styles_consistent(patch_eol_style, summary.eol_style)
+                    }
+                    None => true,
+                };
+                TargetContentReport {
+                    file_path,
+                    summary,
+                    patch_eol_style,
+                    consistent,
+                }
+            })
+            .collect()
+    }
+
+    /// Without modifying anything, determine where this patch stands
+    /// with respect to the current file contents supplied by
+    /// `provider` (after removing `strip` leading path components):
+    /// not yet applied, already applied, a mixture, or only appliable
+    /// in reverse.
+    pub fn check_against(&self, strip: usize, provider: &dyn FileProvider) -> PatchState {
+        let options = ApplyOptions::default().dry_run(true);
+        let reverse_options = ApplyOptions::default().reverse(true).dry_run(true);
+        let mut all_unapplied = true;
+        let mut all_applied = true;
+        let mut all_reversible = true;
+        for diff_plus in self.diff_pluses.iter() {
+            let (file_path, _) = touched_file(diff_plus, strip);
+            let lines = match provider.fetch(&file_path) {
+                Some(bytes) => Lines::from_string(&String::from_utf8_lossy(&bytes)),
+                None => Vec::new(),
+            };
+            let diff = match diff_plus.diff() {
+                Diff::Unified(diff) => diff,
+                // Neither kind offers evidence either way.
+                Diff::BinaryMarker(_) | Diff::SvnProperties(_) => continue,
+            };
+            let mut log: Vec<u8> = Vec::new();
+            let forward = diff
+                .apply_to_lines(&lines, &mut log, None, &options)
+                .expect("writes to an in-memory log cannot fail");
+            let applied = !forward.hunk_outcomes().is_empty()
+                && forward
+                    .hunk_outcomes()
+                    .iter()
+                    .all(|outcome| matches!(outcome, HunkOutcome::AlreadyApplied));
+            let unapplied = forward.is_successful()
+                && !applied
+                && !forward
+                    .hunk_outcomes()
+                    .iter()
+                    .any(|outcome| matches!(outcome, HunkOutcome::AlreadyApplied));
+            let mut log: Vec<u8> = Vec::new();
+            let backward = diff
+                .apply_to_lines(&lines, &mut log, None, &reverse_options)
+                .expect("writes to an in-memory log cannot fail");
+            let reversible = backward.is_successful()
+                && !backward
+                    .hunk_outcomes()
+                    .iter()
+                    .any(|outcome| matches!(outcome, HunkOutcome::AlreadyApplied));
+            all_unapplied &= unapplied;
+            all_applied &= applied;
+            all_reversible &= applied || reversible;
+        }
+        if all_unapplied {
+            PatchState::NotApplied
+        } else if all_applied {
+            PatchState::FullyApplied
+        } else if all_reversible {
+            PatchState::AppearsReversed
+        } else {
+            PatchState::PartiallyApplied
+        }
+    }
+
+    /// Predict, by dry run under `options` against the file contents
+    /// supplied by `provider` (after removing `strip` leading path
+    /// components), what applying this patch would do to every file
+    /// and hunk: apply cleanly, at an offset, with fuzz, or fail.  No
+    /// output lines are handed back.
+    pub fn validate(
+        &self,
+        strip: usize,
+        provider: &dyn FileProvider,
+        options: &ApplyOptions,
+    ) -> ValidationReport {
+        let options = options.clone().dry_run(true);
+        let files = self
+            .diff_pluses
+            .iter()
+            .map(|diff_plus| {
+                let (file_path, _) = touched_file(diff_plus, strip);
+                let lines = match provider.fetch(&file_path) {
+                    Some(bytes) => Lines::from_string(&String::from_utf8_lossy(&bytes)),
+                    None => Vec::new(),
+                };
+                let diff = match diff_plus.diff() {
+                    Diff::Unified(diff) => diff,
+                    // Line content is absent: there is nothing to dry
+                    // run.
+                    Diff::BinaryMarker(_) | Diff::SvnProperties(_) => {
+                        return FileValidation {
+                            file_path,
+                            hunk_outcomes: Vec::new(),
+                        }
+                    }
+                };
+                let mut log: Vec<u8> = Vec::new();
+                let result = diff
+                    .apply_to_lines(&lines, &mut log, None, &options)
+                    .expect("writes to an in-memory log cannot fail");
+                FileValidation {
+                    file_path,
+                    hunk_outcomes: result.hunk_outcomes().to_vec(),
+                }
+            })
+            .collect();
+        ValidationReport { files }
+    }
+
+    /// Apply this patch to the file tree rooted at `root`: a
+    /// convenience wrapper applying to an `OsWorkTree` (see
+    /// `apply_to_work_tree`).
+    pub fn apply_to_directory(
+        &self,
+        root: &Path,
+        strip: usize,
+        options: &ApplyOptions,
+    ) -> io::Result<PatchApplyReport> {
+        self.apply_to_work_tree(&mut OsWorkTree::new(root), strip, options)
+    }
+
+    /// As `apply_to_directory` but reporting progress events to
+    /// `progress`.
+    pub fn apply_to_directory_with_progress(
+        &self,
+        root: &Path,
+        strip: usize,
+        options: &ApplyOptions,
+        progress: &mut dyn Progress,
+    ) -> io::Result<PatchApplyReport> {
+        self.apply_to_work_tree_with_progress(&mut OsWorkTree::new(root), strip, options, progress)
+    }
+
+    /// Apply this patch to `tree`: resolve each touched file's path
+    /// (after removing `strip` leading components), read it, apply its
+    /// diff under `options` and write the result back, creating added
+    /// files, removing deleted ones and carrying out any rename, copy,
+    /// mode and symlink directives from git preambles.  With
+    /// `ApplyOptions::dry_run` nothing is written.  Failed hunks leave
+    /// conflict markers in the written file exactly as
+    /// `AbstractDiff::apply_to_lines` describes.
+    pub fn apply_to_work_tree<T: WorkTree>(
+        &self,
+        tree: &mut T,
+        strip: usize,
+        options: &ApplyOptions,
+    ) -> io::Result<PatchApplyReport> {
+        self.apply_to_work_tree_with_progress(tree, strip, options, &mut ())
+    }
+
+    /// As `apply_to_work_tree` but reporting progress events to
+    /// `progress`: each touched file as work on it starts and the
+    /// running count of its hunks processed.
+    pub fn apply_to_work_tree_with_progress<T: WorkTree>(
+        &self,
+        tree: &mut T,
+        strip: usize,
+        options: &ApplyOptions,
+        progress: &mut dyn Progress,
+    ) -> io::Result<PatchApplyReport> {
+        let mut log: Vec<u8> = Vec::new();
+        let mut files: Vec<FileApplyOutcome> = Vec::new();
+        for diff_plus in self.diff_pluses.iter() {
+            progress.file_started(&touched_file(diff_plus, strip).0);
+            let application = compute_file_application(tree, diff_plus, strip, options, progress);
+            log.extend_from_slice(&application.log);
+            files.push(write_file_application(
+                tree,
+                diff_plus,
+                application,
+                options,
+            )?);
+        }
+        Ok(PatchApplyReport {
+            files,
+            log: String::from_utf8_lossy(&log).to_string(),
+        })
+    }
+
+    /// As `apply_to_work_tree` but computing every touched file's
+    /// patched content concurrently before carrying out the tree
+    /// changes serially in patch order, so the report (its files and
+    /// its log) is identical to the serial method's.  The diffs are
+    /// assumed to be independent: no diff should read content that an
+    /// earlier one in the same patch writes.
+    #[cfg(feature = "parallel")]
+    pub fn par_apply_to_work_tree<T: WorkTree + Sync>(
+        &self,
+        tree: &mut T,
+        strip: usize,
+        options: &ApplyOptions,
+    ) -> io::Result<PatchApplyReport> {
+        use rayon::prelude::*;
+        let applications: Vec<FileApplication> = self
+            .diff_pluses
+            .par_iter()
+            .map(|diff_plus| compute_file_application(&*tree, diff_plus, strip, options, &mut ()))
+            .collect();
+        let mut log: Vec<u8> = Vec::new();
+        let mut files: Vec<FileApplyOutcome> = Vec::new();
+        for (diff_plus, application) in self.diff_pluses.iter().zip(applications) {
+            log.extend_from_slice(&application.log);
+            files.push(write_file_application(
+                tree,
+                diff_plus,
+                application,
+                options,
+            )?);
+        }
+        Ok(PatchApplyReport {
+            files,
+            log: String::from_utf8_lossy(&log).to_string(),
+        })
+    }
+
+    /// The patch as patch file lines: header, preambles, diffs and
+    /// any trailing rubbish, in the order that they would appear on
+    /// disk.
+    pub fn to_lines(&self) -> Lines {
+        let mut lines = self.header_lines.clone();
+        lines.extend(self.diff_lines());
+        lines.extend(self.rubbish.iter().map(Arc::clone));
+        lines
+    }
+
+    /// The diff sections only (preambles included), as patch file
+    /// lines.
+    fn diff_lines(&self) -> Lines {
+        let mut lines: Lines = Vec::new();
+        for diff_plus in self.diff_pluses.iter() {
+            if let Some(preamble) = diff_plus.preamble() {
+                lines.extend(preamble.lines().iter().map(Arc::clone));
+            }
+            match diff_plus.diff() {
+                Diff::Unified(diff) => {
+                    lines.extend(diff.header.lines.iter().map(Arc::clone));
+                    for hunk in diff.hunks.iter() {
+                        lines.extend(hunk.lines.iter().map(Arc::clone));
+                    }
+                }
+                Diff::BinaryMarker(marker) => {
+                    lines.extend(marker.lines.iter().map(Arc::clone));
+                }
+                Diff::SvnProperties(properties) => {
+                    lines.extend(properties.lines.iter().map(Arc::clone));
+                }
+            }
+        }
+        lines
+    }
+
+    /// The patch as a complete "git am" compatible email: an mbox
+    /// separator, `From:`/`Date:`/`Subject:` headers, this patch's
+    /// description as the commit message, the "---" separator, a
+    /// diffstat, the diffs and a signature line.  `subject` is given
+    /// the conventional "[PATCH] " tag.
+    pub fn format_patch(&self, author: &str, date: &str, subject: &str) -> Lines {
+        let mut lines: Lines = vec![
+            Arc::new(
+                "From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001\n"
+                    .to_string(),
+            ),
+            Arc::new(format!("From: {}\n", author)),
+            Arc::new(format!("Date: {}\n", date)),
+            Arc::new(format!("Subject: [PATCH] {}\n", subject)),
+            Arc::new("\n".to_string()),
+        ];
+        let mut description = self.header().description().clone();
+        // "git format-patch" puts its "---" hard against the message.
+        while description
+            .last()
+            .is_some_and(|line| line.trim().is_empty())
+        {
+            description.pop();
+        }
+        lines.extend(description);
+        lines.push(Arc::new("---\n".to_string()));
+        lines.extend(diffstat_lines(&self.diff_pluses));
+        lines.push(Arc::new("\n".to_string()));
+        lines.extend(self.diff_lines());
+        lines.push(Arc::new("-- \n".to_string()));
+        lines.push(Arc::new(format!("cub_pd {}\n", env!("CARGO_PKG_VERSION"))));
+        lines.push(Arc::new("\n".to_string()));
+        lines
+    }
+
+    /// A copy of this patch with every diff's "a/"/"b/" path prefixes
+    /// rewritten: `ante_prefix` and `post_prefix` (including their
+    /// trailing "/") replace the conventional prefix on the
+    /// respective sides, with "" producing `--no-prefix` style names.
+    /// Prefix-less input names simply gain the requested prefixes, so
+    /// this also normalizes patches from tools that disagree about
+    /// prefixes.
+    pub fn with_prefixes(&self, ante_prefix: &str, post_prefix: &str) -> Patch {
+        let diff_pluses = self
+            .diff_pluses
+            .iter()
+            .map(|diff_plus| {
+                let diff = match diff_plus.diff() {
+                    Diff::Unified(diff) => {
+                        let mut diff = diff.clone();
+                        diff.header.ante_pat.file_path =
+                            reprefixed(&diff.header.ante_pat.file_path, "a", ante_prefix);
+                        diff.header.post_pat.file_path =
+                            reprefixed(&diff.header.post_pat.file_path, "b", post_prefix);
+                        if diff.header.lines.len() == 2 {
+                            diff.header.lines = vec![
+                                header_line("---", &diff.header.ante_pat),
+                                header_line("+++", &diff.header.post_pat),
+                            ];
+                        }
+                        Diff::Unified(diff)
+                    }
+                    Diff::BinaryMarker(marker) => {
+                        let mut marker = marker.clone();
+                        marker.ante_file_path =
+                            reprefixed(&marker.ante_file_path, "a", ante_prefix);
+                        marker.post_file_path =
+                            reprefixed(&marker.post_file_path, "b", post_prefix);
+                        marker.lines = vec![Arc::new(format!(
+                            "Binary files {} and {} differ\n",
+                            marker.ante_file_path.display(),
+                            marker.post_file_path.display()
+                        ))];
+                        Diff::BinaryMarker(marker)
+                    }
+                    // Subversion's names carry no prefixes to rewrite.
+                    Diff::SvnProperties(properties) => Diff::SvnProperties(properties.clone()),
+                };
+                let preamble = diff_plus.preamble().map(|preamble| match preamble {
+                    Preamble::Git(preamble) => {
+                        let mut preamble = preamble.clone();
+                        preamble.ante_file_path =
+                            reprefixed(&preamble.ante_file_path, "a", ante_prefix);
+                        preamble.post_file_path =
+                            reprefixed(&preamble.post_file_path, "b", post_prefix);
+                        preamble.lines[0] = Arc::new(format!(
+                            "diff --git {} {}\n",
+                            encode_c_quoted_path(&preamble.ante_file_path.to_string_lossy()),
+                            encode_c_quoted_path(&preamble.post_file_path.to_string_lossy())
+                        ));
+                        Preamble::Git(preamble)
+                    }
+                    Preamble::Svn(preamble) => Preamble::Svn(preamble.clone()),
+                });
+                DiffPlus { preamble, diff }
+            })
+            .collect();
+        Patch {
+            header_lines: self.header_lines.clone(),
+            diff_pluses,
+            rubbish: self.rubbish.clone(),
+        }
+    }
+
+    /// Scan the added lines of every diff's hunks for the whitespace
+    /// problems that "git apply --whitespace=warn" complains about,
+    /// pairing each finding with the touched file's path (after
+    /// removing `strip` leading components).  See
+    /// `UnifiedDiff::whitespace_errors`.
+    pub fn whitespace_errors(&self, strip: usize) -> Vec<(PathBuf, WhitespaceError)> {
+        let mut findings: Vec<(PathBuf, WhitespaceError)> = Vec::new();
+        for diff_plus in self.diff_pluses.iter() {
+            let (file_path, _) = touched_file(diff_plus, strip);
+            let diff = match diff_plus.diff() {
+                Diff::Unified(diff) => diff,
+                // Neither kind quotes any added text lines.
+                Diff::BinaryMarker(_) | Diff::SvnProperties(_) => continue,
+            };
+            for error in diff.whitespace_errors() {
+                findings.push((file_path.clone(), error));
+            }
+        }
+        findings
+    }
+
+    /// The files and line ranges where this patch and `other` (both
+    /// against the same base) touch overlapping areas.  A non empty
+    /// result is the warning sign for patch queue reordering or
+    /// folding: such hunks neither commute nor combine cleanly.
+    pub fn conflicts_with(&self, other: &Patch) -> Vec<ConflictingRegion> {
+        let keys_other: Vec<PathBuf> = other.diff_pluses.iter().map(file_key).collect();
+        let mut regions: Vec<ConflictingRegion> = Vec::new();
+        for diff_plus in self.diff_pluses.iter() {
+            let key = file_key(diff_plus);
+            let diff_plus_other = match keys_other.iter().position(|key_b| key_b == &key) {
+                Some(index) => &other.diff_pluses[index],
+                None => continue,
+            };
+            match (diff_plus.diff(), diff_plus_other.diff()) {
+                (Diff::Unified(diff_a), Diff::Unified(diff_b)) => {
+                    for hunk_a in diff_a.hunks.iter() {
+                        let hunk_a = hunk_a.get_abstract_diff_hunk();
+                        let a_start = hunk_a.ante_chunk().start_index;
+                        let a_end = a_start + hunk_a.ante_chunk().lines.len();
+                        for hunk_b in diff_b.hunks.iter() {
+                            let hunk_b = hunk_b.get_abstract_diff_hunk();
+                            let b_start = hunk_b.ante_chunk().start_index;
+                            let b_end = b_start + hunk_b.ante_chunk().lines.len();
+                            if a_start < b_end && b_start < a_end {
+                                regions.push(ConflictingRegion {
+                                    file_path: key.clone(),
+                                    range: (a_start.max(b_start), a_end.min(b_end)),
+                                });
+                            }
+                        }
+                    }
+                }
+                // Without line content any shared touch is a conflict.
+                _ => regions.push(ConflictingRegion {
+                    file_path: key.clone(),
+                    range: (0, 0),
+                }),
+            }
+        }
+        regions
+    }
+
+    /// The strip level (`patch`'s `-p` value) under which the most of
+    /// this patch's touched files already exist in the tree rooted at
+    /// `root`.  Ties go to the smallest level; `None` if no level
+    /// makes any file exist (e.g. a patch that only creates files).
+    pub fn detect_strip_level(&self, root: &Path) -> Option<usize> {
+        let max_components = self
+            .diff_pluses
+            .iter()
+            .map(|diff_plus| touched_file(diff_plus, 0).0.components().count())
+            .max()?;
+        let mut best: Option<(usize, usize)> = None;
+        for strip in 0..max_components {
+            let count = self
+                .diff_pluses
+                .iter()
+                .filter(|diff_plus| {
+                    let (file_path, _) = touched_file(diff_plus, strip);
+                    !file_path.as_os_str().is_empty() && root.join(file_path).exists()
+                })
+                .count();
+            if count > 0 && best.is_none_or(|(best_count, _)| count > best_count) {
+                best = Some((count, strip));
+            }
+        }
+        best.map(|(_, strip)| strip)
+    }
+}
+
+/// `path` with its conventional single letter diff prefix (if it has
+/// one) replaced by `new_prefix` ("" for no prefix at all).
+/// "/dev/null" is left alone: it is a placeholder, not a file name.
+fn reprefixed(path: &Path, conventional: &str, new_prefix: &str) -> PathBuf {
+    if is_dev_null(path) {
+        return path.to_path_buf();
+    }
+    let stripped = match path.components().next() {
+        Some(Component::Normal(first)) if first == conventional => strip_path(path, 1),
+        _ => path.to_path_buf(),
+    };
+    if new_prefix.is_empty() {
+        stripped
+    } else {
+        PathBuf::from(format!("{}{}", new_prefix, stripped.display()))
+    }
+}
+
+/// Rebuild a "---"/"+++" header line from its parsed parts.
+fn header_line(tag: &str, pat: &PathAndTimestamp) -> Line {
+    let quoted = encode_c_quoted_path(&pat.file_path.to_string_lossy());
+    match &pat.time_stamp {
+        Some(time_stamp) => Arc::new(format!("{} {}\t{}\n", tag, quoted, time_stamp)),
+        None => Arc::new(format!("{} {}\n", tag, quoted)),
+    }
+}
+
+/// The git mode string that `diff_plus`'s preamble nominates for the
+/// patched file, if any: from a "new mode" or "new file mode" extras
+/// line ("old mode"/"deleted file mode" when applying in reverse).
+fn target_mode_extra(diff_plus: &DiffPlus, reverse: bool) -> Option<&str> {
+    let preamble = diff_plus.preamble()?;
+    let (mode_label, file_mode_label) = if reverse {
+        ("old mode", "deleted file mode")
+    } else {
+        ("new mode", "new file mode")
+    };
+    preamble
+        .get_extra(mode_label)
+        .or_else(|| preamble.get_extra(file_mode_label))
+        .map(|text| text.trim())
+}
+
+/// The Unix permission bits that `diff_plus`'s git preamble nominates
+/// for the patched file, if any.  The git mode strings are octal with
+/// the file type in the leading digits (e.g. "100755").
+fn target_file_mode(diff_plus: &DiffPlus, reverse: bool) -> Option<u32> {
+    u32::from_str_radix(target_mode_extra(diff_plus, reverse)?, 8)
+        .ok()
+        .map(|mode| mode & 0o7777)
+}
+
+/// Does `diff_plus`'s preamble nominate a symlink (git mode 120000)
+/// whose "content" is the link's target text?
+fn target_is_symlink(diff_plus: &DiffPlus, reverse: bool) -> bool {
+    target_mode_extra(diff_plus, reverse) == Some("120000")
+}
+
+/// `kind` as seen when the patch is applied in reverse.
+fn reversed_change_kind(kind: ChangeKind) -> ChangeKind {
+    match kind {
+        ChangeKind::Added => ChangeKind::Deleted,
+        ChangeKind::Deleted => ChangeKind::Added,
+        other => other,
+    }
+}
+
+/// One diff's application computed against the tree's current content
+/// but not yet written back: the read only half of applying a patch,
+/// separated out so that it can run for many files concurrently.
+struct FileApplication {
+    file_path: PathBuf,
+    change_kind: ChangeKind,
+    read_path: PathBuf,
+    write_path: PathBuf,
+    /// The content that the diff was applied to.
+    lines: Lines,
+    /// The target carried a UTF-8 byte order mark (stripped before
+    /// applying, restored on write back).
+    had_bom: bool,
+    result: ApplnResult,
+    /// The diagnostics written while applying.
+    log: Vec<u8>,
+}
+
+/// Read `diff_plus`'s target from `tree` and apply the diff to it
+/// under `options`, without touching the tree.
+fn compute_file_application<T: WorkTree + ?Sized>(
+    tree: &T,
+    diff_plus: &DiffPlus,
+    strip: usize,
+    options: &ApplyOptions,
+    progress: &mut dyn Progress,
+) -> FileApplication {
+    let (file_path, change_kind) = touched_file(diff_plus, strip);
+    let change_kind = if options.reverse {
+        reversed_change_kind(change_kind)
+    } else {
+        change_kind
+    };
+    // Renames and copies patch the content of the origin file named in
+    // the preamble; in reverse the content flows back the other way.
+    let origin_path = match &change_kind {
+        ChangeKind::Renamed(from) | ChangeKind::Copied(from) => Some(PathBuf::from(from)),
+        _ => None,
+    };
+    let (read_path, write_path) = match (&origin_path, options.reverse) {
+        (Some(origin), false) => (origin.clone(), file_path.clone()),
+        (Some(origin), true) => (file_path.clone(), origin.clone()),
+        (None, _) => (file_path.clone(), file_path.clone()),
+    };
+    // A symlink's "content" in a git diff is its unterminated target
+    // text.
+    let mut lines = if let Some(link_target) = tree.read_link(&read_path) {
+        vec![Arc::new(link_target)]
+    } else {
+        match tree.fetch(&read_path) {
+            Some(bytes) => Lines::from_string(&String::from_utf8_lossy(&bytes)),
+            None => Vec::new(),
+        }
+    };
+    let had_bom = crate::lines::strip_utf8_bom(&mut lines);
+    let lines = lines;
+    let diff = match diff_plus.diff() {
+        Diff::Unified(diff) => diff,
+        // The marker records that the file changed but not how: the
+        // change cannot be carried out, so report it as a failure.
+        Diff::BinaryMarker(_) => {
+            use std::io::Write;
+            let mut log: Vec<u8> = Vec::new();
+            writeln!(
+                log,
+                "{}: binary files differ: content not included.",
+                file_path.display()
+            )
+            .expect("writes to an in-memory log cannot fail");
+            return FileApplication {
+                file_path,
+                change_kind,
+                read_path,
+                write_path,
+                lines: lines.clone(),
+                had_bom,
+                result: ApplnResult {
+                    lines,
+                    hunk_outcomes: vec![HunkOutcome::Failed {
+                        conflict_range: (0, 0),
+                    }],
+                    conflicts: Vec::new(),
+                    rejected_hunks: Vec::new(),
+                },
+                log,
+            };
+        }
+        // Property changes have no analogue in the work trees we
+        // write to: note and skip them without failing the patch.
+        Diff::SvnProperties(_) => {
+            use std::io::Write;
+            let mut log: Vec<u8> = Vec::new();
+            writeln!(
+                log,
+                "{}: property changes are not applied.",
+                file_path.display()
+            )
+            .expect("writes to an in-memory log cannot fail");
+            return FileApplication {
+                file_path,
+                change_kind,
+                read_path,
+                write_path,
+                lines: lines.clone(),
+                had_bom,
+                result: ApplnResult {
+                    lines,
+                    hunk_outcomes: Vec::new(),
+                    conflicts: Vec::new(),
+                    rejected_hunks: Vec::new(),
+                },
+                log,
+            };
+        }
+    };
+    let mut log: Vec<u8> = Vec::new();
+    let fixed = if options.fix_whitespace && !options.reverse {
+        let mut fixed = diff.clone();
+        for error in fixed.fix_whitespace_errors() {
+            use std::io::Write;
+            writeln!(
+                log,
+                "{}: Fixed {} on line {}.",
+                file_path.display(),
+                match error.kind {
+                    WhitespaceErrorKind::TrailingWhitespace => "trailing whitespace",
+                    WhitespaceErrorKind::SpaceBeforeTab => "a space before a tab",
+                    WhitespaceErrorKind::BlankAtEof => "a blank line at end of file",
+                },
+                error.post_line_num
+            )
+            .expect("writes to an in-memory log cannot fail");
+        }
+        Some(fixed)
+    } else {
+        None
+    };
+    let diff = fixed.as_ref().unwrap_or(diff);
+    let hunk_count = diff.hunks.len();
+    let result = diff
+        .apply_to_lines_interactive(&lines, &mut log, Some(&file_path), options, |view| {
+            progress.hunks_done(view.hunk_num - 1, hunk_count);
+            HunkDecision::Apply
+        })
+        .expect("writes to an in-memory log cannot fail");
+    progress.hunks_done(hunk_count, hunk_count);
+    FileApplication {
+        file_path,
+        change_kind,
+        read_path,
+        write_path,
+        lines,
+        had_bom,
+        result,
+        log,
+    }
+}
+
+/// Carry out `application`'s tree changes (writes, removals, renames,
+/// symlinks and modes): the mutating half of applying a patch.  With
+/// `ApplyOptions::dry_run` nothing is written.
+fn write_file_application<T: WorkTree>(
+    tree: &mut T,
+    diff_plus: &DiffPlus,
+    application: FileApplication,
+    options: &ApplyOptions,
+) -> io::Result<FileApplyOutcome> {
+    let FileApplication {
+        file_path,
+        change_kind,
+        read_path,
+        write_path,
+        lines,
+        had_bom,
+        result,
+        log: _,
+    } = application;
+    if !options.dry_run {
+        if options.reverse && matches!(change_kind, ChangeKind::Copied(_)) {
+            // Undoing a copy just removes it: the origin was never
+            // modified.
+            if tree.exists(&file_path) {
+                tree.remove(&file_path)?;
+            }
+        } else {
+            if let Some(suffix) = &options.backup_suffix {
+                if *result.lines() != lines {
+                    if let Some(content) = tree.fetch(&write_path) {
+                        let mut backup_name =
+                            write_path.file_name().unwrap_or_default().to_os_string();
+                        backup_name.push(suffix);
+                        tree.store(&write_path.with_file_name(backup_name), &content)?;
+                    }
+                }
+            }
+            if matches!(change_kind, ChangeKind::Renamed(_))
+                && read_path != write_path
+                && tree.exists(&read_path)
+            {
+                tree.rename(&read_path, &write_path)?;
+            }
+            if change_kind == ChangeKind::Deleted && result.lines().is_empty() {
+                if tree.exists(&write_path) {
+                    tree.remove(&write_path)?;
+                }
+            } else {
+                let mut text: String = result.lines().iter().map(|line| line.as_str()).collect();
+                if had_bom {
+                    text.insert(0, crate::lines::UTF8_BOM);
+                }
+                if target_is_symlink(diff_plus, options.reverse) {
+                    let link_target = text.strip_suffix('\n').unwrap_or(&text);
+                    if tree.exists(&write_path) {
+                        tree.remove(&write_path)?;
+                    }
+                    tree.symlink(&write_path, link_target)?;
+                } else {
+                    tree.store(&write_path, text.as_bytes())?;
+                    if let Some(mode) = target_file_mode(diff_plus, options.reverse) {
+                        tree.chmod(&write_path, mode)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(FileApplyOutcome {
+        file_path,
+        change_kind,
+        hunk_outcomes: result.hunk_outcomes().to_vec(),
+    })
+}
+
+/// The outcome of applying a patch to a file tree (see
+/// `Patch::apply_to_directory`).
+#[derive(Debug)]
+pub struct PatchApplyReport {
+    /// The per file outcomes, in patch order.
+    pub files: Vec<FileApplyOutcome>,
+    /// The diagnostics written while applying.
+    pub log: String,
+}
+
+impl PatchApplyReport {
+    /// Did every hunk of every file get merged?
+    pub fn is_successful(&self) -> bool {
+        !self
+            .files
+            .iter()
+            .flat_map(|file| file.hunk_outcomes.iter())
+            .any(|outcome| matches!(outcome, HunkOutcome::Failed { .. }))
+    }
+}
+
+/// What happened to one touched file during `Patch::apply_to_directory`.
+#[derive(Debug)]
+pub struct FileApplyOutcome {
+    pub file_path: PathBuf,
+    pub change_kind: ChangeKind,
+    /// What happened to each of the file's hunks, in order.
+    pub hunk_outcomes: Vec<HunkOutcome>,
+}
+
+/// What applying a patch would do, per file and per hunk, determined
+/// by a dry run (see `Patch::validate`) rather than by applying and
+/// inspecting diagnostic text.
+#[derive(Debug)]
+pub struct ValidationReport {
+    pub files: Vec<FileValidation>,
+}
+
+impl ValidationReport {
+    /// Would every hunk of every file merge (cleanly or otherwise)?
+    pub fn would_succeed(&self) -> bool {
+        !self
+            .files
+            .iter()
+            .flat_map(|file| file.hunk_outcomes.iter())
+            .any(|outcome| matches!(outcome, HunkOutcome::Failed { .. }))
+    }
+
+    /// Would every hunk apply exactly where its header nominates, with
+    /// no fuzz and no offset?
+    pub fn is_clean(&self) -> bool {
+        self.files
+            .iter()
+            .flat_map(|file| file.hunk_outcomes.iter())
+            .all(|outcome| matches!(outcome, HunkOutcome::Clean { offset: 0 }))
+    }
+}
+
+/// One file's slice of a `ValidationReport`.
+#[derive(Debug)]
+pub struct FileValidation {
+    pub file_path: PathBuf,
+    /// What would happen to each of the file's hunks, in order.
+    pub hunk_outcomes: Vec<HunkOutcome>,
+}
+
+/// Read only access to the current content of the files that a patch
+/// touches.  Any compatible closure is a `FileProvider`.
+pub trait FileProvider {
+    /// The content of `file_path`, or `None` if it doesn't exist.
+    fn fetch(&self, file_path: &Path) -> Option<Vec<u8>>;
+}
+
+impl<F: Fn(&Path) -> Option<Vec<u8>>> FileProvider for F {
+    fn fetch(&self, file_path: &Path) -> Option<Vec<u8>> {
+        self(file_path)
+    }
+}
+
+/// An observer of long running parse and apply operations, for
+/// driving progress displays.  Every method has an empty default body
+/// so implementations need only override the events that they can
+/// show; `()` is the no-op observer that the plain entry points use.
+pub trait Progress {
+    /// Work has started on the file at `file_path`.  During parsing
+    /// this is called as each file's diff is recognized.
+    fn file_started(&mut self, _file_path: &Path) {}
+
+    /// `done` of the current file's `total` hunks have been processed.
+    fn hunks_done(&mut self, _done: usize, _total: usize) {}
+
+    /// `bytes` bytes of the input have been consumed so far.
+    fn bytes_consumed(&mut self, _bytes: usize) {}
+}
+
+impl Progress for () {}
+
+/// The operations that patch application needs of its target file
+/// tree, abstracted so that patches can be applied to overlay or in
+/// memory trees (e.g. in tests or a server process) as easily as to
+/// the real filesystem.  All paths are relative to the tree's root.
+pub trait WorkTree {
+    /// The content of `file_path`, or `None` if it doesn't exist.
+    fn fetch(&self, file_path: &Path) -> Option<Vec<u8>>;
+
+    /// Create or overwrite `file_path` (and any missing parent
+    /// directories) with `content`.
+    fn store(&mut self, file_path: &Path, content: &[u8]) -> io::Result<()>;
+
+    /// Remove `file_path`.
+    fn remove(&mut self, file_path: &Path) -> io::Result<()>;
+
+    /// Move `from` to `to`, carrying any tree level metadata with it.
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        match self.fetch(from) {
+            Some(content) => {
+                self.store(to, &content)?;
+                self.remove(from)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Is there something (a file, symlink etc.) at `file_path`?
+    fn exists(&self, file_path: &Path) -> bool;
+
+    /// Set `file_path`'s Unix permission bits.  Trees with no notion
+    /// of permissions may ignore this.
+    fn chmod(&mut self, _file_path: &Path, _mode: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The target text of the symlink at `file_path`, or `None` if
+    /// there isn't one (or the tree has no notion of symlinks).
+    fn read_link(&self, _file_path: &Path) -> Option<String> {
+        None
+    }
+
+    /// Make `file_path` a symlink to `target`.  Trees with no notion
+    /// of symlinks store the target text as ordinary content.
+    fn symlink(&mut self, file_path: &Path, target: &str) -> io::Result<()> {
+        self.store(file_path, target.as_bytes())
+    }
+}
+
+/// The `WorkTree` backed by the real filesystem beneath a root
+/// directory.
+pub struct OsWorkTree {
+    root: PathBuf,
+}
+
+impl OsWorkTree {
+    pub fn new(root: &Path) -> OsWorkTree {
+        OsWorkTree {
+            root: root.to_path_buf(),
+        }
+    }
+}
+
+impl WorkTree for OsWorkTree {
+    fn fetch(&self, file_path: &Path) -> Option<Vec<u8>> {
+        fs::read(self.root.join(file_path)).ok()
+    }
+
+    fn store(&mut self, file_path: &Path, content: &[u8]) -> io::Result<()> {
+        let full_path = self.root.join(file_path);
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        crate::lines::write_bytes_atomically(&full_path, content)
+    }
+
+    fn remove(&mut self, file_path: &Path) -> io::Result<()> {
+        fs::remove_file(self.root.join(file_path))
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        let full_to = self.root.join(to);
+        if let Some(parent) = full_to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(self.root.join(from), full_to)
+    }
+
+    fn exists(&self, file_path: &Path) -> bool {
+        fs::symlink_metadata(self.root.join(file_path)).is_ok()
+    }
+
+    #[cfg(unix)]
+    fn chmod(&mut self, file_path: &Path, mode: u32) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(self.root.join(file_path), fs::Permissions::from_mode(mode))
+    }
+
+    fn read_link(&self, file_path: &Path) -> Option<String> {
+        let full_path = self.root.join(file_path);
+        let metadata = fs::symlink_metadata(&full_path).ok()?;
+        if !metadata.file_type().is_symlink() {
+            return None;
+        }
+        Some(
+            fs::read_link(full_path)
+                .ok()?
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+
+    #[cfg(unix)]
+    fn symlink(&mut self, file_path: &Path, target: &str) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, self.root.join(file_path))
+    }
+}
+
+/// Where a patch stands with respect to the current contents of the
+/// files that it touches (see `Patch::check_against`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchState {
+    /// Every diff applies in the forward direction and none of it is
+    /// in place yet.
+    NotApplied,
+    /// Every hunk's post image is already in place.
+    FullyApplied,
+    /// Some of the patch is in place and some is not (or some of it no
+    /// longer fits at all).
+    PartiallyApplied,
+    /// The patch does not apply forward but does apply in reverse:
+    /// either it has been applied (and since drifted from its nominal
+    /// positions) or its direction is reversed.
+    AppearsReversed,
+}
+
+/// A per file report on whether the target file's textual
+/// characteristics match what the patch's hunks expect of it.
+#[derive(Debug, Clone)]
+pub struct TargetContentReport {
+    pub file_path: PathBuf,
+    /// A summary of the target file's current content or `None` if it
+    /// was unavailable (e.g. the patch creates the file).
+    pub summary: Option<ContentSummary>,
+    /// The end of line style of the lines the patch quotes for the
+    /// file.
+    pub patch_eol_style: EndOfLineStyle,
+    /// False when the target's encoding is not line oriented text or
+    /// its end of line style disagrees with the patch's: fore-warning
+    /// of "hunk failed" outcomes.
+    pub consistent: bool,
+}
+
+/// The end of line style of the source lines that `diff_plus` quotes.
+fn patch_eol_style(diff_plus: &DiffPlus) -> EndOfLineStyle {
+    let diff = match diff_plus.diff() {
+        Diff::Unified(diff) => diff,
+        // Neither kind quotes lines to judge by.
+        Diff::BinaryMarker(_) | Diff::SvnProperties(_) => return EndOfLineStyle::Indeterminate,
+    };
+    let mut lf_count = 0;
+    let mut crlf_count = 0;
+    for hunk in diff.hunks.iter() {
+        for line in hunk.lines[1..].iter() {
+            if line.starts_with('\\') {
+                continue;
+            }
+            if line.ends_with("\r\n") {
+                crlf_count += 1;
+            } else if line.ends_with('\n') {
+                lf_count += 1;
+            }
+        }
+    }
+    eol_style_fm_counts(lf_count, crlf_count)
+}
+
+/// Are the end of line styles of a patch's quoted lines and its target
+/// file's content compatible?
+fn eol_styles_consistent(patch: EndOfLineStyle, target: EndOfLineStyle) -> bool {
+    patch == target
+        || patch == EndOfLineStyle::Indeterminate
+        || target == EndOfLineStyle::Indeterminate
+}
+
+impl Consumed for Patch {
+    fn start_index(&self) -> usize {
+        0
+    }
+
+    fn line_count(&self) -> usize {
+        self.header_lines.len()
+            + self
+                .diff_pluses
+                .iter()
+                .map(|diff_plus| diff_plus.line_count())
+                .sum::<usize>()
+            + self.rubbish.len()
+    }
+}
+
+fn touched_file(diff_plus: &DiffPlus, strip: usize) -> (PathBuf, ChangeKind) {
+    let (header_ante, header_post) = match diff_plus.diff() {
+        Diff::Unified(diff) => (
+            Some(diff.header().ante_pat.file_path.clone()),
+            Some(diff.header().post_pat.file_path.clone()),
+        ),
+        Diff::BinaryMarker(marker) => (
+            Some(marker.ante_file_path().clone()),
+            Some(marker.post_file_path().clone()),
+        ),
+        Diff::SvnProperties(properties) => (
+            Some(properties.file_path().clone()),
+            Some(properties.file_path().clone()),
+        ),
+    };
+    let (preamble_ante, preamble_post, preamble_kind) = match diff_plus.preamble() {
+        Some(preamble) => {
+            let kind = if let Some(from) = preamble.get_extra("rename from") {
+                Some(ChangeKind::Renamed(PathBuf::from(from)))
+            } else if preamble.get_extra("new file mode").is_some() {
+                Some(ChangeKind::Added)
+            } else if preamble.get_extra("deleted file mode").is_some() {
+                Some(ChangeKind::Deleted)
+            } else {
+                preamble
+                    .get_extra("copy from")
+                    .map(|from| ChangeKind::Copied(PathBuf::from(from)))
+            };
+            (
+                Some(preamble.ante_file_path().clone()),
+                Some(preamble.post_file_path().clone()),
+                kind,
+            )
+        }
+        None => (None, None, None),
+    };
+    let ante = header_ante.or(preamble_ante);
+    let post = header_post.or(preamble_post);
+    let kind = if let Some(kind) = preamble_kind {
+        kind
+    } else if ante.as_ref().is_some_and(|p| is_dev_null(p)) {
+        ChangeKind::Added
+    } else if post.as_ref().is_some_and(|p| is_dev_null(p)) {
+        ChangeKind::Deleted
+    } else {
+        ChangeKind::Modified
+    };
+    let file_path = match kind {
+        ChangeKind::Deleted => ante.unwrap_or_default(),
+        _ => match post {
+            Some(path) if !is_dev_null(&path) => path,
+            _ => ante.unwrap_or_default(),
+        },
+    };
+    (strip_path(&file_path, strip), kind)
+}
+
+/// The path used to decide whether two diffs touch the same file when
+/// combining patches: the post file name (ante for deletions) with any
+/// "a/"/"b/" diff prefix removed.
+fn file_key(diff_plus: &DiffPlus) -> PathBuf {
+    let (path, _) = touched_file(diff_plus, 0);
+    match path.components().next() {
+        Some(Component::Normal(first)) if first == "a" || first == "b" => strip_path(&path, 1),
+        _ => path,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CombineSide {
+    A,
+    B,
+}
+
+/// An interval in the coordinates of the intermediate file: the lines
+/// that a hunk of patch A produces or the lines that a hunk of patch B
+/// expects.
+struct CombineItem<'h> {
+    start: usize,
+    end: usize,
+    side: CombineSide,
+    hunk: &'h AbstractHunk,
+}
+
+fn splice_lines(segment: &mut Lines, start: usize, remove: usize, replacement: &Lines) {
+    segment.splice(start..start + remove, replacement.iter().map(Arc::clone));
+}
+
+/// Compose the hunks of patch B (relative to the file produced by
+/// patch A) with those of patch A producing hunks relative to the
+/// original file.  Hunks that touch overlapping regions of the
+/// intermediate file are merged.
+fn combine_hunks(
+    hunks_a: &[AbstractHunk],
+    hunks_b: &[AbstractHunk],
+) -> DiffParseResult<Vec<AbstractHunk>> {
+    let mut items: Vec<CombineItem> = Vec::new();
+    for hunk in hunks_a.iter() {
+        let start = hunk.post_chunk().start_index;
+        items.push(CombineItem {
+            start,
+            end: start + hunk.post_chunk().lines.len(),
+            side: CombineSide::A,
+            hunk,
+        });
+    }
+    for hunk in hunks_b.iter() {
+        let start = hunk.ante_chunk().start_index;
+        items.push(CombineItem {
+            start,
+            end: start + hunk.ante_chunk().lines.len(),
+            side: CombineSide::B,
+            hunk,
+        });
+    }
+    items.sort_by_key(|item| (item.start, item.end));
+    // Cumulative change in line count caused by hunks of A (in
+    // intermediate coordinates) strictly before `mid`.
+    let delta_a_before = |mid: usize| -> isize {
+        hunks_a
+            .iter()
+            .filter(|h| h.post_chunk().start_index + h.post_chunk().lines.len() <= mid)
+            .map(|h| h.post_chunk().lines.len() as isize - h.ante_chunk().lines.len() as isize)
+            .sum()
+    };
+    let delta_b_before = |mid: usize| -> isize {
+        hunks_b
+            .iter()
+            .filter(|h| h.ante_chunk().start_index + h.ante_chunk().lines.len() <= mid)
+            .map(|h| h.post_chunk().lines.len() as isize - h.ante_chunk().lines.len() as isize)
+            .sum()
+    };
+    let mut combined: Vec<AbstractHunk> = Vec::new();
+    let mut i = 0_usize;
+    while i < items.len() {
+        let mut j = i + 1;
+        let mut hi = items[i].end;
+        while j < items.len() && items[j].start < hi {
+            hi = hi.max(items[j].end);
+            j += 1;
+        }
+        let cluster = &items[i..j];
+        let lo = items[i].start;
+        if cluster.len() == 1 {
+            let item = &cluster[0];
+            match item.side {
+                CombineSide::A => {
+                    let post = item.hunk.post_chunk();
+                    let start_index = (post.start_index as isize + delta_b_before(post.start_index))
+                        .max(0) as usize;
+                    combined.push(AbstractHunk::new(
+                        item.hunk.ante_chunk().clone(),
+                        AbstractChunk {
+                            start_index,
+                            lines: post.lines.clone(),
+                        },
+                    ));
+                }
+                CombineSide::B => {
+                    let ante = item.hunk.ante_chunk();
+                    let start_index = (ante.start_index as isize - delta_a_before(ante.start_index))
+                        .max(0) as usize;
+                    combined.push(AbstractHunk::new(
+                        AbstractChunk {
+                            start_index,
+                            lines: ante.lines.clone(),
+                        },
+                        item.hunk.post_chunk().clone(),
+                    ));
+                }
+            }
+        } else {
+            // Reconstruct the intermediate file over the cluster's span.
+            let mut mid_lines: Vec<Option<Line>> = vec![None; hi - lo];
+            for item in cluster.iter() {
+                let chunk = match item.side {
+                    CombineSide::A => item.hunk.post_chunk(),
+                    CombineSide::B => item.hunk.ante_chunk(),
+                };
+                for (k, line) in chunk.lines.iter().enumerate() {
+                    let slot = &mut mid_lines[item.start - lo + k];
+                    match slot {
+                        Some(existing) => {
+                            if existing != line {
+                                return Err(DiffParseError::CombineConflict(item.start + k));
+                            }
+                        }
+                        None => *slot = Some(Arc::clone(line)),
+                    }
+                }
+            }
+            let mid_lines = mid_lines
+                .into_iter()
+                .map(|slot| slot.ok_or(DiffParseError::CombineConflict(lo)))
+                .collect::<DiffParseResult<Lines>>()?;
+            let mut ante_lines = mid_lines.clone();
+            let mut a_items: Vec<&CombineItem> = cluster
+                .iter()
+                .filter(|item| item.side == CombineSide::A)
+                .collect();
+            a_items.sort_by_key(|item| std::cmp::Reverse(item.start));
+            for item in a_items {
+                splice_lines(
+                    &mut ante_lines,
+                    item.start - lo,
+                    item.hunk.post_chunk().lines.len(),
+                    &item.hunk.ante_chunk().lines,
+                );
+            }
+            let mut post_lines = mid_lines;
+            let mut b_items: Vec<&CombineItem> = cluster
+                .iter()
+                .filter(|item| item.side == CombineSide::B)
+                .collect();
+            b_items.sort_by_key(|item| std::cmp::Reverse(item.start));
+            for item in b_items {
+                splice_lines(
+                    &mut post_lines,
+                    item.start - lo,
+                    item.hunk.ante_chunk().lines.len(),
+                    &item.hunk.post_chunk().lines,
+                );
+            }
+            let ante_start = (lo as isize - delta_a_before(lo)).max(0) as usize;
+            let post_start = (lo as isize + delta_b_before(lo)).max(0) as usize;
+            combined.push(AbstractHunk::new(
+                AbstractChunk {
+                    start_index: ante_start,
+                    lines: ante_lines,
+                },
+                AbstractChunk {
+                    start_index: post_start,
+                    lines: post_lines,
+                },
+            ));
+        }
+        i = j;
+    }
+    Ok(combined)
+}
+
+/// Build a `UnifiedDiff` from regenerated hunks and a header.
+fn unified_diff_from(header: TextDiffHeader, abstract_hunks: &[AbstractHunk]) -> UnifiedDiff {
+    let hunks: Vec<UnifiedDiffHunk> = abstract_hunks.iter().map(UnifiedDiffHunk::from).collect();
+    let lines_consumed = header.lines.len() + hunks.iter().map(|hunk| hunk.len()).sum::<usize>();
+    UnifiedDiff {
+        lines_consumed,
+        diff_format: DiffFormat::Unified,
+        header,
+        hunks,
+    }
+}
+
+fn combine_diff_plus(diff_plus_a: &DiffPlus, diff_plus_b: &DiffPlus) -> DiffParseResult<DiffPlus> {
+    let (diff_a, diff_b) = match (diff_plus_a.diff(), diff_plus_b.diff()) {
+        (Diff::Unified(diff_a), Diff::Unified(diff_b)) => (diff_a, diff_b),
+        // Binary content is absent so hunks cannot be merged: the
+        // later diff already describes the file's net state.
+        _ => return Ok(diff_plus_b.clone()),
+    };
+    let hunks_a: Vec<AbstractHunk> = diff_a
+        .hunks
+        .iter()
+        .map(|hunk| hunk.get_abstract_diff_hunk())
+        .collect();
+    let hunks_b: Vec<AbstractHunk> = diff_b
+        .hunks
+        .iter()
+        .map(|hunk| hunk.get_abstract_diff_hunk())
+        .collect();
+    let combined = combine_hunks(&hunks_a, &hunks_b)?;
+    let header = TextDiffHeader {
+        start_index: 0,
+        lines: vec![
+            Arc::clone(&diff_a.header().lines[0]),
+            Arc::clone(&diff_b.header().lines[1]),
+        ],
+        ante_pat: diff_a.header().ante_pat.clone(),
+        post_pat: diff_b.header().post_pat.clone(),
+    };
+    Ok(DiffPlus {
+        preamble: None,
+        diff: Diff::Unified(unified_diff_from(header, &combined)),
+    })
+}
+
+/// Fold `patch_b` (which applies to the output of `patch_a`) into
+/// `patch_a` producing a single equivalent patch (a la "combinediff").
+/// Hunks of the two patches that touch overlapping regions of a file
+/// are merged; an error is returned if the two patches disagree about
+/// the contents of the intermediate file.
+pub fn combine(patch_a: &Patch, patch_b: &Patch) -> DiffParseResult<Patch> {
+    let keys_a: Vec<PathBuf> = patch_a.diff_pluses.iter().map(file_key).collect();
+    let keys_b: Vec<PathBuf> = patch_b.diff_pluses.iter().map(file_key).collect();
+    let mut diff_pluses: Vec<DiffPlus> = Vec::new();
+    for (diff_plus_a, key) in patch_a.diff_pluses.iter().zip(keys_a.iter()) {
+        if let Some(index_b) = keys_b.iter().position(|key_b| key_b == key) {
+            diff_pluses.push(combine_diff_plus(
+                diff_plus_a,
+                &patch_b.diff_pluses[index_b],
+            )?);
+        } else {
+            diff_pluses.push(diff_plus_a.clone());
+        }
+    }
+    for (diff_plus_b, key) in patch_b.diff_pluses.iter().zip(keys_b.iter()) {
+        if !keys_a.contains(key) {
+            diff_pluses.push(diff_plus_b.clone());
+        }
+    }
+    Ok(Patch {
+        header_lines: patch_a.header_lines.clone(),
+        diff_pluses,
+        rubbish: Vec::new(),
+    })
+}
+
+/// A limit imposed on the patch parser which a patch may exceed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchLimit {
+    TotalLines(usize),
+    FileCount(usize),
+}
+
+/// `header` with its ante and post sides exchanged.
+fn swapped_header(header: &TextDiffHeader) -> TextDiffHeader {
+    TextDiffHeader {
+        start_index: 0,
+        lines: vec![
+            Arc::new(format!("--- {}", &header.lines[1][4..])),
+            Arc::new(format!("+++ {}", &header.lines[0][4..])),
+        ],
+        ante_pat: header.post_pat.clone(),
+        post_pat: header.ante_pat.clone(),
+    }
+}
+
+/// `diff_plus` with the direction of its diff reversed.  Any preamble
+/// is dropped as its direction sensitive extras aren't needed here.
+fn reversed_diff_plus(diff_plus: &DiffPlus) -> DiffPlus {
+    let diff = match diff_plus.diff() {
+        Diff::Unified(diff) => diff,
+        Diff::BinaryMarker(marker) => {
+            return DiffPlus {
+                preamble: None,
+                diff: Diff::BinaryMarker(marker.reversed()),
+            }
+        }
+        Diff::SvnProperties(properties) => {
+            return DiffPlus {
+                preamble: None,
+                diff: Diff::SvnProperties(properties.reversed()),
+            }
+        }
+    };
+    let abstract_hunks: Vec<AbstractHunk> = diff
+        .hunks
+        .iter()
+        .map(|hunk| {
+            let abstract_hunk = hunk.get_abstract_diff_hunk();
+            AbstractHunk::new(
+                abstract_hunk.post_chunk().clone(),
+                abstract_hunk.ante_chunk().clone(),
+            )
+        })
+        .collect();
+    let header = swapped_header(diff.header());
+    DiffPlus {
+        preamble: None,
+        diff: Diff::Unified(unified_diff_from(header, &abstract_hunks)),
+    }
+}
+
+/// The difference between two versions of a patch against the same
+/// base: the resulting patch transforms a file tree patched with
+/// `patch_a` into one patched with `patch_b` (a la "interdiff").  An
+/// error is returned if the two patches disagree about the base.
+pub fn interdiff(patch_a: &Patch, patch_b: &Patch) -> DiffParseResult<Patch> {
+    let reversed_a = Patch {
+        header_lines: Vec::new(),
+        diff_pluses: patch_a.diff_pluses.iter().map(reversed_diff_plus).collect(),
+        rubbish: Vec::new(),
+    };
+    combine(&reversed_a, patch_b)
+}
+
+/// Renumber `hunks_b` (which apply after `hunks_a`) so that they apply
+/// directly to the original file and `hunks_a` so that they apply
+/// after the renumbered `hunks_b` i.e. swap the order in which the two
+/// sets of hunks are applied.  Returns `None` if any of the hunks
+/// touch overlapping regions of the intermediate file as such hunks do
+/// not commute.
+fn commute_abstract_hunks(
+    hunks_a: &[AbstractHunk],
+    hunks_b: &[AbstractHunk],
+) -> Option<(Vec<AbstractHunk>, Vec<AbstractHunk>)> {
+    for hunk_b in hunks_b.iter() {
+        let b_start = hunk_b.ante_chunk().start_index;
+        let b_end = b_start + hunk_b.ante_chunk().lines.len();
+        for hunk_a in hunks_a.iter() {
+            let a_start = hunk_a.post_chunk().start_index;
+            let a_end = a_start + hunk_a.post_chunk().lines.len();
+            if b_start < a_end && a_start < b_end {
+                return None;
+            }
+        }
+    }
+    let shifted = |chunk: &AbstractChunk, delta: isize| AbstractChunk {
+        start_index: (chunk.start_index as isize + delta) as usize,
+        lines: chunk.lines.clone(),
+    };
+    // Cumulative change in line count caused by hunks of A (in
+    // intermediate coordinates) strictly before `mid`.
+    let delta_a_before = |mid: usize| -> isize {
+        hunks_a
+            .iter()
+            .filter(|h| h.post_chunk().start_index + h.post_chunk().lines.len() <= mid)
+            .map(|h| h.post_chunk().lines.len() as isize - h.ante_chunk().lines.len() as isize)
+            .sum()
+    };
+    let new_b: Vec<AbstractHunk> = hunks_b
+        .iter()
+        .map(|hunk| {
+            let delta = -delta_a_before(hunk.ante_chunk().start_index);
+            AbstractHunk::new(
+                shifted(hunk.ante_chunk(), delta),
+                shifted(hunk.post_chunk(), delta),
+            )
+        })
+        .collect();
+    // Cumulative change in line count caused by the renumbered hunks
+    // of B (in original file coordinates) strictly before `orig`.
+    let delta_b_before = |orig: usize| -> isize {
+        new_b
+            .iter()
+            .filter(|h| h.ante_chunk().start_index + h.ante_chunk().lines.len() <= orig)
+            .map(|h| h.post_chunk().lines.len() as isize - h.ante_chunk().lines.len() as isize)
+            .sum()
+    };
+    let new_a: Vec<AbstractHunk> = hunks_a
+        .iter()
+        .map(|hunk| {
+            let delta = delta_b_before(hunk.ante_chunk().start_index);
+            AbstractHunk::new(
+                shifted(hunk.ante_chunk(), delta),
+                shifted(hunk.post_chunk(), delta),
+            )
+        })
+        .collect();
+    Some((new_b, new_a))
+}
+
+fn commute_diff_plus(
+    diff_plus_a: &DiffPlus,
+    diff_plus_b: &DiffPlus,
+) -> Option<(DiffPlus, DiffPlus)> {
+    let (diff_a, diff_b) = match (diff_plus_a.diff(), diff_plus_b.diff()) {
+        (Diff::Unified(diff_a), Diff::Unified(diff_b)) => (diff_a, diff_b),
+        // A binary marker carries no line information, so there is no
+        // safe way to reorder around it.
+        _ => return None,
+    };
+    let hunks_a: Vec<AbstractHunk> = diff_a
+        .hunks
+        .iter()
+        .map(|hunk| hunk.get_abstract_diff_hunk())
+        .collect();
+    let hunks_b: Vec<AbstractHunk> = diff_b
+        .hunks
+        .iter()
+        .map(|hunk| hunk.get_abstract_diff_hunk())
+        .collect();
+    let (new_b, new_a) = commute_abstract_hunks(&hunks_a, &hunks_b)?;
+    let new_diff_plus_b = DiffPlus {
+        preamble: diff_plus_b.preamble.clone(),
+        diff: Diff::Unified(unified_diff_from(diff_b.header().clone(), &new_b)),
+    };
+    let new_diff_plus_a = DiffPlus {
+        preamble: diff_plus_a.preamble.clone(),
+        diff: Diff::Unified(unified_diff_from(diff_a.header().clone(), &new_a)),
+    };
+    Some((new_diff_plus_b, new_diff_plus_a))
+}
+
+/// Swap the order of application of `patch_a` and `patch_b` (where
+/// `patch_b` applies to the output of `patch_a`): the result is the
+/// pair of equivalent patches in the opposite order, as needed for
+/// quilt style push/pop reordering.  Returns `None` if the two patches
+/// touch overlapping regions of any file and therefore do not commute.
+pub fn commute(patch_a: &Patch, patch_b: &Patch) -> Option<(Patch, Patch)> {
+    let keys_a: Vec<PathBuf> = patch_a.diff_pluses.iter().map(file_key).collect();
+    let keys_b: Vec<PathBuf> = patch_b.diff_pluses.iter().map(file_key).collect();
+    let mut diff_pluses_a: Vec<DiffPlus> = Vec::new();
+    let mut commuted_b: Vec<Option<DiffPlus>> = vec![None; patch_b.diff_pluses.len()];
+    for (diff_plus_a, key) in patch_a.diff_pluses.iter().zip(keys_a.iter()) {
+        if let Some(index_b) = keys_b.iter().position(|key_b| key_b == key) {
+            let (new_b, new_a) = commute_diff_plus(diff_plus_a, &patch_b.diff_pluses[index_b])?;
+            commuted_b[index_b] = Some(new_b);
+            diff_pluses_a.push(new_a);
+        } else {
+            diff_pluses_a.push(diff_plus_a.clone());
+        }
+    }
+    let diff_pluses_b: Vec<DiffPlus> = patch_b
+        .diff_pluses
+        .iter()
+        .zip(commuted_b.iter_mut())
+        .map(|(diff_plus_b, commuted)| match commuted.take() {
+            Some(new_b) => new_b,
+            None => diff_plus_b.clone(),
+        })
+        .collect();
+    Some((
+        Patch {
+            header_lines: patch_b.header_lines.clone(),
+            diff_pluses: diff_pluses_b,
+            rubbish: Vec::new(),
+        },
+        Patch {
+            header_lines: patch_a.header_lines.clone(),
+            diff_pluses: diff_pluses_a,
+            rubbish: Vec::new(),
+        },
+    ))
+}
+
+/// A region of one file that two patches (against the same base) both
+/// touch: see `Patch::conflicts_with`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictingRegion {
+    /// The touched file's path with any "a/"/"b/" prefix removed.
+    pub file_path: PathBuf,
+    /// The overlapping lines as a zero based, end exclusive (start,
+    /// end) range in the base file ((0, 0) when a binary marker or
+    /// property block leaves no line ranges to compare).
+    pub range: (usize, usize),
+}
+
+/// The value of an "index" preamble line for the given ante and post
+/// content (`None` for a side on which the file doesn't exist): real
+/// blob hashes, which consumers like "git apply --3way" need, with the
+/// file `mode` appended when one is given.
+fn index_extra_value(ante: Option<&[u8]>, post: Option<&[u8]>, mode: Option<&str>) -> String {
+    let oid = |content: Option<&[u8]>| match content {
+        Some(content) => blob_oid(content),
+        None => "0".repeat(40),
+    };
+    let mut value = format!("{}..{}", oid(ante), oid(post));
+    if let Some(mode) = mode {
+        value.push(' ');
+        value.push_str(mode);
+    }
+    value
+}
+
+/// Assemble a `Patch` programmatically: the write path complementing
+/// `PatchParser`.  Files are added as before/after content (which is
+/// diffed internally) or as prebuilt `DiffPlus` objects and the
+/// resulting patch's header carries the description followed by a
+/// generated diffstat.
+pub struct PatchBuilder {
+    description: Lines,
+    comments: Lines,
+    context: usize,
+    diff_pluses: Vec<DiffPlus>,
+}
+
+impl Default for PatchBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PatchBuilder {
+    pub fn new() -> PatchBuilder {
+        PatchBuilder {
+            description: Vec::new(),
+            comments: Vec::new(),
+            context: 3,
+            diff_pluses: Vec::new(),
+        }
+    }
+
+    /// Quote `context` lines of context in generated diffs (the
+    /// default is 3).
+    pub fn with_context(mut self, context: usize) -> PatchBuilder {
+        self.context = context;
+        self
+    }
+
+    /// The free text description to place at the top of the patch.
+    pub fn description(mut self, text: &str) -> PatchBuilder {
+        self.description = Lines::from_string(text);
+        self
+    }
+
+    /// Add free text comment lines to place after the description
+    /// (and before the diffstat) in the header.
+    pub fn comment(mut self, text: &str) -> PatchBuilder {
+        self.comments.extend(Lines::from_string(text));
+        self
+    }
+
+    /// Add an already assembled diff (e.g. one parsed out of another
+    /// patch or produced by `combine`).
+    pub fn diff_plus(mut self, diff_plus: DiffPlus) -> PatchBuilder {
+        self.diff_pluses.push(diff_plus);
+        self
+    }
+
+    /// Add a diff transforming `ante_lines` into `post_lines` for the
+    /// file named `ante_path`/`post_path`.
+    pub fn file_change(
+        mut self,
+        ante_path: &Path,
+        post_path: &Path,
+        ante_lines: &Lines,
+        post_lines: &Lines,
+    ) -> PatchBuilder {
+        let abstract_hunks = generate_abstract_hunks(ante_lines, post_lines, self.context);
+        let header = TextDiffHeader {
+            start_index: 0,
+            lines: vec![
+                Arc::new(format!(
+                    "--- {}\n",
+                    encode_c_quoted_path(&ante_path.to_string_lossy())
+                )),
+                Arc::new(format!(
+                    "+++ {}\n",
+                    encode_c_quoted_path(&post_path.to_string_lossy())
+                )),
+            ],
+            ante_pat: PathAndTimestamp {
+                file_path: ante_path.to_path_buf(),
+                time_stamp: None,
+            },
+            post_pat: PathAndTimestamp {
+                file_path: post_path.to_path_buf(),
+                time_stamp: None,
+            },
+        };
+        self.diff_pluses.push(DiffPlus {
+            preamble: None,
+            diff: Diff::Unified(unified_diff_from(header, &abstract_hunks)),
+        });
+        self
+    }
+
+    /// Add a git style diff creating, changing or deleting the regular
+    /// file at `path`: like `file_change` but with a "diff --git"
+    /// preamble whose "index" line carries the computed ante and post
+    /// blob hashes.  `None` content marks the side on which the file
+    /// does not exist.
+    pub fn git_file_change(
+        mut self,
+        path: &Path,
+        ante_lines: Option<&Lines>,
+        post_lines: Option<&Lines>,
+    ) -> PatchBuilder {
+        const FILE_MODE: &str = "100644";
+        let mut preamble_lines: Lines = vec![Arc::new(format!(
+            "diff --git {0} {1}\n",
+            encode_c_quoted_path(&format!("a/{}", path.display())),
+            encode_c_quoted_path(&format!("b/{}", path.display()))
+        ))];
+        let mut extras: HashMap<String, String> = HashMap::new();
+        let mode_line: Option<(&str, &str)> = match (ante_lines, post_lines) {
+            (None, Some(_)) => Some(("new file mode", FILE_MODE)),
+            (Some(_), None) => Some(("deleted file mode", FILE_MODE)),
+            _ => None,
+        };
+        if let Some((label, value)) = mode_line {
+            preamble_lines.push(Arc::new(format!("{} {}\n", label, value)));
+            extras.insert(label.to_string(), value.to_string());
+        }
+        let content = |lines: Option<&Lines>| {
+            lines.map(|lines| {
+                lines
+                    .iter()
+                    .map(|line| line.as_str())
+                    .collect::<String>()
+                    .into_bytes()
+            })
+        };
+        let index_mode = if mode_line.is_none() {
+            Some(FILE_MODE)
+        } else {
+            None
+        };
+        let index_value = index_extra_value(
+            content(ante_lines).as_deref(),
+            content(post_lines).as_deref(),
+            index_mode,
+        );
+        preamble_lines.push(Arc::new(format!("index {}\n", index_value)));
+        extras.insert("index".to_string(), index_value);
+        let preamble = Preamble::Git(GitPreamble {
+            start_index: 0,
+            lines: preamble_lines,
+            ante_file_path: PathBuf::from(format!("a/{}", path.display())),
+            post_file_path: PathBuf::from(format!("b/{}", path.display())),
+            extras,
+        });
+        let ante_name = match ante_lines {
+            Some(_) => format!("a/{}", path.display()),
+            None => "/dev/null".to_string(),
+        };
+        let post_name = match post_lines {
+            Some(_) => format!("b/{}", path.display()),
+            None => "/dev/null".to_string(),
+        };
+        let empty: Lines = Vec::new();
+        let abstract_hunks = generate_abstract_hunks(
+            ante_lines.unwrap_or(&empty),
+            post_lines.unwrap_or(&empty),
+            self.context,
+        );
+        let header = TextDiffHeader {
+            start_index: 0,
+            lines: vec![
+                Arc::new(format!("--- {}\n", ante_name)),
+                Arc::new(format!("+++ {}\n", post_name)),
+            ],
+            ante_pat: PathAndTimestamp {
+                file_path: PathBuf::from(ante_name),
+                time_stamp: None,
+            },
+            post_pat: PathAndTimestamp {
+                file_path: PathBuf::from(post_name),
+                time_stamp: None,
+            },
+        };
+        self.diff_pluses.push(DiffPlus {
+            preamble: Some(preamble),
+            diff: Diff::Unified(unified_diff_from(header, &abstract_hunks)),
+        });
+        self
+    }
+
+    /// Add a git style diff creating, retargeting or deleting the
+    /// symlink at `path`: `ante_target` and `post_target` are the link
+    /// texts (`None` for the side on which the link does not exist).
+    /// The link text travels as the diff's (unterminated) content with
+    /// the symlink-ness carried by mode 120000 preamble lines.
+    pub fn symlink_change(
+        mut self,
+        path: &Path,
+        ante_target: Option<&str>,
+        post_target: Option<&str>,
+    ) -> PatchBuilder {
+        let mut preamble_lines: Lines = vec![Arc::new(format!(
+            "diff --git {0} {1}\n",
+            encode_c_quoted_path(&format!("a/{}", path.display())),
+            encode_c_quoted_path(&format!("b/{}", path.display()))
+        ))];
+        let mut extras: HashMap<String, String> = HashMap::new();
+        let mode_lines: &[(&str, &str)] = match (ante_target, post_target) {
+            (None, Some(_)) => &[("new file mode", "120000")],
+            (Some(_), None) => &[("deleted file mode", "120000")],
+            _ => &[("old mode", "120000"), ("new mode", "120000")],
+        };
+        for (label, value) in mode_lines {
+            preamble_lines.push(Arc::new(format!("{} {}\n", label, value)));
+            extras.insert(label.to_string(), value.to_string());
+        }
+        // The mode only travels on the "index" line when no mode lines
+        // state it, i.e. for a retarget.
+        let index_mode = match (ante_target, post_target) {
+            (Some(_), Some(_)) => Some("120000"),
+            _ => None,
+        };
+        let index_value = index_extra_value(
+            ante_target.map(|text| text.as_bytes()),
+            post_target.map(|text| text.as_bytes()),
+            index_mode,
+        );
+        preamble_lines.push(Arc::new(format!("index {}\n", index_value)));
+        extras.insert("index".to_string(), index_value);
+        let preamble = Preamble::Git(GitPreamble {
+            start_index: 0,
+            lines: preamble_lines,
+            ante_file_path: PathBuf::from(format!("a/{}", path.display())),
+            post_file_path: PathBuf::from(format!("b/{}", path.display())),
+            extras,
+        });
+        let (ante_name, post_name) = (
+            match ante_target {
+                Some(_) => format!("a/{}", path.display()),
+                None => "/dev/null".to_string(),
+            },
+            match post_target {
+                Some(_) => format!("b/{}", path.display()),
+                None => "/dev/null".to_string(),
+            },
+        );
+        let as_lines = |target: Option<&str>| -> Lines {
+            target
+                .map(|text| vec![Arc::new(text.to_string())])
+                .unwrap_or_default()
+        };
+        let abstract_hunks =
+            generate_abstract_hunks(&as_lines(ante_target), &as_lines(post_target), self.context);
+        let header = TextDiffHeader {
+            start_index: 0,
+            lines: vec![
+                Arc::new(format!("--- {}\n", ante_name)),
+                Arc::new(format!("+++ {}\n", post_name)),
+            ],
+            ante_pat: PathAndTimestamp {
+                file_path: PathBuf::from(ante_name),
+                time_stamp: None,
+            },
+            post_pat: PathAndTimestamp {
+                file_path: PathBuf::from(post_name),
+                time_stamp: None,
+            },
+        };
+        self.diff_pluses.push(DiffPlus {
+            preamble: Some(preamble),
+            diff: Diff::Unified(unified_diff_from(header, &abstract_hunks)),
+        });
+        self
+    }
+
+    /// Produce the assembled patch: description and diffstat as the
+    /// header followed by the diffs.
+    pub fn build(self) -> Patch {
+        let mut header_lines = self.description;
+        header_lines.extend(self.comments);
+        header_lines.extend(diffstat_lines(&self.diff_pluses));
+        Patch {
+            header_lines,
+            diff_pluses: self.diff_pluses,
+            rubbish: Vec::new(),
+        }
+    }
+}
+
+fn plural(count: usize) -> &'static str {
+    if count == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
+
+/// The tallies reported by one "path | N ++--" line of a diffstat.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDiffStats {
+    pub file_path: PathBuf,
+    /// The total change count printed before the bars (zero for
+    /// binary files, whose content isn't counted in lines).
+    pub total: usize,
+    /// The number of "+" marks.
+    pub plus: usize,
+    /// The number of "-" marks.
+    pub minus: usize,
+    /// The number of "!" marks: classic "diffstat" reports in place
+    /// modifications separately from insertions and deletions.
+    pub modified: usize,
+    pub is_binary: bool,
+}
+
+/// Parse the per file "path | N ++--" lines of a diffstat (e.g. one
+/// lifted out of a patch's header), skipping the trailing summary and
+/// any other lines that don't fit the pattern.  Note that the bar
+/// counts may be scaled down from the real change counts: the total is
+/// the accurate figure.
+pub fn parse_diffstat_lines(lines: &Lines) -> Vec<FileDiffStats> {
+    let mut stats: Vec<FileDiffStats> = Vec::new();
+    for line in lines.iter() {
+        let (name, rest) = match line.trim_end_matches('\n').rsplit_once('|') {
+            Some((name, rest)) => (name.trim(), rest.trim()),
+            None => continue,
+        };
+        if name.is_empty() {
+            continue;
+        }
+        if rest.starts_with("Bin") {
+            stats.push(FileDiffStats {
+                file_path: PathBuf::from(name),
+                total: 0,
+                plus: 0,
+                minus: 0,
+                modified: 0,
+                is_binary: true,
+            });
+            continue;
+        }
+        let mut fields = rest.split_whitespace();
+        let total = match fields.next().and_then(|text| text.parse::<usize>().ok()) {
+            Some(total) => total,
+            None => continue,
+        };
+        let bars = fields.next().unwrap_or("");
+        if fields.next().is_some() || !bars.chars().all(|c| matches!(c, '+' | '-' | '!')) {
+            continue;
+        }
+        stats.push(FileDiffStats {
+            file_path: PathBuf::from(name),
+            total,
+            plus: bars.matches('+').count(),
+            minus: bars.matches('-').count(),
+            modified: bars.matches('!').count(),
+            is_binary: false,
+        });
+    }
+    stats
+}
+
+/// The per file tallies that one line of a diffstat reports.
+enum FileStat {
+    /// Lines added and lines removed.
+    Counts(usize, usize),
+    /// A binary difference marker: there is nothing to count.
+    Binary,
+    /// An svn property change block: there is nothing to count.
+    Properties,
+}
+
+/// "diffstat" style summary lines for `diff_pluses`: aligned
+/// "path | 12 ++++----" lines (with the bars scaled down to fit an 80
+/// column terminal when the counts are large) followed by the usual
+/// "N files changed, ..." summary.
+pub fn diffstat_lines(diff_pluses: &[DiffPlus]) -> Lines {
+    let mut stats: Vec<(String, FileStat)> = Vec::new();
+    let mut total_added = 0_usize;
+    let mut total_removed = 0_usize;
+    for diff_plus in diff_pluses.iter() {
+        let (path, _) = touched_file(diff_plus, 0);
+        let stat = match diff_plus.diff() {
+            Diff::Unified(diff) => {
+                let added: usize = diff.hunks.iter().map(|hunk| hunk.added_count()).sum();
+                let removed: usize = diff.hunks.iter().map(|hunk| hunk.removed_count()).sum();
+                total_added += added;
+                total_removed += removed;
+                FileStat::Counts(added, removed)
+            }
+            Diff::BinaryMarker(_) => FileStat::Binary,
+            Diff::SvnProperties(_) => FileStat::Properties,
+        };
+        stats.push((path.display().to_string(), stat));
+    }
+    let name_width = stats.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let max_change = stats
+        .iter()
+        .map(|(_, stat)| match stat {
+            FileStat::Counts(added, removed) => added + removed,
+            _ => 0,
+        })
+        .max()
+        .unwrap_or(0);
+    let count_width = max_change.to_string().len();
+    // The room left for the bars on an 80 column line, after the
+    // name, the count and the " ", " | " and " " separating them.
+    let graph_width = 80_usize
+        .saturating_sub(name_width + count_width + 5)
+        .max(10);
+    let scaled = |count: usize| -> usize {
+        if max_change <= graph_width || count == 0 {
+            count
+        } else {
+            // Never scale a non zero count out of sight.
+            (count * graph_width / max_change).max(1)
+        }
+    };
+    let mut lines: Lines = Vec::new();
+    for (name, stat) in stats.iter() {
+        let line = match stat {
+            FileStat::Counts(added, removed) => {
+                let bars = format!(
+                    "{}{}",
+                    "+".repeat(scaled(*added)),
+                    "-".repeat(scaled(*removed))
+                );
+                if bars.is_empty() {
+                    format!(
+                        " {:<name_width$} | {:>count_width$}\n",
+                        name,
+                        added + removed
+                    )
+                } else {
+                    format!(
+                        " {:<name_width$} | {:>count_width$} {}\n",
+                        name,
+                        added + removed,
+                        bars
+                    )
+                }
+            }
+            FileStat::Binary => format!(" {:<name_width$} | Bin\n", name),
+            FileStat::Properties => format!(" {:<name_width$} | Prop\n", name),
+        };
+        lines.push(Arc::new(line));
+    }
+    lines.push(Arc::new(format!(
+        " {} file{} changed, {} insertion{}(+), {} deletion{}(-)\n",
+        diff_pluses.len(),
+        plural(diff_pluses.len()),
+        total_added,
+        plural(total_added),
+        total_removed,
+        plural(total_removed)
+    )));
+    lines
+}
+
+pub struct PatchParser {
+    diff_plus_parser: DiffPlusParser,
+    max_lines: Option<usize>,
+    max_files: Option<usize>,
+}
+
+impl Default for PatchParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PatchParser {
+    pub fn new() -> PatchParser {
+        PatchParser {
+            diff_plus_parser: DiffPlusParser::new(),
+            max_lines: None,
+            max_files: None,
+        }
+    }
+
+    /// Refuse to parse patches of more than `max_lines` total lines.
+    pub fn with_max_lines(mut self, max_lines: usize) -> PatchParser {
+        self.max_lines = Some(max_lines);
+        self
+    }
+
+    /// Cross-check every hunk header's declared counts against its
+    /// body, rejecting patches whose bodies carry on past the counts:
+    /// see `UnifiedDiffParser::with_strict_counts`.
+    pub fn with_strict_counts(mut self) -> PatchParser {
+        self.diff_plus_parser = self.diff_plus_parser.with_strict_counts();
+        self
+    }
+
+    /// Refuse to parse patches touching more than `max_files` files.
+    pub fn with_max_files(mut self, max_files: usize) -> PatchParser {
+        self.max_files = Some(max_files);
+        self
+    }
+
+    /// Parse a complete patch from `lines`.
+    pub fn parse_lines(&self, lines: &Lines) -> DiffParseResult<Patch> {
+        self.parse_lines_with_progress(lines, &mut ())
+    }
+
+    /// As `parse_lines` but reporting progress events to `progress`:
+    /// the bytes consumed so far after each line or diff and each
+    /// file's diff as it is recognized.
+    pub fn parse_lines_with_progress(
+        &self,
+        lines: &Lines,
+        progress: &mut dyn Progress,
+    ) -> DiffParseResult<Patch> {
+        if let Some(max_lines) = self.max_lines {
+            if lines.len() > max_lines {
+                return Err(DiffParseError::TooLarge(PatchLimit::TotalLines(max_lines)));
+            }
+        }
+        // A leading byte order mark would stop the first line matching
+        // any header pattern: drop it before parsing.
+        let stripped: Lines;
+        let lines = if lines
+            .first()
+            .is_some_and(|line| line.starts_with(crate::lines::UTF8_BOM))
+        {
+            stripped = {
+                let mut lines = lines.clone();
+                crate::lines::strip_utf8_bom(&mut lines);
+                lines
+            };
+            &stripped
+        } else {
+            lines
+        };
+        let mut header_lines: Lines = Vec::new();
+        let mut diff_pluses: Vec<DiffPlus> = Vec::new();
+        let mut rubbish: Lines = Vec::new();
+        let mut index = 0_usize;
+        let mut bytes = 0_usize;
+        while index < lines.len() {
+            if let Some(diff_plus) = self.diff_plus_parser.get_diff_plus_at(lines, index)? {
+                progress.file_started(&touched_file(&diff_plus, 0).0);
+                bytes += lines[index..index + diff_plus.len()]
+                    .iter()
+                    .map(|line| line.len())
+                    .sum::<usize>();
+                index += diff_plus.len();
+                diff_pluses.push(diff_plus);
+                if let Some(max_files) = self.max_files {
+                    if diff_pluses.len() > max_files {
+                        return Err(DiffParseError::TooLarge(PatchLimit::FileCount(max_files)));
+                    }
+                }
+            } else if diff_pluses.is_empty() {
+                bytes += lines[index].len();
+                header_lines.push(Arc::clone(&lines[index]));
+                index += 1;
+            } else {
+                bytes += lines[index].len();
+                rubbish.push(Arc::clone(&lines[index]));
+                index += 1;
+            }
+            progress.bytes_consumed(bytes);
+        }
+        Ok(Patch {
+            header_lines,
+            diff_pluses,
+            rubbish,
+        })
+    }
+
+    /// Parse a complete patch from `string`.
+    pub fn parse_string(&self, string: &str) -> DiffParseResult<Patch> {
+        self.parse_lines(&Lines::from_string(string))
+    }
+}
+
+/// The number of lines that `StreamingPatchParser` reads from its
+/// reader at a time.
+const READ_CHUNK: usize = 64;
+
+/// The most lines that deciding whether a buffered line starts a diff
+/// can require: a full git preamble followed by the "---"/"+++" pair.
+const LOOKAHEAD: usize = 32;
+
+/// Parse the `DiffPlus` sections of a patch from a reader, yielding
+/// each as soon as it is complete: an iterator alternative to
+/// `PatchParser` for patches too large to hold in memory as `Lines`.
+/// Header and rubbish lines between diffs are discarded and the
+/// `start_index` of yielded diffs is relative to the parser's internal
+/// window rather than the whole patch file.
+pub struct StreamingPatchParser<R: io::BufRead> {
+    reader: R,
+    diff_plus_parser: DiffPlusParser,
+    buffer: Lines,
+    start: usize,
+    eof: bool,
+    failed: bool,
+}
+
+impl<R: io::BufRead> StreamingPatchParser<R> {
+    pub fn new(reader: R) -> StreamingPatchParser<R> {
+        StreamingPatchParser {
+            reader,
+            diff_plus_parser: DiffPlusParser::new(),
+            buffer: Vec::new(),
+            start: 0,
+            eof: false,
+            failed: false,
+        }
+    }
+
+    /// Read up to `READ_CHUNK` more lines into the buffer, noting end
+    /// of input.
+    fn fill(&mut self) -> io::Result<()> {
+        for _ in 0..READ_CHUNK {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                self.eof = true;
+                break;
+            }
+            self.buffer.push(Arc::new(line));
+        }
+        Ok(())
+    }
+}
+
+impl<R: io::BufRead> Iterator for StreamingPatchParser<R> {
+    type Item = DiffParseResult<DiffPlus>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            if self.start >= READ_CHUNK {
+                self.buffer.drain(..self.start);
+                self.start = 0;
+            }
+            // Decisions are only final with `LOOKAHEAD` lines (or end
+            // of input) in hand: anything less and a diff's preamble
+            // or header might still be arriving.
+            if !self.eof && self.buffer.len() - self.start < LOOKAHEAD {
+                if let Err(error) = self.fill() {
+                    self.failed = true;
+                    return Some(Err(error.into()));
+                }
+                continue;
+            }
+            if self.start >= self.buffer.len() {
+                return None;
+            }
+            match self
+                .diff_plus_parser
+                .get_diff_plus_at(&self.buffer, self.start)
+            {
+                Ok(Some(diff_plus)) => {
+                    if !self.eof && diff_plus.end_index() == self.buffer.len() {
+                        // The diff consumed the whole buffer so more
+                        // input might extend it: read on and reparse.
+                        if let Err(error) = self.fill() {
+                            self.failed = true;
+                            return Some(Err(error.into()));
+                        }
+                        continue;
+                    }
+                    self.start = diff_plus.end_index();
+                    return Some(Ok(diff_plus));
+                }
+                Ok(None) => self.start += 1,
+                Err(error) => {
+                    if self.eof {
+                        self.failed = true;
+                        return Some(Err(error));
+                    }
+                    // The error may just be a diff truncated by the
+                    // buffer's end: read on and reparse.
+                    if let Err(error) = self.fill() {
+                        self.failed = true;
+                        return Some(Err(error.into()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Drive the patch parser from byte chunks as they arrive (e.g. from
+/// a network connection): the push counterpart of
+/// `StreamingPatchParser`.  Each `feed` returns the diffs that its
+/// chunk completed and `finish` flushes whatever the end of input
+/// settles.  Header and rubbish lines between diffs are discarded and
+/// the `start_index` of yielded diffs is relative to the parser's
+/// internal window.
+#[derive(Default)]
+pub struct PushPatchParser {
+    diff_plus_parser: DiffPlusParser,
+    pending: Vec<u8>,
+    buffer: Lines,
+    start: usize,
+}
+
+impl PushPatchParser {
+    pub fn new() -> PushPatchParser {
+        PushPatchParser::default()
+    }
+
+    /// Move the complete lines sitting in `pending` into the line
+    /// buffer.
+    fn take_pending_lines(&mut self) -> DiffParseResult<()> {
+        while let Some(position) = self.pending.iter().position(|byte| *byte == b'\n') {
+            let line_bytes: Vec<u8> = self.pending.drain(..=position).collect();
+            let line = String::from_utf8(line_bytes).map_err(|error| {
+                DiffParseError::IoError(io::Error::new(io::ErrorKind::InvalidData, error))
+            })?;
+            self.buffer.push(Arc::new(line));
+        }
+        Ok(())
+    }
+
+    /// Extract the diffs that the buffered lines complete, deferring
+    /// anything that more input might still extend unless `at_end`.
+    fn drain_complete(&mut self, at_end: bool) -> DiffParseResult<Vec<DiffPlus>> {
+        let mut completed: Vec<DiffPlus> = Vec::new();
+        loop {
+            if self.start >= READ_CHUNK {
+                self.buffer.drain(..self.start);
+                self.start = 0;
+            }
+            if self.start >= self.buffer.len()
+                || (!at_end && self.buffer.len() - self.start < LOOKAHEAD)
+            {
+                return Ok(completed);
+            }
+            match self
+                .diff_plus_parser
+                .get_diff_plus_at(&self.buffer, self.start)
+            {
+                Ok(Some(diff_plus)) => {
+                    if !at_end && diff_plus.end_index() == self.buffer.len() {
+                        return Ok(completed);
+                    }
+                    self.start = diff_plus.end_index();
+                    completed.push(diff_plus);
+                }
+                Ok(None) => self.start += 1,
+                Err(error) => {
+                    if at_end {
+                        return Err(error);
+                    }
+                    // Probably a diff truncated by the chunk boundary:
+                    // wait for more input.
+                    return Ok(completed);
+                }
+            }
+        }
+    }
+
+    /// Feed the next chunk of the patch, returning the diffs that it
+    /// completed.
+    pub fn feed(&mut self, bytes: &[u8]) -> DiffParseResult<Vec<DiffPlus>> {
+        self.pending.extend_from_slice(bytes);
+        self.take_pending_lines()?;
+        self.drain_complete(false)
+    }
+
+    /// Signal the end of the input, returning the remaining diffs.
+    pub fn finish(mut self) -> DiffParseResult<Vec<DiffPlus>> {
+        if !self.pending.is_empty() {
+            // A final line without a terminating newline.
+            let line_bytes = std::mem::take(&mut self.pending);
+            let line = String::from_utf8(line_bytes).map_err(|error| {
+                DiffParseError::IoError(io::Error::new(io::ErrorKind::InvalidData, error))
+            })?;
+            self.buffer.push(Arc::new(line));
+        }
+        self.drain_complete(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abstract_diff::ApplyOptions;
+
+    #[test]
+    fn parse_patch_from_test_file() {
+        let lines = Lines::read("test_diffs/test_1.diff").unwrap();
+        let patch = PatchParser::new().parse_lines(&lines).unwrap();
+        assert_eq!(patch.diff_pluses().len(), 2);
+        assert!(patch.header_lines().is_empty());
+        assert!(patch.rubbish().is_empty());
+    }
+
+    #[test]
+    fn consumed_line_bookkeeping() {
+        let lines = Lines::read("test_diffs/test_1.diff").unwrap();
+        let patch = PatchParser::new().parse_lines(&lines).unwrap();
+        assert_eq!(patch.line_count(), lines.len());
+        let first = &patch.diff_pluses()[0];
+        assert_eq!(first.start_index(), 0);
+        assert_eq!(first.line_count(), first.len());
+        let second = &patch.diff_pluses()[1];
+        assert_eq!(second.start_index(), first.end_index());
+        assert_eq!(second.diff().start_index(), second.start_index() + 2);
+        assert_eq!(patch.end_index(), lines.len());
+    }
+
+    #[test]
+    fn split_patch_per_file() {
+        let lines = Lines::read("test_diffs/test_1.diff").unwrap();
+        let patch = PatchParser::new().parse_lines(&lines).unwrap();
+        let split = patch.split_per_file();
+        assert_eq!(split.len(), 2);
+        for (split_patch, touched) in split.iter().zip(patch.touched_files(1)) {
+            assert!(split_patch.header_lines().is_empty());
+            assert_eq!(split_patch.diff_pluses().len(), 1);
+            assert_eq!(split_patch.touched_files(1), vec![touched]);
+        }
+    }
+
+    #[test]
+    fn select_hunks_into_new_patch() {
+        let parser = PatchParser::new();
+        let patch = parser
+            .parse_string(
+                "--- a/x\n+++ b/x\n@@ -1,3 +1,4 @@\n a\n+A\n b\n c\n\
+                 @@ -8,3 +9,3 @@\n h\n-i\n+I\n j\n\
+                 --- a/y\n+++ b/y\n@@ -1,1 +1,1 @@\n-p\n+P\n",
+            )
+            .unwrap();
+        let selected =
+            patch.select(|path, hunk| path == Path::new("b/x") && hunk.removed_count() > 0);
+        assert_eq!(selected.diff_pluses().len(), 1);
+        let diff = selected.diff_pluses()[0].diff().unified().unwrap();
+        assert_eq!(*diff.header().lines[1], "+++ b/x\n");
+        let target = Lines::from_string("a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n");
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&target, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(
+            result.is_successful(),
+            "{}",
+            String::from_utf8_lossy(&err_w)
+        );
+        assert_eq!(
+            *result.lines(),
+            Lines::from_string("a\nb\nc\nd\ne\nf\ng\nh\nI\nj\n")
+        );
+    }
+
+    #[test]
+    fn touched_files_modified() {
+        let lines = Lines::read("test_diffs/test_1.diff").unwrap();
+        let patch = PatchParser::new().parse_lines(&lines).unwrap();
+        let touched = patch.touched_files(1);
+        assert_eq!(
+            touched,
+            vec![
+                (PathBuf::from("src/lib.rs"), ChangeKind::Modified),
+                (PathBuf::from("src/text_diff.rs"), ChangeKind::Modified),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_patch_state_against_tree() {
+        let patch_text = "--- a/x\n+++ b/x\n\
+                          @@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                          @@ -8,3 +8,3 @@\n h\n-i\n+I\n j\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        let fresh = |_: &Path| Some(b"a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n".to_vec());
+        assert_eq!(patch.check_against(1, &fresh), PatchState::NotApplied);
+        let applied = |_: &Path| Some(b"a\nB\nc\nd\ne\nf\ng\nh\nI\nj\n".to_vec());
+        assert_eq!(patch.check_against(1, &applied), PatchState::FullyApplied);
+        let partial = |_: &Path| Some(b"a\nB\nc\nd\ne\nf\ng\nh\ni\nj\n".to_vec());
+        assert_eq!(
+            patch.check_against(1, &partial),
+            PatchState::PartiallyApplied
+        );
+        // The patch's work is present but no longer at its nominal
+        // positions: only reverse application fits.
+        let shifted = |_: &Path| Some(b"p\nq\na\nB\nc\nd\ne\nf\ng\nh\nI\nj\n".to_vec());
+        assert_eq!(
+            patch.check_against(1, &shifted),
+            PatchState::AppearsReversed
+        );
+    }
+
+    #[test]
+    fn validate_predicts_per_hunk_outcomes() {
+        let patch_text = "--- a/x\n+++ b/x\n\
+                          @@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                          --- a/y\n+++ b/y\n\
+                          @@ -1,3 +1,3 @@\n p\n-q\n+Q\n r\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        let provider = |path: &Path| {
+            if path == Path::new("x") {
+                // The hunk will only fit one line further down.
+                Some(b"z\na\nb\nc\n".to_vec())
+            } else {
+                Some(b"p\nX\nr\n".to_vec())
+            }
+        };
+        let report = patch.validate(1, &provider, &ApplyOptions::default());
+        assert!(!report.would_succeed());
+        assert!(!report.is_clean());
+        assert_eq!(report.files.len(), 2);
+        assert_eq!(report.files[0].file_path, PathBuf::from("x"));
+        assert_eq!(
+            report.files[0].hunk_outcomes,
+            vec![HunkOutcome::Clean { offset: 1 }]
+        );
+        assert!(matches!(
+            report.files[1].hunk_outcomes[0],
+            HunkOutcome::Failed { .. }
+        ));
+    }
+
+    #[test]
+    fn apply_patch_to_directory_tree() {
+        let root = std::env::temp_dir().join(format!("cub_pd_apply_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("x"), b"a\nb\nc\n").unwrap();
+        fs::write(root.join("old.txt"), b"goodbye\n").unwrap();
+        let patch_text = "--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                          --- /dev/null\n+++ b/sub/new.txt\n@@ -0,0 +1,1 @@\n+hello\n\
+                          --- a/old.txt\n+++ /dev/null\n@@ -1,1 +0,0 @@\n-goodbye\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        let report = patch
+            .apply_to_directory(&root, 1, &ApplyOptions::default())
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        assert_eq!(report.files.len(), 3);
+        assert_eq!(fs::read(root.join("x")).unwrap(), b"a\nB\nc\n");
+        assert_eq!(fs::read(root.join("sub/new.txt")).unwrap(), b"hello\n");
+        assert!(!root.join("old.txt").exists());
+        // Reverse application restores the original tree.
+        let report = patch
+            .apply_to_directory(&root, 1, &ApplyOptions::default().reverse(true))
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        assert_eq!(fs::read(root.join("x")).unwrap(), b"a\nb\nc\n");
+        assert!(!root.join("sub/new.txt").exists());
+        assert_eq!(fs::read(root.join("old.txt")).unwrap(), b"goodbye\n");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn utf8_boms_survive_parsing_and_application() {
+        // A BOM on the patch itself doesn't stop the header matching.
+        let patch_text = "\u{feff}--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        assert!(patch.rubbish().is_empty());
+        assert_eq!(patch.diff_pluses().len(), 1);
+        // A BOM on the target is stripped before the context is
+        // matched and put back when the result is written.
+        let root = std::env::temp_dir().join(format!("cub_pd_bom_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("x"), "\u{feff}a\nb\nc\n").unwrap();
+        let report = patch
+            .apply_to_directory(&root, 1, &ApplyOptions::default())
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        assert_eq!(
+            fs::read(root.join("x")).unwrap(),
+            "\u{feff}a\nB\nc\n".as_bytes()
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_apply_matches_the_serial_report() {
+        let root = std::env::temp_dir().join(format!("cub_pd_par_apply_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let mut patch_text = String::new();
+        for index in 0..20 {
+            fs::write(root.join(format!("f{}", index)), b"a\nb\nc\n").unwrap();
+            patch_text.push_str(&format!(
+                "--- a/f{0}\n+++ b/f{0}\n@@ -1,3 +1,3 @@\n a\n-b\n+B{0}\n c\n",
+                index
+            ));
+        }
+        // One hunk that needs an offset and one that fails, so the
+        // merged log carries diagnostics from more than one file.
+        fs::write(root.join("f3"), b"z\na\nb\nc\n").unwrap();
+        fs::write(root.join("f7"), b"unrelated\n").unwrap();
+        let patch = PatchParser::new().parse_string(&patch_text).unwrap();
+        let provider = |path: &Path| fs::read(root.join(path)).ok();
+        let serial = patch.validate(1, &provider, &ApplyOptions::default());
+        let report = patch
+            .par_apply_to_work_tree(&mut OsWorkTree::new(&root), 1, &ApplyOptions::default())
+            .unwrap();
+        assert!(!report.is_successful());
+        assert_eq!(report.files.len(), 20);
+        for (file, validation) in report.files.iter().zip(serial.files.iter()) {
+            assert_eq!(file.file_path, validation.file_path);
+            assert_eq!(file.hunk_outcomes, validation.hunk_outcomes);
+        }
+        assert_eq!(fs::read(root.join("f0")).unwrap(), b"a\nB0\nc\n");
+        assert_eq!(fs::read(root.join("f3")).unwrap(), b"z\na\nB3\nc\n");
+        // The log is in patch order, as the serial applier writes it.
+        let f3_at = report.log.find("f3: Hunk #1 merged").unwrap();
+        let f7_at = report
+            .log
+            .find("f7: Hunk #1 target too short for hunk: NOT MERGED")
+            .unwrap();
+        assert!(f3_at < f7_at);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn backup_only_changed_files_during_directory_apply() {
+        let root = std::env::temp_dir().join(format!("cub_pd_backup_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("x"), b"a\nb\nc\n").unwrap();
+        // The y hunk is already in place so y must not be backed up.
+        fs::write(root.join("y"), b"P\n").unwrap();
+        let patch_text = "--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                          --- a/y\n+++ b/y\n@@ -1,1 +1,1 @@\n-p\n+P\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        let report = patch
+            .apply_to_directory(&root, 1, &ApplyOptions::default().backup(".orig"))
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        assert_eq!(fs::read(root.join("x")).unwrap(), b"a\nB\nc\n");
+        assert_eq!(fs::read(root.join("x.orig")).unwrap(), b"a\nb\nc\n");
+        assert!(!root.join("y.orig").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn preamble_mode_lines_set_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let root = std::env::temp_dir().join(format!("cub_pd_modes_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("x"), b"a\nb\nc\n").unwrap();
+        let patch_text = "diff --git a/x b/x\n\
+                          old mode 100644\n\
+                          new mode 100755\n\
+                          --- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                          diff --git a/run.sh b/run.sh\n\
+                          new file mode 100755\n\
+                          --- /dev/null\n+++ b/run.sh\n@@ -0,0 +1,1 @@\n+exit 0\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        let report = patch
+            .apply_to_directory(&root, 1, &ApplyOptions::default())
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        let mode =
+            |name: &str| fs::metadata(root.join(name)).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode("x"), 0o755);
+        assert_eq!(mode("run.sh"), 0o755);
+        // Reverse application restores the old mode.
+        let report = patch
+            .apply_to_directory(&root, 1, &ApplyOptions::default().reverse(true))
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        assert_eq!(mode("x"), 0o644);
+        assert!(!root.join("run.sh").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn rename_and_copy_preambles_move_files() {
+        let root = std::env::temp_dir().join(format!("cub_pd_rename_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("old.txt"), b"a\nb\nc\n").unwrap();
+        fs::write(root.join("base.txt"), b"p\nq\n").unwrap();
+        // A pure rename, a rename with a content change and a copy
+        // with a content change.
+        let patch_text = "diff --git a/old.txt b/new.txt\n\
+                          similarity index 66%\n\
+                          rename from old.txt\n\
+                          rename to new.txt\n\
+                          --- a/old.txt\n+++ b/new.txt\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                          diff --git a/base.txt b/copy.txt\n\
+                          copy from base.txt\n\
+                          copy to copy.txt\n\
+                          --- a/base.txt\n+++ b/copy.txt\n@@ -1,2 +1,2 @@\n p\n-q\n+Q\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        assert_eq!(
+            patch.touched_files(1),
+            vec![
+                (
+                    PathBuf::from("new.txt"),
+                    ChangeKind::Renamed(PathBuf::from("old.txt"))
+                ),
+                (
+                    PathBuf::from("copy.txt"),
+                    ChangeKind::Copied(PathBuf::from("base.txt"))
+                ),
+            ]
+        );
+        let report = patch
+            .apply_to_directory(&root, 1, &ApplyOptions::default())
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        assert!(!root.join("old.txt").exists());
+        assert_eq!(fs::read(root.join("new.txt")).unwrap(), b"a\nB\nc\n");
+        assert_eq!(fs::read(root.join("base.txt")).unwrap(), b"p\nq\n");
+        assert_eq!(fs::read(root.join("copy.txt")).unwrap(), b"p\nQ\n");
+        // Reverse application moves the file back and drops the copy.
+        let report = patch
+            .apply_to_directory(&root, 1, &ApplyOptions::default().reverse(true))
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        assert_eq!(fs::read(root.join("old.txt")).unwrap(), b"a\nb\nc\n");
+        assert!(!root.join("new.txt").exists());
+        assert!(!root.join("copy.txt").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn pure_rename_without_hunks() {
+        let root = std::env::temp_dir().join(format!("cub_pd_pure_rename_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("old.txt"), b"unchanged\n").unwrap();
+        let patch_text = "diff --git a/old.txt b/new.txt\n\
+                          similarity index 100%\n\
+                          rename from old.txt\n\
+                          rename to new.txt\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        assert_eq!(patch.diff_pluses().len(), 1);
+        assert!(patch.rubbish().is_empty());
+        let report = patch
+            .apply_to_directory(&root, 1, &ApplyOptions::default())
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        assert!(!root.join("old.txt").exists());
+        assert_eq!(fs::read(root.join("new.txt")).unwrap(), b"unchanged\n");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_diffs_create_and_retarget_links() {
+        use std::os::unix::fs::symlink;
+        let root = std::env::temp_dir().join(format!("cub_pd_symlink_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        symlink("old_target", root.join("link")).unwrap();
+        let patch = PatchBuilder::new()
+            .symlink_change(Path::new("link"), Some("old_target"), Some("new_target"))
+            .symlink_change(Path::new("fresh"), None, Some("elsewhere"))
+            .build();
+        let report = patch
+            .apply_to_directory(&root, 1, &ApplyOptions::default())
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        let link_target = |name: &str| fs::read_link(root.join(name)).unwrap();
+        assert_eq!(link_target("link"), PathBuf::from("new_target"));
+        assert_eq!(link_target("fresh"), PathBuf::from("elsewhere"));
+        assert!(fs::symlink_metadata(root.join("fresh"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        // Reverse application restores the old link and removes the
+        // created one.
+        let report = patch
+            .apply_to_directory(&root, 1, &ApplyOptions::default().reverse(true))
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        assert_eq!(link_target("link"), PathBuf::from("old_target"));
+        assert!(fs::symlink_metadata(root.join("fresh")).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn detect_strip_level_against_tree() {
+        let root = std::env::temp_dir().join(format!("cub_pd_strip_{}", std::process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/lib.rs"), b"content\n").unwrap();
+        let p1_patch = PatchParser::new()
+            .parse_string("--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1,1 +1,1 @@\n-a\n+A\n")
+            .unwrap();
+        assert_eq!(p1_patch.detect_strip_level(&root), Some(1));
+        let p0_patch = PatchParser::new()
+            .parse_string("--- src/lib.rs\n+++ src/lib.rs\n@@ -1,1 +1,1 @@\n-a\n+A\n")
+            .unwrap();
+        assert_eq!(p0_patch.detect_strip_level(&root), Some(0));
+        let miss_patch = PatchParser::new()
+            .parse_string("--- a/nowhere\n+++ b/nowhere\n@@ -1,1 +1,1 @@\n-a\n+A\n")
+            .unwrap();
+        assert_eq!(miss_patch.detect_strip_level(&root), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn no_prefix_diffs_parse_and_reprefix() {
+        // "git diff --no-prefix" output: no "a/"/"b/" on either the
+        // preamble or the header names.
+        let text = "diff --git src/lib.rs src/lib.rs\n\
+                    index 1111111..2222222 100644\n\
+                    --- src/lib.rs\n+++ src/lib.rs\n@@ -1,1 +1,1 @@\n-a\n+A\n";
+        let patch = PatchParser::new().parse_string(text).unwrap();
+        assert!(patch.rubbish().is_empty());
+        assert_eq!(
+            patch.touched_files(0),
+            vec![(PathBuf::from("src/lib.rs"), ChangeKind::Modified)]
+        );
+        // Normalize to conventional prefixes and round trip through
+        // the parser.
+        let prefixed = patch.with_prefixes("a/", "b/");
+        let text: String = prefixed
+            .to_lines()
+            .iter()
+            .map(|line| line.as_str())
+            .collect();
+        assert!(text.contains("diff --git a/src/lib.rs b/src/lib.rs\n"));
+        assert!(text.contains("--- a/src/lib.rs\n"));
+        assert!(text.contains("+++ b/src/lib.rs\n"));
+        let reparsed = PatchParser::new().parse_string(&text).unwrap();
+        assert_eq!(
+            reparsed.touched_files(1),
+            vec![(PathBuf::from("src/lib.rs"), ChangeKind::Modified)]
+        );
+        // And strip the prefixes off again.
+        let bare = prefixed.with_prefixes("", "");
+        assert_eq!(
+            bare.touched_files(0),
+            vec![(PathBuf::from("src/lib.rs"), ChangeKind::Modified)]
+        );
+    }
+
+    #[test]
+    fn apply_to_in_memory_work_tree() {
+        struct MemTree(HashMap<PathBuf, Vec<u8>>);
+        impl WorkTree for MemTree {
+            fn fetch(&self, file_path: &Path) -> Option<Vec<u8>> {
+                self.0.get(file_path).cloned()
+            }
+            fn store(&mut self, file_path: &Path, content: &[u8]) -> io::Result<()> {
+                self.0.insert(file_path.to_path_buf(), content.to_vec());
+                Ok(())
+            }
+            fn remove(&mut self, file_path: &Path) -> io::Result<()> {
+                self.0.remove(file_path);
+                Ok(())
+            }
+            fn exists(&self, file_path: &Path) -> bool {
+                self.0.contains_key(file_path)
+            }
+        }
+        let mut tree = MemTree(HashMap::new());
+        tree.0.insert(PathBuf::from("x"), b"a\nb\nc\n".to_vec());
+        tree.0
+            .insert(PathBuf::from("old.txt"), b"keep me\n".to_vec());
+        let patch_text = "--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                          --- /dev/null\n+++ b/new.txt\n@@ -0,0 +1,1 @@\n+hello\n\
+                          diff --git a/old.txt b/renamed.txt\n\
+                          similarity index 100%\n\
+                          rename from old.txt\n\
+                          rename to renamed.txt\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        let report = patch
+            .apply_to_work_tree(&mut tree, 1, &ApplyOptions::default())
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        assert_eq!(tree.0.get(Path::new("x")).unwrap(), b"a\nB\nc\n");
+        assert_eq!(tree.0.get(Path::new("new.txt")).unwrap(), b"hello\n");
+        assert!(!tree.0.contains_key(Path::new("old.txt")));
+        assert_eq!(tree.0.get(Path::new("renamed.txt")).unwrap(), b"keep me\n");
+    }
+
+    #[test]
+    fn progress_reports_parse_and_apply_events() {
+        #[derive(Default)]
+        struct Recorder {
+            files: Vec<PathBuf>,
+            hunks: Vec<(usize, usize)>,
+            bytes: Vec<usize>,
+        }
+        impl Progress for Recorder {
+            fn file_started(&mut self, file_path: &Path) {
+                self.files.push(file_path.to_path_buf());
+            }
+            fn hunks_done(&mut self, done: usize, total: usize) {
+                self.hunks.push((done, total));
+            }
+            fn bytes_consumed(&mut self, bytes: usize) {
+                self.bytes.push(bytes);
+            }
+        }
+
+        let patch_text = "a comment line\n\
+                          --- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                          --- a/y\n+++ b/y\n@@ -1,1 +1,1 @@\n-a\n+A\n";
+        let mut recorder = Recorder::default();
+        let patch = PatchParser::new()
+            .parse_lines_with_progress(&Lines::from_string(patch_text), &mut recorder)
+            .unwrap();
+        assert_eq!(
+            recorder.files,
+            vec![PathBuf::from("b/x"), PathBuf::from("b/y")]
+        );
+        assert!(recorder.bytes.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(*recorder.bytes.last().unwrap(), patch_text.len());
+
+        struct FixedTree;
+        impl WorkTree for FixedTree {
+            fn fetch(&self, _file_path: &Path) -> Option<Vec<u8>> {
+                Some(b"a\nb\nc\n".to_vec())
+            }
+            fn store(&mut self, _file_path: &Path, _content: &[u8]) -> io::Result<()> {
+                Ok(())
+            }
+            fn remove(&mut self, _file_path: &Path) -> io::Result<()> {
+                Ok(())
+            }
+            fn exists(&self, _file_path: &Path) -> bool {
+                true
+            }
+        }
+        let mut tree = FixedTree;
+        let mut recorder = Recorder::default();
+        let report = patch
+            .apply_to_work_tree_with_progress(
+                &mut tree,
+                1,
+                &ApplyOptions::default().dry_run(true),
+                &mut recorder,
+            )
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        assert_eq!(recorder.files, vec![PathBuf::from("x"), PathBuf::from("y")]);
+        assert_eq!(recorder.hunks, vec![(0, 1), (1, 1), (0, 1), (1, 1)]);
+        assert!(recorder.bytes.is_empty());
+    }
+
+    #[test]
+    fn whitespace_errors_are_found_in_added_lines() {
+        use crate::unified_diff::WhitespaceErrorKind;
+        let patch_text = "--- a/x\n+++ b/x\n@@ -1,2 +1,4 @@\n a\n+b \n+ \tc\n b2\n\
+                          --- a/y\n+++ b/y\n@@ -1,1 +1,3 @@\n p\n+q\n+\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        let findings = patch.whitespace_errors(1);
+        let summary: Vec<(&Path, WhitespaceErrorKind, usize, usize)> = findings
+            .iter()
+            .map(|(file_path, error)| {
+                (
+                    file_path.as_path(),
+                    error.kind,
+                    error.line_index,
+                    error.post_line_num,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    Path::new("x"),
+                    WhitespaceErrorKind::TrailingWhitespace,
+                    4,
+                    2
+                ),
+                (Path::new("x"), WhitespaceErrorKind::SpaceBeforeTab, 5, 3),
+                (Path::new("y"), WhitespaceErrorKind::BlankAtEof, 12, 3),
+            ]
+        );
+        assert_eq!(findings[0].1.text, "b ");
+    }
+
+    #[test]
+    fn fix_whitespace_cleans_added_lines_during_apply() {
+        struct MemTree(HashMap<PathBuf, Vec<u8>>);
+        impl WorkTree for MemTree {
+            fn fetch(&self, file_path: &Path) -> Option<Vec<u8>> {
+                self.0.get(file_path).cloned()
+            }
+            fn store(&mut self, file_path: &Path, content: &[u8]) -> io::Result<()> {
+                self.0.insert(file_path.to_path_buf(), content.to_vec());
+                Ok(())
+            }
+            fn remove(&mut self, file_path: &Path) -> io::Result<()> {
+                self.0.remove(file_path);
+                Ok(())
+            }
+            fn exists(&self, file_path: &Path) -> bool {
+                self.0.contains_key(file_path)
+            }
+        }
+        let patch_text = "--- a/x\n+++ b/x\n@@ -1,1 +1,4 @@\n a\n+b \n+ \tc\n+\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+
+        let mut tree = MemTree(HashMap::new());
+        tree.0.insert(PathBuf::from("x"), b"a\n".to_vec());
+        let report = patch
+            .apply_to_work_tree(&mut tree, 1, &ApplyOptions::default())
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        assert_eq!(tree.0.get(Path::new("x")).unwrap(), b"a\nb \n \tc\n\n");
+
+        let mut tree = MemTree(HashMap::new());
+        tree.0.insert(PathBuf::from("x"), b"a\n".to_vec());
+        let report = patch
+            .apply_to_work_tree(&mut tree, 1, &ApplyOptions::default().fix_whitespace(true))
+            .unwrap();
+        assert!(report.is_successful(), "{}", report.log);
+        assert_eq!(tree.0.get(Path::new("x")).unwrap(), b"a\nb\n\tc\n");
+        assert!(report
+            .log
+            .contains("x: Fixed trailing whitespace on line 2."));
+        assert!(report
+            .log
+            .contains("x: Fixed a space before a tab on line 3."));
+        assert!(report
+            .log
+            .contains("x: Fixed a blank line at end of file on line 4."));
+    }
+
+    #[test]
+    fn binary_markers_are_not_rubbish() {
+        let patch_text = "--- a/readme.txt\n+++ b/readme.txt\n\
+                          @@ -1,1 +1,1 @@\n-a\n+b\n\
+                          diff --git a/logo.png b/logo.png\n\
+                          index 1111111..2222222 100644\n\
+                          Binary files a/logo.png and b/logo.png differ\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        assert!(patch.rubbish().is_empty());
+        assert_eq!(patch.diff_pluses().len(), 2);
+        assert_eq!(
+            patch.touched_files(1),
+            vec![
+                (PathBuf::from("readme.txt"), ChangeKind::Modified),
+                (PathBuf::from("logo.png"), ChangeKind::Modified),
+            ]
+        );
+        // The marker round trips and is reported as unappliable.
+        let text: String = patch.to_lines().iter().map(|line| line.as_str()).collect();
+        assert_eq!(text, patch_text);
+        let missing = |_: &Path| -> Option<Vec<u8>> { None };
+        let report = patch.validate(1, &missing, &ApplyOptions::default());
+        assert!(report.files[1].hunk_outcomes.is_empty());
+    }
+
+    #[test]
+    fn format_patch_email_headers_parse() {
+        let patch_text = "From 0123456789abcdef0123456789abcdef01234567 Mon Sep 17 00:00:00 2001\n\
+                          From: Jane Coder <jane@example.com>\n\
+                          Date: Tue, 3 Mar 2020 10:11:12 +1000\n\
+                          Subject: [PATCH 2/7] patch: fix the frobnicator so that\n\
+                          \x20it frobs once\n\
+                          \n\
+                          The frobnicator was frobbing twice.\n\
+                          \n\
+                          ---\n\
+                          \x20src/x | 2 +-\n\
+                          \x201 file changed, 1 insertion(+), 1 deletion(-)\n\
+                          \n\
+                          --- a/x\n+++ b/x\n@@ -1,1 +1,1 @@\n-a\n+b\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        let header = patch.header();
+        assert_eq!(header.author(), Some("Jane Coder <jane@example.com>"));
+        assert_eq!(header.date(), Some("Tue, 3 Mar 2020 10:11:12 +1000"));
+        assert_eq!(
+            header.subject(),
+            Some("patch: fix the frobnicator so that it frobs once")
+        );
+        let description: String = header.description().iter().map(|l| l.as_str()).collect();
+        assert_eq!(description, "The frobnicator was frobbing twice.\n\n");
+        assert_eq!(header.diffstat().len(), 3);
+        // A plain description still comes through whole.
+        let plain_text = "A patch description.\n\n--- a/x\n+++ b/x\n@@ -1,1 +1,1 @@\n-a\n+b\n";
+        let header = PatchParser::new()
+            .parse_string(plain_text)
+            .unwrap()
+            .header();
+        assert!(header.author().is_none());
+        assert!(header.subject().is_none());
+        assert_eq!(*header.description()[0], "A patch description.\n");
+        assert!(header.diffstat().is_empty());
+    }
+
+    #[test]
+    fn diffstat_lines_align_names_and_scale_bars() {
+        let patch_text = "--- a/x\n+++ b/x\n@@ -1,1 +1,1 @@\n-a\n+b\n\
+                          --- a/longer/name\n+++ b/longer/name\n@@ -1,1 +1,1 @@\n-p\n+P\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        let lines = diffstat_lines(patch.diff_pluses());
+        assert_eq!(*lines[0], " b/x           | 2 +-\n");
+        assert_eq!(*lines[1], " b/longer/name | 2 +-\n");
+        assert_eq!(
+            *lines[2],
+            " 2 files changed, 2 insertions(+), 2 deletions(-)\n"
+        );
+        // Large counts get their bars scaled down to fit an 80 column
+        // line (the count itself stays accurate).
+        let mut big = String::from("--- a/x\n+++ b/x\n@@ -1,0 +1,500 @@\n");
+        for _ in 0..500 {
+            big.push_str("+z\n");
+        }
+        let patch = PatchParser::new().parse_string(&big).unwrap();
+        let lines = diffstat_lines(patch.diff_pluses());
+        assert!(lines[0].contains("| 500 +"));
+        assert_eq!(lines[0].trim_end_matches('\n').len(), 80);
+    }
+
+    #[test]
+    fn diffstat_lines_parse_back_into_per_file_stats() {
+        let lines = Lines::from_string(
+            " src/x      |  12 ++++----\n\
+             \x20src/y      | 101 +!\n\
+             \x20logo.png   | Bin\n\
+             \x20tools/doit | Prop\n\
+             \x203 files changed, 8 insertions(+), 4 deletions(-)\n",
+        );
+        let stats = parse_diffstat_lines(&lines);
+        assert_eq!(stats.len(), 3);
+        assert_eq!(stats[0].file_path, PathBuf::from("src/x"));
+        assert_eq!((stats[0].total, stats[0].plus, stats[0].minus), (12, 4, 4));
+        assert!(!stats[0].is_binary);
+        assert_eq!((stats[1].total, stats[1].modified), (101, 1));
+        assert_eq!(stats[2].file_path, PathBuf::from("logo.png"));
+        assert!(stats[2].is_binary);
+        // The parser recovers what the writer emits, via the header.
+        let patch_text = "--- a/x\n+++ b/x\n@@ -1,1 +1,1 @@\n-a\n+b\n";
+        let mut patch = PatchParser::new().parse_string(patch_text).unwrap();
+        patch.set_description("Description.\n");
+        let stats = patch.header().file_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].file_path, PathBuf::from("b/x"));
+        assert_eq!((stats[0].total, stats[0].plus, stats[0].minus), (2, 1, 1));
+    }
+
+    #[test]
+    fn replacing_the_description_keeps_the_rest_of_the_header() {
+        let patch_text = "From: Jane Coder <jane@example.com>\n\
+                          Date: Tue, 3 Mar 2020 10:11:12 +1000\n\
+                          Subject: x: make it better\n\
+                          \n\
+                          The old description.\n\
+                          ---\n\
+                          \x20x | 2 +-\n\
+                          \x201 file changed, 1 insertion(+), 1 deletion(-)\n\
+                          \n\
+                          --- a/x\n+++ b/x\n@@ -1,1 +1,1 @@\n-a\n+b\n";
+        let mut patch = PatchParser::new().parse_string(patch_text).unwrap();
+        patch.set_description("The new description.\n");
+        let header = patch.header();
+        assert_eq!(header.author(), Some("Jane Coder <jane@example.com>"));
+        assert_eq!(header.subject(), Some("x: make it better"));
+        assert_eq!(*header.description()[0], "The new description.\n");
+        // The diffstat was regenerated, not copied through.
+        assert_eq!(*header.diffstat()[0], " b/x | 2 +-\n");
+        // The rewritten patch still parses as a whole.
+        let text: String = patch.to_lines().iter().map(|line| line.as_str()).collect();
+        let reparsed = PatchParser::new().parse_string(&text).unwrap();
+        assert!(reparsed.rubbish().is_empty());
+        assert_eq!(reparsed.diff_pluses().len(), 1);
+        assert_eq!(reparsed.header().subject(), Some("x: make it better"));
+    }
+
+    #[test]
+    fn format_patch_emits_a_git_am_compatible_email() {
+        let patch_text = "Make x better.\n\n--- a/x\n+++ b/x\n@@ -1,1 +1,1 @@\n-a\n+b\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        let email = patch.format_patch(
+            "Jane Coder <jane@example.com>",
+            "Tue, 3 Mar 2020 10:11:12 +1000",
+            "x: make it better",
+        );
+        let text: String = email.iter().map(|line| line.as_str()).collect();
+        assert!(text.starts_with("From 0000000000000000000000000000000000000000"));
+        assert!(text.contains("\nMake x better.\n---\n b/x | 2 +-\n"));
+        assert!(text.contains("\n-- \n"));
+        // The email parses straight back as a patch.
+        let reparsed = PatchParser::new().parse_string(&text).unwrap();
+        let header = reparsed.header();
+        assert_eq!(header.author(), Some("Jane Coder <jane@example.com>"));
+        assert_eq!(header.subject(), Some("x: make it better"));
+        assert_eq!(reparsed.diff_pluses().len(), 1);
+        let diff_text: String = reparsed.diff_pluses()[0]
+            .diff()
+            .unified()
+            .unwrap()
+            .header()
+            .lines
+            .iter()
+            .map(|line| line.as_str())
+            .collect();
+        assert_eq!(diff_text, "--- a/x\n+++ b/x\n");
+        // Only the signature is left over.
+        let rubbish: String = reparsed
+            .rubbish()
+            .iter()
+            .map(|line| line.as_str())
+            .collect();
+        assert!(rubbish.starts_with("-- \n"));
+    }
+
+    #[test]
+    fn svn_diffs_parse_with_preambles_and_properties() {
+        use crate::diff::Diff;
+        use crate::svn_diff::PropertyChangeKind;
+
+        let patch_text = "Index: src/lib.rs\n\
+                          ===================================================================\n\
+                          --- src/lib.rs\t(revision 4)\n\
+                          +++ src/lib.rs\t(working copy)\n\
+                          @@ -1,1 +1,1 @@\n-a\n+b\n\
+                          Index: tools/doit\n\
+                          ===================================================================\n\
+                          Property changes on: tools/doit\n\
+                          ___________________________________________________________________\n\
+                          Added: svn:executable\n\
+                          ## -0,0 +1 ##\n\
+                          +*\n";
+        let patch = PatchParser::new().parse_string(patch_text).unwrap();
+        assert!(patch.rubbish().is_empty());
+        assert_eq!(patch.diff_pluses().len(), 2);
+        assert_eq!(
+            patch.touched_files(0),
+            vec![
+                (PathBuf::from("src/lib.rs"), ChangeKind::Modified),
+                (PathBuf::from("tools/doit"), ChangeKind::Modified),
+            ]
+        );
+        let properties = match patch.diff_pluses()[1].diff() {
+            Diff::SvnProperties(properties) => properties,
+            _ => panic!("expected a property changes block"),
+        };
+        assert_eq!(properties.changes().len(), 1);
+        assert_eq!(properties.changes()[0].kind, PropertyChangeKind::Added);
+        assert_eq!(properties.changes()[0].name, "svn:executable");
+        let text: String = patch.to_lines().iter().map(|line| line.as_str()).collect();
+        assert_eq!(text, patch_text);
+    }
+
+    #[test]
+    fn content_report_explains_eol_mismatch() {
+        let parser = PatchParser::new();
+        let patch = parser
+            .parse_string("--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n")
+            .unwrap();
+        let crlf_target = b"a\r\nb\r\nc\r\n".to_vec();
+        let reports = patch.content_reports(1, |path| {
+            assert_eq!(path, Path::new("x"));
+            Some(crlf_target.clone())
+        });
+        assert_eq!(reports.len(), 1);
+        let report = &reports[0];
+        assert_eq!(report.patch_eol_style, EndOfLineStyle::Lf);
+        let summary = report.summary.unwrap();
+        assert_eq!(summary.eol_style, EndOfLineStyle::CrLf);
+        assert_eq!(summary.encoding, Encoding::Utf8);
+        assert!(!report.consistent);
+        let reports = patch.content_reports(1, |_| Some(b"a\nb\nc\n".to_vec()));
+        assert!(reports[0].consistent);
+        let reports = patch.content_reports(1, |_| None);
+        assert!(reports[0].summary.is_none());
+        assert!(reports[0].consistent);
+    }
+
+    #[test]
+    fn parse_with_size_limits() {
+        let lines = Lines::read("test_diffs/test_1.diff").unwrap();
+        assert!(matches!(
+            PatchParser::new().with_max_lines(10).parse_lines(&lines),
+            Err(DiffParseError::TooLarge(PatchLimit::TotalLines(10)))
+        ));
+        assert!(matches!(
+            PatchParser::new().with_max_files(1).parse_lines(&lines),
+            Err(DiffParseError::TooLarge(PatchLimit::FileCount(1)))
+        ));
+        assert!(PatchParser::new()
+            .with_max_lines(1000)
+            .with_max_files(2)
+            .parse_lines(&lines)
+            .is_ok());
+    }
+
+    #[test]
+    fn combine_overlapping_patches() {
+        let parser = PatchParser::new();
+        let patch_a = parser
+            .parse_string("--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n")
+            .unwrap();
+        let patch_b = parser
+            .parse_string("--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-B\n+Z\n c\n")
+            .unwrap();
+        let combined = combine(&patch_a, &patch_b).unwrap();
+        assert_eq!(combined.diff_pluses().len(), 1);
+        let lines = Lines::from_string("a\nb\nc\n");
+        let mut err_w = Vec::new();
+        let diff = combined.diff_pluses()[0].diff().unified().unwrap();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(
+            result.is_successful(),
+            "{}",
+            String::from_utf8_lossy(&err_w)
+        );
+        assert_eq!(*result.lines(), Lines::from_string("a\nZ\nc\n"));
+    }
+
+    #[test]
+    fn combine_disjoint_hunks_and_files() {
+        let parser = PatchParser::new();
+        let patch_a = parser
+            .parse_string("--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n")
+            .unwrap();
+        let patch_b = parser
+            .parse_string(
+                "--- a/x\n+++ b/x\n@@ -4,3 +4,3 @@\n d\n-e\n+E\n f\n\
+                 --- a/y\n+++ b/y\n@@ -1,1 +1,1 @@\n-p\n+P\n",
+            )
+            .unwrap();
+        let combined = combine(&patch_a, &patch_b).unwrap();
+        assert_eq!(combined.diff_pluses().len(), 2);
+        let lines = Lines::from_string("a\nb\nc\nd\ne\nf\n");
+        let mut err_w = Vec::new();
+        let diff = combined.diff_pluses()[0].diff().unified().unwrap();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(
+            result.is_successful(),
+            "{}",
+            String::from_utf8_lossy(&err_w)
+        );
+        assert_eq!(*result.lines(), Lines::from_string("a\nB\nc\nd\nE\nf\n"));
+    }
+
+    #[test]
+    fn interdiff_between_patch_versions() {
+        let parser = PatchParser::new();
+        let patch_v1 = parser
+            .parse_string("--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n")
+            .unwrap();
+        let patch_v2 = parser
+            .parse_string("--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+Z\n c\n")
+            .unwrap();
+        let inter = interdiff(&patch_v1, &patch_v2).unwrap();
+        assert_eq!(inter.diff_pluses().len(), 1);
+        let v1_applied = Lines::from_string("a\nB\nc\n");
+        let mut err_w = Vec::new();
+        let diff = inter.diff_pluses()[0].diff().unified().unwrap();
+        let result = diff
+            .apply_to_lines(&v1_applied, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(
+            result.is_successful(),
+            "{}",
+            String::from_utf8_lossy(&err_w)
+        );
+        assert_eq!(*result.lines(), Lines::from_string("a\nZ\nc\n"));
+    }
+
+    #[test]
+    fn combine_conflicting_patches() {
+        let parser = PatchParser::new();
+        let patch_a = parser
+            .parse_string("--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n")
+            .unwrap();
+        let patch_b = parser
+            .parse_string("--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-q\n+Z\n c\n")
+            .unwrap();
+        assert!(matches!(
+            combine(&patch_a, &patch_b),
+            Err(DiffParseError::CombineConflict(_))
+        ));
+    }
+
+    #[test]
+    fn commute_disjoint_patches() {
+        let parser = PatchParser::new();
+        let patch_a = parser
+            .parse_string("--- a/x\n+++ b/x\n@@ -1,3 +1,4 @@\n a\n+A\n b\n c\n")
+            .unwrap();
+        let patch_b = parser
+            .parse_string("--- a/x\n+++ b/x\n@@ -9,3 +9,3 @@\n h\n-i\n+I\n j\n")
+            .unwrap();
+        let (new_b, new_a) = commute(&patch_a, &patch_b).unwrap();
+        let lines = Lines::from_string("a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n");
+        let mut err_w = Vec::new();
+        let diff_b = new_b.diff_pluses()[0].diff().unified().unwrap();
+        let result = diff_b
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(
+            result.is_successful(),
+            "{}",
+            String::from_utf8_lossy(&err_w)
+        );
+        let diff_a = new_a.diff_pluses()[0].diff().unified().unwrap();
+        let result = diff_a
+            .apply_to_lines(result.lines(), &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(
+            result.is_successful(),
+            "{}",
+            String::from_utf8_lossy(&err_w)
+        );
+        assert_eq!(
+            *result.lines(),
+            Lines::from_string("a\nA\nb\nc\nd\ne\nf\ng\nh\nI\nj\n")
+        );
+    }
+
+    #[test]
+    fn commute_overlapping_patches() {
+        let parser = PatchParser::new();
+        let patch_a = parser
+            .parse_string("--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n")
+            .unwrap();
+        let patch_b = parser
+            .parse_string("--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-B\n+Z\n c\n")
+            .unwrap();
+        assert!(commute(&patch_a, &patch_b).is_none());
+        let patch_c = parser
+            .parse_string("--- a/y\n+++ b/y\n@@ -1,1 +1,1 @@\n-p\n+P\n")
+            .unwrap();
+        assert!(commute(&patch_a, &patch_c).is_some());
+    }
+
+    #[test]
+    fn conflicting_regions_are_reported_per_file() {
+        let parser = PatchParser::new();
+        let patch_a = parser
+            .parse_string("--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n")
+            .unwrap();
+        let patch_b = parser
+            .parse_string(
+                "--- a/x\n+++ b/x\n@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n\
+                 --- a/y\n+++ b/y\n@@ -1,1 +1,1 @@\n-p\n+P\n",
+            )
+            .unwrap();
+        let regions = patch_a.conflicts_with(&patch_b);
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].file_path, PathBuf::from("x"));
+        assert_eq!(regions[0].range, (1, 3));
+        // Hunks of the same file that don't overlap are not reported.
+        let patch_c = parser
+            .parse_string("--- a/x\n+++ b/x\n@@ -10,3 +10,3 @@\n p\n-q\n+Q\n r\n")
+            .unwrap();
+        assert!(patch_a.conflicts_with(&patch_c).is_empty());
+        // Both touching a binary file conflicts wholesale.
+        let marker = "Binary files a/logo.png and b/logo.png differ\n";
+        let patch_d = parser.parse_string(marker).unwrap();
+        let regions = patch_d.conflicts_with(&patch_d);
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].file_path, PathBuf::from("logo.png"));
+    }
+
+    #[test]
+    fn reverse_patch_undoes_application() {
+        let text = "diff --git a/x b/x\n\
+                    index 0123abc..456def7 100644\n\
+                    --- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n";
+        let patch = PatchParser::new().parse_string(text).unwrap();
+        let reversed = patch.reverse();
+        let preamble = reversed.diff_pluses()[0].preamble().unwrap();
+        assert_eq!(preamble.get_extra("index"), Some("456def7..0123abc 100644"));
+        let lines = Lines::from_string("a\nB\nc\n");
+        let mut err_w = Vec::new();
+        let diff = reversed.diff_pluses()[0].diff().unified().unwrap();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(
+            result.is_successful(),
+            "{}",
+            String::from_utf8_lossy(&err_w)
+        );
+        assert_eq!(*result.lines(), Lines::from_string("a\nb\nc\n"));
+    }
+
+    #[test]
+    fn build_patch_from_file_contents() {
+        let before = Lines::from_string("a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n");
+        let after = Lines::from_string("a\nB\nc\nd\ne\nf\ng\nh\ni\nJ\nj\n");
+        let patch = PatchBuilder::new()
+            .description("Change b and add J.\n")
+            .with_context(2)
+            .file_change(Path::new("a/x"), Path::new("b/x"), &before, &after)
+            .build();
+        assert_eq!(patch.diff_pluses().len(), 1);
+        assert_eq!(*patch.header_lines()[0], "Change b and add J.\n");
+        assert_eq!(*patch.header_lines()[1], " b/x | 3 ++-\n");
+        assert_eq!(
+            *patch.header_lines()[2],
+            " 1 file changed, 2 insertions(+), 1 deletion(-)\n"
+        );
+        let diff = patch.diff_pluses()[0].diff().unified().unwrap();
+        assert_eq!(diff.hunks.len(), 2);
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&before, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(
+            result.is_successful(),
+            "{}",
+            String::from_utf8_lossy(&err_w)
+        );
+        assert_eq!(*result.lines(), after);
+    }
+
+    #[test]
+    fn build_patch_from_existing_diff_pluses() {
+        let source = PatchParser::new()
+            .parse_string("--- a/x\n+++ b/x\n@@ -1,1 +1,1 @@\n-a\n+b\n")
+            .unwrap();
+        let patch = PatchBuilder::new()
+            .description("Reuse a parsed diff.\n")
+            .comment("Cherry picked from the nightly branch.\n")
+            .diff_plus(source.diff_pluses()[0].clone())
+            .build();
+        assert_eq!(*patch.header_lines()[0], "Reuse a parsed diff.\n");
+        assert_eq!(
+            *patch.header_lines()[1],
+            "Cherry picked from the nightly branch.\n"
+        );
+        assert_eq!(*patch.header_lines()[2], " b/x | 2 +-\n");
+        // The assembled patch's text parses straight back.
+        let text: String = patch.to_lines().iter().map(|line| line.as_str()).collect();
+        let reparsed = PatchParser::new().parse_string(&text).unwrap();
+        assert!(reparsed.rubbish().is_empty());
+        assert_eq!(reparsed.diff_pluses().len(), 1);
+    }
+
+    #[test]
+    fn generated_index_lines_carry_blob_hashes() {
+        let before = Lines::from_string("a\nb\nc\n");
+        let after = Lines::from_string("a\nB\nc\n");
+        let patch = PatchBuilder::new()
+            .git_file_change(Path::new("x"), Some(&before), Some(&after))
+            .git_file_change(Path::new("new.txt"), None, Some(&before))
+            .symlink_change(Path::new("lnk"), Some("here\n"), Some("there\n"))
+            .build();
+        // The patch must round trip through the parser with the
+        // computed hashes intact.
+        let text: String = patch.to_lines().iter().map(|line| line.as_str()).collect();
+        let patch = PatchParser::new().parse_string(&text).unwrap();
+        let (ante_id, post_id) = patch.diff_pluses()[0].index_blob_ids().unwrap();
+        assert_eq!(ante_id, crate::git_hash::blob_oid(b"a\nb\nc\n"));
+        assert_eq!(post_id, crate::git_hash::blob_oid(b"a\nB\nc\n"));
+        let preamble = patch.diff_pluses()[0].preamble().unwrap();
+        assert_eq!(
+            preamble.get_extra("index").unwrap(),
+            &format!("{}..{} 100644", ante_id, post_id)
+        );
+        // A created file has the all zero OID on its absent side and
+        // its mode on the "new file mode" line instead.
+        let (ante_id, post_id) = patch.diff_pluses()[1].index_blob_ids().unwrap();
+        assert_eq!(ante_id, "0".repeat(40));
+        assert_eq!(post_id, crate::git_hash::blob_oid(b"a\nb\nc\n"));
+        assert!(!patch.diff_pluses()[1]
+            .preamble()
+            .unwrap()
+            .get_extra("index")
+            .unwrap()
+            .ends_with("100644"));
+        // Symlink blobs hash the link text.
+        let (ante_id, post_id) = patch.diff_pluses()[2].index_blob_ids().unwrap();
+        assert_eq!(ante_id, crate::git_hash::blob_oid(b"here\n"));
+        assert_eq!(post_id, crate::git_hash::blob_oid(b"there\n"));
+    }
+
+    #[test]
+    fn streaming_parser_yields_diffs_as_completed() {
+        // A hunk big enough to straddle several read chunks.
+        let before = Lines::from_string("a\nb\nc\n");
+        let after_text: String = (0..200).map(|n| format!("line {}\n", n)).collect();
+        let after = Lines::from_string(&after_text);
+        let patch = PatchBuilder::new()
+            .description("A large change.\n")
+            .git_file_change(Path::new("big.txt"), Some(&before), Some(&after))
+            .file_change(Path::new("a/x"), Path::new("b/x"), &before, &after)
+            .build();
+        let text: String = patch.to_lines().iter().map(|line| line.as_str()).collect();
+        let streamed: Vec<DiffPlus> = StreamingPatchParser::new(std::io::Cursor::new(&text))
+            .collect::<DiffParseResult<Vec<DiffPlus>>>()
+            .unwrap();
+        let parsed = PatchParser::new().parse_string(&text).unwrap();
+        assert_eq!(streamed.len(), parsed.diff_pluses().len());
+        for (streamed, parsed) in streamed.iter().zip(parsed.diff_pluses().iter()) {
+            let streamed = streamed.diff().unified().unwrap();
+            let parsed = parsed.diff().unified().unwrap();
+            assert_eq!(streamed.hunks.len(), parsed.hunks.len());
+            let mut err_w = Vec::new();
+            let result = streamed
+                .apply_to_lines(&before, &mut err_w, None, &ApplyOptions::default())
+                .unwrap();
+            assert!(result.is_successful());
+            assert_eq!(*result.lines(), after);
+        }
+        // A truncated hunk only errors once end of input proves that
+        // no more of it is coming.
+        let truncated = &text[..text.len() - 40];
+        let mut items = StreamingPatchParser::new(std::io::Cursor::new(truncated));
+        assert!(items.next().unwrap().is_ok());
+        assert!(items.next().unwrap().is_err());
+        assert!(items.next().is_none());
+    }
+
+    #[test]
+    fn push_parser_accepts_arbitrary_chunking() {
+        let before = Lines::from_string("a\nb\nc\n");
+        let after_text: String = (0..120).map(|n| format!("line {}\n", n)).collect();
+        let after = Lines::from_string(&after_text);
+        let patch = PatchBuilder::new()
+            .description("A large change.\n")
+            .git_file_change(Path::new("big.txt"), Some(&before), Some(&after))
+            .file_change(Path::new("a/x"), Path::new("b/x"), &before, &after)
+            .build();
+        let text: String = patch.to_lines().iter().map(|line| line.as_str()).collect();
+        for chunk_size in [1, 7, 4096] {
+            let mut parser = PushPatchParser::new();
+            let mut streamed: Vec<DiffPlus> = Vec::new();
+            for chunk in text.as_bytes().chunks(chunk_size) {
+                streamed.extend(parser.feed(chunk).unwrap());
+            }
+            streamed.extend(parser.finish().unwrap());
+            assert_eq!(streamed.len(), 2);
+            for diff_plus in streamed.iter() {
+                let diff = diff_plus.diff().unified().unwrap();
+                let mut err_w = Vec::new();
+                let result = diff
+                    .apply_to_lines(&before, &mut err_w, None, &ApplyOptions::default())
+                    .unwrap();
+                assert!(result.is_successful());
+                assert_eq!(*result.lines(), after);
+            }
+        }
+        // Invalid UTF-8 is reported rather than panicking.
+        let mut parser = PushPatchParser::new();
+        assert!(matches!(
+            parser.feed(b"--- a/x\xff\n"),
+            Err(DiffParseError::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn touched_files_added_and_deleted() {
+        let text = "--- /dev/null\n\
+                    +++ b/new_file.txt\n\
+                    @@ -0,0 +1,2 @@\n\
+                    +hello\n\
+                    +world\n\
+                    --- a/old_file.txt\n\
+                    +++ /dev/null\n\
+                    @@ -1,1 +0,0 @@\n\
+                    -goodbye\n";
+        let patch = PatchParser::new().parse_string(text).unwrap();
+        let touched = patch.touched_files(1);
+        assert_eq!(
+            touched,
+            vec![
+                (PathBuf::from("new_file.txt"), ChangeKind::Added),
+                (PathBuf::from("old_file.txt"), ChangeKind::Deleted),
+            ]
+        );
+    }
+}
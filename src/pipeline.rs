@@ -0,0 +1,315 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::{Path, PathBuf};
+
+use crate::abstract_diff::{ApplnResult, ApplyOptions, HunkOutcome};
+use crate::diff::Diff;
+use crate::lines::{Lines, LinesIfce};
+use crate::patch::{Patch, PatchParser, TargetContentReport};
+use crate::text_diff::DiffParseResult;
+use crate::unified_diff::UnifiedDiffHunk;
+
+/// A hook deciding whether a touched file should take part in the
+/// application at all.
+pub type FileFilter<'a> = &'a dyn Fn(&Path) -> bool;
+
+/// A hook deciding whether an individual hunk should be applied.
+pub type HunkFilter<'a> = &'a dyn Fn(&Path, &UnifiedDiffHunk) -> bool;
+
+/// A hook given the chance to veto application after the lint and
+/// validation stages have reported: return `true` to veto.
+pub type ApplnVeto<'a> = &'a dyn Fn(&Patch, &[String], &[TargetContentReport]) -> bool;
+
+/// What happened to one touched file during the apply stage.
+#[derive(Debug)]
+pub struct FileApplnOutcome {
+    pub file_path: PathBuf,
+    pub result: ApplnResult,
+}
+
+/// Everything a frontend needs to report after a pipeline run.
+#[derive(Debug)]
+pub struct PipelineOutcome {
+    /// The (filtered) patch that the pipeline worked with.
+    pub patch: Patch,
+    /// Human readable notes from the lint stage.
+    pub lint_notes: Vec<String>,
+    /// Per file validation of the targets against the patch.
+    pub content_reports: Vec<TargetContentReport>,
+    /// True if the veto hook stopped the pipeline before the apply
+    /// stage (in which case `file_outcomes` is empty).
+    pub vetoed: bool,
+    /// The per file application results, in patch order.
+    pub file_outcomes: Vec<FileApplnOutcome>,
+    /// The diagnostics written while applying.
+    pub log: String,
+}
+
+impl PipelineOutcome {
+    /// Did every attempted hunk of every file get merged (and the run
+    /// escape a veto)?
+    pub fn is_successful(&self) -> bool {
+        !self.vetoed
+            && self
+                .file_outcomes
+                .iter()
+                .all(|outcome| outcome.result.is_successful())
+    }
+}
+
+/// A high level parse → lint → validate → apply → report pipeline with
+/// user hooks between the stages, so that frontends share one
+/// orchestration path instead of each reimplementing the sequence.
+/// Hunks that fail to merge leave conflict markers in the result (see
+/// `AbstractDiff::apply_to_lines`).
+pub struct PatchPipeline<'a> {
+    parser: PatchParser,
+    strip: usize,
+    file_filter: Option<FileFilter<'a>>,
+    hunk_filter: Option<HunkFilter<'a>>,
+    veto: Option<ApplnVeto<'a>>,
+}
+
+impl Default for PatchPipeline<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> PatchPipeline<'a> {
+    pub fn new() -> PatchPipeline<'a> {
+        PatchPipeline {
+            parser: PatchParser::new(),
+            strip: 1,
+            file_filter: None,
+            hunk_filter: None,
+            veto: None,
+        }
+    }
+
+    /// Use `parser` (e.g. one with size limits) for the parse stage.
+    pub fn with_parser(mut self, parser: PatchParser) -> PatchPipeline<'a> {
+        self.parser = parser;
+        self
+    }
+
+    /// Remove `strip` leading components from the patch's file names
+    /// when resolving them against the tree.
+    pub fn with_strip(mut self, strip: usize) -> PatchPipeline<'a> {
+        self.strip = strip;
+        self
+    }
+
+    /// Only apply the diffs for files that `filter` accepts.
+    pub fn file_filter(mut self, filter: FileFilter<'a>) -> PatchPipeline<'a> {
+        self.file_filter = Some(filter);
+        self
+    }
+
+    /// Only apply the hunks that `filter` accepts.
+    pub fn hunk_filter(mut self, filter: HunkFilter<'a>) -> PatchPipeline<'a> {
+        self.hunk_filter = Some(filter);
+        self
+    }
+
+    /// Give `veto` the lint notes and validation reports and the
+    /// chance to stop the pipeline before anything is applied.
+    pub fn veto(mut self, veto: ApplnVeto<'a>) -> PatchPipeline<'a> {
+        self.veto = Some(veto);
+        self
+    }
+
+    /// Run the pipeline over the patch in `lines` fetching the current
+    /// content of each target file from `fetch` (`None` for files that
+    /// don't exist).  Nothing is written back: the caller disposes of
+    /// the resulting lines.
+    pub fn run<F>(&self, lines: &Lines, fetch: F) -> DiffParseResult<PipelineOutcome>
+    where
+        F: Fn(&Path) -> Option<Vec<u8>>,
+    {
+        let parsed = self.parser.parse_lines(lines)?;
+        let strip = self.strip;
+        let file_filter = self.file_filter;
+        let hunk_filter = self.hunk_filter;
+        let patch = parsed.select(|path, hunk| {
+            let stripped = crate::patch::strip_path(path, strip);
+            if let Some(filter) = file_filter {
+                if !filter(&stripped) {
+                    return false;
+                }
+            }
+            match hunk_filter {
+                Some(filter) => filter(&stripped, hunk),
+                None => true,
+            }
+        });
+        let lint_notes = lint(&parsed, &patch);
+        let content_reports = patch.content_reports(self.strip, &fetch);
+        if let Some(veto) = self.veto {
+            if veto(&patch, &lint_notes, &content_reports) {
+                return Ok(PipelineOutcome {
+                    patch,
+                    lint_notes,
+                    content_reports,
+                    vetoed: true,
+                    file_outcomes: Vec::new(),
+                    log: String::new(),
+                });
+            }
+        }
+        let mut file_outcomes: Vec<FileApplnOutcome> = Vec::new();
+        let mut err_w: Vec<u8> = Vec::new();
+        for (diff_plus, (file_path, _)) in patch
+            .diff_pluses()
+            .iter()
+            .zip(patch.touched_files(self.strip))
+        {
+            // A missing target gets an empty set of lines: harmless
+            // when the patch creates the file and reported per hunk by
+            // the applier otherwise.
+            let target_lines = match fetch(&file_path) {
+                Some(bytes) => Lines::from_string(&String::from_utf8_lossy(&bytes)),
+                None => Vec::new(),
+            };
+            let result = match diff_plus.diff() {
+                Diff::Unified(diff) => diff
+                    .apply_to_lines(
+                        &target_lines,
+                        &mut err_w,
+                        Some(&file_path),
+                        &ApplyOptions::default(),
+                    )
+                    .expect("writes to an in-memory log cannot fail"),
+                // Property changes are noted and skipped without
+                // failing the file.
+                Diff::SvnProperties(_) => {
+                    use std::io::Write;
+                    writeln!(
+                        err_w,
+                        "{}: property changes are not applied.",
+                        file_path.display()
+                    )
+                    .expect("writes to an in-memory log cannot fail");
+                    ApplnResult {
+                        lines: target_lines,
+                        hunk_outcomes: Vec::new(),
+                        conflicts: Vec::new(),
+                        rejected_hunks: Vec::new(),
+                    }
+                }
+                // The marker records that the file changed but not
+                // how: report the file as unappliable.
+                Diff::BinaryMarker(_) => {
+                    use std::io::Write;
+                    writeln!(
+                        err_w,
+                        "{}: binary files differ: content not included.",
+                        file_path.display()
+                    )
+                    .expect("writes to an in-memory log cannot fail");
+                    ApplnResult {
+                        lines: target_lines,
+                        hunk_outcomes: vec![HunkOutcome::Failed {
+                            conflict_range: (0, 0),
+                        }],
+                        conflicts: Vec::new(),
+                        rejected_hunks: Vec::new(),
+                    }
+                }
+            };
+            file_outcomes.push(FileApplnOutcome { file_path, result });
+        }
+        Ok(PipelineOutcome {
+            patch,
+            lint_notes,
+            content_reports,
+            vetoed: false,
+            file_outcomes,
+            log: String::from_utf8_lossy(&err_w).to_string(),
+        })
+    }
+}
+
+/// Human readable notes about suspicious aspects of a parsed patch:
+/// the lint stage of the pipeline.
+fn lint(parsed: &Patch, filtered: &Patch) -> Vec<String> {
+    let mut notes: Vec<String> = Vec::new();
+    if !parsed.rubbish().is_empty() {
+        notes.push(format!(
+            "{} unparseable line(s) between or after the diffs.",
+            parsed.rubbish().len()
+        ));
+    }
+    if parsed.diff_pluses().is_empty() {
+        notes.push("the patch contains no diffs.".to_string());
+    }
+    let dropped = parsed.diff_pluses().len() - filtered.diff_pluses().len();
+    if dropped > 0 {
+        notes.push(format!("{} file(s) excluded by the hooks.", dropped));
+    }
+    notes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn pipeline_applies_filtered_patch() {
+        let patch_text = "--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                          --- a/y\n+++ b/y\n@@ -1,1 +1,1 @@\n-p\n+P\n";
+        let mut tree: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+        tree.insert(PathBuf::from("x"), b"a\nb\nc\n".to_vec());
+        tree.insert(PathBuf::from("y"), b"p\n".to_vec());
+        let filter = |path: &Path| path == Path::new("x");
+        let pipeline = PatchPipeline::new().file_filter(&filter);
+        let outcome = pipeline
+            .run(&Lines::from_string(patch_text), |path| {
+                tree.get(path).cloned()
+            })
+            .unwrap();
+        assert!(outcome.is_successful());
+        assert_eq!(outcome.patch.diff_pluses().len(), 1);
+        assert_eq!(outcome.lint_notes, vec!["1 file(s) excluded by the hooks."]);
+        assert!(outcome
+            .content_reports
+            .iter()
+            .all(|report| report.consistent));
+        assert_eq!(outcome.file_outcomes.len(), 1);
+        assert_eq!(outcome.file_outcomes[0].file_path, PathBuf::from("x"));
+        assert_eq!(
+            *outcome.file_outcomes[0].result.lines(),
+            Lines::from_string("a\nB\nc\n")
+        );
+    }
+
+    #[test]
+    fn pipeline_veto_stops_application() {
+        let patch_text = "--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n";
+        let veto = |_: &Patch, _: &[String], reports: &[TargetContentReport]| {
+            reports.iter().any(|report| !report.consistent)
+        };
+        let pipeline = PatchPipeline::new().veto(&veto);
+        let outcome = pipeline
+            .run(&Lines::from_string(patch_text), |_| {
+                Some(b"a\r\nb\r\nc\r\n".to_vec())
+            })
+            .unwrap();
+        assert!(outcome.vetoed);
+        assert!(!outcome.is_successful());
+        assert!(outcome.file_outcomes.is_empty());
+    }
+}
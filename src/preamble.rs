@@ -0,0 +1,482 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+
+use regex::Regex;
+
+use crate::lines::{Line, Lines};
+use crate::svn_diff::{SvnPreamble, SvnPreambleParser};
+use crate::text_diff::{decode_c_quoted_path, stripped_path, Consumed, PATH_RE_STR};
+
+/// A preamble of any of the kinds we recognize.
+#[derive(Debug, Clone)]
+pub enum Preamble {
+    Git(GitPreamble),
+    Svn(SvnPreamble),
+}
+
+impl Preamble {
+    /// The number of lines in the patch file that this preamble
+    /// occupies.
+    pub fn len(&self) -> usize {
+        match self {
+            Preamble::Git(preamble) => preamble.len(),
+            Preamble::Svn(preamble) => preamble.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn ante_file_path(&self) -> &PathBuf {
+        match self {
+            Preamble::Git(preamble) => preamble.ante_file_path(),
+            // Subversion names the file once, prefix free.
+            Preamble::Svn(preamble) => preamble.file_path(),
+        }
+    }
+
+    pub fn post_file_path(&self) -> &PathBuf {
+        match self {
+            Preamble::Git(preamble) => preamble.post_file_path(),
+            Preamble::Svn(preamble) => preamble.file_path(),
+        }
+    }
+
+    /// The ante side's file path after removing `strip` leading
+    /// components (and any leading "./").
+    pub fn ante_path(&self, strip: usize) -> PathBuf {
+        stripped_path(self.ante_file_path(), strip)
+    }
+
+    /// The post side's file path after removing `strip` leading
+    /// components (and any leading "./").
+    pub fn post_path(&self, strip: usize) -> PathBuf {
+        stripped_path(self.post_file_path(), strip)
+    }
+
+    /// The value of the extras line labelled `label` (e.g. "index",
+    /// "rename from") if this preamble kind has such a line.
+    pub fn get_extra(&self, label: &str) -> Option<&str> {
+        match self {
+            Preamble::Git(preamble) => preamble.get_extra(label),
+            Preamble::Svn(_) => None,
+        }
+    }
+
+    /// This preamble with its direction reversed.
+    pub fn reversed(&self) -> Preamble {
+        match self {
+            Preamble::Git(preamble) => Preamble::Git(preamble.reversed()),
+            // Nothing in an svn preamble is direction sensitive.
+            Preamble::Svn(preamble) => Preamble::Svn(preamble.clone()),
+        }
+    }
+
+    /// The git preamble within, if that is this preamble's kind.
+    pub fn git(&self) -> Option<&GitPreamble> {
+        match self {
+            Preamble::Git(preamble) => Some(preamble),
+            Preamble::Svn(_) => None,
+        }
+    }
+
+    pub(crate) fn lines(&self) -> &Lines {
+        match self {
+            Preamble::Git(preamble) => &preamble.lines,
+            Preamble::Svn(preamble) => &preamble.lines,
+        }
+    }
+}
+
+impl Consumed for Preamble {
+    fn start_index(&self) -> usize {
+        match self {
+            Preamble::Git(preamble) => preamble.start_index(),
+            Preamble::Svn(preamble) => preamble.start_index(),
+        }
+    }
+
+    fn line_count(&self) -> usize {
+        self.len()
+    }
+}
+
+/// The "diff --git" line and any "extras" lines (mode changes, renames,
+/// index data etc.) that precede a diff in "git diff" output.
+#[derive(Debug, Clone)]
+pub struct GitPreamble {
+    pub(crate) start_index: usize,
+    pub(crate) lines: Lines,
+    pub(crate) ante_file_path: PathBuf,
+    pub(crate) post_file_path: PathBuf,
+    pub(crate) extras: HashMap<String, String>,
+}
+
+impl GitPreamble {
+    /// The number of lines in the patch file that this preamble
+    /// occupies.
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn ante_file_path(&self) -> &PathBuf {
+        &self.ante_file_path
+    }
+
+    pub fn post_file_path(&self) -> &PathBuf {
+        &self.post_file_path
+    }
+
+    /// The ante side's file path after removing `strip` leading
+    /// components (and any leading "./").
+    pub fn ante_path(&self, strip: usize) -> PathBuf {
+        stripped_path(&self.ante_file_path, strip)
+    }
+
+    /// The post side's file path after removing `strip` leading
+    /// components (and any leading "./").
+    pub fn post_path(&self, strip: usize) -> PathBuf {
+        stripped_path(&self.post_file_path, strip)
+    }
+
+    /// The value of the extras line labelled `label` (e.g. "index",
+    /// "rename from") if it was present.
+    pub fn get_extra(&self, label: &str) -> Option<&str> {
+        self.extras.get(label).map(|s| s.as_str())
+    }
+
+    /// This preamble with its direction reversed: the file paths
+    /// exchanged and the direction sensitive extras (mode changes,
+    /// file creation/deletion, copies and renames) swapped to match.
+    pub fn reversed(&self) -> GitPreamble {
+        let mut lines: Lines = Vec::with_capacity(self.lines.len());
+        lines.push(Arc::new(format!(
+            "diff --git {} {}\n",
+            self.post_file_path.display(),
+            self.ante_file_path.display()
+        )));
+        for line in self.lines[1..].iter() {
+            lines.push(Arc::new(reversed_extras_line(line)));
+        }
+        let extras: HashMap<String, String> = self
+            .extras
+            .iter()
+            .map(|(label, value)| {
+                (
+                    swapped_label(label).to_string(),
+                    reversed_value(label, value),
+                )
+            })
+            .collect();
+        GitPreamble {
+            start_index: self.start_index,
+            lines,
+            ante_file_path: self.post_file_path.clone(),
+            post_file_path: self.ante_file_path.clone(),
+            extras,
+        }
+    }
+}
+
+/// The label of the extras line that `label`'s line becomes when the
+/// preamble's direction is reversed.
+fn swapped_label(label: &str) -> &str {
+    match label {
+        "old mode" => "new mode",
+        "new mode" => "old mode",
+        "new file mode" => "deleted file mode",
+        "deleted file mode" => "new file mode",
+        "copy from" => "copy to",
+        "copy to" => "copy from",
+        "rename from" => "rename to",
+        "rename to" => "rename from",
+        _ => label,
+    }
+}
+
+/// The value of the extras line labelled `label` with its direction
+/// reversed (only "index" values are direction sensitive).
+fn reversed_value(label: &str, value: &str) -> String {
+    if label == "index" {
+        let (hashes, rest) = match value.split_once(char::is_whitespace) {
+            Some((hashes, rest)) => (hashes, Some(rest)),
+            None => (value, None),
+        };
+        if let Some((ante_hash, post_hash)) = hashes.split_once("..") {
+            return match rest {
+                Some(rest) => format!("{}..{} {}", post_hash, ante_hash, rest),
+                None => format!("{}..{}", post_hash, ante_hash),
+            };
+        }
+    }
+    value.to_string()
+}
+
+/// An extras `line` with its direction reversed.
+fn reversed_extras_line(line: &Line) -> String {
+    let mut labels: Vec<&str> = EXTRAS_LABELS.split('|').collect();
+    labels.sort_by_key(|label| std::cmp::Reverse(label.len()));
+    for label in labels {
+        if let Some(rest) = line.strip_prefix(label) {
+            let value = rest.trim();
+            return format!(
+                "{} {}\n",
+                swapped_label(label),
+                reversed_value(label, value)
+            );
+        }
+    }
+    line.to_string()
+}
+
+impl Consumed for GitPreamble {
+    fn start_index(&self) -> usize {
+        self.start_index
+    }
+
+    fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+}
+
+pub(crate) const EXTRAS_LABELS: &str = "old mode|new mode|deleted file mode|new file mode\
+                             |copy from|copy to|rename from|rename to\
+                             |similarity index|dissimilarity index|index";
+
+pub struct GitPreambleParser {
+    diff_cre: &'static Regex,
+    extras_cre: &'static Regex,
+}
+
+impl Default for GitPreambleParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GitPreambleParser {
+    pub fn new() -> GitPreambleParser {
+        // The regexes are compiled once per process, not per parser.
+        static DIFF_CRE: OnceLock<Regex> = OnceLock::new();
+        static EXTRAS_CRE: OnceLock<Regex> = OnceLock::new();
+        let diff_cre = DIFF_CRE.get_or_init(|| {
+            let e = format!(
+                r"^diff\s+--git\s+({})\s+({})(\n)?$",
+                PATH_RE_STR, PATH_RE_STR
+            );
+            Regex::new(&e).unwrap()
+        });
+        let extras_cre = EXTRAS_CRE.get_or_init(|| {
+            let e = format!(r"^({})\s+(.+?)\s*(\n)?$", EXTRAS_LABELS);
+            Regex::new(&e).unwrap()
+        });
+        GitPreambleParser {
+            diff_cre,
+            extras_cre,
+        }
+    }
+
+    /// A process wide instance for callers that don't want to carry
+    /// their own.
+    pub fn shared() -> &'static GitPreambleParser {
+        static SHARED: OnceLock<GitPreambleParser> = OnceLock::new();
+        SHARED.get_or_init(GitPreambleParser::new)
+    }
+
+    /// If `lines` contains a git preamble starting at `start_index`
+    /// return it.
+    pub fn get_preamble_at(&self, lines: &Lines, start_index: usize) -> Option<GitPreamble> {
+        let captures = self.diff_cre.captures(&lines[start_index])?;
+        // Group 2/5 hold the content of a quoted path, group 1/4 the
+        // whole (unquoted) alternative.
+        let ante_file_path = match captures.get(2) {
+            Some(quoted) => decode_c_quoted_path(quoted.as_str()),
+            None => captures.get(1).unwrap().as_str().to_string(),
+        };
+        let post_file_path = match captures.get(5) {
+            Some(quoted) => decode_c_quoted_path(quoted.as_str()),
+            None => captures.get(4).unwrap().as_str().to_string(),
+        };
+        let mut extras: HashMap<String, String> = HashMap::new();
+        let mut index = start_index + 1;
+        while index < lines.len() {
+            if let Some(captures) = self.extras_cre.captures(&lines[index]) {
+                extras.insert(
+                    captures.get(1).unwrap().as_str().to_string(),
+                    captures.get(2).unwrap().as_str().to_string(),
+                );
+                index += 1;
+            } else {
+                break;
+            }
+        }
+        Some(GitPreamble {
+            start_index,
+            lines: lines[start_index..index].to_vec(),
+            ante_file_path: PathBuf::from(ante_file_path),
+            post_file_path: PathBuf::from(post_file_path),
+            extras,
+        })
+    }
+}
+
+/// A parser for one kind of preamble.
+pub trait PreambleParserIfce: Send + Sync {
+    /// If `lines` contains a preamble of this parser's kind starting
+    /// at `start_index` return it.
+    fn get_preamble_at(&self, lines: &Lines, start_index: usize) -> Option<Preamble>;
+}
+
+impl PreambleParserIfce for GitPreambleParser {
+    fn get_preamble_at(&self, lines: &Lines, start_index: usize) -> Option<Preamble> {
+        GitPreambleParser::get_preamble_at(self, lines, start_index).map(Preamble::Git)
+    }
+}
+
+/// A registry of preamble parsers: tries each registered kind in turn
+/// so that new kinds can be supported without touching the diff and
+/// patch parsers that use it.  `new` registers the git parser.
+pub struct PreambleParser {
+    parsers: Vec<Box<dyn PreambleParserIfce>>,
+}
+
+impl Default for PreambleParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PreambleParser {
+    pub fn new() -> PreambleParser {
+        PreambleParser {
+            parsers: vec![
+                Box::new(GitPreambleParser::new()),
+                Box::new(SvnPreambleParser::new()),
+            ],
+        }
+    }
+
+    /// Add a parser for another preamble kind.  Parsers are tried in
+    /// registration order.
+    pub fn register(&mut self, parser: Box<dyn PreambleParserIfce>) {
+        self.parsers.push(parser);
+    }
+
+    /// If `lines` contains a preamble of any registered kind starting
+    /// at `start_index` return it.
+    pub fn get_preamble_at(&self, lines: &Lines, start_index: usize) -> Option<Preamble> {
+        self.parsers
+            .iter()
+            .find_map(|parser| parser.get_preamble_at(lines, start_index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lines::LinesIfce;
+
+    #[test]
+    fn parse_git_preamble() {
+        let lines = Lines::from_string(
+            "diff --git a/src/lib.rs b/src/lib.rs\n\
+             index 6826c6c..a48404a 100644\n\
+             --- a/src/lib.rs\n",
+        );
+        let parser = GitPreambleParser::new();
+        let preamble = parser.get_preamble_at(&lines, 0).unwrap();
+        assert_eq!(preamble.len(), 2);
+        assert_eq!(preamble.ante_file_path(), &PathBuf::from("a/src/lib.rs"));
+        assert_eq!(preamble.ante_path(1), PathBuf::from("src/lib.rs"));
+        assert_eq!(preamble.post_path(0), PathBuf::from("b/src/lib.rs"));
+        assert_eq!(preamble.get_extra("index"), Some("6826c6c..a48404a 100644"));
+        assert!(parser.get_preamble_at(&lines, 2).is_none());
+    }
+
+    #[test]
+    fn parse_git_rename_preamble() {
+        let lines = Lines::from_string(
+            "diff --git a/old.txt b/new.txt\n\
+             similarity index 100%\n\
+             rename from old.txt\n\
+             rename to new.txt\n",
+        );
+        let parser = GitPreambleParser::new();
+        let preamble = parser.get_preamble_at(&lines, 0).unwrap();
+        assert_eq!(preamble.len(), 4);
+        assert_eq!(preamble.get_extra("rename from"), Some("old.txt"));
+        assert_eq!(preamble.get_extra("rename to"), Some("new.txt"));
+    }
+
+    #[test]
+    fn preamble_parser_registry_is_pluggable() {
+        // A stand in for an hg/svn/quilt style preamble parser.
+        struct IndexLineParser;
+
+        impl PreambleParserIfce for IndexLineParser {
+            fn get_preamble_at(&self, lines: &Lines, start_index: usize) -> Option<Preamble> {
+                let name = lines[start_index].strip_prefix("Index: ")?.trim_end();
+                Some(Preamble::Git(GitPreamble {
+                    start_index,
+                    lines: lines[start_index..start_index + 1].to_vec(),
+                    ante_file_path: PathBuf::from(format!("a/{}", name)),
+                    post_file_path: PathBuf::from(format!("b/{}", name)),
+                    extras: HashMap::new(),
+                }))
+            }
+        }
+
+        let mut parser = PreambleParser::new();
+        parser.register(Box::new(IndexLineParser));
+        let lines = Lines::from_string("Index: src/lib.rs\n--- a/src/lib.rs\n");
+        let preamble = parser.get_preamble_at(&lines, 0).unwrap();
+        assert_eq!(preamble.len(), 1);
+        assert_eq!(preamble.post_file_path(), &PathBuf::from("b/src/lib.rs"));
+        // The default git kind is still recognized.
+        let lines = Lines::from_string("diff --git a/x b/x\n");
+        assert!(matches!(
+            parser.get_preamble_at(&lines, 0),
+            Some(Preamble::Git(_))
+        ));
+    }
+
+    #[test]
+    fn reverse_git_preamble() {
+        let lines = Lines::from_string(
+            "diff --git a/old.txt b/new.txt\n\
+             new file mode 100755\n\
+             rename from old.txt\n\
+             rename to new.txt\n",
+        );
+        let parser = GitPreambleParser::new();
+        let preamble = parser.get_preamble_at(&lines, 0).unwrap();
+        let reversed = preamble.reversed();
+        assert_eq!(*reversed.lines[0], "diff --git b/new.txt a/old.txt\n");
+        assert_eq!(reversed.ante_file_path(), &PathBuf::from("b/new.txt"));
+        assert_eq!(reversed.post_file_path(), &PathBuf::from("a/old.txt"));
+        assert_eq!(reversed.get_extra("deleted file mode"), Some("100755"));
+        assert_eq!(reversed.get_extra("rename from"), Some("new.txt"));
+        assert_eq!(reversed.get_extra("rename to"), Some("old.txt"));
+        assert_eq!(reversed.len(), preamble.len());
+    }
+}
@@ -0,0 +1,376 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for the preambles and property change blocks that
+//! "svn diff" emits around otherwise ordinary unified diffs.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::lines::{Line, Lines};
+use crate::preamble::{Preamble, PreambleParserIfce};
+use crate::text_diff::{stripped_path, Consumed};
+
+/// The "Index: path" line and "====" separator that precede a diff in
+/// "svn diff" output.
+#[derive(Debug, Clone)]
+pub struct SvnPreamble {
+    pub(crate) start_index: usize,
+    pub(crate) lines: Lines,
+    pub(crate) file_path: PathBuf,
+}
+
+impl SvnPreamble {
+    /// The number of lines in the patch file that this preamble
+    /// occupies.
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// The touched file's path.  Subversion names the file once, with
+    /// no "a/"/"b/" style prefixes.
+    pub fn file_path(&self) -> &PathBuf {
+        &self.file_path
+    }
+
+    /// The file path after removing `strip` leading components (and
+    /// any leading "./").
+    pub fn path(&self, strip: usize) -> PathBuf {
+        stripped_path(&self.file_path, strip)
+    }
+}
+
+impl Consumed for SvnPreamble {
+    fn start_index(&self) -> usize {
+        self.start_index
+    }
+
+    fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+}
+
+/// Is `line` one of the "====" separator lines that "svn diff" puts
+/// under its "Index:" lines?
+fn is_separator(line: &Line, separator: char) -> bool {
+    let text = line.trim_end_matches('\n');
+    text.len() >= 5 && text.chars().all(|c| c == separator)
+}
+
+pub struct SvnPreambleParser;
+
+impl Default for SvnPreambleParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SvnPreambleParser {
+    pub fn new() -> SvnPreambleParser {
+        SvnPreambleParser
+    }
+
+    /// If `lines` contains an svn preamble starting at `start_index`
+    /// return it.
+    pub fn get_preamble_at(&self, lines: &Lines, start_index: usize) -> Option<SvnPreamble> {
+        let path = lines
+            .get(start_index)?
+            .strip_prefix("Index: ")?
+            .trim_end_matches('\n');
+        if !is_separator(lines.get(start_index + 1)?, '=') {
+            return None;
+        }
+        Some(SvnPreamble {
+            start_index,
+            lines: lines[start_index..start_index + 2].to_vec(),
+            file_path: PathBuf::from(path),
+        })
+    }
+}
+
+impl PreambleParserIfce for SvnPreambleParser {
+    fn get_preamble_at(&self, lines: &Lines, start_index: usize) -> Option<Preamble> {
+        SvnPreambleParser::get_preamble_at(self, lines, start_index).map(Preamble::Svn)
+    }
+}
+
+/// What a property change block did to one property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PropertyChangeKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
+/// One property's change within a "Property changes on:" block.
+#[derive(Debug, Clone)]
+pub struct PropertyChange {
+    pub kind: PropertyChangeKind,
+    /// The property's name (e.g. "svn:executable").
+    pub name: String,
+    /// The property's value lines before the change (prefixes
+    /// removed).
+    pub ante_value: Vec<String>,
+    /// The property's value lines after the change (prefixes
+    /// removed).
+    pub post_value: Vec<String>,
+}
+
+/// A "Property changes on: path" block: the property analogue of a
+/// diff, with "## -a,b +c,d ##" hunks in place of "@@" ones.
+#[derive(Debug, Clone)]
+pub struct SvnPropertiesDiff {
+    pub(crate) start_index: usize,
+    pub(crate) lines: Lines,
+    pub(crate) file_path: PathBuf,
+    pub(crate) changes: Vec<PropertyChange>,
+}
+
+impl SvnPropertiesDiff {
+    /// The number of lines in the patch file that this block
+    /// occupies.
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// The path of the file (or directory) whose properties changed.
+    pub fn file_path(&self) -> &PathBuf {
+        &self.file_path
+    }
+
+    /// The file path after removing `strip` leading components (and
+    /// any leading "./").
+    pub fn path(&self, strip: usize) -> PathBuf {
+        stripped_path(&self.file_path, strip)
+    }
+
+    /// What happened to each property, in the order reported.
+    pub fn changes(&self) -> &[PropertyChange] {
+        &self.changes
+    }
+
+    /// This block with its direction reversed: additions become
+    /// deletions (and vice versa) and the values swap sides.
+    pub(crate) fn reversed(&self) -> SvnPropertiesDiff {
+        let changes: Vec<PropertyChange> = self
+            .changes
+            .iter()
+            .map(|change| PropertyChange {
+                kind: match change.kind {
+                    PropertyChangeKind::Added => PropertyChangeKind::Deleted,
+                    PropertyChangeKind::Modified => PropertyChangeKind::Modified,
+                    PropertyChangeKind::Deleted => PropertyChangeKind::Added,
+                },
+                name: change.name.clone(),
+                ante_value: change.post_value.clone(),
+                post_value: change.ante_value.clone(),
+            })
+            .collect();
+        let mut lines: Lines = vec![
+            Arc::new(format!(
+                "Property changes on: {}\n",
+                self.file_path.display()
+            )),
+            Arc::new(format!("{}\n", "_".repeat(67))),
+        ];
+        for change in changes.iter() {
+            let kind = match change.kind {
+                PropertyChangeKind::Added => "Added",
+                PropertyChangeKind::Modified => "Modified",
+                PropertyChangeKind::Deleted => "Deleted",
+            };
+            lines.push(Arc::new(format!("{}: {}\n", kind, change.name)));
+            lines.push(Arc::new(format!(
+                "## -{} +{} ##\n",
+                property_range(change.ante_value.len()),
+                property_range(change.post_value.len())
+            )));
+            for value in change.ante_value.iter() {
+                lines.push(Arc::new(format!("-{}\n", value)));
+            }
+            for value in change.post_value.iter() {
+                lines.push(Arc::new(format!("+{}\n", value)));
+            }
+        }
+        SvnPropertiesDiff {
+            start_index: self.start_index,
+            lines,
+            file_path: self.file_path.clone(),
+            changes,
+        }
+    }
+}
+
+/// The "start,length" text for a property hunk side holding `count`
+/// value lines.
+fn property_range(count: usize) -> String {
+    match count {
+        0 => "0,0".to_string(),
+        1 => "1".to_string(),
+        _ => format!("1,{}", count),
+    }
+}
+
+impl Consumed for SvnPropertiesDiff {
+    fn start_index(&self) -> usize {
+        self.start_index
+    }
+
+    fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+}
+
+pub struct SvnPropertiesParser;
+
+impl Default for SvnPropertiesParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SvnPropertiesParser {
+    pub fn new() -> SvnPropertiesParser {
+        SvnPropertiesParser
+    }
+
+    /// If `lines` contains a "Property changes on:" block starting at
+    /// `start_index` return it.
+    pub fn get_properties_at(
+        &self,
+        lines: &Lines,
+        start_index: usize,
+    ) -> Option<SvnPropertiesDiff> {
+        let path = lines
+            .get(start_index)?
+            .strip_prefix("Property changes on: ")?
+            .trim_end_matches('\n');
+        if !is_separator(lines.get(start_index + 1)?, '_') {
+            return None;
+        }
+        let mut changes: Vec<PropertyChange> = Vec::new();
+        let mut index = start_index + 2;
+        while index < lines.len() {
+            let line = lines[index].trim_end_matches('\n');
+            let (kind, name) = if let Some(name) = line.strip_prefix("Added: ") {
+                (PropertyChangeKind::Added, name)
+            } else if let Some(name) = line.strip_prefix("Modified: ") {
+                (PropertyChangeKind::Modified, name)
+            } else if let Some(name) = line.strip_prefix("Deleted: ") {
+                (PropertyChangeKind::Deleted, name)
+            } else {
+                break;
+            };
+            index += 1;
+            let mut ante_value: Vec<String> = Vec::new();
+            let mut post_value: Vec<String> = Vec::new();
+            while index < lines.len() {
+                let line = lines[index].trim_end_matches('\n');
+                if line.starts_with("## ") && line.ends_with(" ##") {
+                    // A property hunk header: the counts are implied
+                    // by the value lines that follow.
+                } else if let Some(value) = line.strip_prefix('-') {
+                    ante_value.push(value.to_string());
+                } else if let Some(value) = line.strip_prefix('+') {
+                    post_value.push(value.to_string());
+                } else if line.starts_with('\\') {
+                    // "\ No newline at end of property" annotations.
+                } else {
+                    break;
+                }
+                index += 1;
+            }
+            changes.push(PropertyChange {
+                kind,
+                name: name.to_string(),
+                ante_value,
+                post_value,
+            });
+        }
+        if changes.is_empty() {
+            return None;
+        }
+        Some(SvnPropertiesDiff {
+            start_index,
+            lines: lines[start_index..index].to_vec(),
+            file_path: PathBuf::from(path),
+            changes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lines::LinesIfce;
+
+    #[test]
+    fn parse_svn_preamble() {
+        let lines = Lines::from_string(
+            "Index: src/lib.rs\n\
+             ===================================================================\n\
+             --- src/lib.rs\t(revision 4)\n",
+        );
+        let parser = SvnPreambleParser::new();
+        let preamble = parser.get_preamble_at(&lines, 0).unwrap();
+        assert_eq!(preamble.len(), 2);
+        assert_eq!(preamble.file_path(), &PathBuf::from("src/lib.rs"));
+        assert!(parser.get_preamble_at(&lines, 2).is_none());
+    }
+
+    #[test]
+    fn parse_property_changes_block() {
+        let lines = Lines::from_string(
+            "Property changes on: tools/doit\n\
+             ___________________________________________________________________\n\
+             Added: svn:executable\n\
+             ## -0,0 +1 ##\n\
+             +*\n\
+             \\ No newline at end of property\n\
+             Modified: svn:keywords\n\
+             ## -1 +1 ##\n\
+             -Id\n\
+             +Id Rev\n\
+             Deleted: svn:mime-type\n\
+             ## -1 +0,0 ##\n\
+             -application/octet-stream\n",
+        );
+        let parser = SvnPropertiesParser::new();
+        let properties = parser.get_properties_at(&lines, 0).unwrap();
+        assert_eq!(properties.len(), 13);
+        assert_eq!(properties.file_path(), &PathBuf::from("tools/doit"));
+        let changes = properties.changes();
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0].kind, PropertyChangeKind::Added);
+        assert_eq!(changes[0].name, "svn:executable");
+        assert_eq!(changes[0].post_value, vec!["*".to_string()]);
+        assert_eq!(changes[1].kind, PropertyChangeKind::Modified);
+        assert_eq!(changes[1].ante_value, vec!["Id".to_string()]);
+        assert_eq!(changes[1].post_value, vec!["Id Rev".to_string()]);
+        assert_eq!(changes[2].kind, PropertyChangeKind::Deleted);
+        // Reversal swaps the sides and the add/delete kinds.
+        let reversed = properties.reversed();
+        assert_eq!(reversed.changes()[0].kind, PropertyChangeKind::Deleted);
+        assert_eq!(reversed.changes()[0].ante_value, vec!["*".to_string()]);
+        assert_eq!(reversed.changes()[2].kind, PropertyChangeKind::Added);
+    }
+}
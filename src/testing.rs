@@ -0,0 +1,254 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Deterministic pseudo random generators for file contents and the
+//! patches that transform them, covering far more of the input space
+//! than the hand written fixtures in `test_diffs/`.  Everything is
+//! driven by a caller supplied seed, so the generators plug straight
+//! into proptest or quickcheck (generate the seed, derive the case)
+//! and any failure reproduces exactly from its seed.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::lines::{Line, Lines};
+use crate::patch::{Patch, PatchBuilder};
+
+/// A splitmix64 generator: deterministic from its seed, dependency
+/// free and well enough distributed to spread test inputs around.
+#[derive(Debug, Clone)]
+pub struct TestRng {
+    state: u64,
+}
+
+impl TestRng {
+    pub fn new(seed: u64) -> TestRng {
+        TestRng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A value below `bound` (0 when `bound` is 0).
+    pub fn below(&mut self, bound: usize) -> usize {
+        if bound == 0 {
+            0
+        } else {
+            (self.next_u64() % bound as u64) as usize
+        }
+    }
+
+    /// True with probability `numerator` in `denominator`.
+    pub fn chance(&mut self, numerator: usize, denominator: usize) -> bool {
+        self.below(denominator) < numerator
+    }
+}
+
+const WORDS: &[&str] = &[
+    "alpha", "bravo", "charlie", "delta", "echo", "foxtrot", "golf", "hotel", "india", "juliet",
+    "kilo", "lima", "mike", "november", "oscar", "papa",
+];
+
+fn random_line(rng: &mut TestRng) -> Line {
+    if rng.chance(1, 10) {
+        return Arc::new("\n".to_string());
+    }
+    let words: Vec<&str> = (0..1 + rng.below(4))
+        .map(|_| WORDS[rng.below(WORDS.len())])
+        .collect();
+    Arc::new(format!("{}\n", words.join(" ")))
+}
+
+/// Random file content: up to `max_lines` lines of short words with
+/// the occasional blank line, and every now and then no newline on the
+/// last line.
+pub fn random_lines(rng: &mut TestRng, max_lines: usize) -> Lines {
+    let mut lines: Lines = (0..rng.below(max_lines + 1))
+        .map(|_| random_line(rng))
+        .collect();
+    if rng.chance(1, 8) {
+        if let Some(last) = lines.pop() {
+            lines.push(Arc::new(last.trim_end_matches('\n').to_string()));
+        }
+    }
+    lines
+}
+
+/// A randomly edited copy of `lines`: a handful of insertions,
+/// deletions and replacements of the kind a real edit would make.  The
+/// result can equal the input (an edit can replace a line with itself
+/// and empty input offers nothing to delete).
+pub fn mutate_lines(rng: &mut TestRng, lines: &Lines) -> Lines {
+    let mut post = lines.clone();
+    for _ in 0..1 + rng.below(4) {
+        match rng.below(3) {
+            0 => {
+                let index = rng.below(post.len().max(1));
+                post.insert(index, random_line(rng));
+            }
+            1 if !post.is_empty() => {
+                post.remove(rng.below(post.len()));
+            }
+            _ if !post.is_empty() => {
+                let index = rng.below(post.len());
+                post[index] = random_line(rng);
+            }
+            _ => post.push(random_line(rng)),
+        }
+    }
+    post
+}
+
+/// A generated test case: a file's before and after content and a
+/// valid patch transforming the one into the other.
+#[derive(Debug)]
+pub struct PatchCase {
+    /// The touched file's path as it stands after "-p1" stripping.
+    pub file_path: PathBuf,
+    pub ante: Lines,
+    pub post: Lines,
+    pub patch: Patch,
+}
+
+/// Derive a complete test case from `seed`: random before content, a
+/// randomly mutated after and the patch (quoting `context` lines of
+/// context, 0 to stress contextless hunks) transforming the former
+/// into the latter.
+pub fn patch_case(seed: u64, context: usize) -> PatchCase {
+    let mut rng = TestRng::new(seed);
+    let file_path = PathBuf::from(WORDS[rng.below(WORDS.len())]);
+    let ante = random_lines(&mut rng, 40);
+    let post = mutate_lines(&mut rng, &ante);
+    let patch = PatchBuilder::new()
+        .with_context(context)
+        .file_change(
+            &PathBuf::from("a").join(&file_path),
+            &PathBuf::from("b").join(&file_path),
+            &ante,
+            &post,
+        )
+        .build();
+    PatchCase {
+        file_path,
+        ante,
+        post,
+        patch,
+    }
+}
+
+/// Damage `patch_text` in one of the ways real patches get mangled in
+/// transit: a line dropped or duplicated, a hunk body line's leading
+/// character clobbered, or the tail truncated.  The result exercises
+/// the parsers' error and rubbish paths, though a forgiving parser may
+/// still make sense of it.
+pub fn corrupt_patch_text(rng: &mut TestRng, patch_text: &str) -> String {
+    let mut lines: Vec<String> = patch_text
+        .split_inclusive('\n')
+        .map(|line| line.to_string())
+        .collect();
+    if lines.is_empty() {
+        return patch_text.to_string();
+    }
+    match rng.below(4) {
+        0 => {
+            lines.remove(rng.below(lines.len()));
+        }
+        1 => {
+            let index = rng.below(lines.len());
+            lines.insert(index, lines[index].clone());
+        }
+        2 => {
+            let index = rng.below(lines.len());
+            lines[index].replace_range(0..1, "?");
+        }
+        _ => lines.truncate(rng.below(lines.len())),
+    }
+    lines.concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abstract_diff::ApplyOptions;
+    use crate::patch::PatchParser;
+
+    #[test]
+    fn generated_cases_reproduce_from_their_seeds() {
+        for seed in 0..8 {
+            let first = patch_case(seed, 3);
+            let second = patch_case(seed, 3);
+            assert_eq!(first.ante, second.ante);
+            assert_eq!(first.post, second.post);
+            assert_eq!(first.patch.to_lines(), second.patch.to_lines());
+        }
+    }
+
+    #[test]
+    fn generated_patches_round_trip_and_apply() {
+        let options = ApplyOptions::default();
+        for seed in 0..64 {
+            let case = patch_case(seed, 3);
+            let text: String = case.patch.to_lines().iter().map(|l| l.as_str()).collect();
+            let parsed = PatchParser::new().parse_string(&text).unwrap();
+            assert!(parsed.rubbish().is_empty(), "seed {}: {}", seed, text);
+            let diff = match parsed.diff_pluses().first() {
+                Some(diff_plus) => match diff_plus.diff().unified() {
+                    Some(diff) => diff,
+                    // Identical before and after content: no diff at
+                    // all is generated.
+                    None => continue,
+                },
+                None => continue,
+            };
+            let mut log: Vec<u8> = Vec::new();
+            let result = diff
+                .apply_to_lines(&case.ante, &mut log, None, &options)
+                .unwrap();
+            assert_eq!(*result.lines(), case.post, "seed {}: {}", seed, text);
+        }
+    }
+
+    #[test]
+    fn corrupted_patches_never_panic_the_parser() {
+        for seed in 0..64 {
+            let case = patch_case(seed, 3);
+            let text: String = case.patch.to_lines().iter().map(|l| l.as_str()).collect();
+            let mut rng = TestRng::new(!seed);
+            let corrupted = corrupt_patch_text(&mut rng, &text);
+            // Forgiving or failing are both fine: falling over is not.
+            let _ = PatchParser::new().parse_string(&corrupted);
+            let _ = PatchParser::new()
+                .with_strict_counts()
+                .parse_string(&corrupted);
+        }
+    }
+
+    #[test]
+    fn mutations_stay_within_the_line_discipline() {
+        let mut rng = TestRng::new(42);
+        for _ in 0..32 {
+            let lines = random_lines(&mut rng, 20);
+            let mutated = mutate_lines(&mut rng, &lines);
+            // Only the last line may lack its terminating newline.
+            for line in mutated.iter().rev().skip(1) {
+                assert!(line.ends_with('\n'), "{:?}", mutated);
+            }
+        }
+    }
+}
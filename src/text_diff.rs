@@ -0,0 +1,1040 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::num::ParseIntError;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use regex::Captures;
+
+use crate::abstract_diff::{
+    AbstractDiff, AbstractHunk, ApplnResult, ApplyOptions, ApplyReporter, HunkDecision, HunkView,
+};
+use crate::lines::{Line, Lines};
+use crate::DiffFormat;
+
+#[derive(Debug)]
+pub enum DiffParseError {
+    MissingAfterFileData(usize),
+    ParseNumberError(ParseIntError),
+    UnexpectedEndOfInput,
+    UnexpectedEndHunk(DiffFormat, usize),
+    SyntaxError(DiffFormat, usize),
+    /// A hunk header's declared counts disagree with the body that
+    /// follows it (the contained index is the header's): only raised
+    /// by parsers in strict count checking mode.
+    CountMismatch(DiffFormat, usize),
+    /// Two patches being combined disagree about the contents of the
+    /// intermediate file at the contained line index.
+    CombineConflict(usize),
+    /// The patch exceeds a limit imposed on the parser.
+    TooLarge(crate::patch::PatchLimit),
+    /// Reading the patch from a stream failed.
+    IoError(io::Error),
+    /// Zlib inflating binary diff data at the contained line index
+    /// failed.
+    ZlibError(usize, io::Error),
+    /// An error annotated with the text of the line that it refers to
+    /// and (optionally) the path of the patch file that it came from.
+    /// See `DiffParseError::in_context`.
+    InContext {
+        line_text: Option<Line>,
+        file_path: Option<PathBuf>,
+        source: Box<DiffParseError>,
+    },
+}
+
+impl DiffParseError {
+    /// The (zero based) index of the patch file line that this error
+    /// refers to, if it refers to one.
+    pub fn line_index(&self) -> Option<usize> {
+        match self {
+            DiffParseError::MissingAfterFileData(index)
+            | DiffParseError::UnexpectedEndHunk(_, index)
+            | DiffParseError::SyntaxError(_, index)
+            | DiffParseError::CountMismatch(_, index)
+            | DiffParseError::CombineConflict(index)
+            | DiffParseError::ZlibError(index, _) => Some(*index),
+            DiffParseError::InContext { source, .. } => source.line_index(),
+            _ => None,
+        }
+    }
+
+    /// This error annotated with the text of the line that it refers
+    /// to (looked up in `lines`) and, optionally, the path of the
+    /// patch file being parsed, so that messages can quote their
+    /// evidence.
+    pub fn in_context(self, lines: &Lines, file_path: Option<&Path>) -> DiffParseError {
+        let line_text = self
+            .line_index()
+            .and_then(|index| lines.get(index))
+            .map(Arc::clone);
+        DiffParseError::InContext {
+            line_text,
+            file_path: file_path.map(Path::to_path_buf),
+            source: Box::new(self),
+        }
+    }
+}
+
+impl fmt::Display for DiffParseError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiffParseError::MissingAfterFileData(index) => write!(
+                formatter,
+                "missing \"+++\" line after the \"---\" line at line {}",
+                index + 1
+            ),
+            DiffParseError::ParseNumberError(_) => {
+                write!(formatter, "malformed number in a hunk header")
+            }
+            DiffParseError::UnexpectedEndOfInput => {
+                write!(formatter, "unexpected end of input")
+            }
+            DiffParseError::UnexpectedEndHunk(diff_format, index) => write!(
+                formatter,
+                "{:?} format hunk truncated at line {}",
+                diff_format,
+                index + 1
+            ),
+            DiffParseError::SyntaxError(diff_format, index) => write!(
+                formatter,
+                "{:?} format syntax error at line {}",
+                diff_format,
+                index + 1
+            ),
+            DiffParseError::CountMismatch(diff_format, index) => write!(
+                formatter,
+                "{:?} format hunk header counts disagree with the hunk body at line {}",
+                diff_format,
+                index + 1
+            ),
+            DiffParseError::CombineConflict(index) => write!(
+                formatter,
+                "patches being combined disagree about line {}",
+                index + 1
+            ),
+            DiffParseError::TooLarge(limit) => match limit {
+                crate::patch::PatchLimit::TotalLines(max_lines) => write!(
+                    formatter,
+                    "patch exceeds the limit of {} total lines",
+                    max_lines
+                ),
+                crate::patch::PatchLimit::FileCount(max_files) => write!(
+                    formatter,
+                    "patch touches more than the limit of {} files",
+                    max_files
+                ),
+            },
+            DiffParseError::IoError(_) => write!(formatter, "reading the patch failed"),
+            DiffParseError::ZlibError(index, _) => write!(
+                formatter,
+                "zlib inflation of the binary diff data at line {} failed",
+                index + 1
+            ),
+            DiffParseError::InContext {
+                line_text,
+                file_path,
+                source,
+            } => {
+                if let Some(file_path) = file_path {
+                    write!(formatter, "{}: ", file_path.display())?;
+                }
+                write!(formatter, "{}", source)?;
+                if let Some(line_text) = line_text {
+                    write!(formatter, ": {:?}", line_text.trim_end_matches('\n'))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl error::Error for DiffParseError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DiffParseError::ParseNumberError(error) => Some(error),
+            DiffParseError::IoError(error) | DiffParseError::ZlibError(_, error) => Some(error),
+            DiffParseError::InContext { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseIntError> for DiffParseError {
+    fn from(error: ParseIntError) -> DiffParseError {
+        DiffParseError::ParseNumberError(error)
+    }
+}
+
+impl From<io::Error> for DiffParseError {
+    fn from(error: io::Error) -> DiffParseError {
+        DiffParseError::IoError(error)
+    }
+}
+
+pub type DiffParseResult<T> = Result<T, DiffParseError>;
+
+/// Uniform access to the lines of the patch file that a parsed
+/// construct consumed, replacing ad hoc `len()`/tuple returns for
+/// index bookkeeping.
+pub trait Consumed {
+    /// The index of the patch file line where this construct started.
+    fn start_index(&self) -> usize;
+
+    /// The number of patch file lines that this construct consumed
+    /// (including any swallowed trailing annotations).
+    fn line_count(&self) -> usize;
+
+    /// The index of the first patch file line after this construct.
+    fn end_index(&self) -> usize {
+        self.start_index() + self.line_count()
+    }
+}
+
+/// Maps the line oriented positions that parsed constructs record
+/// (see `Consumed`) back to byte offsets in the patch text, for
+/// editor and review tooling that needs to point at exact spots in
+/// the patch file.
+#[derive(Debug)]
+pub struct ByteSpans {
+    /// The byte offset of the start of each line, with the total byte
+    /// length appended as a final sentinel.
+    offsets: Vec<usize>,
+}
+
+impl ByteSpans {
+    /// Build the map for `lines`: the complete patch text that the
+    /// constructs of interest were parsed from.
+    pub fn new(lines: &Lines) -> ByteSpans {
+        let mut offsets = Vec::with_capacity(lines.len() + 1);
+        let mut offset = 0_usize;
+        for line in lines.iter() {
+            offsets.push(offset);
+            offset += line.len();
+        }
+        offsets.push(offset);
+        ByteSpans { offsets }
+    }
+
+    /// The byte range of the (zero based) line at `index`: a hunk's
+    /// line `j` is the patch file line `hunk.start_index() + j`.
+    pub fn line_span(&self, index: usize) -> std::ops::Range<usize> {
+        self.offsets[index]..self.offsets[index + 1]
+    }
+
+    /// The byte range of the patch file lines that `consumed`
+    /// occupied.
+    pub fn span_of(&self, consumed: &dyn Consumed) -> std::ops::Range<usize> {
+        self.offsets[consumed.start_index()]..self.offsets[consumed.end_index()]
+    }
+}
+
+/// The parsed fields of a unified "@@ -l,s +l,s @@" hunk header line:
+/// the numeric ranges plus whatever text (function context, newline)
+/// follows the closing "@@".
+#[derive(Debug)]
+pub(crate) struct HunkHeader<'a> {
+    pub(crate) ante_start: usize,
+    pub(crate) ante_length: Option<usize>,
+    pub(crate) post_start: usize,
+    pub(crate) post_length: Option<usize>,
+    pub(crate) tail: &'a str,
+}
+
+/// The leading run of ASCII digits of `text` parsed, and the text
+/// after it, `None` if it doesn't start with a digit.
+fn eat_number(text: &str) -> DiffParseResult<Option<(usize, &str)>> {
+    let end = text
+        .find(|character: char| !character.is_ascii_digit())
+        .unwrap_or(text.len());
+    if end == 0 {
+        return Ok(None);
+    }
+    Ok(Some((text[..end].parse::<usize>()?, &text[end..])))
+}
+
+/// `text` after its leading run of whitespace, `None` if there is no
+/// whitespace to eat.
+fn eat_whitespace(text: &str) -> Option<&str> {
+    let trimmed = text.trim_start();
+    if trimmed.len() == text.len() {
+        None
+    } else {
+        Some(trimmed)
+    }
+}
+
+/// A "start" or "start,length" range of `text` parsed, and the text
+/// after it, `None` if there isn't one there.
+#[allow(clippy::type_complexity)]
+fn eat_chunk(text: &str) -> DiffParseResult<Option<(usize, Option<usize>, &str)>> {
+    let (start, text) = match eat_number(text)? {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+    match text.strip_prefix(',') {
+        Some(text) => match eat_number(text)? {
+            Some((length, text)) => Ok(Some((start, Some(length), text))),
+            None => Ok(None),
+        },
+        None => Ok(Some((start, None, text))),
+    }
+}
+
+/// Parse `line` as a unified "@@" hunk header, `Ok(None)` if it isn't
+/// one.  This runs for every candidate line on the parse hot path so
+/// it is hand written rather than a regex (an `Err` is only possible
+/// for a range number too large for `usize`).
+pub(crate) fn parse_hunk_header(line: &str) -> DiffParseResult<Option<HunkHeader<'_>>> {
+    let text = match line.strip_prefix("@@").and_then(eat_whitespace) {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+    let text = match text.strip_prefix('-') {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+    let (ante_start, ante_length, text) = match eat_chunk(text)? {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+    let text = match eat_whitespace(text).and_then(|text| text.strip_prefix('+')) {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+    let (post_start, post_length, text) = match eat_chunk(text)? {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+    let tail = match eat_whitespace(text).and_then(|text| text.strip_prefix("@@")) {
+        Some(tail) => tail,
+        None => return Ok(None),
+    };
+    Ok(Some(HunkHeader {
+        ante_start,
+        ante_length,
+        post_start,
+        post_length,
+        tail,
+    }))
+}
+
+/// A file path and the optional timestamp that follows it in a
+/// `---`/`+++` (or `***`) header line.
+#[derive(Debug, Clone)]
+pub struct PathAndTimestamp {
+    pub file_path: PathBuf,
+    pub time_stamp: Option<String>,
+}
+
+impl PathAndTimestamp {
+    /// The file path with any leading "./" normalized away and
+    /// `strip` leading components removed: `patch`'s `-p` handling.
+    pub fn path(&self, strip: usize) -> PathBuf {
+        stripped_path(&self.file_path, strip)
+    }
+
+    /// The timestamp parsed into a `SystemTime`, if one was captured
+    /// and it parses.  Both the "2019-01-07 10:00:00.000000000 +0000"
+    /// and the "Mon Jan  7 10:00:00 2019" header forms are
+    /// understood (the latter is taken to be UTC).  The original text
+    /// is kept in `time_stamp` so diffs round-trip unchanged.
+    pub fn time(&self) -> Option<SystemTime> {
+        parse_timestamp(self.time_stamp.as_deref()?)
+    }
+}
+
+/// The number of days between 1970-01-01 and the given civil date
+/// (Howard Hinnant's "days from civil" algorithm).
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Parse one of the timestamp forms that diff headers use into a
+/// `SystemTime`: "YYYY-MM-DD HH:MM[:SS][.fraction] [+-]ZZZZ" with the
+/// seconds, fraction and zone optional, the ctime like
+/// "Www Mmm D HH:MM:SS YYYY" (taken to be UTC), or a bare
+/// "@<seconds since the epoch>".
+fn parse_timestamp(text: &str) -> Option<SystemTime> {
+    if let Some(seconds) = text.trim().strip_prefix('@') {
+        let (seconds, nanos) = match seconds.split_once('.') {
+            Some((seconds, fraction)) => {
+                let digits: String = fraction.chars().take(9).collect();
+                (seconds, format!("{:0<9}", digits).parse::<u32>().ok()?)
+            }
+            None => (seconds, 0),
+        };
+        return Some(UNIX_EPOCH + Duration::new(seconds.parse().ok()?, nanos));
+    }
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    let (date, time, zone) = match fields.as_slice() {
+        [date, time] if date.contains('-') => (*date, *time, None),
+        [date, time, zone] if date.contains('-') => (*date, *time, Some(*zone)),
+        [_weekday, month, day, time, year] => {
+            let month = match *month {
+                "Jan" => 1,
+                "Feb" => 2,
+                "Mar" => 3,
+                "Apr" => 4,
+                "May" => 5,
+                "Jun" => 6,
+                "Jul" => 7,
+                "Aug" => 8,
+                "Sep" => 9,
+                "Oct" => 10,
+                "Nov" => 11,
+                "Dec" => 12,
+                _ => return None,
+            };
+            let days = days_from_civil(year.parse().ok()?, month, day.parse().ok()?);
+            return time_fm_parts(days, time, 0);
+        }
+        _ => return None,
+    };
+    let mut date_parts = date.split('-');
+    let days = days_from_civil(
+        date_parts.next()?.parse().ok()?,
+        date_parts.next()?.parse().ok()?,
+        date_parts.next()?.parse().ok()?,
+    );
+    let offset = match zone {
+        Some(zone) => {
+            let (sign, digits) = zone.split_at(1);
+            let minutes: i64 =
+                digits[..2].parse::<i64>().ok()? * 60 + digits[2..].parse::<i64>().ok()?;
+            match sign {
+                "+" => minutes * 60,
+                "-" => -minutes * 60,
+                _ => return None,
+            }
+        }
+        None => 0,
+    };
+    time_fm_parts(days, time, offset)
+}
+
+/// Combine a day count, an "HH:MM:SS[.fraction]" field and a zone
+/// offset (in seconds east of UTC) into a `SystemTime`.
+fn time_fm_parts(days: i64, time: &str, offset: i64) -> Option<SystemTime> {
+    let (time, nanos) = match time.split_once('.') {
+        Some((time, fraction)) => {
+            let digits: String = fraction.chars().take(9).collect();
+            let nanos = format!("{:0<9}", digits).parse::<u32>().ok()?;
+            (time, nanos)
+        }
+        None => (time, 0),
+    };
+    let mut time_parts = time.split(':');
+    let seconds = days * 86400
+        + time_parts.next()?.parse::<i64>().ok()? * 3600
+        + time_parts.next()?.parse::<i64>().ok()? * 60
+        + time_parts.next().map_or(Some(0), |s| s.parse().ok())?
+        - offset;
+    if seconds >= 0 {
+        Some(UNIX_EPOCH + Duration::new(seconds as u64, nanos))
+    } else {
+        Some(UNIX_EPOCH - Duration::new((-seconds) as u64, 0) + Duration::new(0, nanos))
+    }
+}
+
+/// Decode git's `core.quotepath` style C quoting: `text` is the
+/// content between the quotes, with `\\NNN` octal escapes denoting
+/// bytes of the UTF-8 encoded name and the usual C escapes for
+/// quotes, backslashes and control characters.
+pub(crate) fn decode_c_quoted_path(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'\\' && index + 1 < bytes.len() {
+            index += 1;
+            match bytes[index] {
+                b'a' => decoded.push(0x07),
+                b'b' => decoded.push(0x08),
+                b't' => decoded.push(b'\t'),
+                b'n' => decoded.push(b'\n'),
+                b'v' => decoded.push(0x0b),
+                b'f' => decoded.push(0x0c),
+                b'r' => decoded.push(b'\r'),
+                byte @ b'0'..=b'7' => {
+                    let mut value = (byte - b'0') as u32;
+                    for _ in 0..2 {
+                        match bytes.get(index + 1) {
+                            Some(byte @ b'0'..=b'7') => {
+                                value = value * 8 + (byte - b'0') as u32;
+                                index += 1;
+                            }
+                            _ => break,
+                        }
+                    }
+                    decoded.push(value as u8);
+                }
+                byte => decoded.push(byte),
+            }
+            index += 1;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Re-encode `text` (a file name) in git's quoted form if it contains
+/// characters that need it; the plain text otherwise.
+pub(crate) fn encode_c_quoted_path(text: &str) -> String {
+    if !text
+        .bytes()
+        .any(|byte| !(0x20..0x7f).contains(&byte) || byte == b'"' || byte == b'\\')
+    {
+        return text.to_string();
+    }
+    let mut encoded = String::with_capacity(text.len() + 2);
+    encoded.push('"');
+    for byte in text.bytes() {
+        match byte {
+            b'"' => encoded.push_str("\\\""),
+            b'\\' => encoded.push_str("\\\\"),
+            0x07 => encoded.push_str("\\a"),
+            0x08 => encoded.push_str("\\b"),
+            b'\t' => encoded.push_str("\\t"),
+            b'\n' => encoded.push_str("\\n"),
+            0x0b => encoded.push_str("\\v"),
+            0x0c => encoded.push_str("\\f"),
+            b'\r' => encoded.push_str("\\r"),
+            0x20..=0x7e => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    encoded.push('"');
+    encoded
+}
+
+/// `path` with any leading "./" components dropped and then `strip`
+/// leading components removed.
+pub(crate) fn stripped_path(path: &Path, strip: usize) -> PathBuf {
+    let mut components = path.components();
+    while components.clone().next() == Some(Component::CurDir) {
+        components.next();
+    }
+    for _ in 0..strip {
+        components.next();
+    }
+    components.as_path().to_path_buf()
+}
+
+/// The two header lines of a text diff.
+#[derive(Debug, Clone)]
+pub struct TextDiffHeader {
+    pub(crate) start_index: usize,
+    pub lines: Lines,
+    pub ante_pat: PathAndTimestamp,
+    pub post_pat: PathAndTimestamp,
+}
+
+impl TextDiffHeader {
+    /// The ante side's file path after removing `strip` leading
+    /// components (and any leading "./").
+    pub fn ante_path(&self, strip: usize) -> PathBuf {
+        self.ante_pat.path(strip)
+    }
+
+    /// The post side's file path after removing `strip` leading
+    /// components (and any leading "./").
+    pub fn post_path(&self, strip: usize) -> PathBuf {
+        self.post_pat.path(strip)
+    }
+}
+
+impl Consumed for TextDiffHeader {
+    fn start_index(&self) -> usize {
+        self.start_index
+    }
+
+    fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+}
+
+/// Is `path` the "/dev/null" placeholder that diffs use for the
+/// missing side of a file creation or deletion?
+pub(crate) fn is_dev_null(path: &Path) -> bool {
+    path.components().next() == Some(Component::RootDir) && path.ends_with("dev/null")
+}
+
+/// Extract the source lines for one side of a diff from `lines`
+/// stripping `trim_left_n` characters from the front of each line,
+/// omitting lines for which `skip` is true and taking account of any
+/// "\ No newline at end of file" markers.
+pub fn extract_source_lines<F: Fn(&Line) -> bool>(
+    lines: &[Line],
+    trim_left_n: usize,
+    skip: F,
+) -> Lines {
+    let mut trimmed_lines: Lines = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        if skip(line) || line.starts_with('\\') {
+            continue;
+        }
+        if (index + 1) == lines.len() || !lines[index + 1].starts_with('\\') {
+            trimmed_lines.push(Arc::new(line[trim_left_n..].to_string()));
+        } else {
+            // The line is followed by a "\ No newline at end of file"
+            // marker: strip exactly the one newline that the diff
+            // format obliged it to carry.
+            let text = &line[trim_left_n..];
+            let text = text.strip_suffix('\n').unwrap_or(text);
+            trimmed_lines.push(Arc::new(text.to_string()));
+        }
+    }
+    trimmed_lines
+}
+
+/// The classification of one line of a text diff hunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HunkLineKind {
+    /// A line introducing the hunk (e.g. a "@@" line).
+    Header,
+    Added,
+    Removed,
+    Context,
+    /// An annotation such as "\ No newline at end of file".
+    Annotation,
+}
+
+/// Operations expected of a single hunk of a text diff whatever its
+/// format.
+pub trait TextDiffHunk {
+    /// The number of lines in the patch file that this hunk occupies.
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// The classification of each of this hunk's lines in order.
+    fn line_kinds(&self) -> Vec<HunkLineKind>;
+    /// This hunk reduced to its format independent essentials.
+    fn get_abstract_diff_hunk(&self) -> AbstractHunk;
+
+    /// The number of lines that this hunk adds.
+    fn added_count(&self) -> usize {
+        self.line_kinds()
+            .iter()
+            .filter(|kind| **kind == HunkLineKind::Added)
+            .count()
+    }
+
+    /// The number of lines that this hunk removes.
+    fn removed_count(&self) -> usize {
+        self.line_kinds()
+            .iter()
+            .filter(|kind| **kind == HunkLineKind::Removed)
+            .count()
+    }
+
+    /// The number of unchanged lines that this hunk quotes as context.
+    fn context_count(&self) -> usize {
+        self.line_kinds()
+            .iter()
+            .filter(|kind| **kind == HunkLineKind::Context)
+            .count()
+    }
+}
+
+/// A pair of adjacent hunks that overlap (or descend) in one of the
+/// file's coordinate spaces: see `TextDiff::hunk_order_violations`.
+/// The contained values index into `hunks`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HunkOrderViolation {
+    /// The pair collides in the original file's coordinates.
+    AnteOverlap(usize, usize),
+    /// The pair collides in the patched file's coordinates.
+    PostOverlap(usize, usize),
+}
+
+/// A text diff of some format: a header followed by one or more hunks.
+#[derive(Debug, Clone)]
+pub struct TextDiff<H: TextDiffHunk> {
+    pub(crate) lines_consumed: usize,
+    pub(crate) diff_format: DiffFormat,
+    pub(crate) header: TextDiffHeader,
+    pub(crate) hunks: Vec<H>,
+}
+
+impl<H: TextDiffHunk> Consumed for TextDiff<H> {
+    fn start_index(&self) -> usize {
+        self.header.start_index
+    }
+
+    fn line_count(&self) -> usize {
+        self.lines_consumed
+    }
+}
+
+impl<H: TextDiffHunk> TextDiff<H> {
+    /// The number of lines in the patch file that this diff occupies.
+    pub fn len(&self) -> usize {
+        self.lines_consumed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines_consumed == 0
+    }
+
+    pub fn diff_format(&self) -> DiffFormat {
+        self.diff_format
+    }
+
+    pub fn header(&self) -> &TextDiffHeader {
+        &self.header
+    }
+
+    /// The diff's hunks in the order that they were parsed.
+    pub fn hunks(&self) -> &[H] {
+        &self.hunks
+    }
+
+    /// Does this diff create its file (its ante side is "/dev/null")?
+    pub fn is_creation(&self) -> bool {
+        is_dev_null(&self.header.ante_pat.file_path)
+    }
+
+    /// Does this diff delete its file (its post side is "/dev/null")?
+    pub fn is_deletion(&self) -> bool {
+        is_dev_null(&self.header.post_pat.file_path)
+    }
+
+    /// Check that the hunks are ascending and non overlapping in both
+    /// the original and the patched file's coordinates, reporting each
+    /// offending adjacent pair.  The appliers assume this ordering, so
+    /// a diff that fails the check would be applied wrongly rather
+    /// than rejected.
+    pub fn hunk_order_violations(&self) -> Vec<HunkOrderViolation> {
+        let abstract_hunks: Vec<AbstractHunk> = self
+            .hunks
+            .iter()
+            .map(|hunk| hunk.get_abstract_diff_hunk())
+            .collect();
+        let mut violations: Vec<HunkOrderViolation> = Vec::new();
+        for (index, pair) in abstract_hunks.windows(2).enumerate() {
+            let ante_end = pair[0].ante_chunk().start_index + pair[0].ante_chunk().lines.len();
+            if pair[1].ante_chunk().start_index < ante_end {
+                violations.push(HunkOrderViolation::AnteOverlap(index, index + 1));
+            }
+            let post_end = pair[0].post_chunk().start_index + pair[0].post_chunk().lines.len();
+            if pair[1].post_chunk().start_index < post_end {
+                violations.push(HunkOrderViolation::PostOverlap(index, index + 1));
+            }
+        }
+        violations
+    }
+
+    /// Apply this diff to `lines` as directed by `options` reporting
+    /// diagnostics to `reporter`.  See `AbstractDiff::apply_to_lines`.
+    pub fn apply_to_lines<R: ApplyReporter>(
+        &self,
+        lines: &Lines,
+        reporter: &mut R,
+        repd_file_path: Option<&Path>,
+        options: &ApplyOptions,
+    ) -> io::Result<ApplnResult> {
+        let abstract_hunks: Vec<AbstractHunk> = self
+            .hunks
+            .iter()
+            .map(|hunk| hunk.get_abstract_diff_hunk())
+            .collect();
+        AbstractDiff::new(abstract_hunks).apply_to_lines(lines, reporter, repd_file_path, options)
+    }
+
+    /// As `apply_to_lines` but asking `decide` for each hunk whether
+    /// it should be applied, skipped or abandoned.  See
+    /// `AbstractDiff::apply_to_lines_interactive`.
+    pub fn apply_to_lines_interactive<R, F>(
+        &self,
+        lines: &Lines,
+        reporter: &mut R,
+        repd_file_path: Option<&Path>,
+        options: &ApplyOptions,
+        decide: F,
+    ) -> io::Result<ApplnResult>
+    where
+        R: ApplyReporter,
+        F: FnMut(&HunkView) -> HunkDecision,
+    {
+        let abstract_hunks: Vec<AbstractHunk> = self
+            .hunks
+            .iter()
+            .map(|hunk| hunk.get_abstract_diff_hunk())
+            .collect();
+        AbstractDiff::new(abstract_hunks).apply_to_lines_interactive(
+            lines,
+            reporter,
+            repd_file_path,
+            options,
+            decide,
+        )
+    }
+}
+
+/// Operations expected of a parser for a particular text diff format.
+pub trait TextDiffParser<H: TextDiffHunk> {
+    fn new() -> Self;
+    fn diff_format(&self) -> DiffFormat;
+    fn ante_file_rec<'t>(&self, line: &'t Line) -> Option<Captures<'t>>;
+    fn post_file_rec<'t>(&self, line: &'t Line) -> Option<Captures<'t>>;
+    fn get_hunk_at(&self, lines: &Lines, index: usize) -> DiffParseResult<Option<H>>;
+
+    fn _get_file_data_fm_captures(&self, captures: &Captures) -> PathAndTimestamp {
+        let file_path = if let Some(path) = captures.get(2) {
+            // A quoted path: undo git's `core.quotepath` escaping.
+            decode_c_quoted_path(path.as_str())
+        } else {
+            captures.get(1).unwrap().as_str().to_string() // the path must be there to match
+        };
+        let time_stamp = captures.get(5).map(|ts| ts.as_str().to_string());
+        PathAndTimestamp {
+            file_path: PathBuf::from(file_path),
+            time_stamp,
+        }
+    }
+
+    fn get_header_at(
+        &self,
+        lines: &Lines,
+        start_index: usize,
+    ) -> DiffParseResult<Option<TextDiffHeader>> {
+        let ante_pat = if let Some(captures) = self.ante_file_rec(&lines[start_index]) {
+            self._get_file_data_fm_captures(&captures)
+        } else {
+            return Ok(None);
+        };
+        let post_pat = if let Some(captures) = self.post_file_rec(&lines[start_index + 1]) {
+            self._get_file_data_fm_captures(&captures)
+        } else {
+            return Err(DiffParseError::MissingAfterFileData(start_index));
+        };
+        let lines = lines[start_index..start_index + 2].to_vec();
+        Ok(Some(TextDiffHeader {
+            start_index,
+            lines,
+            ante_pat,
+            post_pat,
+        }))
+    }
+
+    fn get_diff_at(
+        &self,
+        lines: &Lines,
+        start_index: usize,
+    ) -> DiffParseResult<Option<TextDiff<H>>> {
+        if lines.len() - start_index < 2 {
+            return Ok(None);
+        }
+        let mut index = start_index;
+        let header = if let Some(header) = self.get_header_at(lines, index)? {
+            index += header.lines.len();
+            header
+        } else {
+            return Ok(None);
+        };
+        let mut hunks: Vec<H> = Vec::new();
+        while index < lines.len() {
+            if let Some(hunk) = self.get_hunk_at(lines, index)? {
+                index += hunk.len();
+                hunks.push(hunk);
+            } else {
+                break;
+            }
+        }
+        let diff = TextDiff::<H> {
+            lines_consumed: index - start_index,
+            diff_format: self.diff_format(),
+            header,
+            hunks,
+        };
+        Ok(Some(diff))
+    }
+}
+
+pub(crate) const PATH_RE_STR: &str = r###""((?:[^"\\]|\\.)+)"|(\S+)"###;
+
+pub(crate) const TIMESTAMP_RE_STR: &str =
+    r"\d{4}-\d{2}-\d{2}\s+\d{2}:\d{2}(?::\d{2})?(?:\.\d+)?(?:\s+[-+]\d{4})?";
+pub(crate) const ALT_TIMESTAMP_RE_STR: &str =
+    r"[A-Z][a-z]{2}\s+[A-Z][a-z]{2}\s+\d{1,2}\s+\d{2}:\d{2}:\d{2}\s+\d{4}";
+pub(crate) const EPOCH_TIMESTAMP_RE_STR: &str = r"@\d+(?:\.\d+)?";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lines::LinesIfce;
+    use std::error::Error;
+
+    #[test]
+    fn parse_errors_display_their_evidence() {
+        let error = DiffParseError::SyntaxError(DiffFormat::Unified, 2);
+        assert_eq!(
+            format!("{}", error),
+            "Unified format syntax error at line 3"
+        );
+        assert!(error.source().is_none());
+
+        let lines = Lines::from_string("--- a/x\n+++ b/x\n@@ not a hunk header\n");
+        let error = error.in_context(&lines, Some(Path::new("fixes.patch")));
+        assert_eq!(error.line_index(), Some(2));
+        assert_eq!(
+            format!("{}", error),
+            "fixes.patch: Unified format syntax error at line 3: \"@@ not a hunk header\""
+        );
+        assert!(matches!(
+            error.source().unwrap().downcast_ref::<DiffParseError>(),
+            Some(DiffParseError::SyntaxError(DiffFormat::Unified, 2))
+        ));
+
+        let error = DiffParseError::from("1x".parse::<usize>().unwrap_err());
+        assert_eq!(format!("{}", error), "malformed number in a hunk header");
+        assert!(error.source().unwrap().is::<std::num::ParseIntError>());
+
+        let error = DiffParseError::from(io::Error::other("gone"));
+        assert_eq!(format!("{}", error), "reading the patch failed");
+        assert_eq!(format!("{}", error.source().unwrap()), "gone");
+    }
+
+    #[test]
+    fn c_quoted_paths_decode_and_re_encode() {
+        assert_eq!(
+            decode_c_quoted_path("pfad/\\303\\244.txt"),
+            "pfad/\u{e4}.txt"
+        );
+        assert_eq!(
+            decode_c_quoted_path("a \\\"b\\\" \\\\c\\t"),
+            "a \"b\" \\c\t"
+        );
+        assert_eq!(
+            encode_c_quoted_path("pfad/\u{e4}.txt"),
+            "\"pfad/\\303\\244.txt\""
+        );
+        assert_eq!(encode_c_quoted_path("plain/name.txt"), "plain/name.txt");
+        let quoted = encode_c_quoted_path("a \"b\" \\c\t");
+        assert_eq!(
+            decode_c_quoted_path(&quoted[1..quoted.len() - 1]),
+            "a \"b\" \\c\t"
+        );
+    }
+
+    #[test]
+    fn header_paths_honor_strip_levels() {
+        let header = TextDiffHeader {
+            start_index: 0,
+            lines: Vec::new(),
+            ante_pat: PathAndTimestamp {
+                file_path: PathBuf::from("./a/src/x.rs"),
+                time_stamp: None,
+            },
+            post_pat: PathAndTimestamp {
+                file_path: PathBuf::from("b/src/x.rs"),
+                time_stamp: None,
+            },
+        };
+        assert_eq!(header.ante_path(0), PathBuf::from("a/src/x.rs"));
+        assert_eq!(header.ante_path(1), PathBuf::from("src/x.rs"));
+        assert_eq!(header.post_path(2), PathBuf::from("x.rs"));
+        assert_eq!(header.post_path(5), PathBuf::new());
+    }
+
+    #[test]
+    fn header_timestamps_parse_into_system_time() {
+        let pat = |time_stamp: &str| PathAndTimestamp {
+            file_path: PathBuf::from("x"),
+            time_stamp: Some(time_stamp.to_string()),
+        };
+        assert_eq!(pat("1970-01-01 00:00:00 +0000").time(), Some(UNIX_EPOCH));
+        assert_eq!(
+            pat("1970-01-02 01:00:00 -0230").time(),
+            Some(UNIX_EPOCH + Duration::from_secs(86400 + 3600 + 9000))
+        );
+        assert_eq!(
+            pat("2019-01-07 10:00:00.5 +0000").time(),
+            Some(UNIX_EPOCH + Duration::new(1_546_855_200, 500_000_000))
+        );
+        assert_eq!(pat("Thu Jan  1 00:00:00 1970").time(), Some(UNIX_EPOCH));
+        assert_eq!(
+            pat("Mon Jan  7 10:00:00 2019").time(),
+            Some(UNIX_EPOCH + Duration::from_secs(1_546_855_200))
+        );
+        assert_eq!(
+            pat("@86400").time(),
+            Some(UNIX_EPOCH + Duration::from_secs(86400))
+        );
+        assert_eq!(
+            pat("@1.25").time(),
+            Some(UNIX_EPOCH + Duration::new(1, 250_000_000))
+        );
+        assert_eq!(
+            pat("1970-01-01 01:00 +0000").time(),
+            Some(UNIX_EPOCH + Duration::from_secs(3600))
+        );
+        assert_eq!(pat("not a time").time(), None);
+        assert_eq!(
+            PathAndTimestamp {
+                file_path: PathBuf::from("x"),
+                time_stamp: None,
+            }
+            .time(),
+            None
+        );
+    }
+
+    #[test]
+    fn byte_spans_map_constructs_back_to_the_text() {
+        let patch_text = "a header line\n\
+                          diff --git a/x b/x\n\
+                          --- a/x\n+++ b/x\n@@ -1,2 +1,2 @@\n a\n-b\n+B\n\
+                          @@ -9,1 +9,1 @@\n-y\n+Y\n";
+        let lines = Lines::from_string(patch_text);
+        let spans = ByteSpans::new(&lines);
+        let patch = crate::patch::PatchParser::new()
+            .parse_lines(&lines)
+            .unwrap();
+        assert_eq!(&patch_text[spans.span_of(&patch)], patch_text);
+        let diff_plus = &patch.diff_pluses()[0];
+        assert_eq!(
+            &patch_text[spans.span_of(diff_plus.preamble().unwrap())],
+            "diff --git a/x b/x\n"
+        );
+        let diff = diff_plus.diff().unified().unwrap();
+        assert_eq!(
+            &patch_text[spans.span_of(diff.header())],
+            "--- a/x\n+++ b/x\n"
+        );
+        let hunk = &diff.hunks[1];
+        assert_eq!(
+            &patch_text[spans.span_of(hunk)],
+            "@@ -9,1 +9,1 @@\n-y\n+Y\n"
+        );
+        // A hunk's line j is the patch file line start_index() + j.
+        assert_eq!(&patch_text[spans.line_span(hunk.start_index() + 1)], "-y\n");
+    }
+}
@@ -0,0 +1,1221 @@
+// Copyright 2019 Peter Williams <pwil3058@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, OnceLock};
+
+use regex::{Captures, Regex};
+
+use crate::abstract_diff::{
+    merge_abstract_hunks, shortest_edit_script, AbstractChunk, AbstractHunk, Edit,
+};
+use crate::lines::{Line, Lines};
+use crate::text_diff::{
+    extract_source_lines, parse_hunk_header, Consumed, DiffParseError, DiffParseResult,
+    HunkLineKind, TextDiff, TextDiffHunk, TextDiffParser, ALT_TIMESTAMP_RE_STR,
+    EPOCH_TIMESTAMP_RE_STR, PATH_RE_STR, TIMESTAMP_RE_STR,
+};
+use crate::DiffFormat;
+
+/// The range data from one side of a "@@" line: where the chunk starts
+/// and how many lines it covers.
+#[derive(Debug, Clone, Copy)]
+pub struct UnifiedDiffChunk {
+    pub(crate) start_line_num: usize,
+    pub(crate) length: usize,
+}
+
+impl UnifiedDiffChunk {
+    /// The (one based) line number from the "@@" line where this
+    /// chunk starts.
+    pub fn start_line_num(&self) -> usize {
+        self.start_line_num
+    }
+
+    /// The number of lines that this chunk covers.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// The index within the file of this chunk's first line.
+    pub(crate) fn start_index(&self) -> usize {
+        if self.length == 0 {
+            // A zero length chunk starts *after* the nominated line.
+            self.start_line_num
+        } else {
+            self.start_line_num - 1
+        }
+    }
+}
+
+/// A single "@@" hunk of a unified diff.
+#[derive(Debug, Clone)]
+pub struct UnifiedDiffHunk {
+    pub(crate) start_index: usize,
+    pub(crate) lines: Lines,
+    pub(crate) ante_chunk: UnifiedDiffChunk,
+    pub(crate) post_chunk: UnifiedDiffChunk,
+}
+
+impl Consumed for UnifiedDiffHunk {
+    fn start_index(&self) -> usize {
+        self.start_index
+    }
+
+    fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+}
+
+impl TextDiffHunk for UnifiedDiffHunk {
+    fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    fn line_kinds(&self) -> Vec<HunkLineKind> {
+        let mut kinds = vec![HunkLineKind::Header];
+        for line in self.lines[1..].iter() {
+            if line.starts_with('+') {
+                kinds.push(HunkLineKind::Added);
+            } else if line.starts_with('-') {
+                kinds.push(HunkLineKind::Removed);
+            } else if line.starts_with('\\') {
+                kinds.push(HunkLineKind::Annotation);
+            } else {
+                kinds.push(HunkLineKind::Context);
+            }
+        }
+        kinds
+    }
+
+    fn get_abstract_diff_hunk(&self) -> AbstractHunk {
+        let ante_lines = extract_source_lines(&self.lines[1..], 1, |l| l.starts_with('+'));
+        let post_lines = extract_source_lines(&self.lines[1..], 1, |l| l.starts_with('-'));
+        let ante_chunk = AbstractChunk {
+            start_index: self.ante_chunk.start_index(),
+            lines: ante_lines,
+        };
+        let post_chunk = AbstractChunk {
+            start_index: self.post_chunk.start_index(),
+            lines: post_lines,
+        };
+        let abstract_hunk = AbstractHunk::new(ante_chunk, post_chunk);
+        match self.lines[0]
+            .splitn(3, "@@")
+            .nth(2)
+            .map(|tail| tail.trim_end_matches('\n'))
+            .filter(|tail| !tail.trim().is_empty())
+        {
+            Some(heading) => abstract_hunk.with_heading(heading),
+            None => abstract_hunk,
+        }
+    }
+}
+
+fn chunk_header_spec(chunk: &UnifiedDiffChunk) -> String {
+    format!("{},{}", chunk.start_line_num, chunk.length)
+}
+
+fn push_source_line(lines: &mut Lines, prefix: char, line: &Line) {
+    if line.ends_with('\n') {
+        lines.push(Arc::new(format!("{}{}", prefix, line)));
+    } else {
+        lines.push(Arc::new(format!("{}{}\n", prefix, line)));
+        lines.push(Arc::new("\\ No newline at end of file\n".to_string()));
+    }
+}
+
+impl From<&AbstractHunk> for UnifiedDiffHunk {
+    fn from(abstract_hunk: &AbstractHunk) -> UnifiedDiffHunk {
+        let ante = abstract_hunk.ante_chunk();
+        let post = abstract_hunk.post_chunk();
+        let ante_chunk = UnifiedDiffChunk {
+            start_line_num: if ante.lines.is_empty() {
+                ante.start_index
+            } else {
+                ante.start_index + 1
+            },
+            length: ante.lines.len(),
+        };
+        let post_chunk = UnifiedDiffChunk {
+            start_line_num: if post.lines.is_empty() {
+                post.start_index
+            } else {
+                post.start_index + 1
+            },
+            length: post.lines.len(),
+        };
+        let mut lines: Lines = Vec::new();
+        lines.push(Arc::new(format!(
+            "@@ -{} +{} @@{}\n",
+            chunk_header_spec(&ante_chunk),
+            chunk_header_spec(&post_chunk),
+            abstract_hunk.heading().unwrap_or("")
+        )));
+        // Emit the body from the edit script so that common lines
+        // interior to the hunk come out as context (removals before
+        // insertions within each change run).
+        let mut ante_iter = ante.lines.iter();
+        let mut post_iter = post.lines.iter();
+        let mut removed: Vec<&Line> = Vec::new();
+        let mut inserted: Vec<&Line> = Vec::new();
+        let flush = |lines: &mut Lines, removed: &mut Vec<&Line>, inserted: &mut Vec<&Line>| {
+            for line in removed.drain(..) {
+                push_source_line(lines, '-', line);
+            }
+            for line in inserted.drain(..) {
+                push_source_line(lines, '+', line);
+            }
+        };
+        for edit in shortest_edit_script(&ante.lines, &post.lines) {
+            match edit {
+                Edit::Keep => {
+                    flush(&mut lines, &mut removed, &mut inserted);
+                    let line = ante_iter.next().unwrap();
+                    let _ = post_iter.next();
+                    push_source_line(&mut lines, ' ', line);
+                }
+                Edit::Remove => removed.push(ante_iter.next().unwrap()),
+                Edit::Insert => inserted.push(post_iter.next().unwrap()),
+            }
+        }
+        flush(&mut lines, &mut removed, &mut inserted);
+        UnifiedDiffHunk {
+            start_index: 0,
+            lines,
+            ante_chunk,
+            post_chunk,
+        }
+    }
+}
+
+/// Count the ante and post lines in the body of a unified diff hunk.
+fn count_hunk_body(lines: &[Line]) -> (usize, usize) {
+    let mut ante_length = 0;
+    let mut post_length = 0;
+    for line in lines.iter() {
+        if line.starts_with('-') {
+            ante_length += 1;
+        } else if line.starts_with('+') {
+            post_length += 1;
+        } else if !line.starts_with('\\') {
+            ante_length += 1;
+            post_length += 1;
+        }
+    }
+    (ante_length, post_length)
+}
+
+/// The post side start line implied by an ante side start line, the
+/// cumulative line count change `delta` of the preceding hunks and the
+/// two chunks' lengths (zero length chunks nominate the line *before*
+/// the change).
+fn recounted_post_start(
+    ante_start: usize,
+    delta: isize,
+    ante_length: usize,
+    post_length: usize,
+) -> usize {
+    let post_start = (ante_start as isize + delta).max(0) as usize;
+    if post_length == 0 {
+        post_start.saturating_sub(1)
+    } else if ante_length == 0 {
+        post_start + 1
+    } else {
+        post_start
+    }
+}
+
+/// One hunk body line with its classification and the (one based)
+/// line numbers that it has in the ante and post files, as yielded by
+/// `UnifiedDiffHunk::iter_tagged`.
+#[derive(Debug, Clone)]
+pub struct TaggedHunkLine<'a> {
+    pub kind: HunkLineKind,
+    pub line: &'a Line,
+    /// The line's number in the ante file: `None` for added lines and
+    /// annotations.
+    pub ante_line_num: Option<usize>,
+    /// The line's number in the post file: `None` for removed lines
+    /// and annotations.
+    pub post_line_num: Option<usize>,
+}
+
+impl UnifiedDiffHunk {
+    /// The range data from the ante side of the hunk's "@@" line.
+    pub fn ante_chunk(&self) -> &UnifiedDiffChunk {
+        &self.ante_chunk
+    }
+
+    /// The range data from the post side of the hunk's "@@" line.
+    pub fn post_chunk(&self) -> &UnifiedDiffChunk {
+        &self.post_chunk
+    }
+
+    /// The hunk's body lines tagged with their classification and the
+    /// line numbers that they have on each side, so that renderers
+    /// don't have to re-inspect the leading character themselves.
+    /// The "@@" line isn't yielded.
+    pub fn iter_tagged(&self) -> impl Iterator<Item = TaggedHunkLine<'_>> {
+        let mut ante_line_num = self.ante_chunk.start_line_num;
+        let mut post_line_num = self.post_chunk.start_line_num;
+        self.lines[1..].iter().map(move |line| {
+            if line.starts_with('+') {
+                let tagged = TaggedHunkLine {
+                    kind: HunkLineKind::Added,
+                    line,
+                    ante_line_num: None,
+                    post_line_num: Some(post_line_num),
+                };
+                post_line_num += 1;
+                tagged
+            } else if line.starts_with('-') {
+                let tagged = TaggedHunkLine {
+                    kind: HunkLineKind::Removed,
+                    line,
+                    ante_line_num: Some(ante_line_num),
+                    post_line_num: None,
+                };
+                ante_line_num += 1;
+                tagged
+            } else if line.starts_with('\\') {
+                TaggedHunkLine {
+                    kind: HunkLineKind::Annotation,
+                    line,
+                    ante_line_num: None,
+                    post_line_num: None,
+                }
+            } else {
+                let tagged = TaggedHunkLine {
+                    kind: HunkLineKind::Context,
+                    line,
+                    ante_line_num: Some(ante_line_num),
+                    post_line_num: Some(post_line_num),
+                };
+                ante_line_num += 1;
+                post_line_num += 1;
+                tagged
+            }
+        })
+    }
+
+    /// Recompute this hunk's chunk data from its body given the
+    /// cumulative line count change `delta` of the preceding hunks and
+    /// rewrite its "@@" line to match.  Returns the cumulative change
+    /// including this hunk.
+    fn recount(&mut self, delta: isize) -> isize {
+        let (ante_length, post_length) = count_hunk_body(&self.lines[1..]);
+        let ante_start = self.ante_chunk.start_line_num;
+        self.ante_chunk = UnifiedDiffChunk {
+            start_line_num: ante_start,
+            length: ante_length,
+        };
+        self.post_chunk = UnifiedDiffChunk {
+            start_line_num: recounted_post_start(ante_start, delta, ante_length, post_length),
+            length: post_length,
+        };
+        let tail = match self.lines[0].splitn(3, "@@").nth(2) {
+            Some(tail) => tail.to_string(),
+            None => "\n".to_string(),
+        };
+        self.lines[0] = Arc::new(format!(
+            "@@ -{} +{} @@{}",
+            chunk_header_spec(&self.ante_chunk),
+            chunk_header_spec(&self.post_chunk),
+            tail
+        ));
+        delta + post_length as isize - ante_length as isize
+    }
+}
+
+/// Render `rejected_hunks` (each in the orientation in which its
+/// application was attempted, see `ApplnResult::rejected_hunks`) as a
+/// well formed unified diff for `file_path`: the equivalent of
+/// `patch`'s `.rej` files, ready to be fixed up and applied by hand.
+/// `None` if there is nothing to reject.
+pub fn format_reject_stream(
+    file_path: &std::path::Path,
+    rejected_hunks: &[AbstractHunk],
+) -> Option<String> {
+    if rejected_hunks.is_empty() {
+        return None;
+    }
+    let mut text = format!("--- {0}\n+++ {0}\n", file_path.display());
+    for hunk in rejected_hunks.iter() {
+        for line in UnifiedDiffHunk::from(hunk).lines.iter() {
+            text.push_str(line);
+        }
+    }
+    Some(text)
+}
+
+pub type UnifiedDiff = TextDiff<UnifiedDiffHunk>;
+
+/// The kinds of whitespace problem in added lines that
+/// `whitespace_errors` detects, mirroring the checks behind
+/// "git apply --whitespace=warn".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhitespaceErrorKind {
+    /// The line ends in spaces or tabs.
+    TrailingWhitespace,
+    /// A space sits before a tab in the line's indentation.
+    SpaceBeforeTab,
+    /// The line is a blank line added at the end of the file.
+    BlankAtEof,
+}
+
+/// A whitespace problem found in an added line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhitespaceError {
+    pub kind: WhitespaceErrorKind,
+    /// The (zero based) index of the offending "+" line in the patch
+    /// file.
+    pub line_index: usize,
+    /// The (one based) line number that the line will have in the
+    /// patched file.
+    pub post_line_num: usize,
+    /// The added line's text (without its "+" or newline).
+    pub text: String,
+}
+
+impl UnifiedDiff {
+    /// Merge every run of hunks whose contexts touch or overlap into a
+    /// single hunk with recomputed counts: the inverse of splitting,
+    /// useful after raising the displayed context or filtering lines.
+    /// Hunks that aren't merged are left untouched.
+    pub fn merge_adjacent_hunks(&mut self) {
+        let mut merged: Vec<UnifiedDiffHunk> = Vec::new();
+        for hunk in self.hunks.drain(..) {
+            if let Some(last) = merged.last() {
+                if let Some(combined) = merge_abstract_hunks(
+                    &last.get_abstract_diff_hunk(),
+                    &hunk.get_abstract_diff_hunk(),
+                ) {
+                    *merged.last_mut().unwrap() = UnifiedDiffHunk::from(&combined);
+                    continue;
+                }
+            }
+            merged.push(hunk);
+        }
+        self.lines_consumed =
+            self.header.lines.len() + merged.iter().map(|hunk| hunk.len()).sum::<usize>();
+        self.hunks = merged;
+    }
+
+    /// A copy of this diff containing only the hunks whose (zero
+    /// based) positions appear in `indices`, with the post side start
+    /// lines renumbered as if the omitted hunks had never existed.
+    pub fn select_hunks(&self, indices: &[usize]) -> UnifiedDiff {
+        let mut hunks: Vec<UnifiedDiffHunk> = self
+            .hunks
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| indices.contains(index))
+            .map(|(_, hunk)| hunk.clone())
+            .collect();
+        let mut delta = 0_isize;
+        for hunk in hunks.iter_mut() {
+            delta = hunk.recount(delta);
+        }
+        UnifiedDiff {
+            lines_consumed: self.header.lines.len()
+                + hunks.iter().map(|hunk| hunk.len()).sum::<usize>(),
+            diff_format: self.diff_format,
+            header: self.header.clone(),
+            hunks,
+        }
+    }
+
+    /// Scan this diff's added lines for the whitespace problems that
+    /// "git apply --whitespace=warn" complains about: trailing
+    /// whitespace, a space before a tab in the indentation and blank
+    /// lines added at the end of the file.
+    pub fn whitespace_errors(&self) -> Vec<WhitespaceError> {
+        let mut errors: Vec<WhitespaceError> = Vec::new();
+        for (hunk_index, hunk) in self.hunks.iter().enumerate() {
+            // The index (within the hunk, annotations skipped) of the
+            // first line of a trailing run of added blank lines: only
+            // the last hunk can add blank lines at the end of the
+            // file.
+            let eof_blanks_start = if hunk_index + 1 == self.hunks.len() {
+                let mut start = hunk.lines.len();
+                for (index, line) in hunk.lines.iter().enumerate().skip(1).rev() {
+                    if line.starts_with('\\') {
+                        continue;
+                    } else if line.starts_with('+') && line[1..].trim().is_empty() {
+                        start = index;
+                    } else {
+                        break;
+                    }
+                }
+                start
+            } else {
+                hunk.lines.len()
+            };
+            let mut post_line_num = hunk.post_chunk.start_line_num;
+            for (index, line) in hunk.lines.iter().enumerate().skip(1) {
+                if line.starts_with('-') || line.starts_with('\\') {
+                    continue;
+                }
+                if let Some(added) = line.strip_prefix('+') {
+                    let text = added.trim_end_matches('\n');
+                    let mut report = |kind| {
+                        errors.push(WhitespaceError {
+                            kind,
+                            line_index: hunk.start_index + index,
+                            post_line_num,
+                            text: text.to_string(),
+                        });
+                    };
+                    if text.ends_with([' ', '\t']) {
+                        report(WhitespaceErrorKind::TrailingWhitespace);
+                    }
+                    let indent_len = text.len() - text.trim_start().len();
+                    if text[..indent_len].contains(" \t") {
+                        report(WhitespaceErrorKind::SpaceBeforeTab);
+                    }
+                    if index >= eof_blanks_start {
+                        report(WhitespaceErrorKind::BlankAtEof);
+                    }
+                }
+                post_line_num += 1;
+            }
+        }
+        errors
+    }
+
+    /// Fix the whitespace problems that `whitespace_errors` reports,
+    /// as "git apply --whitespace=fix" does: trailing whitespace is
+    /// stripped, spaces before tabs in the indentation are collapsed
+    /// into the tab and blank lines added at the end of the file are
+    /// dropped.  The hunk counts are recomputed to cover any dropped
+    /// lines and the errors that were fixed are returned.
+    pub fn fix_whitespace_errors(&mut self) -> Vec<WhitespaceError> {
+        let errors = self.whitespace_errors();
+        if errors.is_empty() {
+            return errors;
+        }
+        let mut dropped = false;
+        for hunk in self.hunks.iter_mut() {
+            for error in errors.iter() {
+                let index = match error.line_index.checked_sub(hunk.start_index) {
+                    Some(index) if index < hunk.lines.len() => index,
+                    _ => continue,
+                };
+                let line = &hunk.lines[index];
+                let eol = &line[line.trim_end_matches(['\n', '\r']).len()..];
+                match error.kind {
+                    WhitespaceErrorKind::TrailingWhitespace => {
+                        let text = line.trim_end_matches(['\n', '\r']).trim_end();
+                        hunk.lines[index] = Arc::new(format!("{}{}", text, eol));
+                    }
+                    WhitespaceErrorKind::SpaceBeforeTab => {
+                        let text = &line[1..];
+                        let indent_len = text.len() - text.trim_start().len();
+                        let mut indent = text[..indent_len].to_string();
+                        while indent.contains(" \t") {
+                            indent = indent.replace(" \t", "\t");
+                        }
+                        hunk.lines[index] = Arc::new(format!("+{}{}", indent, &text[indent_len..]));
+                    }
+                    WhitespaceErrorKind::BlankAtEof => {
+                        // Mark the line for removal below: an empty
+                        // line can't otherwise occur in a hunk body.
+                        hunk.lines[index] = Arc::new(String::new());
+                        dropped = true;
+                    }
+                }
+            }
+            if dropped {
+                hunk.lines.retain(|line| !line.is_empty());
+            }
+        }
+        if dropped {
+            self.recount();
+            self.lines_consumed =
+                self.header.lines.len() + self.hunks.iter().map(|hunk| hunk.len()).sum::<usize>();
+        }
+        errors
+    }
+
+    /// Recompute every hunk's "@@" counts and post side start lines
+    /// from the hunk bodies (a la "recountdiff"), repairing headers
+    /// broken by manual editing.
+    pub fn recount(&mut self) {
+        let mut delta = 0_isize;
+        for hunk in self.hunks.iter_mut() {
+            delta = hunk.recount(delta);
+        }
+    }
+}
+
+/// Could the line at `index` be part of the body of a unified diff
+/// hunk?  A "---" line followed by a "+++" line is taken to start the
+/// next diff's header rather than quote removed text.
+fn is_hunk_body_line_at(lines: &Lines, index: usize) -> bool {
+    let line = &lines[index];
+    if line.starts_with("--- ") {
+        index + 1 >= lines.len() || !lines[index + 1].starts_with("+++ ")
+    } else {
+        line.starts_with(['-', '+', ' ', '\\']) || **line == "\n"
+    }
+}
+
+/// Rewrite the "@@" lines in the text of a unified format patch so
+/// that their counts and post side start lines match the hunk bodies
+/// that follow them (a la "recountdiff").  This makes patches that
+/// have been edited by hand acceptable to the (strict) parser which
+/// trusts the counts it is given.
+pub fn recount_lines(lines: &Lines) -> Lines {
+    let mut output: Lines = Vec::with_capacity(lines.len());
+    let mut delta = 0_isize;
+    let mut index = 0_usize;
+    while index < lines.len() {
+        if let Ok(Some(header)) = parse_hunk_header(&lines[index]) {
+            let mut end = index + 1;
+            while end < lines.len() && is_hunk_body_line_at(lines, end) {
+                end += 1;
+            }
+            let (ante_length, post_length) = count_hunk_body(&lines[index + 1..end]);
+            let ante_start = header.ante_start;
+            let post_start = recounted_post_start(ante_start, delta, ante_length, post_length);
+            output.push(Arc::new(format!(
+                "@@ -{},{} +{},{} @@{}",
+                ante_start, ante_length, post_start, post_length, header.tail
+            )));
+            output.extend(lines[index + 1..end].iter().map(Arc::clone));
+            delta += post_length as isize - ante_length as isize;
+            index = end;
+        } else {
+            if lines[index].starts_with("--- ") || lines[index].starts_with("diff ") {
+                delta = 0;
+            }
+            output.push(Arc::clone(&lines[index]));
+            index += 1;
+        }
+    }
+    output
+}
+
+pub struct UnifiedDiffParser {
+    ante_file_cre: &'static Regex,
+    post_file_cre: &'static Regex,
+    strict_counts: bool,
+}
+
+impl UnifiedDiffParser {
+    /// A process wide instance for callers that don't want to carry
+    /// their own.
+    pub fn shared() -> &'static UnifiedDiffParser {
+        static SHARED: OnceLock<UnifiedDiffParser> = OnceLock::new();
+        SHARED.get_or_init(UnifiedDiffParser::new)
+    }
+
+    /// Cross-check every "@@" line's declared counts against the body
+    /// that follows it, rejecting hunks whose bodies carry on past the
+    /// declared counts with `DiffParseError::CountMismatch`.  The
+    /// default (lenient) behaviour stops counting as soon as the
+    /// declared counts are satisfied, silently leaving any surplus
+    /// body lines behind.
+    pub fn with_strict_counts(mut self) -> UnifiedDiffParser {
+        self.strict_counts = true;
+        self
+    }
+}
+
+impl TextDiffParser<UnifiedDiffHunk> for UnifiedDiffParser {
+    fn new() -> UnifiedDiffParser {
+        // The regexes are compiled once per process, not per parser.
+        static ANTE_FILE_CRE: OnceLock<Regex> = OnceLock::new();
+        static POST_FILE_CRE: OnceLock<Regex> = OnceLock::new();
+        let ante_file_cre = ANTE_FILE_CRE.get_or_init(|| {
+            let e_ts_re_str = format!(
+                "({}|{}|{})",
+                TIMESTAMP_RE_STR, ALT_TIMESTAMP_RE_STR, EPOCH_TIMESTAMP_RE_STR
+            );
+            let e = format!(r"^--- ({})(\s+{})?(.*)(\n)?$", PATH_RE_STR, e_ts_re_str);
+            Regex::new(&e).unwrap()
+        });
+        let post_file_cre = POST_FILE_CRE.get_or_init(|| {
+            let e_ts_re_str = format!(
+                "({}|{}|{})",
+                TIMESTAMP_RE_STR, ALT_TIMESTAMP_RE_STR, EPOCH_TIMESTAMP_RE_STR
+            );
+            let e = format!(r"^\+\+\+ ({})(\s+{})?(.*)(\n)?$", PATH_RE_STR, e_ts_re_str);
+            Regex::new(&e).unwrap()
+        });
+        UnifiedDiffParser {
+            ante_file_cre,
+            post_file_cre,
+            strict_counts: false,
+        }
+    }
+
+    fn diff_format(&self) -> DiffFormat {
+        DiffFormat::Unified
+    }
+
+    fn ante_file_rec<'t>(&self, line: &'t Line) -> Option<Captures<'t>> {
+        self.ante_file_cre.captures(line)
+    }
+
+    fn post_file_rec<'t>(&self, line: &'t Line) -> Option<Captures<'t>> {
+        self.post_file_cre.captures(line)
+    }
+
+    fn get_hunk_at(&self, lines: &Lines, index: usize) -> DiffParseResult<Option<UnifiedDiffHunk>> {
+        let header = match parse_hunk_header(&lines[index])? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let ante_chunk = UnifiedDiffChunk {
+            start_line_num: header.ante_start,
+            length: header.ante_length.unwrap_or(1),
+        };
+        let post_chunk = UnifiedDiffChunk {
+            start_line_num: header.post_start,
+            length: header.post_length.unwrap_or(1),
+        };
+        let mut index = index + 1;
+        let start_context = index;
+        let mut ante_count = 0;
+        let mut post_count = 0;
+        while ante_count < ante_chunk.length || post_count < post_chunk.length {
+            if index >= lines.len() {
+                return Err(DiffParseError::UnexpectedEndHunk(
+                    DiffFormat::Unified,
+                    index,
+                ));
+            }
+            if lines[index].starts_with('-') {
+                ante_count += 1
+            } else if lines[index].starts_with('+') {
+                post_count += 1
+            } else if lines[index].starts_with(' ') || *lines[index] == "\n" {
+                ante_count += 1;
+                post_count += 1
+            } else if !lines[index].starts_with('\\') {
+                return Err(DiffParseError::SyntaxError(DiffFormat::Unified, index));
+            }
+            index += 1;
+        }
+        if index < lines.len() && lines[index].starts_with('\\') {
+            index += 1;
+        }
+        if self.strict_counts && index < lines.len() {
+            let line = &lines[index];
+            let continues = if line.starts_with("--- ") {
+                // A "---"/"+++" pair starts the next diff: anything
+                // else looking like removed text is surplus body.
+                index + 1 >= lines.len() || !lines[index + 1].starts_with("+++ ")
+            } else {
+                line.starts_with(['-', '+', ' '])
+            };
+            if continues {
+                return Err(DiffParseError::CountMismatch(
+                    DiffFormat::Unified,
+                    start_context - 1,
+                ));
+            }
+        }
+        let hunk = UnifiedDiffHunk {
+            start_index: start_context - 1,
+            lines: lines[start_context - 1..index].to_vec(),
+            ante_chunk,
+            post_chunk,
+        };
+        Ok(Some(hunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abstract_diff::ApplyOptions;
+    use crate::lines::LinesIfce;
+
+    #[test]
+    fn parse_diff_from_test_file() {
+        let lines = Lines::read("test_diffs/test_1.diff").unwrap();
+        let parser = UnifiedDiffParser::new();
+        let result = parser.get_diff_at(&lines, 0);
+        assert!(matches!(result, Ok(None)));
+        let result = parser.get_diff_at(&lines, 2);
+        let diff = result.unwrap().unwrap();
+        assert_eq!(diff.diff_format(), DiffFormat::Unified);
+        assert_eq!(diff.hunks.len(), 1);
+        assert_eq!(diff.len(), 10);
+        assert_eq!(
+            diff.header.ante_pat.file_path.to_string_lossy(),
+            "a/src/lib.rs"
+        );
+        let result = parser.get_diff_at(&lines, 14);
+        let diff = result.unwrap().unwrap();
+        assert!(diff.hunks.len() > 1);
+    }
+
+    #[test]
+    fn strict_counts_reject_understated_hunk_headers() {
+        // The header claims two lines a side but the body has three.
+        let bad_text = "--- a/x\n+++ b/x\n@@ -1,2 +1,2 @@\n a\n-b\n+B\n c\n";
+        let lines = Lines::from_string(bad_text);
+        // The lenient parser stops counting when the counts are met.
+        let diff = UnifiedDiffParser::new()
+            .get_diff_at(&lines, 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(diff.hunks[0].lines.len(), 4);
+        let strict = UnifiedDiffParser::new().with_strict_counts();
+        assert!(matches!(
+            strict.get_diff_at(&lines, 0),
+            Err(DiffParseError::CountMismatch(DiffFormat::Unified, 2))
+        ));
+        // Honest counts still parse, back to back diffs included.
+        let good_text = "--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                         --- a/y\n+++ b/y\n@@ -1,1 +1,1 @@\n-p\n+P\n";
+        let patch = crate::patch::PatchParser::new()
+            .with_strict_counts()
+            .parse_string(good_text)
+            .unwrap();
+        assert_eq!(patch.diff_pluses().len(), 2);
+        assert!(crate::patch::PatchParser::new()
+            .with_strict_counts()
+            .parse_string(bad_text)
+            .is_err());
+    }
+
+    #[test]
+    fn overlapping_hunks_are_detected() {
+        use crate::text_diff::HunkOrderViolation;
+        let good_text = "--- a/x\n+++ b/x\n\
+                         @@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                         @@ -10,3 +10,3 @@\n p\n-q\n+Q\n r\n";
+        let parser = UnifiedDiffParser::new();
+        let diff = parser
+            .get_diff_at(&Lines::from_string(good_text), 0)
+            .unwrap()
+            .unwrap();
+        assert!(diff.hunk_order_violations().is_empty());
+        // The second hunk doubles back over the first on both sides.
+        let bad_text = "--- a/x\n+++ b/x\n\
+                        @@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                        @@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n";
+        let diff = parser
+            .get_diff_at(&Lines::from_string(bad_text), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            diff.hunk_order_violations(),
+            vec![
+                HunkOrderViolation::AnteOverlap(0, 1),
+                HunkOrderViolation::PostOverlap(0, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn hunk_line_statistics() {
+        let diff_text = "--- before.txt\n+++ after.txt\n\
+                         @@ -1,4 +1,3 @@\n a\n-b\n-c\n+B\n d\n";
+        let diff_lines = Lines::from_string(diff_text);
+        let parser = UnifiedDiffParser::new();
+        let diff = parser.get_diff_at(&diff_lines, 0).unwrap().unwrap();
+        let hunk = &diff.hunks[0];
+        assert_eq!(hunk.added_count(), 1);
+        assert_eq!(hunk.removed_count(), 2);
+        assert_eq!(hunk.context_count(), 2);
+        assert_eq!(hunk.line_kinds()[0], HunkLineKind::Header);
+    }
+
+    #[test]
+    fn hunk_header_edge_cases() {
+        // Single line chunks may omit their ",1" lengths.
+        let diff_text = "--- a/x\n+++ b/x\n@@ -2 +2 @@ fn tail()\n-b\n+B\n";
+        let parser = UnifiedDiffParser::new();
+        let diff = parser
+            .get_diff_at(&Lines::from_string(diff_text), 0)
+            .unwrap()
+            .unwrap();
+        let hunk = &diff.hunks[0];
+        assert_eq!(hunk.ante_chunk.start_line_num, 2);
+        assert_eq!(hunk.ante_chunk.length, 1);
+        assert_eq!(hunk.post_chunk.length, 1);
+        // Near misses must not be taken for hunk headers.
+        for text in [
+            "@@-1,3 +1,3 @@\n",
+            "@@ -1,3 +1,3@@\n",
+            "@@ -1,3 1,3 @@\n",
+            "@@ -1, +1,3 @@\n",
+            "@@ -1,3 +1,3\n",
+        ] {
+            let lines = Lines::from_string(text);
+            assert!(parser.get_hunk_at(&lines, 0).unwrap().is_none(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn recount_repairs_edited_headers() {
+        // Counts and the second post start are wrong after "editing".
+        let edited = "--- a/x\n+++ b/x\n\
+                      @@ -1,3 +1,3 @@\n a\n+A\n b\n c\n\
+                      @@ -8,3 +9,4 @@ heading\n h\n-i\n+I\n j\n";
+        let lines = recount_lines(&Lines::from_string(edited));
+        assert_eq!(*lines[2], "@@ -1,3 +1,4 @@\n");
+        assert_eq!(*lines[7], "@@ -8,3 +9,3 @@ heading\n");
+        let parser = UnifiedDiffParser::new();
+        let diff = parser.get_diff_at(&lines, 0).unwrap().unwrap();
+        assert_eq!(diff.len(), lines.len());
+        let target = Lines::from_string("a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n");
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&target, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(
+            result.is_successful(),
+            "{}",
+            String::from_utf8_lossy(&err_w)
+        );
+        assert_eq!(
+            *result.lines(),
+            Lines::from_string("a\nA\nb\nc\nd\ne\nf\ng\nh\nI\nj\n")
+        );
+    }
+
+    #[test]
+    fn recount_parsed_diff() {
+        let diff_text = "--- a/x\n+++ b/x\n\
+                         @@ -1,3 +1,4 @@\n a\n+A\n b\n c\n\
+                         @@ -8,3 +8,3 @@\n h\n-i\n+I\n j\n";
+        let parser = UnifiedDiffParser::new();
+        let mut diff = parser
+            .get_diff_at(&Lines::from_string(diff_text), 0)
+            .unwrap()
+            .unwrap();
+        diff.recount();
+        assert_eq!(*diff.hunks[1].lines[0], "@@ -8,3 +9,3 @@\n");
+        assert_eq!(diff.hunks[1].post_chunk.start_line_num, 9);
+    }
+
+    #[test]
+    fn select_hunks_renumbers_offsets() {
+        let diff_text = "--- a/x\n+++ b/x\n\
+                         @@ -1,3 +1,4 @@\n a\n+A\n b\n c\n\
+                         @@ -8,3 +9,3 @@\n h\n-i\n+I\n j\n";
+        let parser = UnifiedDiffParser::new();
+        let diff = parser
+            .get_diff_at(&Lines::from_string(diff_text), 0)
+            .unwrap()
+            .unwrap();
+        let selected = diff.select_hunks(&[1]);
+        assert_eq!(selected.hunks.len(), 1);
+        assert_eq!(*selected.hunks[0].lines[0], "@@ -8,3 +8,3 @@\n");
+        assert_eq!(selected.len(), 2 + selected.hunks[0].len());
+        let target = Lines::from_string("a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n");
+        let mut err_w = Vec::new();
+        let result = selected
+            .apply_to_lines(&target, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(
+            result.is_successful(),
+            "{}",
+            String::from_utf8_lossy(&err_w)
+        );
+        assert_eq!(
+            *result.lines(),
+            Lines::from_string("a\nb\nc\nd\ne\nf\ng\nh\nI\nj\n")
+        );
+    }
+
+    #[test]
+    fn merge_adjacent_hunks() {
+        let diff_text = "--- a/x\n+++ b/x\n\
+                         @@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                         @@ -3,3 +3,3 @@\n c\n-d\n+D\n e\n\
+                         @@ -9,2 +9,2 @@\n i\n-j\n+J\n";
+        let parser = UnifiedDiffParser::new();
+        let mut diff = parser
+            .get_diff_at(&Lines::from_string(diff_text), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(diff.hunks.len(), 3);
+        diff.merge_adjacent_hunks();
+        assert_eq!(diff.hunks.len(), 2);
+        assert_eq!(*diff.hunks[0].lines[0], "@@ -1,5 +1,5 @@\n");
+        let target = Lines::from_string("a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n");
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&target, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(
+            result.is_successful(),
+            "{}",
+            String::from_utf8_lossy(&err_w)
+        );
+        assert_eq!(
+            *result.lines(),
+            Lines::from_string("a\nB\nc\nD\ne\nf\ng\nh\ni\nJ\n")
+        );
+    }
+
+    #[test]
+    fn reject_stream_collects_failed_hunks() {
+        let diff_text = "--- a/x\n+++ b/x\n\
+                         @@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\
+                         @@ -8,3 +8,3 @@\n h\n-i\n+I\n j\n";
+        let diff_lines = Lines::from_string(diff_text);
+        let parser = UnifiedDiffParser::new();
+        let diff = parser.get_diff_at(&diff_lines, 0).unwrap().unwrap();
+        let target = Lines::from_string("a\nb\nc\nd\ne\nf\ng\nh\nX\nj\n");
+        let options = ApplyOptions::default().structured_conflicts(true);
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&target, &mut err_w, None, &options)
+            .unwrap();
+        assert!(!result.is_successful());
+        assert_eq!(result.rejected_hunks().len(), 1);
+        let stream =
+            format_reject_stream(std::path::Path::new("x"), result.rejected_hunks()).unwrap();
+        assert_eq!(stream, "--- x\n+++ x\n@@ -8,3 +8,3 @@\n h\n-i\n+I\n j\n");
+        // The stream must itself be parseable so that it can be
+        // applied once the target has been fixed up.
+        let reject = parser
+            .get_diff_at(&Lines::from_string(&stream), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(reject.hunks.len(), 1);
+        assert!(format_reject_stream(std::path::Path::new("x"), &[]).is_none());
+    }
+
+    #[test]
+    fn dev_null_headers_mark_creation_and_deletion() {
+        let parser = UnifiedDiffParser::new();
+        let creation = parser
+            .get_diff_at(
+                &Lines::from_string("--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1,1 @@\n+hello\n"),
+                0,
+            )
+            .unwrap()
+            .unwrap();
+        assert!(creation.is_creation());
+        assert!(!creation.is_deletion());
+        let deletion = parser
+            .get_diff_at(
+                &Lines::from_string("--- a/old.txt\n+++ /dev/null\n@@ -1,1 +0,0 @@\n-bye\n"),
+                0,
+            )
+            .unwrap()
+            .unwrap();
+        assert!(!deletion.is_creation());
+        assert!(deletion.is_deletion());
+        let modification = parser
+            .get_diff_at(
+                &Lines::from_string("--- a/x\n+++ b/x\n@@ -1,1 +1,1 @@\n-a\n+A\n"),
+                0,
+            )
+            .unwrap()
+            .unwrap();
+        assert!(!modification.is_creation());
+        assert!(!modification.is_deletion());
+    }
+
+    #[test]
+    fn no_newline_at_end_of_file() {
+        let diff_text = "--- a/x\n+++ b/x\n\
+                         @@ -1,3 +1,3 @@\n a\n b\n-c\n+C\n\\ No newline at end of file\n";
+        let parser = UnifiedDiffParser::new();
+        let diff = parser
+            .get_diff_at(&Lines::from_string(diff_text), 0)
+            .unwrap()
+            .unwrap();
+        let hunk = diff.hunks[0].get_abstract_diff_hunk();
+        assert!(!hunk.ante_chunk().ends_without_newline());
+        assert!(hunk.post_chunk().ends_without_newline());
+        let lines = Lines::from_string("a\nb\nc\n");
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(result.into_string(), "a\nb\nC");
+        // Reverse application restores the final newline.
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(
+                &Lines::from_string("a\nb\nC"),
+                &mut err_w,
+                None,
+                &ApplyOptions::default().reverse(true),
+            )
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(result.into_string(), "a\nb\nc\n");
+        // Rewriting the hunk from its abstract form regenerates the
+        // marker.
+        let regenerated = UnifiedDiffHunk::from(&diff.hunks[0].get_abstract_diff_hunk());
+        assert_eq!(
+            **regenerated.lines.last().unwrap(),
+            "\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn parse_and_apply_diff() {
+        let diff_text = "--- before.txt\t2019-03-01 10:01:00.000000000 +1100\n\
+                         +++ after.txt\t2019-03-01 10:02:00.000000000 +1100\n\
+                         @@ -1,5 +1,5 @@\n a\n b\n-c\n+C\n d\n e\n";
+        let diff_lines = Lines::from_string(diff_text);
+        let parser = UnifiedDiffParser::new();
+        let diff = parser.get_diff_at(&diff_lines, 0).unwrap().unwrap();
+        assert_eq!(diff.len(), diff_lines.len());
+        let lines = Lines::from_string("a\nb\nc\nd\ne\n");
+        let mut err_w = Vec::new();
+        let result = diff
+            .apply_to_lines(&lines, &mut err_w, None, &ApplyOptions::default())
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(*result.lines(), Lines::from_string("a\nb\nC\nd\ne\n"));
+        let result = diff
+            .apply_to_lines(
+                result.lines(),
+                &mut err_w,
+                None,
+                &ApplyOptions::default().reverse(true),
+            )
+            .unwrap();
+        assert!(result.is_successful());
+        assert_eq!(*result.lines(), lines);
+    }
+
+    #[test]
+    fn quoted_header_paths_decode_to_real_names() {
+        let text = "--- \"a/pfad/\\303\\244.txt\"\n+++ \"b/pfad/\\303\\244.txt\"\n\
+                    @@ -1,1 +1,1 @@\n-a\n+b\n";
+        let diff = UnifiedDiffParser::new()
+            .get_diff_at(&Lines::from_string(text), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            diff.header().ante_pat.file_path,
+            std::path::Path::new("a/pfad/\u{e4}.txt")
+        );
+    }
+
+    #[test]
+    fn additional_header_timestamp_forms_are_accepted() {
+        use std::time::{Duration, UNIX_EPOCH};
+        let text = "--- a/x\t@1546855200\n+++ b/x\t2019-01-07 10:00\n@@ -1,1 +1,1 @@\n-a\n+b\n";
+        let diff = UnifiedDiffParser::new()
+            .get_diff_at(&Lines::from_string(text), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            diff.header().ante_pat.time(),
+            Some(UNIX_EPOCH + Duration::from_secs(1_546_855_200))
+        );
+        assert!(diff.header().post_pat.time().is_some());
+        // A tab separated label in place of a timestamp doesn't stop
+        // the header parsing.
+        let text = "--- a/x\t(revision 42)\n+++ b/x\n@@ -1,1 +1,1 @@\n-a\n+b\n";
+        let diff = UnifiedDiffParser::new()
+            .get_diff_at(&Lines::from_string(text), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            diff.header().ante_pat.file_path,
+            std::path::Path::new("a/x")
+        );
+        assert_eq!(diff.header().ante_pat.time_stamp, None);
+    }
+
+    #[test]
+    fn parsed_diffs_are_inspectable() {
+        let text = "--- a/x\t2019-01-01 10:00:00\n+++ b/x\n@@ -3,2 +5,3 @@\n a\n-b\n+B\n+C\n";
+        let diff = UnifiedDiffParser::new()
+            .get_diff_at(&Lines::from_string(text), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            diff.header().ante_pat.file_path,
+            std::path::Path::new("a/x")
+        );
+        assert_eq!(
+            diff.header().ante_pat.time_stamp.as_deref(),
+            Some("2019-01-01 10:00:00")
+        );
+        assert_eq!(diff.hunks().len(), 1);
+        let hunk = &diff.hunks()[0];
+        assert_eq!(hunk.ante_chunk().start_line_num(), 3);
+        assert_eq!(hunk.ante_chunk().length(), 2);
+        assert_eq!(hunk.post_chunk().start_line_num(), 5);
+        assert_eq!(hunk.post_chunk().length(), 3);
+    }
+
+    #[test]
+    fn section_headings_survive_the_abstract_round_trip() {
+        let text = "--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@ fn main()\n a\n-b\n+B\n c\n";
+        let parser = UnifiedDiffParser::new();
+        let diff = parser
+            .get_diff_at(&Lines::from_string(text), 0)
+            .unwrap()
+            .unwrap();
+        let abstract_hunk = diff.hunks[0].get_abstract_diff_hunk();
+        assert_eq!(abstract_hunk.heading(), Some(" fn main()"));
+        let regenerated = UnifiedDiffHunk::from(&abstract_hunk);
+        assert_eq!(*regenerated.lines[0], *"@@ -1,3 +1,3 @@ fn main()\n");
+        // A headingless hunk regenerates without trailing text.
+        let text = "--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n";
+        let diff = parser
+            .get_diff_at(&Lines::from_string(text), 0)
+            .unwrap()
+            .unwrap();
+        let regenerated = UnifiedDiffHunk::from(&diff.hunks[0].get_abstract_diff_hunk());
+        assert_eq!(*regenerated.lines[0], *"@@ -1,3 +1,3 @@\n");
+    }
+
+    #[test]
+    fn tagged_iteration_numbers_both_sides() {
+        let text =
+            "--- a/x\n+++ b/x\n@@ -3,3 +3,3 @@\n a\n-b\n+B\n c\n\\ No newline at end of file\n";
+        let parser = UnifiedDiffParser::new();
+        let diff = parser
+            .get_diff_at(&Lines::from_string(text), 0)
+            .unwrap()
+            .unwrap();
+        let tagged: Vec<(HunkLineKind, &str, Option<usize>, Option<usize>)> = diff.hunks[0]
+            .iter_tagged()
+            .map(|tagged| {
+                (
+                    tagged.kind,
+                    tagged.line.as_str(),
+                    tagged.ante_line_num,
+                    tagged.post_line_num,
+                )
+            })
+            .collect();
+        assert_eq!(
+            tagged,
+            vec![
+                (HunkLineKind::Context, " a\n", Some(3), Some(3)),
+                (HunkLineKind::Removed, "-b\n", Some(4), None),
+                (HunkLineKind::Added, "+B\n", None, Some(4)),
+                (HunkLineKind::Context, " c\n", Some(5), Some(5)),
+                (
+                    HunkLineKind::Annotation,
+                    "\\ No newline at end of file\n",
+                    None,
+                    None
+                ),
+            ]
+        );
+    }
+}